@@ -0,0 +1,1589 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Tracking the assets provided by the document.
+//!
+//! Files copied into the output tree with `tdux:provideFile` and
+//! `tdux:provideDirectory` are recorded here, so that templates can refer to
+//! them with the Tera functions registered by [`register_functions`]:
+//!
+//! - `asset_url(path="css/extra.css")` returns the URL of the asset relative
+//!   to the page being rendered
+//! - `inline_asset(path="css/extra.css")` returns the contents of the asset,
+//!   so that small files such as critical CSS can be inlined into the page
+//!
+//! Every request to provide a file, whether it comes from the document or
+//! from the application driving the engine, is first claimed with
+//! [`Assets::claim`]. This is what ensures that a file provided by a
+//! `tdux:provideFile` seen during initialization, and then seen again in the
+//! main stream, is only copied once, and that two different sources can't
+//! quietly overwrite each other's output.
+//!
+//! Claimed assets aren't written right away. Whatever the request, and
+//! whenever it's made, the copy is queued with [`Assets::defer`], and all
+//! of the queued copies are made at the same point: just before the next
+//! page is rendered, so that its template can use them, or at the end of the
+//! run. The sources of the copies are only read then, so they must still
+//! exist at that point, which may be the end of the run.
+//!
+//! The font files written by the engine are recorded here too, so that the
+//! asset manifest describes the whole output. A manifest from an earlier run
+//! can be handed back to the engine as an [`AssetManifest`], so that it makes
+//! the same decisions about fonts again; see
+//! [`crate::Spx2HtmlEngine::precomputed_assets`].
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tectonic_errors::prelude::*;
+use tera::{Tera, Value};
+
+use crate::{
+    font::{GlyphAlternateMapping, GlyphId},
+    output::OutputTree,
+    Spx2HtmlError,
+};
+
+/// The largest asset that can be inlined into a page, in bytes.
+pub const MAX_INLINE_ASSET_SIZE: u64 = 256 * 1024;
+
+#[derive(Debug, Default)]
+struct AssetState {
+    /// The normalized paths of the provided assets, relative to the output
+    /// root.
+    provided: HashSet<String>,
+
+    /// The path from the page currently being rendered to the output root.
+    rel_top: String,
+
+    /// The name of the template currently being rendered.
+    template: String,
+
+    /// The origins of the claimed assets, keyed by their normalized paths.
+    claims: HashMap<String, AssetOrigin>,
+
+    /// The font files written by the engine, keyed by their paths.
+    fonts: HashMap<String, AssetOrigin>,
+
+    /// The copies of claimed assets that haven't been made yet, in order.
+    pending: Vec<PendingCopy>,
+}
+
+/// The copy of a claimed asset into the output tree, waiting to be made.
+#[derive(Debug)]
+pub struct PendingCopy {
+    /// The path of the copy, relative to the output root.
+    pub dest: String,
+
+    /// Where the contents come from.
+    pub source: CopySource,
+}
+
+/// Where the contents of a pending copy come from.
+#[derive(Debug)]
+pub enum CopySource {
+    /// An input, opened through the I/O layer, and looked for along the
+    /// template search path if provided files are searched for.
+    Input(String),
+
+    /// A file in a provided directory, opened through the I/O layer if it
+    /// has it, and from the filesystem otherwise.
+    DirectoryFile(String),
+
+    /// A file on the filesystem, provided by the application.
+    Path(PathBuf),
+
+    /// Contents that are already at hand, such as fetched or decoded data.
+    Bytes(Vec<u8>),
+}
+
+/// Where a provided asset comes from.
+///
+/// The serialized names of the variants and their fields are part of the
+/// asset manifest format, so they're pinned explicitly rather than derived
+/// from the Rust names.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "origin")]
+pub enum AssetOrigin {
+    /// The application driving the engine.
+    #[serde(rename = "application")]
+    Application,
+
+    /// The engine itself.
+    #[serde(rename = "engine")]
+    Engine,
+
+    /// An input copied by `tdux:provideFile`.
+    #[serde(rename = "copy")]
+    Copy {
+        /// The name of the input.
+        #[serde(rename = "src")]
+        src: String,
+    },
+
+    /// An input copied as part of a directory by `tdux:provideDirectory`.
+    #[serde(rename = "directory_copy")]
+    DirectoryCopy {
+        /// The name of the input.
+        #[serde(rename = "src")]
+        src: String,
+    },
+
+    /// A remote URL fetched for `tdux:provideFile`.
+    #[serde(rename = "remote_copy")]
+    RemoteCopy {
+        /// The URL.
+        #[serde(rename = "url")]
+        url: String,
+
+        /// The SHA-256 digest of the fetched contents, in hexadecimal.
+        #[serde(rename = "digest")]
+        digest: String,
+    },
+
+    /// Data given in the document with `tdux:provideData`.
+    #[serde(rename = "data")]
+    Data {
+        /// The SHA-256 digest of the data, in hexadecimal.
+        #[serde(rename = "digest")]
+        digest: String,
+    },
+
+    /// A font file loaded by the document.
+    #[serde(rename = "font_file")]
+    FontFile {
+        /// The name of the input that the font was loaded from.
+        #[serde(rename = "src")]
+        src: String,
+
+        /// The faces of the file that the document used.
+        #[serde(rename = "faces")]
+        faces: Vec<FontFace>,
+    },
+
+    /// A variant of a font, with a customized character map.
+    #[serde(rename = "font_variant")]
+    FontVariant {
+        /// The CSS font family of the face that the variant derives from.
+        #[serde(rename = "family")]
+        family: String,
+
+        /// The index of the variant, starting at zero.
+        #[serde(rename = "index")]
+        index: usize,
+
+        /// The glyphs that the character map of the variant maps to, with the
+        /// characters that map to them.
+        #[serde(rename = "glyphs")]
+        glyphs: Vec<(GlyphId, char)>,
+    },
+
+    /// An origin that this version of the engine doesn't know about, read
+    /// from a manifest written by a newer one. Such entries are ignored, and
+    /// don't survive being written back.
+    #[serde(rename = "unrecognized", other)]
+    Unrecognized,
+}
+
+/// A face of a font file, as recorded in the asset manifest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FontFace {
+    /// The index of the face within the file.
+    #[serde(rename = "index")]
+    pub index: u32,
+
+    /// The CSS font family generated for the face.
+    #[serde(rename = "family")]
+    pub family: String,
+}
+
+impl fmt::Display for AssetOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetOrigin::Application => f.write_str("the application"),
+            AssetOrigin::Engine => f.write_str("the engine"),
+            AssetOrigin::Copy { src } => write!(f, "`tdux:provideFile` of `{}`", src),
+            AssetOrigin::DirectoryCopy { src } => {
+                write!(f, "`tdux:provideDirectory` of `{}`", src)
+            }
+            AssetOrigin::RemoteCopy { url, .. } => write!(f, "`tdux:provideFile` of `{}`", url),
+            AssetOrigin::Data { digest } => {
+                write!(f, "`tdux:provideData` of data with digest {}", digest)
+            }
+            AssetOrigin::FontFile { src, .. } => write!(f, "the font `{}`", src),
+            AssetOrigin::FontVariant { family, index, .. } => {
+                write!(f, "variant {} of font family `{}`", index, family)
+            }
+            AssetOrigin::Unrecognized => f.write_str("an unrecognized origin"),
+        }
+    }
+}
+
+/// An entry of the asset manifest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    /// The path of the file, relative to the output root.
+    #[serde(rename = "path")]
+    pub path: String,
+
+    /// Where the file comes from.
+    #[serde(flatten)]
+    pub origin: AssetOrigin,
+}
+
+/// The version of the asset manifest format written by this version of the
+/// engine.
+///
+/// Version 0 manifests are bare arrays of entries. From version 1 on, the
+/// entries are wrapped in an object that records the version.
+///
+/// The format only ever grows: names are never changed or removed, so that
+/// manifests written by older engines can always be read. Going the other
+/// way, fields that aren't known are ignored rather than rejected, and so are
+/// entries with unknown origins, so that an older engine can read the part
+/// of a newer manifest that it understands. That's why the manifest types
+/// deliberately don't use `#[serde(deny_unknown_fields)]`.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// The asset manifest of a run of the engine.
+///
+/// This is the data written to the file named with
+/// [`crate::Spx2HtmlEngine::asset_manifest`]. It can be deserialized from
+/// that file, in any version of the format, and handed to
+/// [`crate::Spx2HtmlEngine::precomputed_assets`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(from = "ManifestRepr")]
+pub struct AssetManifest {
+    /// The version of the format, which is [`MANIFEST_VERSION`] for
+    /// manifests made by the engine, and the version that a manifest was read
+    /// in otherwise.
+    #[serde(rename = "version")]
+    pub version: u32,
+
+    /// The entries of the manifest, in order of their paths.
+    #[serde(rename = "entries")]
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl AssetManifest {
+    /// Serialize the manifest as it's written to a file.
+    ///
+    /// The JSON is pretty-printed, with the keys of each object in sorted
+    /// order, so that the manifests of different runs can be compared with
+    /// ordinary diffs.
+    pub fn to_json(&self) -> Result<String> {
+        let value = atry!(
+            tera::to_value(self);
+            ["failed to serialize the asset manifest"]
+        );
+        Ok(format!("{:#}\n", sort_keys(value)))
+    }
+}
+
+/// Sort the keys of the objects in *value*, whether or not serde_json
+/// preserves their order.
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut items: Vec<_> = map.into_iter().collect();
+            items.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(items.into_iter().map(|(k, v)| (k, sort_keys(v))).collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
+}
+
+impl Default for AssetManifest {
+    fn default() -> Self {
+        AssetManifest {
+            version: MANIFEST_VERSION,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// The serialized forms of the asset manifest, in all versions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestRepr {
+    /// Version 0.
+    Bare(Vec<ManifestEntry>),
+
+    /// Version 1 and later.
+    Versioned {
+        #[serde(rename = "version")]
+        version: u32,
+
+        #[serde(rename = "entries")]
+        entries: Vec<ManifestEntry>,
+    },
+}
+
+impl From<ManifestRepr> for AssetManifest {
+    fn from(repr: ManifestRepr) -> Self {
+        match repr {
+            ManifestRepr::Bare(entries) => AssetManifest {
+                version: 0,
+                entries,
+            },
+            ManifestRepr::Versioned { version, entries } => AssetManifest { version, entries },
+        }
+    }
+}
+
+/// The decisions about fonts recorded in an asset manifest.
+#[derive(Debug, Default)]
+pub(crate) struct FontPins {
+    /// The font data keys, keyed by input name and face index.
+    keys: HashMap<(String, u32), usize>,
+
+    /// The alternative mappings of glyphs, keyed by font data key.
+    alternatives: HashMap<usize, Vec<(GlyphId, GlyphAlternateMapping)>>,
+}
+
+/// Get the font data key from a CSS font family generated by the engine.
+fn parse_family(family: &str) -> Option<usize> {
+    family.strip_prefix("tdux")?.parse().ok()
+}
+
+impl FontPins {
+    /// Collect the decisions about fonts recorded in *manifest*. Returns None
+    /// if it doesn't list any font faces.
+    ///
+    /// Font families that the engine couldn't have generated are ignored.
+    pub fn new(manifest: &AssetManifest) -> Option<Self> {
+        let mut pins = FontPins::default();
+
+        for entry in &manifest.entries {
+            match &entry.origin {
+                AssetOrigin::FontFile { src, faces } => {
+                    for face in faces {
+                        if let Some(fd_key) = parse_family(&face.family) {
+                            pins.keys.insert((src.clone(), face.index), fd_key);
+                        }
+                    }
+                }
+
+                AssetOrigin::FontVariant {
+                    family,
+                    index,
+                    glyphs,
+                } => {
+                    if let Some(fd_key) = parse_family(family) {
+                        pins.alternatives
+                            .entry(fd_key)
+                            .or_default()
+                            .extend(glyphs.iter().map(|(glyph, usv)| {
+                                (
+                                    *glyph,
+                                    GlyphAlternateMapping {
+                                        usv: *usv,
+                                        alternate_map_index: *index,
+                                    },
+                                )
+                            }));
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        if pins.keys.is_empty() {
+            None
+        } else {
+            Some(pins)
+        }
+    }
+
+    /// Get the font data key recorded for a face of a font.
+    pub fn key(&self, name: &str, face_index: u32) -> Option<usize> {
+        self.keys.get(&(name.to_owned(), face_index)).copied()
+    }
+
+    /// Get the alternative mappings recorded for some font data.
+    pub fn alternatives(&self, fd_key: usize) -> &[(GlyphId, GlyphAlternateMapping)] {
+        self.alternatives
+            .get(&fd_key)
+            .map(|a| a.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// An asset referenced by a template function.
+struct AssetRef {
+    /// The normalized path of the asset.
+    path: String,
+
+    /// The path from the current page to the output root.
+    rel_top: String,
+
+    /// The name of the current template.
+    template: String,
+}
+
+/// The assets known to the engine, shared with the Tera functions.
+#[derive(Clone, Debug)]
+pub struct Assets {
+    state: Arc<Mutex<AssetState>>,
+
+    /// The output tree that the assets are written to.
+    out: OutputTree,
+}
+
+/// Normalize a path relative to the output root, so that equivalent
+/// spellings such as `css/a.css` and `/css//a.css` compare equal.
+pub fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Assets {
+    /// Create an empty set of assets, to be written to *out*.
+    pub fn new(out: OutputTree) -> Self {
+        Assets {
+            state: Default::default(),
+            out,
+        }
+    }
+
+    /// Claim the output path *dest* on behalf of *origin*.
+    ///
+    /// Returns false if the same origin has already claimed the path, in
+    /// which case the asset shouldn't be provided again. It's an error for a
+    /// different origin to have claimed it.
+    pub fn claim(&self, dest: &str, origin: AssetOrigin) -> Result<bool, Spx2HtmlError> {
+        let mut state = self.state.lock().unwrap();
+        let path = normalize(dest);
+
+        match state.claims.get(&path) {
+            Some(first) if *first == origin => Ok(false),
+            Some(first) => Err(Spx2HtmlError::AssetConflict {
+                path,
+                first: first.to_string(),
+                second: origin.to_string(),
+            }),
+            None => {
+                state.claims.insert(path, origin);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Queue the copy of a claimed asset, to be made at the next call to
+    /// [`Self::take_pending`].
+    pub fn defer(&self, dest: &str, source: CopySource) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.push(PendingCopy {
+            dest: dest.to_owned(),
+            source,
+        });
+    }
+
+    /// Take the queued copies, so that they can be made.
+    pub fn take_pending(&self) -> Vec<PendingCopy> {
+        let mut state = self.state.lock().unwrap();
+        std::mem::take(&mut state.pending)
+    }
+
+    /// Record that an asset has been provided.
+    pub fn add(&self, dest: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.provided.insert(normalize(dest));
+    }
+
+    /// Record that a face of a font file has been written to *path*.
+    pub fn add_font_face(&self, path: &str, src: &str, face: FontFace) {
+        let mut state = self.state.lock().unwrap();
+        let origin = state
+            .fonts
+            .entry(path.to_owned())
+            .or_insert_with(|| AssetOrigin::FontFile {
+                src: src.to_owned(),
+                faces: Vec::new(),
+            });
+
+        if let AssetOrigin::FontFile { faces, .. } = origin {
+            if !faces.contains(&face) {
+                faces.push(face);
+            }
+        }
+    }
+
+    /// Record that a variant of a font has been written to *path*.
+    pub fn add_font_variant(&self, path: &str, origin: AssetOrigin) {
+        let mut state = self.state.lock().unwrap();
+        state.fonts.insert(path.to_owned(), origin);
+    }
+
+    /// Get the manifest of the provided assets and the font files.
+    pub fn manifest(&self) -> AssetManifest {
+        let state = self.state.lock().unwrap();
+
+        let mut entries: Vec<_> = state
+            .claims
+            .iter()
+            .filter(|(path, _)| state.provided.contains(*path))
+            .chain(state.fonts.iter())
+            .map(|(path, origin)| ManifestEntry {
+                path: path.clone(),
+                origin: origin.clone(),
+            })
+            .collect();
+        entries.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        AssetManifest {
+            version: MANIFEST_VERSION,
+            entries,
+        }
+    }
+
+    /// Note the page about to be rendered.
+    pub fn set_page(&self, rel_top: &str, template: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.rel_top = rel_top.to_owned();
+        state.template = template.to_owned();
+    }
+
+    /// Get the `path` argument of a function, and the corresponding asset.
+    fn lookup(&self, func: &str, args: &HashMap<String, Value>) -> tera::Result<AssetRef> {
+        let state = self.state.lock().unwrap();
+
+        let path = match args.get("path") {
+            Some(Value::String(s)) => normalize(s),
+            _ => {
+                return Err(tera::Error::msg(format!(
+                    "the `{}` function in template `{}` requires a string `path` argument",
+                    func, state.template
+                )))
+            }
+        };
+
+        if !state.provided.contains(&path) {
+            return Err(tera::Error::chain(
+                format!(
+                    "the `{}` function failed; assets must be provided with tdux:provideFile before use",
+                    func
+                ),
+                Spx2HtmlError::UnknownAsset {
+                    path,
+                    template: state.template.clone(),
+                },
+            ));
+        }
+
+        Ok(AssetRef {
+            path,
+            rel_top: state.rel_top.clone(),
+            template: state.template.clone(),
+        })
+    }
+
+    fn asset_url(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let asset = self.lookup("asset_url", args)?;
+        Ok(Value::String(format!("{}{}", asset.rel_top, asset.path)))
+    }
+
+    fn inline_asset(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let asset = self.lookup("inline_asset", args)?;
+        let (path, template) = (&asset.path, &asset.template);
+
+        let read = || -> std::io::Result<Option<String>> {
+            if self.out.size(path)? > MAX_INLINE_ASSET_SIZE {
+                return Ok(None);
+            }
+
+            String::from_utf8(self.out.read(path)?)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        };
+
+        match read() {
+            Ok(Some(text)) => Ok(Value::String(text)),
+            Ok(None) => Err(tera::Error::chain(
+                "the `inline_asset` function failed",
+                Spx2HtmlError::AssetTooLarge {
+                    path: path.clone(),
+                    template: template.clone(),
+                    limit: MAX_INLINE_ASSET_SIZE,
+                },
+            )),
+            Err(e) => Err(tera::Error::chain(
+                format!(
+                    "failed to read asset `{}` inlined in template `{}`",
+                    path, template
+                ),
+                Spx2HtmlError::Io {
+                    path: self.out.display(path),
+                    source: e,
+                },
+            )),
+        }
+    }
+}
+
+/// The `inline_asset` function, whose output is marked safe so that it isn't
+/// escaped.
+struct InlineAsset(Assets);
+
+impl tera::Function for InlineAsset {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        self.0.inline_asset(args)
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// Register the asset-related functions with a Tera instance.
+pub fn register_functions(tera: &mut Tera, assets: &Assets) {
+    let a = assets.clone();
+    tera.register_function("asset_url", move |args: &HashMap<String, Value>| {
+        a.asset_url(args)
+    });
+    tera.register_function("inline_asset", InlineAsset(assets.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decode_base64, html::FontLookup, test_util::*, AssetSource, EngineState, Spx2HtmlEngine,
+    };
+    use tectonic_xdv::XdvEvents;
+
+    /// A manifest with an entry of each origin.
+    fn sample_manifest() -> AssetManifest {
+        let entry = |path: &str, origin| ManifestEntry {
+            path: path.to_owned(),
+            origin,
+        };
+
+        AssetManifest {
+            version: MANIFEST_VERSION,
+            entries: vec![
+                entry("app.js", AssetOrigin::Application),
+                entry(
+                    "css/extra.css",
+                    AssetOrigin::Copy {
+                        src: "extra.css".to_owned(),
+                    },
+                ),
+                entry(
+                    "data.bin",
+                    AssetOrigin::Data {
+                        digest: "ab".repeat(32),
+                    },
+                ),
+                entry(
+                    "img/a.png",
+                    AssetOrigin::DirectoryCopy {
+                        src: "img/a.png".to_owned(),
+                    },
+                ),
+                entry(
+                    "remote.css",
+                    AssetOrigin::RemoteCopy {
+                        url: "https://example.com/remote.css".to_owned(),
+                        digest: "cd".repeat(32),
+                    },
+                ),
+                entry(
+                    "serif.otf",
+                    AssetOrigin::FontFile {
+                        src: "serif.otf".to_owned(),
+                        faces: vec![FontFace {
+                            index: 0,
+                            family: "tdux0".to_owned(),
+                        }],
+                    },
+                ),
+                entry("tdux-base.css", AssetOrigin::Engine),
+                entry(
+                    "vg0123456789abcdef-serif.otf",
+                    AssetOrigin::FontVariant {
+                        family: "tdux0".to_owned(),
+                        index: 0,
+                        glyphs: vec![(12, 'x'), (34, '\u{2032}')],
+                    },
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn manifest_golden() {
+        let golden = include_str!("../tests/golden/asset-manifest-v1.json");
+        let manifest = sample_manifest();
+        assert_eq!(manifest.to_json().unwrap(), golden);
+
+        let parsed: AssetManifest = serde_json::from_str(golden).unwrap();
+        assert_eq!(parsed, manifest);
+    }
+
+    #[test]
+    fn manifest_v0() {
+        let parsed: AssetManifest =
+            serde_json::from_str(include_str!("../tests/golden/asset-manifest-v0.json")).unwrap();
+        assert_eq!(parsed.version, 0);
+
+        // Version 0 had no way to record directory copies or data.
+        let expected: Vec<_> = sample_manifest()
+            .entries
+            .into_iter()
+            .filter(|e| {
+                !matches!(
+                    e.origin,
+                    AssetOrigin::DirectoryCopy { .. } | AssetOrigin::Data { .. }
+                )
+            })
+            .collect();
+        assert_eq!(parsed.entries, expected);
+
+        // It's written back in the current version.
+        let upgraded = AssetManifest {
+            version: MANIFEST_VERSION,
+            ..parsed
+        };
+        let reparsed: AssetManifest = serde_json::from_str(&upgraded.to_json().unwrap()).unwrap();
+        assert_eq!(reparsed, upgraded);
+    }
+
+    #[test]
+    fn manifest_from_the_future() {
+        let json = r#"{
+            "version": 7,
+            "checksum": "0000",
+            "entries": [
+                { "path": "a.css", "origin": "copy", "src": "a.css", "size": 12 },
+                { "path": "b.wasm", "origin": "compiled", "src": "b.rs" },
+                {
+                    "path": "c.otf",
+                    "origin": "font_file",
+                    "src": "c.otf",
+                    "faces": [{ "index": 1, "family": "tdux3", "weight": 400 }]
+                }
+            ]
+        }"#;
+
+        let parsed: AssetManifest = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.version, 7);
+        assert_eq!(
+            parsed.entries[0].origin,
+            AssetOrigin::Copy {
+                src: "a.css".to_owned()
+            }
+        );
+        assert_eq!(parsed.entries[1].origin, AssetOrigin::Unrecognized);
+        assert_eq!(
+            parsed.entries[2].origin,
+            AssetOrigin::FontFile {
+                src: "c.otf".to_owned(),
+                faces: vec![FontFace {
+                    index: 1,
+                    family: "tdux3".to_owned(),
+                }],
+            }
+        );
+
+        // The fonts that it records are still followed.
+        let pins = FontPins::new(&parsed).unwrap();
+        assert_eq!(pins.key("c.otf", 1), Some(3));
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = sample_manifest();
+        let json = manifest.to_json().unwrap();
+        let parsed: AssetManifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.to_json().unwrap(), json);
+
+        let empty = AssetManifest::default();
+        assert_eq!(empty.version, MANIFEST_VERSION);
+        let parsed: AssetManifest = serde_json::from_str(&empty.to_json().unwrap()).unwrap();
+        assert_eq!(parsed, empty);
+    }
+
+    #[test]
+    fn asset_functions() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("extra.css", "p { color: red; }");
+        io.add(
+            "assets.html",
+            "<style>{{ inline_asset(path=\"css/extra.css\") }}</style>\
+             <link href=\"{{ asset_url(path=\"css/extra.css\") }}\">",
+        );
+        io.add("bad.html", "{{ asset_url(path=\"css/missing.css\") }}");
+
+        let (result, _status) = run_engine(io, tempdir.path(), |s| {
+            s.special("tdux:addTemplate assets.html")?;
+            s.special("tdux:setTemplate assets.html")?;
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:setOutputPath ch1/index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplate bad.html")?;
+            s.special("tdux:emit")
+        });
+
+        let html = std::fs::read_to_string(tempdir.path().join("ch1/index.html")).unwrap();
+        assert_eq!(
+            html,
+            "<style>p { color: red; }</style><link href=\"../css/extra.css\">"
+        );
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::TemplateRender { name, .. }) if name == "bad.html"
+        ));
+
+        let unknown = err
+            .chain()
+            .filter_map(|e| e.downcast_ref::<Spx2HtmlError>())
+            .find(|e| matches!(e, Spx2HtmlError::UnknownAsset { .. }))
+            .unwrap();
+        assert_eq!(
+            unknown.to_string(),
+            "template `bad.html` references unknown asset `css/missing.css`"
+        );
+    }
+
+    #[test]
+    fn early_provide_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+
+        let (result, status) = run_engine(io, tempdir.path(), |s| {
+            // Before the templates are set up:
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+        assert_eq!(status.notes.len(), 1);
+        assert!(status.notes[0].contains("1 tdux:provideFile request(s) deferred"));
+
+        let css = std::fs::read_to_string(tempdir.path().join("css/extra.css")).unwrap();
+        assert_eq!(css, "p {}");
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "<html><body>Hello</body></html>");
+    }
+
+    #[test]
+    fn driver_inputs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let logo = tempdir.path().join("logo-source.svg");
+        std::fs::write(&logo, "<svg/>").unwrap();
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .add_template(
+                "site.html",
+                "<title>{{ site }} {{ revision }}</title>\
+                 <link href=\"{{ asset_url(path=\"style.css\") }}\">\
+                 {{ tduxContent }}",
+            )
+            .set_variable("site", "Example".into())
+            .set_variable("revision", 42.into())
+            .provide_asset("style.css", AssetSource::Bytes(b"body {}".to_vec()))
+            .provide_asset("js/app.js", AssetSource::Reader(Box::new(&b"run();"[..])))
+            .provide_asset("logo.svg", AssetSource::Path(logo));
+
+        let (result, _status) = run_engine_with(&mut engine, TestIo::new(), tempdir.path(), |s| {
+            s.text("Hello")
+        });
+        result.unwrap();
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(
+            html,
+            "<title>Example 42</title><link href=\"style.css\">Hello"
+        );
+        let css = std::fs::read_to_string(tempdir.path().join("style.css")).unwrap();
+        assert_eq!(css, "body {}");
+        let js = std::fs::read_to_string(tempdir.path().join("js/app.js")).unwrap();
+        assert_eq!(js, "run();");
+        assert!(tempdir.path().join("logo.svg").exists());
+
+        // The document's variables take precedence over the driver's.
+        let (result, _status) = run_engine_with(&mut engine, TestIo::new(), tempdir.path(), |s| {
+            s.special("tdux:setTemplateVariable site Override")?;
+            s.text("Hello")
+        });
+        result.unwrap();
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(html.starts_with("<title>Override 42</title>"), "{}", html);
+
+        // The document can't provide a file that the driver already has.
+        let mut io = TestIo::new();
+        io.add("mine.css", "p {}");
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:provideFile mine.css ./style.css")?;
+            s.text("Hello")
+        });
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::AssetConflict { .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "output file `style.css` would be provided by both the application and \
+             `tdux:provideFile` of `mine.css`"
+        );
+    }
+
+    #[test]
+    fn provide_file_phases() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let out = tempdir.path().to_owned();
+        let mut io = simple_io();
+        io.add("early.css", "early");
+        io.add("mid.css", "mid");
+        io.add("late.css", "late");
+        io.add("other.css", "other");
+
+        let (result, status) = run_engine(io.clone(), tempdir.path(), |s| {
+            s.special("tdux:provideFile early.css early.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+
+            // Whatever the phase, the copies are made just before the next
+            // page is rendered.
+            assert!(!out.join("early.css").exists());
+            s.special("tdux:provideFile mid.css mid.css")?;
+            s.special("tdux:emit")?;
+            assert!(out.join("early.css").exists());
+            assert!(out.join("mid.css").exists());
+
+            // Repeated requests are only honored once, in whichever phase
+            // they first appear.
+            std::fs::remove_file(out.join("early.css")).unwrap();
+            s.special("tdux:provideFile early.css /early.css")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:provideFile late.css late.css")?;
+            s.special("tdux:provideFile mid.css mid.css")?;
+            assert!(!out.join("late.css").exists());
+            Ok(())
+        });
+        result.unwrap();
+
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+        assert_eq!(status.notes.len(), 1);
+        assert!(!tempdir.path().join("early.css").exists());
+        let css = std::fs::read_to_string(tempdir.path().join("mid.css")).unwrap();
+        assert_eq!(css, "mid");
+        let css = std::fs::read_to_string(tempdir.path().join("late.css")).unwrap();
+        assert_eq!(css, "late");
+
+        // Different sources can't provide the same file, whether or not the
+        // first request was deferred.
+        for early in [true, false] {
+            let (result, _status) = run_engine(io.clone(), tempdir.path(), |s| {
+                if early {
+                    s.special("tdux:provideFile early.css shared.css")?;
+                }
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Hello")?;
+                if !early {
+                    s.special("tdux:provideFile early.css shared.css")?;
+                }
+                s.special("tdux:provideFile other.css ./shared.css")
+            });
+
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "output file `shared.css` would be provided by both \
+                 `tdux:provideFile` of `early.css` and `tdux:provideFile` of `other.css`"
+            );
+        }
+    }
+
+    #[test]
+    fn provide_file_missing_source() {
+        // A missing source is reported in the same way whichever phase the
+        // request comes in.
+        for phase in 0..3 {
+            let tempdir = tempfile::tempdir().unwrap();
+            let (result, _status) = run_engine(simple_io(), tempdir.path(), |s| {
+                if phase == 0 {
+                    s.special("tdux:provideFile nosuch.css a.css")?;
+                }
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Hello")?;
+                if phase == 1 {
+                    s.special("tdux:provideFile nosuch.css a.css")?;
+                }
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")?;
+                if phase == 2 {
+                    s.special("tdux:provideFile nosuch.css a.css")?;
+                }
+                Ok(())
+            });
+
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "1 referenced input(s) could not be found: provideFile source `nosuch.css`",
+                "phase {}",
+                phase
+            );
+            assert!(!tempdir.path().join("a.css").exists());
+        }
+    }
+
+    #[test]
+    fn engine_options() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let manifest = tempdir.path().join("assets.json");
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .default_output_path("start.html")
+            .copy_fonts(false)
+            .asset_manifest(Some(manifest.clone()))
+            .provide_asset("app.js", AssetSource::Bytes(b"1;".to_vec()));
+
+        let (files, _status) = run_engine_in_memory(&mut engine, io.clone(), |s| {
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            simple_events(s)
+        });
+        let files = files.unwrap();
+
+        let mut names: Vec<_> = files.keys().map(|k| k.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["app.js", "css/extra.css", "start.html"]);
+
+        let listed: tera::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+        assert_eq!(
+            listed,
+            serde_json::json!({
+                "version": 1,
+                "entries": [
+                    { "path": "app.js", "origin": "application" },
+                    { "path": "css/extra.css", "origin": "copy", "src": "extra.css" },
+                ],
+            })
+        );
+
+        // Warnings don't stop processing, but fail it at the end.
+        let mut engine = Spx2HtmlEngine::default();
+        engine.fatal_warnings(true);
+
+        let (result, status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.text("Hello")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        assert_eq!(status.warnings.len(), 2);
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::FatalWarnings { count: 2 })
+        ));
+        assert!(tempdir.path().join("index.html").exists());
+    }
+
+    #[test]
+    fn application_paths() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let out = tempdir.path().join("out");
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+
+        // Documents can't write outside of the output directory.
+        let (result, _status) = run_engine(io.clone(), &out, |s| {
+            s.special("tdux:provideFile extra.css ../meta/extra.css")?;
+            simple_events(s)
+        });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::PathRejected { .. })
+        ));
+        assert!(!tempdir.path().join("meta").exists());
+
+        // The application can.
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .depfile(Some(out.join("../meta/deps.mk")))
+            .asset_manifest(Some(tempdir.path().join("meta/assets.json")));
+
+        let (result, _status) = run_engine_with(&mut engine, io.clone(), &out, simple_events);
+        result.unwrap();
+        assert!(tempdir.path().join("meta/deps.mk").exists());
+        assert!(tempdir.path().join("meta/assets.json").exists());
+
+        // Even when the other files are kept in memory.
+        std::fs::remove_file(tempdir.path().join("meta/deps.mk")).unwrap();
+        let (result, _status) = run_engine_in_memory(&mut engine, io.clone(), simple_events);
+        result.unwrap();
+        assert!(tempdir.path().join("meta/deps.mk").exists());
+
+        // But such files can't collide with each other or with the other
+        // output files.
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .depfile(Some(tempdir.path().join("meta/both")))
+            .asset_manifest(Some(tempdir.path().join("meta/../meta/both")));
+
+        let (result, _status) = run_engine_with(&mut engine, io.clone(), &out, simple_events);
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::OutputConflict { .. })
+        ));
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.depfile(Some(out.join("index.html")));
+
+        let (result, _status) = run_engine_with(&mut engine, io, &out, simple_events);
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::OutputConflict { path }) if path == "index.html"
+        ));
+    }
+
+    #[test]
+    fn precomputed_assets() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let manifest_path = tempdir.path().join("assets.json");
+        let mut io = simple_io();
+        io.add("second.otf", test_font_data());
+        io.add("third.otf", test_font_data());
+
+        // The runs define fonts in different orders, as could happen when a
+        // document is edited.
+        let events = |fonts: [(&'static str, i32); 2]| {
+            move |s: &mut EngineState| -> Result<()> {
+                for (name, num) in fonts {
+                    s.handle_define_native_font(
+                        name,
+                        num,
+                        TEST_FONT_SIZE,
+                        0,
+                        None,
+                        None,
+                        None,
+                        None,
+                    )?;
+                }
+
+                s.define_test_font(4, TEST_FONT_SIZE)?;
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text_in(2, "a")?;
+                s.text_in(3, "b")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            }
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.asset_manifest(Some(manifest_path.clone()));
+        let (files, _status) = run_engine_in_memory(
+            &mut engine,
+            io.clone(),
+            events([("second", 2), ("third", 3)]),
+        );
+        let first = files.unwrap();
+
+        let manifest: AssetManifest =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        let second_font = manifest
+            .entries
+            .iter()
+            .find(|e| e.path == "second.otf")
+            .unwrap();
+        assert_eq!(
+            second_font.origin,
+            AssetOrigin::FontFile {
+                src: "second.otf".to_owned(),
+                faces: vec![FontFace {
+                    index: 0,
+                    family: "tdux1".to_owned()
+                }],
+            }
+        );
+
+        // Left to itself, the engine names the font families differently.
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, _status) = run_engine_in_memory(
+            &mut engine,
+            io.clone(),
+            events([("third", 3), ("second", 2)]),
+        );
+        assert_ne!(files.unwrap()["index.html"], first["index.html"]);
+
+        // But not when it follows the manifest.
+        engine.precomputed_assets(Some(manifest.clone()));
+        let (files, _status) = run_engine_in_memory(
+            &mut engine,
+            io.clone(),
+            events([("third", 3), ("second", 2)]),
+        );
+        assert_eq!(files.unwrap()["index.html"], first["index.html"]);
+
+        // Fonts missing from the manifest can't be used.
+        io.add("fourth.otf", test_font_data());
+        let (result, _status) =
+            run_engine_in_memory(&mut engine, io, events([("fourth", 3), ("second", 2)]));
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::FontNotInManifest { name, face_index: 0 }) if name == "fourth.otf"
+        ));
+
+        // Variants are allocated as they were, even if the glyphs that they
+        // map turn up in a different order.
+        let x = glyph_for('x');
+        let y = glyph_for('y');
+        let glyphs = vec![(x, 'q'), (y, 'q')];
+        let pins = FontPins::new(&AssetManifest {
+            version: MANIFEST_VERSION,
+            entries: vec![
+                ManifestEntry {
+                    path: "face.otf".to_owned(),
+                    origin: AssetOrigin::FontFile {
+                        src: "face.otf".to_owned(),
+                        faces: vec![FontFace {
+                            index: 0,
+                            family: "tdux7".to_owned(),
+                        }],
+                    },
+                },
+                ManifestEntry {
+                    path: "vg-face.otf".to_owned(),
+                    origin: AssetOrigin::FontVariant {
+                        family: "tdux7".to_owned(),
+                        index: 1,
+                        glyphs,
+                    },
+                },
+            ],
+        })
+        .unwrap();
+
+        let mut fonts = FontLookup::default();
+        fonts.pin(pins);
+        assert!(!fonts.can_add("other.otf", 0));
+        assert_eq!(
+            fonts
+                .add_font_data("face.otf", "face.otf", test_font_data(), 0)
+                .unwrap(),
+            7
+        );
+
+        let fd = fonts.font_data.get_mut(&7).unwrap();
+        let z = glyph_for('z');
+        assert_eq!(fd.request_alternative(z, 'q').alternate_map_index, 2);
+        assert_eq!(fd.request_alternative(y, 'q').alternate_map_index, 1);
+    }
+
+    #[test]
+    fn process_for_assets() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let manifest_path = tempdir.path().join("assets.json");
+        let mut io = simple_io();
+        io.add("second.otf", test_font_data());
+        io.add("extra.css", "p {}");
+        io.add("faces.html", "{{ tduxFontFaces | safe }}{{ tduxContent }}");
+
+        let events = |s: &mut EngineState| -> Result<()> {
+            s.handle_define_native_font(
+                "second.otf",
+                2,
+                TEST_FONT_SIZE,
+                0,
+                None,
+                None,
+                None,
+                None,
+            )?;
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:setTemplate faces.html")?;
+            s.text_in(2, "Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("World")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, _status) = run_engine_in_memory(&mut engine, io.clone(), events);
+        let one_pass = files.unwrap();
+
+        // The scan doesn't write anything, not even the configured manifest.
+        engine.asset_manifest(Some(manifest_path.clone()));
+        let (manifest, _status) = run_engine_for_assets(&mut engine, io.clone(), events);
+        let manifest = manifest.unwrap();
+        assert!(!manifest_path.exists());
+
+        let paths: Vec<_> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["css/extra.css", &format!("{}.otf", TEST_FONT), "second.otf"]
+        );
+
+        // The second pass writes the same output as a single one.
+        engine
+            .asset_manifest(None)
+            .precomputed_assets(Some(manifest));
+        let (files, _status) = run_engine_in_memory(&mut engine, io, events);
+        assert_eq!(files.unwrap(), one_pass);
+    }
+
+    #[test]
+    fn provide_directory() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let src = tempdir.path().join("static");
+        std::fs::create_dir_all(src.join("img/icons")).unwrap();
+        std::fs::write(src.join("site.css"), "p {}").unwrap();
+        std::fs::write(src.join("img/icons/a.svg"), "<svg/>").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(src.join("site.css"), src.join("img/linked.css")).unwrap();
+
+        let depfile = tempdir.path().join("deps.mk");
+        let out = tempdir.path().join("out");
+        let mut engine = Spx2HtmlEngine::default();
+        engine.depfile(Some(depfile.clone()));
+        let src_path = src.display().to_string();
+
+        let (result, status) = run_engine_with(&mut engine, simple_io(), &out, |s| {
+            // Deferred until the end of initialization:
+            s.special(&format!("tdux:provideDirectory {} assets/", src_path))?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special(&format!("tdux:provideDirectory {} assets", src_path))?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert!(status.notes[0].contains("1 tdux:provideDirectory request(s) deferred"));
+        let css = std::fs::read_to_string(out.join("assets/site.css")).unwrap();
+        assert_eq!(css, "p {}");
+        let svg = std::fs::read_to_string(out.join("assets/img/icons/a.svg")).unwrap();
+        assert_eq!(svg, "<svg/>");
+
+        #[cfg(unix)]
+        {
+            assert!(!out.join("assets/img/linked.css").exists());
+            assert_eq!(status.warnings.len(), 2, "{:?}", status.warnings);
+            assert!(status.warnings[0].contains("linked.css`, which is a symbolic link"));
+        }
+
+        let mk = std::fs::read_to_string(&depfile).unwrap();
+        assert!(
+            mk.contains(&format!("{}/img/icons/a.svg", src_path)),
+            "{}",
+            mk
+        );
+        assert!(mk.contains(&format!("{}/site.css", src_path)), "{}", mk);
+
+        // Destinations are sanitized like any other.
+        let (result, _status) = run_engine(simple_io(), &out, |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special(&format!("tdux:provideDirectory {} ../escaped", src_path))
+        });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::PathRejected { .. })
+        ));
+
+        // The source must be a directory.
+        let (result, _status) = run_engine(simple_io(), &out, |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special(&format!("tdux:provideDirectory {}/site.css css", src_path))
+        });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!(
+                "source `{}/site.css` of tdux:provideDirectory isn't a directory",
+                src_path
+            )
+        );
+    }
+
+    #[test]
+    fn linked_assets() {
+        let mut io = simple_io();
+        io.add("a.css", "a");
+        io.add("b.css", "b");
+        io.add("app.js", "app");
+        io.add(
+            "links.html",
+            "{% for f in tduxCssFiles %}<link href=\"{{ f }}\">{% endfor %}\
+             {% for f in tduxJsFiles %}<script src=\"{{ f }}\"></script>{% endfor %}",
+        );
+
+        let (files, status) = run_engine_in_memory(&mut Spx2HtmlEngine::default(), io, |s| {
+            s.special("tdux:addCss a.css css/a.css")?;
+            s.special("tdux:addTemplate links.html")?;
+            s.special("tdux:setTemplate links.html")?;
+            s.text("Hello")?;
+            s.special("tdux:addJs app.js app.js")?;
+            s.special("tdux:addCss b.css css/b.css")?;
+            s.special("tdux:addCss a.css ./css//a.css")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath guide/part/index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(status.notes.len(), 1, "{:?}", status.notes);
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+        assert_eq!(files["css/a.css"], b"a");
+        assert_eq!(files["css/b.css"], b"b");
+        assert_eq!(files["app.js"], b"app");
+        assert_eq!(
+            String::from_utf8_lossy(&files["index.html"]),
+            "<link href=\"css/a.css\"><link href=\"css/b.css\">\
+             <script src=\"app.js\"></script>"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&files["guide/part/index.html"]),
+            "<link href=\"../../css/a.css\"><link href=\"../../css/b.css\">\
+             <script src=\"../../app.js\"></script>"
+        );
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64("aGk").unwrap(), b"hi");
+        assert_eq!(decode_base64("aGV5").unwrap(), b"hey");
+        assert_eq!(decode_base64(" aGVs\nbG8= ").unwrap(), b"hello");
+        assert_eq!(decode_base64("//7+").unwrap(), [0xff, 0xfe, 0xfe]);
+        assert!(decode_base64("aGV5a").is_err());
+        assert!(decode_base64("aG=k").is_err());
+        assert!(decode_base64("aGk==").is_err());
+        assert!(decode_base64("aG-k").is_err());
+    }
+
+    #[test]
+    fn provide_data() {
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><circle r=\"1\"/></svg>";
+        let encoded =
+            "PHN2ZyB4bWxucz0iaHR0cDovL3d3dy53My5vcmcvMjAwMC9zdmciPjxjaXJjbGUgcj0iMSIvPjwvc3ZnPg==";
+        assert_eq!(decode_base64(encoded).unwrap(), svg.as_bytes());
+
+        let (files, status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                // Chunks needn't split the payload at group boundaries, and
+                // can start during initialization.
+                s.special(&format!(
+                    "tdux:provideDataChunk icons/dot.svg {}",
+                    &encoded[..10]
+                ))?;
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Hello")?;
+                s.special(&format!(
+                    "tdux:provideDataChunk icons//dot.svg {}",
+                    &encoded[10..33]
+                ))?;
+                s.special("tdux:provideData config.json eyJhIjogMX0=")?;
+                s.special(&format!(
+                    "tdux:provideDataChunk icons/dot.svg {}",
+                    &encoded[33..70]
+                ))?;
+                s.special(&format!(
+                    "tdux:provideData ./icons/dot.svg {}",
+                    &encoded[70..]
+                ))?;
+                s.special("tdux:provideData bad.bin aGk*")?;
+                s.special("tdux:provideDataChunk lost.bin aGk=")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+        let files = files.unwrap();
+
+        assert_eq!(files["icons/dot.svg"], svg.as_bytes());
+        assert_eq!(files["config.json"], b"{\"a\": 1}");
+        assert!(!files.contains_key("bad.bin"));
+        assert!(!files.contains_key("lost.bin"));
+        assert_eq!(status.notes.len(), 1);
+        assert_eq!(
+            status.warnings,
+            [
+                "not providing `bad.bin` with tdux:provideData: its data isn't valid base64, \
+                 since `*` isn't a base64 digit",
+                "not providing `lost.bin`: its tdux:provideDataChunk data was never finished \
+                 with tdux:provideData",
+            ]
+        );
+
+        // Different data can't be provided at the same path, and escaping
+        // the output directory is an error.
+        for (special, error) in [
+            (
+                "tdux:provideData config.json e30=",
+                "output file `config.json` would be provided by both `tdux:provideData` of \
+                 data with digest",
+            ),
+            ("tdux:provideData ../up.json e30=", "`../up.json`"),
+        ] {
+            let (result, _) =
+                run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                    s.special("tdux:addTemplate page.html")?;
+                    s.special("tdux:setTemplate page.html")?;
+                    s.text("Hello")?;
+                    s.special("tdux:provideData config.json eyJhIjogMX0=")?;
+                    s.special(special)
+                });
+            let message = result.unwrap_err().to_string();
+            assert!(message.contains(error), "{}", message);
+        }
+
+        // Specials deferred during initialization are handled in the order in
+        // which they were given, whatever their kinds.
+        let mut io = simple_io();
+        io.add("style.css", "body {}");
+        let (result, _) = run_engine_in_memory(&mut Spx2HtmlEngine::default(), io, |s| {
+            s.special("tdux:provideData style.css Ym9keSB7fQ==")?;
+            s.special("tdux:addCss style.css style.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")
+        });
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.starts_with(
+                "output file `style.css` would be provided by both `tdux:provideData` of data"
+            ),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Colors set with the specials of the standard `color` package.
+//!
+//! With the `dvips` driver conventions that XeTeX follows, a color group is
+//! emitted as `color push <spec>` ... `color pop`, where the specification is
+//! a color model and its values, such as `rgb 1 0 0` or `gray 0.5`, or one of
+//! the named colors of `dvipsnam.def`, such as `BrickRed`. The engine keeps a
+//! stack of these colors and applies the innermost one to the text and canvas
+//! glyphs inside of the group.
+
+/// The named colors that `dvips` knows about, with their CMYK values.
+const NAMED_COLORS: &[(&str, [f32; 4])] = &[
+    ("GreenYellow", [0.15, 0., 0.69, 0.]),
+    ("Yellow", [0., 0., 1., 0.]),
+    ("Goldenrod", [0., 0.10, 0.84, 0.]),
+    ("Dandelion", [0., 0.29, 0.84, 0.]),
+    ("Apricot", [0., 0.32, 0.52, 0.]),
+    ("Peach", [0., 0.50, 0.70, 0.]),
+    ("Melon", [0., 0.46, 0.50, 0.]),
+    ("YellowOrange", [0., 0.42, 1., 0.]),
+    ("Orange", [0., 0.61, 0.87, 0.]),
+    ("BurntOrange", [0., 0.51, 1., 0.]),
+    ("Bittersweet", [0., 0.75, 1., 0.24]),
+    ("RedOrange", [0., 0.77, 0.87, 0.]),
+    ("Mahogany", [0., 0.85, 0.87, 0.35]),
+    ("Maroon", [0., 0.87, 0.68, 0.32]),
+    ("BrickRed", [0., 0.89, 0.94, 0.28]),
+    ("Red", [0., 1., 1., 0.]),
+    ("OrangeRed", [0., 1., 0.50, 0.]),
+    ("RubineRed", [0., 1., 0.13, 0.]),
+    ("WildStrawberry", [0., 0.96, 0.39, 0.]),
+    ("Salmon", [0., 0.53, 0.38, 0.]),
+    ("CarnationPink", [0., 0.63, 0., 0.]),
+    ("Magenta", [0., 1., 0., 0.]),
+    ("VioletRed", [0., 0.81, 0., 0.]),
+    ("Rhodamine", [0., 0.82, 0., 0.]),
+    ("Mulberry", [0.34, 0.90, 0., 0.02]),
+    ("RedViolet", [0.07, 0.90, 0., 0.34]),
+    ("Fuchsia", [0.47, 0.91, 0., 0.08]),
+    ("Lavender", [0., 0.48, 0., 0.]),
+    ("Thistle", [0.12, 0.59, 0., 0.]),
+    ("Orchid", [0.32, 0.64, 0., 0.]),
+    ("DarkOrchid", [0.40, 0.80, 0.20, 0.]),
+    ("Purple", [0.45, 0.86, 0., 0.]),
+    ("Plum", [0.50, 1., 0., 0.]),
+    ("Violet", [0.79, 0.88, 0., 0.]),
+    ("RoyalPurple", [0.75, 0.90, 0., 0.]),
+    ("BlueViolet", [0.86, 0.91, 0., 0.04]),
+    ("Periwinkle", [0.57, 0.55, 0., 0.]),
+    ("CadetBlue", [0.62, 0.57, 0.23, 0.]),
+    ("CornflowerBlue", [0.65, 0.13, 0., 0.]),
+    ("MidnightBlue", [0.98, 0.13, 0., 0.43]),
+    ("NavyBlue", [0.94, 0.54, 0., 0.]),
+    ("RoyalBlue", [1., 0.50, 0., 0.]),
+    ("Blue", [1., 1., 0., 0.]),
+    ("Cerulean", [0.94, 0.11, 0., 0.]),
+    ("Cyan", [1., 0., 0., 0.]),
+    ("ProcessBlue", [0.96, 0., 0., 0.]),
+    ("SkyBlue", [0.62, 0., 0.12, 0.]),
+    ("Turquoise", [0.85, 0., 0.20, 0.]),
+    ("TealBlue", [0.86, 0., 0.34, 0.02]),
+    ("Aquamarine", [0.82, 0., 0.30, 0.]),
+    ("BlueGreen", [0.85, 0., 0.33, 0.]),
+    ("Emerald", [1., 0., 0.50, 0.]),
+    ("JungleGreen", [0.99, 0., 0.52, 0.]),
+    ("SeaGreen", [0.69, 0., 0.50, 0.]),
+    ("Green", [1., 0., 1., 0.]),
+    ("ForestGreen", [0.91, 0., 0.88, 0.12]),
+    ("PineGreen", [0.92, 0., 0.59, 0.25]),
+    ("LimeGreen", [0.50, 0., 1., 0.]),
+    ("YellowGreen", [0.44, 0., 0.74, 0.]),
+    ("SpringGreen", [0.26, 0., 0.76, 0.]),
+    ("OliveGreen", [0.64, 0., 0.95, 0.40]),
+    ("RawSienna", [0., 0.72, 1., 0.45]),
+    ("Sepia", [0., 0.83, 1., 0.70]),
+    ("Brown", [0., 0.81, 1., 0.60]),
+    ("Tan", [0.14, 0.42, 0.56, 0.]),
+    ("Gray", [0., 0., 0., 0.50]),
+    ("Black", [0., 0., 0., 1.]),
+    ("White", [0., 0., 0., 0.]),
+];
+
+/// Convert RGB components between 0 and 1 into an opaque RGBA color.
+fn rgba(r: f32, g: f32, b: f32) -> u32 {
+    let byte = |v: f32| (v.clamp(0., 1.) * 255.).round() as u32;
+    byte(r) << 24 | byte(g) << 16 | byte(b) << 8 | 0xff
+}
+
+/// Convert CMYK components between 0 and 1 into an opaque RGBA color.
+fn cmyk_to_rgba([c, m, y, k]: [f32; 4]) -> u32 {
+    rgba(
+        (1. - c) * (1. - k),
+        (1. - m) * (1. - k),
+        (1. - y) * (1. - k),
+    )
+}
+
+/// Parse a color specification, such as `rgb 1 0 0`, into an RGBA color.
+pub fn parse_color(spec: &str) -> Option<u32> {
+    let mut words = spec.split(' ');
+    let model = words.next()?;
+    let values = words
+        .map(|w| w.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    match (model, values.as_slice()) {
+        ("rgb", &[r, g, b]) => Some(rgba(r, g, b)),
+        ("gray", &[g]) => Some(rgba(g, g, g)),
+        ("cmyk", &[c, m, y, k]) => Some(cmyk_to_rgba([c, m, y, k])),
+        (name, &[]) => NAMED_COLORS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, cmyk)| cmyk_to_rgba(*cmyk)),
+        _ => None,
+    }
+}
+
+/// The stack of colors pushed with `color push`.
+#[derive(Debug, Default)]
+pub struct ColorStack {
+    /// The pushed colors, innermost last. Unrecognized colors are pushed as
+    /// None, so that they leave the color unchanged and pair up with their
+    /// pops.
+    stack: Vec<Option<u32>>,
+}
+
+impl ColorStack {
+    /// Handle the arguments of a `color` special.
+    ///
+    /// Returns a description of the problem if the special is malformed or
+    /// unpaired, in which case it's ignored.
+    pub fn handle(&mut self, args: &str) -> Result<(), String> {
+        if args == "pop" {
+            return match self.stack.pop() {
+                Some(_) => Ok(()),
+                None => Err("there's no color to pop".to_owned()),
+            };
+        }
+
+        let spec = match args.strip_prefix("push ") {
+            Some(s) => s,
+            None => return Err("only `push` and `pop` are supported".to_owned()),
+        };
+
+        let color = parse_color(spec);
+        self.stack.push(color);
+
+        match color {
+            Some(_) => Ok(()),
+            None => Err(format!("the color `{}` isn't recognized", spec)),
+        }
+    }
+
+    /// Get the color that applies to the current content, if any.
+    pub fn current(&self) -> Option<u32> {
+        self.stack.iter().rev().find_map(|c| *c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine};
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("rgb 1 0 0.5"), Some(0xff0080ff));
+        assert_eq!(parse_color("gray 0"), Some(0x000000ff));
+        assert_eq!(parse_color("cmyk 0 0 0 0.5"), Some(0x808080ff));
+        assert_eq!(parse_color("Red"), Some(0xff0000ff));
+        assert_eq!(parse_color("Black"), Some(0x000000ff));
+        assert_eq!(parse_color("rgb 1 0"), None);
+        assert_eq!(parse_color("rgb 1 0 x"), None);
+        assert_eq!(parse_color("NoSuchColor"), None);
+        assert_eq!(parse_color("hsb 0 1 1"), None);
+    }
+
+    #[test]
+    fn stack() {
+        let mut colors = ColorStack::default();
+        assert_eq!(colors.current(), None);
+        colors.handle("push Red").unwrap();
+        colors.handle("push rgb 0 0 1").unwrap();
+        assert_eq!(colors.current(), Some(0x0000ffff));
+        assert!(colors.handle("push Mauve").is_err());
+        assert_eq!(colors.current(), Some(0x0000ffff));
+        colors.handle("pop").unwrap();
+        colors.handle("pop").unwrap();
+        assert_eq!(colors.current(), Some(0xff0000ff));
+        colors.handle("pop").unwrap();
+        assert_eq!(colors.current(), None);
+        assert!(colors.handle("pop").is_err());
+        assert!(colors.handle("Red").is_err());
+    }
+
+    #[test]
+    fn color_specials() {
+        let mut engine = Spx2HtmlEngine::default();
+        let x = glyph_for('x');
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("color push rgb 1 0 0")?;
+            s.text("b")?;
+            s.special("color push  gray 0.5 ")?;
+            s.text("c")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("color pop")?;
+            s.special("color push Mauve")?;
+            s.text("d")?;
+            s.special("color pop")?;
+            s.special("color pop")?;
+            s.text("e")?;
+            s.special("color pop")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec![
+                "ignoring special `color push Mauve`: the color `Mauve` isn't recognized",
+                "ignoring special `color pop`: there's no color to pop",
+            ]
+        );
+
+        let html = String::from_utf8_lossy(&files["index.html"]);
+        assert!(html.starts_with(
+            "<html><body>a <span style=\"color: #ff0000ff\">b</span> \
+             <span style=\"color: #808080ff\">c</span> "
+        ));
+        assert!(html.contains("; color: #808080ff\">x</span>"));
+        assert!(html.ends_with("<span style=\"color: #ff0000ff\">d</span> e</body></html>"));
+    }
+}
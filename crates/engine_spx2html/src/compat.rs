@@ -0,0 +1,455 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Compatibility with older names of specials, and with other versions of
+//! the tdux format.
+//!
+//! When a special is renamed, its old name is listed in [`DEPRECATED`] so
+//! that documents using it keep working: the special is translated to its
+//! modern form before it is handled.
+//!
+//! A document can declare the version of the format that it was written for
+//! with `tdux:formatVersion <n>`, early on. A version newer than
+//! [`FORMAT_VERSION`] is an error, since the document would rely on specials
+//! that this engine would silently ignore. Declaring a version also makes
+//! unknown `tdux:` specials draw a warning, since they can't be explained by
+//! the engine being too old.
+//!
+//! The declared version also decides what happens to the old names of
+//! specials. In a document declaring a version from before a special was
+//! renamed, the old name was the right one, so it's translated silently. In
+//! a document declaring a version in which the special was already renamed,
+//! the old name is an error. In a document that doesn't declare a version,
+//! the first use of each old name draws a warning naming the replacement.
+
+use std::{borrow::Cow, collections::HashSet};
+
+/// The newest version of the tdux format, the vocabulary of specials, that
+/// the engine supports.
+///
+/// Version 2 renamed `tdux:directBegin` and `tdux:fontFallback`, so that
+/// pairs of specials are consistently named `...Start` and `...End`, and
+/// specials setting options `set...`.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// A special that has been renamed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Renamed {
+    /// The old name of the special, such as `tdux:foo`.
+    pub old: &'static str,
+
+    /// The modern name of the special.
+    pub new: &'static str,
+
+    /// The format version in which the special was renamed.
+    pub since: u32,
+}
+
+/// The specials that have been renamed, with their modern names. The
+/// arguments of a renamed special must be the same as those of its
+/// replacement.
+pub const DEPRECATED: &[Renamed] = &[
+    Renamed {
+        old: "tdux:directBegin",
+        new: "tdux:directStart",
+        since: 2,
+    },
+    Renamed {
+        old: "tdux:fontFallback",
+        new: "tdux:setFontFallback",
+        since: 2,
+    },
+];
+
+/// What to do about a special that was translated from an old name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Deprecation {
+    /// Nothing: the document declared a version in which the old name was
+    /// right, or it has already been warned about.
+    Accept,
+
+    /// Warn about it, since it's the first use of the old name in a document
+    /// that doesn't declare its version.
+    Warn(Renamed),
+
+    /// Fail, since the document declared a version in which the special was
+    /// already renamed.
+    Reject(Renamed),
+}
+
+/// Translates deprecated specials, keeping track of which ones have been
+/// warned about.
+#[derive(Debug, Default)]
+pub struct Compat {
+    warned: HashSet<&'static str>,
+
+    /// The format version declared by the document, if any.
+    format_version: Option<u32>,
+
+    /// The names of the unknown specials that have been warned about.
+    unknown_warned: HashSet<String>,
+}
+
+impl Compat {
+    /// Translate a special into its modern form, using the given table of
+    /// renamed specials.
+    ///
+    /// What the caller should do about the old name, if the special had
+    /// one, depends on the format version that the document declared, and
+    /// is returned as well.
+    pub fn translate<'a>(
+        &mut self,
+        contents: &'a str,
+        table: &'static [Renamed],
+    ) -> (Cow<'a, str>, Deprecation) {
+        let (name, args) = match contents.split_once(' ') {
+            Some((n, a)) => (n, Some(a)),
+            None => (contents, None),
+        };
+
+        let renamed = match table.iter().find(|r| r.old == name) {
+            Some(r) => *r,
+            None => return (Cow::Borrowed(contents), Deprecation::Accept),
+        };
+
+        let modern = match args {
+            Some(a) => format!("{} {}", renamed.new, a),
+            None => renamed.new.to_owned(),
+        };
+
+        let deprecation = match self.format_version {
+            Some(v) if v < renamed.since => Deprecation::Accept,
+            Some(_) => Deprecation::Reject(renamed),
+            None if self.warned.insert(renamed.old) => Deprecation::Warn(renamed),
+            None => Deprecation::Accept,
+        };
+
+        (Cow::Owned(modern), deprecation)
+    }
+
+    /// Record the format version declared by the document.
+    ///
+    /// Returns false if the version is newer than [`FORMAT_VERSION`], in
+    /// which case it isn't recorded.
+    pub fn declare_format_version(&mut self, version: u32) -> bool {
+        if version > FORMAT_VERSION {
+            return false;
+        }
+
+        self.format_version = Some(version);
+        true
+    }
+
+    /// Get the format version declared by the document, if any.
+    pub fn format_version(&self) -> Option<u32> {
+        self.format_version
+    }
+
+    /// Note the use of a `tdux:` special with the given name that the engine
+    /// doesn't know.
+    ///
+    /// Returns true if the caller should warn about it: if the document
+    /// declared its format version, and this is the first use of the name.
+    pub fn unknown_special(&mut self, name: &str) -> bool {
+        self.format_version.is_some() && self.unknown_warned.insert(name.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, SpecialContext, Spx2HtmlEngine, Spx2HtmlError};
+    use tectonic_bridge_core::MinimalDriver;
+
+    const TABLE: &[Renamed] = &[
+        Renamed {
+            old: "tdux:oldEmit",
+            new: "tdux:emit",
+            since: 2,
+        },
+        Renamed {
+            old: "tdux:oldSetTemplate",
+            new: "tdux:setTemplate",
+            since: 3,
+        },
+    ];
+
+    #[test]
+    fn translate() {
+        let mut compat = Compat::default();
+
+        let (s, d) = compat.translate("tdux:emit", TABLE);
+        assert!(matches!(s, Cow::Borrowed("tdux:emit")));
+        assert_eq!(d, Deprecation::Accept);
+
+        let (s, d) = compat.translate("tdux:oldSetTemplate page.html", TABLE);
+        assert_eq!(s, "tdux:setTemplate page.html");
+        assert_eq!(d, Deprecation::Warn(TABLE[1]));
+
+        // Only the first use of each form is warned about.
+        let (s, d) = compat.translate("tdux:oldSetTemplate other.html", TABLE);
+        assert_eq!(s, "tdux:setTemplate other.html");
+        assert_eq!(d, Deprecation::Accept);
+
+        let (s, d) = compat.translate("tdux:oldEmit", TABLE);
+        assert_eq!(s, "tdux:emit");
+        assert_eq!(d, Deprecation::Warn(TABLE[0]));
+
+        // Names are matched exactly.
+        let (s, d) = compat.translate("tdux:oldEmitted", TABLE);
+        assert_eq!(s, "tdux:oldEmitted");
+        assert_eq!(d, Deprecation::Accept);
+    }
+
+    #[test]
+    fn translate_declared_versions() {
+        // Old names are right in the versions from before the renaming.
+        let mut compat = Compat::default();
+        assert!(compat.declare_format_version(1));
+        let (s, d) = compat.translate("tdux:oldEmit", TABLE);
+        assert_eq!(s, "tdux:emit");
+        assert_eq!(d, Deprecation::Accept);
+
+        // And wrong from then on, every time.
+        let mut compat = Compat::default();
+        assert!(compat.declare_format_version(2));
+        let (s, d) = compat.translate("tdux:oldSetTemplate page.html", TABLE);
+        assert_eq!(s, "tdux:setTemplate page.html");
+        assert_eq!(d, Deprecation::Accept);
+
+        for _ in 0..2 {
+            let (s, d) = compat.translate("tdux:oldEmit", TABLE);
+            assert_eq!(s, "tdux:emit");
+            assert_eq!(d, Deprecation::Reject(TABLE[0]));
+        }
+    }
+
+    #[test]
+    fn format_versions() {
+        let mut compat = Compat::default();
+
+        // Unknown specials are only reported once a version is declared.
+        assert!(!compat.unknown_special("tdux:bogus"));
+        assert!(!compat.declare_format_version(FORMAT_VERSION + 1));
+        assert_eq!(compat.format_version(), None);
+        assert!(!compat.unknown_special("tdux:bogus"));
+
+        assert!(compat.declare_format_version(FORMAT_VERSION));
+        assert_eq!(compat.format_version(), Some(FORMAT_VERSION));
+        assert!(compat.unknown_special("tdux:bogus"));
+        assert!(!compat.unknown_special("tdux:bogus"));
+        assert!(compat.unknown_special("tdux:other"));
+    }
+
+    #[test]
+    fn table_is_consistent() {
+        for r in DEPRECATED {
+            assert!(r.old.starts_with("tdux:") && r.new.starts_with("tdux:"));
+            assert!(!DEPRECATED.iter().any(|o| o.old == r.new));
+            assert!(crate::specials::is_known(r.new));
+            assert!(!crate::specials::is_known(r.old));
+            assert!(r.since > 1 && r.since <= FORMAT_VERSION);
+        }
+    }
+
+    #[test]
+    fn deprecated_specials() {
+        let events = |version: Option<u32>| {
+            move |s: &mut crate::EngineState| -> tectonic_errors::Result<()> {
+                if let Some(v) = version {
+                    s.special(&format!("tdux:formatVersion {}", v))?;
+                }
+
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.special("tdux:directBegin")?;
+                s.special("tdux:directLine <hr>")?;
+                s.special("tdux:directEnd")?;
+                s.special("tdux:directBegin")?;
+                s.special("tdux:directEnd")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            }
+        };
+
+        let (files, status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), events(None));
+        assert_eq!(
+            files.unwrap()["index.html"],
+            b"<html><body><hr></body></html>"
+        );
+        assert_eq!(
+            status.warnings,
+            ["the special `tdux:directBegin` is deprecated; use `tdux:directStart` instead"]
+        );
+
+        let (files, status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), events(Some(1)));
+        assert_eq!(
+            files.unwrap()["index.html"],
+            b"<html><body><hr></body></html>"
+        );
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        let (result, _status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), events(Some(2)));
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::RenamedSpecial {
+                since: 2,
+                declared: 2,
+                ..
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "the special `tdux:directBegin` was renamed to `tdux:directStart` in tdux format 2, \
+             but the document declares format 2; use `tdux:directStart` instead"
+        );
+    }
+
+    /// Convert the document described by a golden-test fixture, and return
+    /// its HTML output files, one after the other, and the warnings.
+    fn convert_fixture(fixture: &str) -> (String, Vec<String>) {
+        let mut spx = SpxBuilder::default();
+        spx.font(1, TEST_FONT, TEST_FONT_SIZE);
+
+        for line in fixture.lines().filter(|l| !l.starts_with('#')) {
+            match line.split_once(' ') {
+                Some(("special", text)) => spx.special(text),
+                Some(("text", text)) => spx.text(1, text),
+                _ => panic!("bad fixture line `{}`", line),
+            };
+        }
+
+        let mut io = simple_io();
+        io.add(
+            "style.html",
+            "<style>body { font-family: {{ tduxMainBodyFontFamily }}; }</style>",
+        );
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut status = CapturingStatus::default();
+        Spx2HtmlEngine::default()
+            .bundled_fallback_font(false)
+            .process_reader_to_filesystem(
+                &mut MinimalDriver::new(io),
+                &mut status,
+                std::io::Cursor::new(spx.build()),
+                tempdir.path(),
+            )
+            .unwrap();
+
+        let mut names: Vec<_> = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|n| n.ends_with(".html"))
+            .collect();
+        names.sort();
+
+        let mut output = String::new();
+
+        for name in names {
+            let html = std::fs::read_to_string(tempdir.path().join(&name)).unwrap();
+            output.push_str(&format!("== {}\n{}\n", name, html));
+        }
+
+        (output, status.warnings)
+    }
+
+    /// Check that a document using the old names of the renamed specials
+    /// keeps producing the same output.
+    #[test]
+    fn deprecated_forms_golden() {
+        let fixture = include_str!("../tests/golden/deprecated-forms.txt");
+        let (html, mut warnings) = convert_fixture(fixture);
+        assert_eq!(html, include_str!("../tests/golden/deprecated-forms.out"));
+
+        // The fixture has to use all of the old names.
+        warnings.sort();
+        let mut expected: Vec<_> = DEPRECATED
+            .iter()
+            .map(|r| {
+                format!(
+                    "the special `{}` is deprecated; use `{}` instead",
+                    r.old, r.new
+                )
+            })
+            .collect();
+        expected.sort();
+        assert_eq!(warnings, expected);
+
+        // And the old names have to mean the same as the new ones.
+        let mut modern = fixture.to_owned();
+
+        for r in DEPRECATED {
+            modern = modern.replace(&format!("{} ", r.old), &format!("{} ", r.new));
+            modern = modern.replace(&format!("{}\n", r.old), &format!("{}\n", r.new));
+        }
+
+        let (modern_html, warnings) = convert_fixture(&modern);
+        assert_eq!(modern_html, html);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn format_version() {
+        assert_eq!(Spx2HtmlEngine::default().format_version(), FORMAT_VERSION);
+
+        // A document for a newer format can't be processed.
+        let (result, _status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                s.special(&format!("tdux:formatVersion {}", FORMAT_VERSION + 1))?;
+                s.special("tdux:setTemplate page.html")?;
+                s.special("tdux:contentFinished")
+            });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!(
+                "the document requires tdux format {}, but this Tectonic supports format {}; \
+                 a newer Tectonic is needed to process it",
+                FORMAT_VERSION + 1,
+                FORMAT_VERSION
+            )
+        );
+
+        // Once the version is declared, unknown specials are reported once
+        // each, but not those that the application handles.
+        let mut engine = Spx2HtmlEngine::default();
+        engine.register_special_handler(
+            "tdux:myproj:",
+            Box::new(|_special: &str, _x, _y, _cx: &mut SpecialContext| Ok(())),
+        );
+
+        let (result, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:formatVersion 0")?;
+            s.special("tdux:emitt")?;
+            s.special(&format!("tdux:formatVersion {}", FORMAT_VERSION))?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:setTemplat page.html")?;
+            s.text("a")?;
+            s.special("tdux:myproj:thing")?;
+            s.special("tdux:emitt")?;
+            s.special("tdux:emitt")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+        let unknown = |name: &str| {
+            format!(
+                "ignoring unknown special `{}`, which isn't part of tdux format {}; \
+                 further instances won't be reported",
+                name, FORMAT_VERSION
+            )
+        };
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring tdux:formatVersion `0`, which isn't a positive integer".to_owned(),
+                unknown("tdux:setTemplat"),
+                unknown("tdux:emitt"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,2431 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Accumulating the HTML content of a page.
+//!
+//! All of the markup and text that the engine generates for a page flows
+//! through a [`ContentSink`]. Besides collecting the HTML, the sink performs a
+//! few "peephole" cleanups, such as merging adjacent text runs that have the
+//! same styling into a single `<span>`. It also keeps track of which elements
+//! are open, so that it can generate structural markup on the document's
+//! behalf, and check the document's markup against a few of HTML's
+//! content-model rules.
+//!
+//! Content can be diverted from the main body of the page into other
+//! [`Destination`]s, such as named regions or the footnote buffer. Each
+//! destination has its own markup and element stack, and all of them get the
+//! same treatment of text, markup, and canvases.
+
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{Options, Serialization, SlugStyle};
+
+/// The normalized styling of a run of text.
+///
+/// Each field is `None` if the run matches the main body text in that
+/// respect. Two runs with equal styles are rendered identically, even if they
+/// originated from different TeX fonts.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct TextStyle {
+    /// The CSS font family.
+    pub family: Option<String>,
+
+    /// The font size, in thousandths of a rem.
+    pub size_millirem: Option<i32>,
+
+    /// The text color, as RGBA.
+    pub color_rgba: Option<u32>,
+}
+
+impl TextStyle {
+    /// Returns true if this style is the same as the main body text, so that
+    /// no styling markup is needed.
+    pub fn is_plain(&self) -> bool {
+        self.family.is_none() && self.size_millirem.is_none() && self.color_rgba.is_none()
+    }
+
+    fn write_css(&self, dest: &mut String) {
+        let mut sep = "";
+
+        if let Some(f) = self.family.as_ref() {
+            write!(dest, "{}font-family: {}", sep, tera::escape_html(f)).unwrap();
+            sep = "; ";
+        }
+
+        if let Some(s) = self.size_millirem {
+            write!(dest, "{}font-size: {}rem", sep, s as f32 / 1000.).unwrap();
+            sep = "; ";
+        }
+
+        if let Some(c) = self.color_rgba {
+            write!(dest, "{}color: #{:08x}", sep, c).unwrap();
+        }
+    }
+}
+
+/// An element that has been opened but not yet closed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct OpenElement {
+    /// The element name.
+    name: String,
+
+    /// If true, the element was generated by the engine rather than
+    /// requested by the document.
+    generated: bool,
+
+    /// For generated sections, the level of the heading that caused the
+    /// section to be opened.
+    level: u8,
+
+    /// The offset in the buffer just past the element name in its start tag,
+    /// where attributes can be inserted.
+    attr_pos: usize,
+
+    /// For headings, the text content accumulated so far.
+    heading_text: Option<String>,
+
+    /// The number of child elements, text runs, and canvases added inside
+    /// of the element so far.
+    n_children: usize,
+
+    /// For figures, the child index of the caption, if one has been opened.
+    caption_index: Option<usize>,
+}
+
+/// A heading that was given an id.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HeadingInfo {
+    /// The heading level, from 1 to 6.
+    pub level: u8,
+
+    /// The id assigned to the heading.
+    pub id: String,
+
+    /// The text content of the heading.
+    pub text: String,
+}
+
+/// The alignment of the content of a table cell.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CellAlign {
+    /// Left-aligned.
+    Left,
+
+    /// Centered.
+    Center,
+
+    /// Right-aligned.
+    Right,
+}
+
+impl CellAlign {
+    /// Parse an alignment specification, either spelled out or in the form
+    /// of a LaTeX column specifier.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "l" | "left" => Some(CellAlign::Left),
+            "c" | "center" => Some(CellAlign::Center),
+            "r" | "right" => Some(CellAlign::Right),
+            _ => None,
+        }
+    }
+
+    fn as_css(&self) -> &'static str {
+        match self {
+            CellAlign::Left => "left",
+            CellAlign::Center => "center",
+            CellAlign::Right => "right",
+        }
+    }
+}
+
+/// The positions in the element stack of the parts of the innermost open
+/// table.
+#[derive(Clone, Copy, Debug, Default)]
+struct TableContext {
+    table: Option<usize>,
+    row: Option<usize>,
+    cell: Option<usize>,
+}
+
+/// The class given to the markers inserted by `tdux:printBreakBefore`.
+pub const PRINT_BREAK_CLASS: &str = "tdux-print-break";
+
+/// The class given to elements that shouldn't be split across printed pages.
+pub const AVOID_BREAK_CLASS: &str = "tdux-avoid-break";
+
+/// Default styling for printed output, made available to templates as
+/// `tduxPrintCss`. It's meant to be included inside of an `@media print`
+/// block.
+pub const PRINT_CSS: &str = "\
+.tdux-print-break { break-before: page; }
+.tdux-avoid-break { break-inside: avoid; }
+h1, h2, h3, h4, h5, h6 { break-after: avoid; }
+a[href^=\"http\"]::after { content: \" (\" attr(href) \")\"; font-size: 90%; }
+.tdux-anchor, .tdux-no-print { display: none; }
+.tdux-print-only { display: block; }
+";
+
+/// The length, in bytes, beyond which the lines of a text run are broken.
+///
+/// Long runs are broken at spaces, which are turned into newlines, so that the
+/// HTML doesn't end up with enormous lines. HTML treats the two alike, except
+/// in `<pre>` and `<textarea>` elements, whose runs are left alone.
+pub const MAX_LINE_LEN: usize = 4096;
+
+/// The length, in bytes, of text runs that are reported as unusually long.
+pub const LONG_RUN_THRESHOLD: usize = 64 * 1024;
+
+/// Characters after which no space is inserted before a following text run:
+/// opening brackets and quotes, and dashes.
+pub const DEFAULT_NO_SPACE_AFTER: &str =
+    "([{\u{2018}\u{201A}\u{201C}\u{201E}\u{AB}\u{2039}\u{BF}\u{A1}\u{2013}\u{2014}";
+
+/// Characters before which no space is inserted when they start a text run:
+/// closing brackets and quotes, dashes, and punctuation that attaches to the
+/// preceding word.
+pub const DEFAULT_NO_SPACE_BEFORE: &str =
+    ")]}\u{2019}\u{201D}\u{BB}\u{203A}\u{2013}\u{2014},.;:!?%\u{2026}";
+
+/// The elements that can be opened with `tdux:asSemantic`, keyed by the names
+/// that the document uses for them.
+const SEMANTIC_ELEMENTS: &[(&str, &str)] = &[
+    ("blockquote", "blockquote"),
+    ("cite", "cite"),
+    ("dd", "dd"),
+    ("dl", "dl"),
+    ("dt", "dt"),
+    ("figcaption", "figcaption"),
+    ("figure", "figure"),
+    ("quote", "q"),
+];
+
+/// Map a semantic alias, as used with `tdux:asSemantic`, to an HTML element.
+pub fn semantic_element(alias: &str) -> Option<&'static str> {
+    SEMANTIC_ELEMENTS
+        .iter()
+        .find(|(a, _)| *a == alias)
+        .map(|(_, e)| *e)
+}
+
+/// Returns true if the element is block-level, in the sense that starting it
+/// implicitly closes an open `<p>` element.
+fn is_block(element: &str) -> bool {
+    heading_level(element).is_some()
+        || matches!(
+            element,
+            "address"
+                | "article"
+                | "aside"
+                | "blockquote"
+                | "dd"
+                | "details"
+                | "div"
+                | "dl"
+                | "dt"
+                | "fieldset"
+                | "figcaption"
+                | "figure"
+                | "footer"
+                | "form"
+                | "header"
+                | "hr"
+                | "li"
+                | "main"
+                | "nav"
+                | "ol"
+                | "p"
+                | "pre"
+                | "section"
+                | "table"
+                | "ul"
+        )
+}
+
+/// Returns true if the element is a void element, which has no content and
+/// no end tag.
+fn is_void(element: &str) -> bool {
+    matches!(
+        element,
+        "area"
+            | "base"
+            | "br"
+            | "col"
+            | "embed"
+            | "hr"
+            | "img"
+            | "input"
+            | "link"
+            | "meta"
+            | "source"
+            | "track"
+            | "wbr"
+    )
+}
+
+/// Escape the characters of text that XML doesn't allow to appear literally.
+pub fn escape_text(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>']) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+/// Returns true if the text is acceptable as the name of an element.
+pub fn is_element_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Returns true if the text is acceptable as the name of an attribute.
+pub fn is_attribute_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':')
+}
+
+/// Write text into a buffer, turning spaces into newlines where needed to
+/// keep its lines from growing much longer than [`MAX_LINE_LEN`].
+fn push_broken_lines(buf: &mut String, mut text: &str) {
+    while text.len() > MAX_LINE_LEN {
+        let mut limit = MAX_LINE_LEN;
+
+        while !text.is_char_boundary(limit) {
+            limit -= 1;
+        }
+
+        let space = text[..limit]
+            .rfind(' ')
+            .or_else(|| text[limit..].find(' ').map(|i| i + limit));
+
+        match space {
+            Some(i) => {
+                buf.push_str(&text[..i]);
+                buf.push('\n');
+                text = &text[i + 1..];
+            }
+            None => break,
+        }
+    }
+
+    buf.push_str(text);
+}
+
+/// Decide whether a space belongs between two text runs, given the last
+/// character of the previous run, if known, and the text of the next run. The
+/// `no_space_*` arguments extend the default character classes.
+fn space_between(
+    no_space_after: &str,
+    no_space_before: &str,
+    prev: Option<char>,
+    next: &str,
+) -> bool {
+    if let Some(c) = prev {
+        if DEFAULT_NO_SPACE_AFTER.contains(c) || no_space_after.contains(c) {
+            return false;
+        }
+    }
+
+    match next.chars().next() {
+        Some(c) => !(DEFAULT_NO_SPACE_BEFORE.contains(c) || no_space_before.contains(c)),
+        None => true,
+    }
+}
+
+/// If the element is a heading (`h1` to `h6`), get its level.
+fn heading_level(element: &str) -> Option<u8> {
+    let level = element.strip_prefix('h')?.parse::<u8>().ok()?;
+
+    if (1..=6).contains(&level) {
+        Some(level)
+    } else {
+        None
+    }
+}
+
+/// Derive an element id from some text.
+///
+/// Letters and digits are lowercased and kept; everything else turns into
+/// hyphens, without leading, trailing, or repeated hyphens. If nothing is
+/// left, the result is `section`.
+pub fn slugify(text: &str, style: SlugStyle) -> String {
+    let text = match style {
+        SlugStyle::Ascii => std::borrow::Cow::Owned(deunicode::deunicode(text)),
+        SlugStyle::Unicode => std::borrow::Cow::Borrowed(text),
+    };
+
+    let mut slug = String::with_capacity(text.len());
+    let mut need_hyphen = false;
+
+    for c in text.chars() {
+        let keep = match style {
+            SlugStyle::Ascii => c.is_ascii_alphanumeric(),
+            SlugStyle::Unicode => c.is_alphanumeric(),
+        };
+
+        if keep {
+            if need_hyphen && !slug.is_empty() {
+                slug.push('-');
+            }
+
+            need_hyphen = false;
+            slug.extend(c.to_lowercase());
+        } else {
+            need_hyphen = true;
+        }
+    }
+
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+
+    slug
+}
+
+/// Where the content added to a [`ContentSink`] ends up.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Destination {
+    /// The main content of the page.
+    #[default]
+    Main,
+
+    /// A named region of the page, to be placed by the template. These are
+    /// the streams of `tdux:deferStart`.
+    Region(String),
+
+    /// The buffer of the footnote being collected, between
+    /// `tdux:footnoteStart` and `tdux:footnoteEnd`.
+    Footnotes,
+}
+
+/// The markup accumulated for a destination that isn't currently active.
+#[derive(Debug, Default)]
+struct ParkedStream {
+    buf: String,
+    pending: Option<(TextStyle, String)>,
+    ends_with_text: bool,
+    stack: Vec<OpenElement>,
+    implicitly_closed: Vec<String>,
+}
+
+/// A destination for the HTML content of a page.
+///
+/// The fields describing the markup apply to the active destination; the
+/// markup of the other destinations is parked until they become active
+/// again.
+#[derive(Debug, Default)]
+pub struct ContentSink {
+    /// The completed markup.
+    buf: String,
+
+    /// A text run that hasn't been written to the buffer yet, because
+    /// subsequent runs might be merged into it.
+    pending: Option<(TextStyle, String)>,
+
+    /// Whether the most recent content was text, in which case a space is
+    /// needed before any further text.
+    ends_with_text: bool,
+
+    /// The stack of currently open elements, innermost last.
+    stack: Vec<OpenElement>,
+
+    /// Whether to generate `<section>` elements around headings.
+    auto_sections: bool,
+
+    /// If not None, generate ids for headings, slugified in this style.
+    heading_ids: Option<SlugStyle>,
+
+    /// Whether to add permalink markers to headings with generated ids.
+    heading_anchor_links: bool,
+
+    /// The ids used so far in the current output file.
+    ids: HashSet<String>,
+
+    /// The headings that have been given ids in the current output file.
+    headings: Vec<HeadingInfo>,
+
+    /// The text of the most recently closed heading. Unlike the other
+    /// per-file state, this carries over from one output file to the next.
+    last_heading_text: Option<String>,
+
+    /// Document elements that were closed implicitly, whose explicit end
+    /// tags should therefore be swallowed.
+    implicitly_closed: Vec<String>,
+
+    /// The destination that content is currently being added to.
+    destination: Destination,
+
+    /// The markup of the inactive destinations.
+    parked: BTreeMap<Destination, ParkedStream>,
+
+    /// Whether to keep control characters in text as numeric character
+    /// references, rather than removing them.
+    preserve_control_chars: bool,
+
+    /// Whether to serialize the markup as well-formed XML.
+    xhtml: bool,
+
+    /// The control characters found in text since they were last taken, and
+    /// how many times each occurred.
+    control_chars: BTreeMap<char, usize>,
+
+    /// The lengths of the text runs longer than [`LONG_RUN_THRESHOLD`] found
+    /// since they were last taken.
+    long_runs: Vec<usize>,
+
+    /// Characters after which no space is inserted between text runs, in
+    /// addition to [`DEFAULT_NO_SPACE_AFTER`].
+    no_space_after: String,
+
+    /// Characters before which no space is inserted between text runs, in
+    /// addition to [`DEFAULT_NO_SPACE_BEFORE`].
+    no_space_before: String,
+}
+
+impl ContentSink {
+    /// Create a new sink, configured by the engine options.
+    pub fn new(options: &Options) -> Self {
+        ContentSink {
+            auto_sections: options.auto_sections,
+            heading_ids: options.heading_ids,
+            heading_anchor_links: options.heading_anchor_links,
+            preserve_control_chars: options.keeps_control_chars(),
+            xhtml: options.serialization == Serialization::Xhtml,
+            no_space_after: options.no_space_after.clone(),
+            no_space_before: options.no_space_before.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if no content has been added since the sink was last
+    /// taken.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+            && self.pending.is_none()
+            && self
+                .parked
+                .values()
+                .all(|s| s.buf.is_empty() && s.pending.is_none())
+    }
+
+    /// Divert subsequent content to a different destination.
+    ///
+    /// Content previously added to the destination is retained, and the
+    /// elements left open in it remain open.
+    pub fn set_destination(&mut self, dest: Destination) {
+        if dest == self.destination {
+            return;
+        }
+
+        let incoming = self.parked.remove(&dest).unwrap_or_default();
+        let outgoing = self.swap_stream(incoming);
+        let prev = std::mem::replace(&mut self.destination, dest);
+        self.parked.insert(prev, outgoing);
+    }
+
+    /// Exchange the markup of the active destination with a parked stream,
+    /// returning the previously active markup.
+    fn swap_stream(&mut self, stream: ParkedStream) -> ParkedStream {
+        ParkedStream {
+            buf: std::mem::replace(&mut self.buf, stream.buf),
+            pending: std::mem::replace(&mut self.pending, stream.pending),
+            ends_with_text: std::mem::replace(&mut self.ends_with_text, stream.ends_with_text),
+            stack: std::mem::replace(&mut self.stack, stream.stack),
+            implicitly_closed: std::mem::replace(
+                &mut self.implicitly_closed,
+                stream.implicitly_closed,
+            ),
+        }
+    }
+
+    /// Add a run of text with the given style.
+    ///
+    /// If the previous content was a text run with the same style, the two
+    /// runs are merged, with the inter-run space placed inside the styling
+    /// span. The space is omitted if the previous run ends with an opening
+    /// bracket or the like, or if this run starts with closing punctuation.
+    /// Control characters other than tab and newline are removed, or turned
+    /// into character references, and counted.
+    pub fn text(&mut self, style: TextStyle, text: &str) {
+        let text = self.scrub_control_chars(text);
+        let text = text.as_ref();
+
+        if text.len() > LONG_RUN_THRESHOLD {
+            self.long_runs.push(text.len());
+        }
+
+        for e in &mut self.stack {
+            if let Some(acc) = e.heading_text.as_mut() {
+                let prev = acc.chars().last();
+
+                if prev.is_some()
+                    && space_between(&self.no_space_after, &self.no_space_before, prev, text)
+                {
+                    acc.push(' ');
+                }
+
+                acc.push_str(text);
+            }
+        }
+
+        let prev = self.pending.as_ref().and_then(|(_, t)| t.chars().last());
+        let space = space_between(&self.no_space_after, &self.no_space_before, prev, text);
+
+        if let Some((pstyle, ptext)) = self.pending.as_mut() {
+            if *pstyle == style {
+                if space {
+                    ptext.push(' ');
+                }
+
+                ptext.push_str(text);
+                return;
+            }
+        }
+
+        self.flush();
+        self.note_child();
+
+        if self.ends_with_text && space {
+            self.buf.push(' ');
+        }
+
+        self.pending = Some((style, text.to_owned()));
+        self.ends_with_text = true;
+    }
+
+    /// Open an element requested by the document.
+    ///
+    /// Returns descriptions of any problems, which the caller should relay as
+    /// warnings.
+    pub fn open_tag(&mut self, element: &str) -> Vec<String> {
+        self.open_tag_with_attrs(element, &[])
+    }
+
+    /// Open an element requested by the document, with attributes.
+    ///
+    /// The attribute values are escaped as needed, but the names should
+    /// already have been validated. Returns descriptions of any problems,
+    /// which the caller should relay as warnings.
+    pub fn open_tag_with_attrs(&mut self, element: &str, attrs: &[(&str, &str)]) -> Vec<String> {
+        let element = self.element_name(element);
+        let element = element.as_ref();
+        self.flush();
+        self.start_block(element);
+        let problems = self.check_content_model(element);
+        let level = heading_level(element);
+        let mut section_attr_pos = None;
+
+        if self.auto_sections {
+            if let Some(level) = level {
+                section_attr_pos = self.open_section(level);
+            }
+        }
+
+        let mut attr_text = String::new();
+
+        for (name, value) in attrs {
+            write!(attr_text, " {}=\"{}\"", name, tera::escape_html(value)).unwrap();
+        }
+
+        // In XHTML, a void element is closed right away, and the document's
+        // end tag for it is ignored.
+
+        if self.xhtml && is_void(element) {
+            self.note_child();
+            write!(self.buf, "<{}{} />", element, attr_text).unwrap();
+            self.ends_with_text = false;
+            return problems;
+        }
+
+        self.push_element(element, &attr_text, false, 0);
+
+        if level.is_some() {
+            let top = self.stack.last_mut().unwrap();
+            top.heading_text = Some(String::new());
+
+            if let Some(pos) = section_attr_pos {
+                top.attr_pos = pos;
+            }
+        }
+
+        problems
+    }
+
+    /// If a block-level element is about to be started inside of a `<p>`
+    /// element, close the paragraph, as an HTML parser would.
+    fn start_block(&mut self, element: &str) {
+        if !is_block(element) {
+            return;
+        }
+
+        let mut pos = None;
+
+        for (i, e) in self.stack.iter().enumerate().rev() {
+            if !e.generated && e.name == "p" {
+                pos = Some(i);
+                break;
+            } else if is_block(&e.name) {
+                break;
+            }
+        }
+
+        if let Some(pos) = pos {
+            while self.stack.len() > pos {
+                let e = self.stack.last().unwrap();
+
+                if !e.generated {
+                    self.implicitly_closed.push(e.name.clone());
+                }
+
+                self.pop_element();
+            }
+        }
+    }
+
+    /// Check an element about to be opened against the content-model rules
+    /// that we know about, returning descriptions of any violations.
+    fn check_content_model(&mut self, element: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut authored = self.stack.iter_mut().rev().filter(|e| !e.generated);
+        let parent = authored.next();
+
+        match element {
+            "dt" | "dd" => {
+                let ok = match parent {
+                    Some(p) if p.name == "dl" => true,
+                    Some(p) if p.name == "div" => {
+                        authored.next().map(|g| g.name == "dl") == Some(true)
+                    }
+                    _ => false,
+                };
+
+                if !ok {
+                    problems.push(format!("<{}> should only appear inside of <dl>", element));
+                }
+            }
+
+            "figcaption" => match parent {
+                Some(p) if p.name == "figure" => {
+                    if p.caption_index.is_some() {
+                        problems.push("<figure> should have only one <figcaption>".to_owned());
+                    } else {
+                        p.caption_index = Some(p.n_children);
+                    }
+                }
+                _ => problems.push("<figcaption> should only appear inside of <figure>".to_owned()),
+            },
+
+            _ => {}
+        }
+
+        problems
+    }
+
+    /// Close an element requested by the document.
+    ///
+    /// Any generated elements nested inside of the element are closed first.
+    /// Returns descriptions of any problems, which the caller should relay as
+    /// warnings.
+    pub fn close_tag(&mut self, element: &str) -> Vec<String> {
+        let element = self.element_name(element);
+        let element = element.as_ref();
+        self.flush();
+        let mut problems = Vec::new();
+
+        if self.xhtml && is_void(element) {
+            return problems;
+        }
+
+        let pos = self
+            .stack
+            .iter()
+            .rposition(|e| !e.generated && e.name == element);
+
+        if let Some(pos) = pos {
+            while self.stack.len() > pos + 1 {
+                if self.stack.last().unwrap().generated {
+                    self.pop_element();
+                } else {
+                    // A mismatched document element; we leave it to the
+                    // document to close it.
+                    self.stack.pop();
+                }
+            }
+
+            let e = self.stack.pop().unwrap();
+
+            if let Some(i) = e.caption_index {
+                if i != 0 && i + 1 != e.n_children {
+                    problems.push(
+                        "<figcaption> should be the first or last child of <figure>".to_owned(),
+                    );
+                }
+            }
+
+            if let Some(text) = e.heading_text {
+                self.finish_heading(element, e.attr_pos, text);
+            }
+        } else if let Some(i) = self.implicitly_closed.iter().rposition(|e| e == element) {
+            // The element was already closed when a block-level element was
+            // started inside of it.
+            self.implicitly_closed.remove(i);
+            return problems;
+        }
+
+        write!(self.buf, "</{}>", element).unwrap();
+        self.ends_with_text = false;
+        problems
+    }
+
+    /// Check whether the document has opened an element that is still open.
+    pub fn is_open(&self, element: &str) -> bool {
+        let element = self.element_name(element);
+        self.stack
+            .iter()
+            .any(|e| !e.generated && e.name == element.as_ref())
+    }
+
+    /// Get the name of an element as it's written, which is lowercased in
+    /// XHTML.
+    fn element_name<'e>(&self, element: &'e str) -> Cow<'e, str> {
+        if self.xhtml && element.contains(|c: char| c.is_ascii_uppercase()) {
+            Cow::Owned(element.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(element)
+        }
+    }
+
+    /// Record the text of a heading that is about to be closed, and assign it
+    /// an id if so configured.
+    ///
+    /// The id attribute is inserted into the start tag whose attributes begin
+    /// at *attr_pos*. Nothing after that position can be the start tag of an
+    /// element that is still open, so no other recorded offsets are
+    /// invalidated.
+    fn finish_heading(&mut self, element: &str, attr_pos: usize, text: String) {
+        self.last_heading_text = Some(text.clone());
+
+        let style = match self.heading_ids {
+            Some(s) => s,
+            None => return,
+        };
+
+        let base = slugify(&text, style);
+        let mut id = base.clone();
+        let mut n = 1;
+
+        while self.ids.contains(&id) {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+
+        if self.heading_anchor_links {
+            write!(self.buf, "<a class=\"tdux-anchor\" href=\"#{}\">#</a>", id).unwrap();
+        }
+
+        self.buf.insert_str(attr_pos, &format!(" id=\"{}\"", id));
+        self.ids.insert(id.clone());
+        self.headings.push(HeadingInfo {
+            level: heading_level(element).unwrap_or(1),
+            id,
+            text,
+        });
+    }
+
+    /// Open a generated section for a heading of the given level.
+    ///
+    /// If a section was opened, returns the offset where attributes may be
+    /// inserted into its start tag.
+    fn open_section(&mut self, level: u8) -> Option<usize> {
+        // Close generated sections of the same or deeper levels, as long as
+        // no document elements are open inside of them.
+
+        while let Some(top) = self.stack.last() {
+            if top.generated && top.level >= level {
+                self.pop_element();
+            } else {
+                break;
+            }
+        }
+
+        // If the document has opened a section itself, the heading belongs to
+        // it and we shouldn't wrap it again.
+
+        if let Some(top) = self.stack.last() {
+            if !top.generated && top.name == "section" {
+                return None;
+            }
+        }
+
+        self.push_element("section", "", true, level);
+        self.stack.last().map(|e| e.attr_pos)
+    }
+
+    /// Write the start tag of an element and push it onto the stack.
+    ///
+    /// *attrs* is inserted into the start tag verbatim, so it should either
+    /// be empty or start with a space.
+    fn push_element(&mut self, element: &str, attrs: &str, generated: bool, level: u8) {
+        self.note_child();
+        write!(self.buf, "<{}{}>", element, attrs).unwrap();
+        self.ends_with_text = false;
+        self.stack.push(OpenElement {
+            name: element.to_owned(),
+            generated,
+            level,
+            attr_pos: self.buf.len() - 1,
+            heading_text: None,
+            n_children: 0,
+            caption_index: None,
+        });
+    }
+
+    /// Record that a child is being added to the innermost open element.
+    fn note_child(&mut self) {
+        if let Some(top) = self.stack.last_mut() {
+            top.n_children += 1;
+        }
+    }
+
+    /// Pop the innermost element from the stack and write its end tag.
+    fn pop_element(&mut self) {
+        if let Some(e) = self.stack.pop() {
+            write!(self.buf, "</{}>", e.name).unwrap();
+            self.ends_with_text = false;
+        }
+    }
+
+    /// Close all generated elements, along with any elements that were left
+    /// open inside of them.
+    fn close_generated(&mut self) {
+        if let Some(pos) = self.stack.iter().position(|e| e.generated) {
+            while self.stack.len() > pos {
+                self.pop_element();
+            }
+        }
+    }
+
+    /// Add a rendered canvas.
+    ///
+    /// Like text, a canvas is separated from preceding text by a space.
+    pub fn canvas(&mut self, html: &str) {
+        if self.ends_with_text {
+            self.flush();
+            self.buf.push(' ');
+        }
+
+        self.raw_html(html);
+    }
+
+    /// Add markup verbatim, without any automatic spacing.
+    pub fn raw_html(&mut self, html: &str) {
+        self.flush();
+        self.note_child();
+        self.buf.push_str(html);
+        self.ends_with_text = false;
+    }
+
+    /// Add markup verbatim that reads as a part of the text, such as the call
+    /// of a footnote. Like text, it's separated by a space from any text that
+    /// follows it.
+    pub fn text_html(&mut self, html: &str) {
+        self.raw_html(html);
+        self.ends_with_text = true;
+    }
+
+    /// Add a void element, such as `<img>`, with attributes.
+    ///
+    /// The attribute values are escaped as needed, but the names should
+    /// already have been validated.
+    pub fn void_element(&mut self, element: &str, attrs: &[(&str, &str)]) {
+        let mut html = format!("<{}", element);
+
+        for (name, value) in attrs {
+            write!(html, " {}=\"{}\"", name, tera::escape_html(value)).unwrap();
+        }
+
+        html.push_str(if self.xhtml { " />" } else { ">" });
+        self.raw_html(&html);
+    }
+
+    /// Add an empty element with the given id, as a target for links.
+    pub fn anchor(&mut self, id: &str) {
+        self.raw_html(&format!("<span id=\"{}\"></span>", tera::escape_html(id)));
+        self.ids.insert(id.to_owned());
+    }
+
+    /// Add a marker requesting a page break before the following content
+    /// when the page is printed.
+    pub fn print_break(&mut self) {
+        self.flush();
+        self.start_block("div");
+        self.raw_html(&format!(
+            "<div class=\"{}\" aria-hidden=\"true\"></div>",
+            PRINT_BREAK_CLASS
+        ));
+    }
+
+    /// Start a table.
+    pub fn table_start(&mut self) {
+        self.flush();
+        self.start_block("table");
+        self.push_element(
+            "table",
+            &format!(" class=\"{}\"", AVOID_BREAK_CLASS),
+            false,
+            0,
+        );
+    }
+
+    /// Start a table row, closing the current row of the innermost table if
+    /// one is open.
+    ///
+    /// Returns descriptions of any problems, which the caller should relay as
+    /// warnings.
+    pub fn table_row_start(&mut self) -> Vec<String> {
+        self.flush();
+        let ctx = self.table_context();
+        let mut problems = Vec::new();
+
+        let table = match ctx.table {
+            Some(t) => t,
+            None => {
+                problems.push("ignoring table row start outside of a table".to_owned());
+                return problems;
+            }
+        };
+
+        self.close_table_part(ctx.row.unwrap_or(table + 1), &mut problems);
+        self.push_element("tr", "", false, 0);
+        problems
+    }
+
+    /// Start a table cell, closing the current cell of the innermost table if
+    /// one is open.
+    ///
+    /// Returns descriptions of any problems, which the caller should relay as
+    /// warnings.
+    pub fn table_cell_start(
+        &mut self,
+        align: Option<CellAlign>,
+        colspan: Option<u32>,
+    ) -> Vec<String> {
+        self.flush();
+        let ctx = self.table_context();
+        let mut problems = Vec::new();
+
+        let row = match ctx.row {
+            Some(r) => r,
+            None => {
+                problems.push("ignoring table cell start outside of a table row".to_owned());
+                return problems;
+            }
+        };
+
+        self.close_table_part(ctx.cell.unwrap_or(row + 1), &mut problems);
+
+        let mut attrs = String::new();
+
+        if let Some(a) = align {
+            write!(attrs, " style=\"text-align: {}\"", a.as_css()).unwrap();
+        }
+
+        if let Some(n) = colspan {
+            if n > 1 {
+                write!(attrs, " colspan=\"{}\"", n).unwrap();
+            }
+        }
+
+        self.push_element("td", &attrs, false, 0);
+        problems
+    }
+
+    /// End the current cell of the innermost table.
+    pub fn table_cell_end(&mut self) -> Vec<String> {
+        self.table_part_end("cell", |ctx| ctx.cell)
+    }
+
+    /// End the current row of the innermost table, along with any cell that
+    /// is open in it.
+    pub fn table_row_end(&mut self) -> Vec<String> {
+        self.table_part_end("row", |ctx| ctx.row)
+    }
+
+    /// End the innermost table, along with any row and cell that are open in
+    /// it.
+    pub fn table_end(&mut self) -> Vec<String> {
+        self.table_part_end("table", |ctx| ctx.table)
+    }
+
+    fn table_part_end<F>(&mut self, desc: &str, get: F) -> Vec<String>
+    where
+        F: FnOnce(&TableContext) -> Option<usize>,
+    {
+        self.flush();
+        let mut problems = Vec::new();
+
+        match get(&self.table_context()) {
+            Some(pos) => self.close_table_part(pos, &mut problems),
+            None => problems.push(format!("ignoring unpaired table {} end", desc)),
+        }
+
+        problems
+    }
+
+    /// Find the parts of the innermost open table.
+    fn table_context(&self) -> TableContext {
+        let mut ctx = TableContext::default();
+
+        for (i, e) in self.stack.iter().enumerate().rev() {
+            if e.generated {
+                continue;
+            }
+
+            match e.name.as_str() {
+                "td" if ctx.cell.is_none() && ctx.row.is_none() => ctx.cell = Some(i),
+                "tr" if ctx.row.is_none() => ctx.row = Some(i),
+                "table" => {
+                    ctx.table = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        if ctx.table.is_none() {
+            ctx = TableContext::default();
+        }
+
+        ctx
+    }
+
+    /// Close the element at position *pos* in the stack, and everything open
+    /// inside of it. Table parts are closed silently, while other document
+    /// elements that are closed implicitly are noted as problems.
+    fn close_table_part(&mut self, pos: usize, problems: &mut Vec<String>) {
+        while self.stack.len() > pos {
+            let top = self.stack.last().unwrap();
+
+            if !top.generated
+                && self.stack.len() > pos + 1
+                && !["table", "tr", "td"].contains(&top.name.as_str())
+            {
+                problems.push(format!(
+                    "implicitly closing unclosed <{}> inside of a table",
+                    top.name
+                ));
+            }
+
+            self.pop_element();
+        }
+    }
+
+    /// Take the accumulated main content, leaving it empty.
+    ///
+    /// Any generated elements that are still open are closed first. Elements
+    /// do not carry over from one output file to the next, and neither do
+    /// element ids.
+    pub fn take(&mut self) -> String {
+        self.ids.clear();
+        self.take_destination(Destination::Main)
+    }
+
+    /// Take the content accumulated for a destination, leaving it empty.
+    ///
+    /// Any generated elements that are still open in the destination are
+    /// closed first. The active destination is not changed.
+    pub fn take_destination(&mut self, dest: Destination) -> String {
+        if dest == self.destination {
+            return self.take_active();
+        }
+
+        match self.parked.remove(&dest) {
+            Some(stream) => {
+                let active = self.swap_stream(stream);
+                let content = self.take_active();
+                self.swap_stream(active);
+                content
+            }
+
+            None => String::new(),
+        }
+    }
+
+    /// Take the content of the active destination, leaving it empty.
+    fn take_active(&mut self) -> String {
+        self.flush();
+        self.close_generated();
+        self.stack.clear();
+        self.implicitly_closed.clear();
+        self.ends_with_text = false;
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Get the text of the most recently closed heading, if any.
+    pub fn last_heading_text(&self) -> Option<&str> {
+        self.last_heading_text.as_deref()
+    }
+
+    /// Deal with any control characters in some text.
+    fn scrub_control_chars<'t>(&mut self, text: &'t str) -> Cow<'t, str> {
+        let is_bad = |c: char| c.is_control() && c != '\t' && c != '\n';
+
+        if !text.contains(is_bad) {
+            return Cow::Borrowed(text);
+        }
+
+        let mut clean = String::with_capacity(text.len());
+
+        for c in text.chars() {
+            if is_bad(c) {
+                *self.control_chars.entry(c).or_default() += 1;
+
+                if self.preserve_control_chars {
+                    write!(clean, "&#x{:X};", c as u32).unwrap();
+                }
+            } else {
+                clean.push(c);
+            }
+        }
+
+        Cow::Owned(clean)
+    }
+
+    /// Take the lengths of the unusually long text runs found since this
+    /// function was last called.
+    pub fn take_long_runs(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.long_runs)
+    }
+
+    /// Take the counts of the control characters found in text since this
+    /// function was last called.
+    pub fn take_control_chars(&mut self) -> BTreeMap<char, usize> {
+        std::mem::take(&mut self.control_chars)
+    }
+
+    /// Take the list of headings that were given ids since this function was
+    /// last called.
+    pub fn take_headings(&mut self) -> Vec<HeadingInfo> {
+        std::mem::take(&mut self.headings)
+    }
+
+    /// Write any pending text run into the buffer.
+    fn flush(&mut self) {
+        if let Some((style, text)) = self.pending.take() {
+            let preformatted = self
+                .stack
+                .iter()
+                .any(|e| e.name == "pre" || e.name == "textarea");
+
+            if !style.is_plain() {
+                self.buf.push_str("<span style=\"");
+                style.write_css(&mut self.buf);
+                self.buf.push_str("\">");
+            }
+
+            let text = if self.xhtml {
+                escape_text(&text)
+            } else {
+                Cow::Borrowed(text.as_str())
+            };
+
+            if preformatted {
+                self.buf.push_str(&text);
+            } else {
+                push_broken_lines(&mut self.buf, &text);
+            }
+
+            if !style.is_plain() {
+                self.buf.push_str("</span>");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine, UrlStyle};
+    use tectonic_xdv::XdvEvents;
+
+    fn sink(auto_sections: bool, heading_ids: Option<SlugStyle>) -> ContentSink {
+        ContentSink::new(&Options {
+            auto_sections,
+            heading_ids,
+            ..Options::default()
+        })
+    }
+
+    fn big() -> TextStyle {
+        TextStyle {
+            size_millirem: Some(1200),
+            ..TextStyle::default()
+        }
+    }
+
+    #[test]
+    fn merge_runs() {
+        let mut sink = ContentSink::default();
+        sink.text(TextStyle::default(), "before");
+        sink.text(big(), "three");
+        sink.text(big(), "word");
+        sink.text(big(), "phrase");
+        sink.text(TextStyle::default(), "after");
+
+        assert_eq!(
+            sink.take(),
+            "before <span style=\"font-size: 1.2rem\">three word phrase</span> after"
+        );
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn markup_breaks_runs() {
+        let mut sink = ContentSink::default();
+        sink.open_tag("p");
+        sink.text(big(), "a");
+        sink.close_tag("p");
+        sink.open_tag("p");
+        sink.text(big(), "b");
+        sink.canvas("<div></div>");
+        sink.text(TextStyle::default(), "c");
+        sink.close_tag("p");
+
+        assert_eq!(
+            sink.take(),
+            "<p><span style=\"font-size: 1.2rem\">a</span></p><p><span style=\"font-size: 1.2rem\">b</span> <div></div>c</p>"
+        );
+    }
+
+    fn heading(sink: &mut ContentSink, element: &str, text: &str) {
+        sink.open_tag(element);
+        sink.text(TextStyle::default(), text);
+        sink.close_tag(element);
+    }
+
+    #[test]
+    fn auto_sections() {
+        let mut sink = sink(true, None);
+        heading(&mut sink, "h2", "A");
+        heading(&mut sink, "h3", "B");
+        sink.open_tag("p");
+        sink.text(TextStyle::default(), "text");
+        sink.close_tag("p");
+        heading(&mut sink, "h2", "C");
+
+        assert_eq!(
+            sink.take(),
+            "<section><h2>A</h2><section><h3>B</h3><p>text</p></section></section><section><h2>C</h2></section>"
+        );
+
+        // Nothing carries over to the next page.
+        heading(&mut sink, "h3", "D");
+        assert_eq!(sink.take(), "<section><h3>D</h3></section>");
+    }
+
+    #[test]
+    fn auto_sections_authored() {
+        let mut sink = sink(true, None);
+        sink.open_tag("section");
+        heading(&mut sink, "h2", "A");
+        sink.close_tag("section");
+        sink.open_tag("div");
+        heading(&mut sink, "h2", "B");
+        sink.close_tag("div");
+
+        assert_eq!(
+            sink.take(),
+            "<section><h2>A</h2></section><div><section><h2>B</h2></section></div>"
+        );
+    }
+
+    #[test]
+    fn no_auto_sections() {
+        let mut sink = sink(false, None);
+        heading(&mut sink, "h2", "A");
+        assert_eq!(sink.take(), "<h2>A</h2>");
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("Hello, World!", SlugStyle::Ascii), "hello-world");
+        assert_eq!(slugify("  1.2 Results ", SlugStyle::Ascii), "1-2-results");
+        assert_eq!(slugify("Ärger über Öl", SlugStyle::Ascii), "arger-uber-ol");
+        assert_eq!(
+            slugify("Ärger über Öl", SlugStyle::Unicode),
+            "ärger-über-öl"
+        );
+        assert_eq!(slugify("∑", SlugStyle::Unicode), "section");
+    }
+
+    #[test]
+    fn heading_ids() {
+        let mut sink = sink(false, Some(SlugStyle::Ascii));
+        sink.open_tag("h2");
+        sink.text(TextStyle::default(), "Intro");
+        sink.text(big(), "duction");
+        sink.close_tag("h2");
+        heading(&mut sink, "h2", "Introduction");
+        heading(&mut sink, "h3", "Intro duction");
+        sink.open_tag("p");
+        sink.text(TextStyle::default(), "Intro duction");
+        sink.close_tag("p");
+
+        assert_eq!(
+            sink.take(),
+            "<h2 id=\"intro-duction\">Intro <span style=\"font-size: 1.2rem\">duction</span></h2>\
+             <h2 id=\"introduction\">Introduction</h2>\
+             <h3 id=\"intro-duction-2\">Intro duction</h3><p>Intro duction</p>"
+        );
+
+        let headings = sink.take_headings();
+        assert_eq!(headings.len(), 3);
+        assert_eq!(headings[2].level, 3);
+        assert_eq!(headings[2].id, "intro-duction-2");
+        assert_eq!(headings[2].text, "Intro duction");
+
+        // Uniqueness is per page.
+        heading(&mut sink, "h2", "Introduction");
+        assert_eq!(sink.take(), "<h2 id=\"introduction\">Introduction</h2>");
+    }
+
+    #[test]
+    fn heading_ids_sections_and_links() {
+        let mut sink = ContentSink::new(&Options {
+            auto_sections: true,
+            heading_ids: Some(SlugStyle::Ascii),
+            heading_anchor_links: true,
+            ..Options::default()
+        });
+        heading(&mut sink, "h2", "A");
+        heading(&mut sink, "h3", "B");
+
+        assert_eq!(
+            sink.take(),
+            "<section id=\"a\"><h2>A<a class=\"tdux-anchor\" href=\"#a\">#</a></h2>\
+             <section id=\"b\"><h3>B<a class=\"tdux-anchor\" href=\"#b\">#</a></h3></section></section>"
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let mut sink = sink(false, None);
+        assert_eq!(sink.table_row_start().len(), 1);
+        sink.table_start();
+
+        for row in 0..3 {
+            assert!(sink.table_row_start().is_empty());
+
+            if row == 1 {
+                assert!(sink
+                    .table_cell_start(Some(CellAlign::Center), Some(2))
+                    .is_empty());
+                sink.text(TextStyle::default(), "wide");
+                assert!(sink.table_cell_end().is_empty());
+                assert!(sink.table_cell_start(None, Some(1)).is_empty());
+                sink.text(TextStyle::default(), "x");
+            } else {
+                for col in 0..3 {
+                    // Cells are closed implicitly by the next cell start, or
+                    // by the row end.
+                    assert!(sink
+                        .table_cell_start(CellAlign::parse("r"), None)
+                        .is_empty());
+                    sink.text(TextStyle::default(), &format!("{}{}", row, col));
+                }
+            }
+
+            assert!(sink.table_row_end().is_empty());
+        }
+
+        assert!(sink.table_end().is_empty());
+        assert_eq!(sink.table_end().len(), 1);
+
+        assert_eq!(
+            sink.take(),
+            "<table class=\"tdux-avoid-break\">\
+             <tr><td style=\"text-align: right\">00</td><td style=\"text-align: right\">01</td><td style=\"text-align: right\">02</td></tr>\
+             <tr><td style=\"text-align: center\" colspan=\"2\">wide</td><td>x</td></tr>\
+             <tr><td style=\"text-align: right\">20</td><td style=\"text-align: right\">21</td><td style=\"text-align: right\">22</td></tr>\
+             </table>"
+        );
+    }
+
+    #[test]
+    fn nested_tables() {
+        let mut sink = sink(false, None);
+        sink.table_start();
+        sink.table_row_start();
+        sink.table_cell_start(None, None);
+        sink.table_start();
+        sink.table_row_start();
+        sink.table_cell_start(None, None);
+        sink.open_tag("em");
+        sink.text(TextStyle::default(), "inner");
+
+        // The <em> is left open, so we should hear about it.
+        assert_eq!(sink.table_end().len(), 1);
+        sink.table_cell_start(None, None);
+        sink.text(TextStyle::default(), "outer");
+        sink.table_end();
+
+        assert_eq!(
+            sink.take(),
+            "<table class=\"tdux-avoid-break\"><tr><td><table class=\"tdux-avoid-break\"><tr><td><em>inner</em></td></tr></table></td><td>outer</td></tr></table>"
+        );
+    }
+
+    #[test]
+    fn block_closes_paragraph() {
+        let mut sink = sink(false, None);
+        assert!(sink.open_tag("p").is_empty());
+        sink.text(TextStyle::default(), "said");
+        assert!(sink.open_tag("em").is_empty());
+        sink.text(TextStyle::default(), "this:");
+        assert!(sink.open_tag("blockquote").is_empty());
+        sink.text(TextStyle::default(), "quoted");
+        assert!(sink.close_tag("blockquote").is_empty());
+
+        // The end tags of the implicitly closed elements are swallowed.
+        assert!(sink.close_tag("em").is_empty());
+        assert!(sink.close_tag("p").is_empty());
+
+        assert_eq!(
+            sink.take(),
+            "<p>said<em>this:</em></p><blockquote>quoted</blockquote>"
+        );
+    }
+
+    #[test]
+    fn content_model() {
+        let mut sink = sink(false, None);
+        assert!(sink.open_tag("dl").is_empty());
+        assert!(sink.open_tag("dt").is_empty());
+        assert!(sink.close_tag("dt").is_empty());
+        assert!(sink.open_tag("div").is_empty());
+        assert!(sink.open_tag("dd").is_empty());
+        assert!(sink.close_tag("dd").is_empty());
+        assert!(sink.close_tag("div").is_empty());
+        assert!(sink.close_tag("dl").is_empty());
+        assert_eq!(sink.open_tag("dd").len(), 1);
+        assert!(sink.close_tag("dd").is_empty());
+
+        assert!(sink.open_tag("figure").is_empty());
+        sink.canvas("<img>");
+        assert!(sink.open_tag("figcaption").is_empty());
+        assert!(sink.close_tag("figcaption").is_empty());
+        assert!(sink.close_tag("figure").is_empty());
+
+        assert!(sink.open_tag("figure").is_empty());
+        sink.canvas("<img>");
+        assert!(sink.open_tag("figcaption").is_empty());
+        assert!(sink.close_tag("figcaption").is_empty());
+        sink.canvas("<img>");
+        assert_eq!(sink.close_tag("figure").len(), 1);
+
+        assert_eq!(sink.open_tag("figcaption").len(), 1);
+    }
+
+    #[test]
+    fn attributes() {
+        let mut sink = sink(false, None);
+        assert_eq!(semantic_element("quote"), Some("q"));
+        assert_eq!(semantic_element("q"), None);
+
+        sink.open_tag_with_attrs("q", &[("cite", "https://example.com/?a&b")]);
+        sink.text(TextStyle::default(), "hi");
+        sink.close_tag("q");
+        assert_eq!(
+            sink.take(),
+            "<q cite=\"https:&#x2F;&#x2F;example.com&#x2F;?a&amp;b\">hi</q>"
+        );
+    }
+
+    #[test]
+    fn xhtml() {
+        let mut sink = ContentSink::new(&Options {
+            serialization: Serialization::Xhtml,
+            preserve_control_chars: true,
+            ..Options::default()
+        });
+
+        sink.open_tag("P");
+        sink.text(TextStyle::default(), "a & b <c>\u{c}");
+        sink.open_tag("BR");
+        sink.close_tag("br");
+        sink.open_tag_with_attrs("img", &[("alt", "x")]);
+        sink.text(TextStyle::default(), "d");
+        sink.close_tag("P");
+        assert_eq!(
+            sink.take(),
+            "<p>a &amp; b &lt;c&gt;<br /><img alt=\"x\" />d</p>"
+        );
+        assert_eq!(sink.take_control_chars().len(), 1);
+    }
+
+    #[test]
+    fn destinations() {
+        let mut sink = sink(false, None);
+        sink.open_tag("p");
+        sink.text(TextStyle::default(), "main");
+
+        sink.set_destination(Destination::Footnotes);
+        sink.text(TextStyle::default(), "note");
+        sink.raw_html("<br>");
+        sink.text(TextStyle::default(), "more");
+
+        sink.set_destination(Destination::Region("aside".to_owned()));
+        sink.open_tag("em");
+        sink.text(TextStyle::default(), "side");
+
+        // The element opened in the main content is still open there.
+        sink.set_destination(Destination::Main);
+        sink.text(TextStyle::default(), "again");
+        sink.close_tag("p");
+
+        assert!(!sink.is_empty());
+        assert_eq!(sink.take(), "<p>main again</p>");
+        assert_eq!(
+            sink.take_destination(Destination::Footnotes),
+            "note<br>more"
+        );
+        assert_eq!(
+            sink.take_destination(Destination::Region("aside".to_owned())),
+            "<em>side"
+        );
+        assert_eq!(sink.take_destination(Destination::Footnotes), "");
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn control_chars() {
+        let mut sink = sink(false, None);
+        sink.text(TextStyle::default(), "a\u{0}b\u{c}\tc\u{c}\u{85}");
+        assert_eq!(sink.take(), "ab\tc");
+        assert_eq!(
+            sink.take_control_chars().into_iter().collect::<Vec<_>>(),
+            vec![('\u{0}', 1), ('\u{c}', 2), ('\u{85}', 1)]
+        );
+        assert!(sink.take_control_chars().is_empty());
+
+        let mut sink = ContentSink::new(&Options {
+            preserve_control_chars: true,
+            ..Options::default()
+        });
+        sink.text(TextStyle::default(), "a\u{c}b");
+        assert_eq!(sink.take(), "a&#xC;b");
+        assert_eq!(sink.take_control_chars().len(), 1);
+    }
+
+    #[test]
+    fn space_suppression() {
+        // Each case is a pair of text runs in the same style, and then in
+        // different styles, and the expected text between them.
+        const CASES: &[(&str, &str, &str)] = &[
+            ("a", "b", "a b"),
+            ("(", "a", "(a"),
+            ("[", "a", "[a"),
+            ("\u{201C}", "a", "\u{201C}a"),
+            ("\u{AB}", "a", "\u{AB}a"),
+            ("\u{BF}", "a", "\u{BF}a"),
+            ("a", ")", "a)"),
+            ("a", ",", "a,"),
+            ("a", ".", "a."),
+            ("a", "\u{201D}", "a\u{201D}"),
+            ("a", "\u{2026}", "a\u{2026}"),
+            ("a\u{2014}", "b", "a\u{2014}b"),
+            ("a", "\u{2013}b", "a\u{2013}b"),
+            ("a)", "(b", "a) (b"),
+            ("a.", "b", "a. b"),
+        ];
+
+        for (first, second, expected) in CASES {
+            let mut sink = ContentSink::default();
+            sink.text(TextStyle::default(), first);
+            sink.text(TextStyle::default(), second);
+            assert_eq!(sink.take(), *expected, "merged {:?} + {:?}", first, second);
+
+            let mut sink = ContentSink::default();
+            sink.text(TextStyle::default(), first);
+            sink.text(big(), second);
+            let html = sink.take();
+            let joined = html.replace("<span style=\"font-size: 1.2rem\">", "");
+            assert_eq!(joined.replace("</span>", ""), *expected, "split {}", html);
+        }
+
+        // The classes can be extended.
+        let mut extended = ContentSink::new(&Options {
+            no_space_after: "/".to_owned(),
+            no_space_before: "/".to_owned(),
+            ..Options::default()
+        });
+        extended.text(TextStyle::default(), "a");
+        extended.text(TextStyle::default(), "/");
+        extended.text(TextStyle::default(), "b");
+        assert_eq!(extended.take(), "a/b");
+
+        // Heading text follows the same rules.
+        let mut heading = sink(false, Some(SlugStyle::Ascii));
+        heading.open_tag("h1");
+        heading.text(TextStyle::default(), "(a");
+        heading.text(big(), ")");
+        heading.close_tag("h1");
+        assert_eq!(heading.last_heading_text(), Some("(a)"));
+    }
+
+    #[test]
+    fn long_runs() {
+        let word = "x".repeat(MAX_LINE_LEN / 2 + 1);
+        let text = format!("{} {} {}", word, word, word);
+
+        let mut sink = ContentSink::default();
+        sink.text(TextStyle::default(), &text);
+        assert_eq!(sink.take(), format!("{}\n{}\n{}", word, word, word));
+        assert!(sink.take_long_runs().is_empty());
+
+        // Runs without spaces can't be broken.
+        let mut sink = ContentSink::default();
+        sink.text(big(), &word.repeat(3));
+        assert!(!sink.take().contains('\n'));
+
+        let mut sink = ContentSink::default();
+        sink.open_tag("pre");
+        sink.text(TextStyle::default(), &text);
+        sink.close_tag("pre");
+        assert_eq!(sink.take(), format!("<pre>{}</pre>", text));
+    }
+
+    #[test]
+    fn table_with_multicolumn_cell() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let (result, status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:tableStart")?;
+
+            // Like a LaTeX tabular with columns `lcr`, where the middle row
+            // has a `\multicolumn{2}{c}` cell. The TeX support code doesn't
+            // end the final cell of each row explicitly.
+            for row in 0..3 {
+                s.special("tdux:rowStart")?;
+
+                if row == 1 {
+                    s.special("tdux:cellStart c 2")?;
+                    s.text("span")?;
+                    s.special("tdux:cellEnd")?;
+                    s.special("tdux:cellStart r")?;
+                    s.text("x")?;
+                } else {
+                    for (col, align) in ["l", "c", "r"].iter().enumerate() {
+                        s.special(&format!("tdux:cellStart {}", align))?;
+                        s.text(&format!("{}{}", row, col))?;
+                    }
+                }
+
+                s.special("tdux:rowEnd")?;
+            }
+
+            s.special("tdux:tableEnd")?;
+            s.special("tdux:rowEnd")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert!(status.warnings[0].contains("unpaired table row end"));
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(
+            html,
+            "<html><body><table class=\"tdux-avoid-break\">\
+             <tr><td style=\"text-align: left\">00</td><td style=\"text-align: center\">01</td><td style=\"text-align: right\">02</td></tr>\
+             <tr><td style=\"text-align: center\" colspan=\"2\">span</td><td style=\"text-align: right\">x</td></tr>\
+             <tr><td style=\"text-align: left\">20</td><td style=\"text-align: center\">21</td><td style=\"text-align: right\">22</td></tr>\
+             </table></body></html>"
+        );
+    }
+
+    #[test]
+    fn semantic_markup() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let (result, status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Terms")?;
+            s.special("tdux:as p")?;
+            s.special("tdux:asSemantic blockquote class=\"pull quote\" bogus")?;
+            s.text("quoted")?;
+            s.special("tdux:aeSemantic blockquote")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:asSemantic dl")?;
+            s.special("tdux:asSemantic dt")?;
+            s.text("term")?;
+            s.special("tdux:aeSemantic dt")?;
+            s.special("tdux:aeSemantic dl")?;
+            s.special("tdux:asSemantic dd")?;
+            s.special("tdux:aeSemantic dd")?;
+            s.special("tdux:asSemantic marquee")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.warnings.len(), 3, "{:?}", status.warnings);
+        assert!(status.warnings[0].contains("malformed attribute `bogus`"));
+        assert!(status.warnings[1].contains("<dd> should only appear inside of <dl>"));
+        assert!(status.warnings[2].contains("unrecognized semantic element `marquee`"));
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(
+            html,
+            "<html><body>Terms<p></p><blockquote class=\"pull quote\">quoted</blockquote>\
+             <dl><dt>term</dt></dl><dd></dd></body></html>"
+        );
+    }
+
+    #[test]
+    fn control_chars_warning() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let (result, status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello\u{0}")?;
+            s.text("\u{c}world\u{c}")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert_eq!(
+            status.warnings[0],
+            "3 control character(s) in the text of `index.html` were removed: U+0000 (1), U+000C (2)"
+        );
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "<html><body>Hello world</body></html>");
+    }
+
+    #[test]
+    fn print_support() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("print.css", "{{ tduxPrintCss | safe }}");
+        io.add(
+            "outline.html",
+            "{% for h in tduxOutline %}{{ h.path }}#{{ h.id }} {{ h.level }} {{ h.text }};{% endfor %}",
+        );
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.heading_ids(Some(SlugStyle::Ascii));
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:as h1")?;
+            s.text("One")?;
+            s.special("tdux:ae h1")?;
+            s.special("tdux:as p")?;
+            s.text("a")?;
+            s.special("tdux:printBreakBefore")?;
+            s.text("b")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:as h2")?;
+            s.text("Two")?;
+            s.special("tdux:ae h2")?;
+            s.special("tdux:setTemplate outline.html")?;
+            s.special("tdux:setOutputPath outline.txt")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplate print.css")?;
+            s.special("tdux:setOutputPath print.css")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let html = std::fs::read_to_string(tempdir.path().join("two.html")).unwrap();
+        assert_eq!(
+            html,
+            "<html><body><h1 id=\"one\">One</h1><p>a</p>\
+             <div class=\"tdux-print-break\" aria-hidden=\"true\"></div>b</body></html>"
+        );
+
+        let outline = std::fs::read_to_string(tempdir.path().join("outline.txt")).unwrap();
+        assert_eq!(outline, "two.html#one 1 One;outline.txt#two 2 Two;");
+
+        let css = std::fs::read_to_string(tempdir.path().join("print.css")).unwrap();
+        assert_eq!(
+            css,
+            ".tdux-print-break { break-before: page; }\n\
+             .tdux-avoid-break { break-inside: avoid; }\n\
+             h1, h2, h3, h4, h5, h6 { break-after: avoid; }\n\
+             a[href^=\"http\"]::after { content: \" (\" attr(href) \")\"; font-size: 90%; }\n\
+             .tdux-anchor, .tdux-no-print { display: none; }\n\
+             .tdux-print-only { display: block; }\n"
+        );
+    }
+
+    #[test]
+    fn long_text_run() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let text = (0..150_000)
+            .map(|i| format!("w{}", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert!(text.len() > 1024 * 1024);
+
+        let (result, status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text(&text)?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert!(status.warnings[0].contains(&format!(
+            "a text run in `index.html` is {} bytes long",
+            text.len()
+        )));
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(html.lines().all(|l| l.len() <= MAX_LINE_LEN + 12));
+        assert_eq!(
+            html.replace('\n', " "),
+            format!("<html><body>{}</body></html>", text)
+        );
+    }
+
+    #[test]
+    fn url_styles() {
+        const TEMPLATE: &str = "{{ tduxUrlStyle }}|{{ tduxCurrentUrl }}|\
+            {% for h in tduxOutline %}{{ tduxRelTop }}{{ h.url }}#{{ h.id }} {% endfor %}";
+
+        let cases = [
+            (
+                UrlStyle::Html,
+                [
+                    ("index.html", "html|index.html|"),
+                    (
+                        "guide/setup.html",
+                        "html|guide/setup.html|../guide/setup.html#setup ",
+                    ),
+                    (
+                        "guide/index.html",
+                        "html|guide/index.html|../guide/setup.html#setup ../guide/index.html#guide ",
+                    ),
+                ],
+            ),
+            (
+                UrlStyle::Directory,
+                [
+                    ("index.html", "directory|./|"),
+                    (
+                        "guide/setup/index.html",
+                        "directory|guide/setup/|../../guide/setup/#setup ",
+                    ),
+                    (
+                        "guide/index.html",
+                        "directory|guide/|../guide/setup/#setup ../guide/#guide ",
+                    ),
+                ],
+            ),
+            (
+                UrlStyle::Bare,
+                [
+                    ("index.html", "bare|./|"),
+                    ("guide/setup.html", "bare|guide/setup|../guide/setup#setup "),
+                    (
+                        "guide/index.html",
+                        "bare|guide/|../guide/setup#setup ../guide/#guide ",
+                    ),
+                ],
+            ),
+        ];
+
+        for (style, expected) in cases {
+            let tempdir = tempfile::tempdir().unwrap();
+            let mut io = TestIo::new();
+            io.add("page.html", TEMPLATE);
+
+            let mut engine = Spx2HtmlEngine::default();
+            engine.heading_ids(Some(SlugStyle::Ascii)).url_style(style);
+
+            let (result, status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:setOutputPath guide/setup.html")?;
+                s.special("tdux:as h1")?;
+                s.text("Setup")?;
+                s.special("tdux:ae h1")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:setOutputPath guide/")?;
+                s.special("tdux:as h1")?;
+                s.text("Guide")?;
+                s.special("tdux:ae h1")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+            result.unwrap();
+            assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+            for (path, html) in expected {
+                let actual = std::fs::read_to_string(tempdir.path().join(path))
+                    .unwrap_or_else(|e| panic!("{:?}: {}: {}", style, path, e));
+                assert_eq!(actual, html, "{:?}", style);
+            }
+        }
+    }
+
+    #[test]
+    fn xhtml_serialization() {
+        let mut io = simple_io();
+        io.add(
+            "page.xhtml",
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\"><body>{{ tduxContent }}</body></html>",
+        );
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .serialization(Serialization::Xhtml)
+            .auto_sections(true)
+            .heading_ids(Some(SlugStyle::Ascii))
+            .heading_anchor_links(true);
+
+        let x = glyph_for('x');
+        let (result, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate page.xhtml")?;
+            s.special("tdux:setTemplate page.xhtml")?;
+            s.text("Intro")?;
+            s.special("tdux:as H1")?;
+            s.text("Fish & Chips")?;
+            s.special("tdux:ae H1")?;
+            s.special("tdux:as p")?;
+            s.text("a < b")?;
+            s.special("tdux:as br")?;
+            s.text("c")?;
+            s.special("tdux:asSemantic quote cite=\"a&b\"")?;
+            s.text("d")?;
+            s.special("tdux:aeSemantic quote")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:printBreakBefore")?;
+            s.special("tdux:tableStart")?;
+            s.special("tdux:rowStart")?;
+            s.special("tdux:cellStart")?;
+            s.text("e")?;
+            s.special("tdux:tableEnd")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = result.unwrap();
+        assert_eq!(status.warnings, Vec::<String>::new());
+
+        let page = String::from_utf8(files["index.html"].clone()).unwrap();
+        assert!(page.contains("<h1>Fish &amp; Chips<a "), "{}", page);
+        assert!(page.contains("<p>a &lt; b<br />c<q "), "{}", page);
+
+        if let Err(e) = roxmltree::Document::parse(&page) {
+            panic!("not well-formed XML: {}\n{}", e, page);
+        }
+    }
+
+    #[test]
+    fn start_tag_with_attributes() {
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:asp main id=\"top\"")?;
+            s.text("a")?;
+            s.special("tdux:asp div class=\"theorem  proof\" id='thm:1' data-n=1&2")?;
+            s.text("b")?;
+            s.special("tdux:ae div")?;
+            s.special("tdux:asp span title=\"x\" bad\"attr title=\"y\"")?;
+            s.text("c")?;
+            s.special("tdux:ae span")?;
+            s.special("tdux:asp div\"><script")?;
+            s.special("tdux:ae main")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:asp p class=\"late\"")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            files["index.html"],
+            b"<html><body><main id=\"top\">a\
+              <div class=\"theorem  proof\" id=\"thm:1\" data-n=\"1&amp;2\">b</div>\
+              <span title=\"x\">c</span></main></body></html>"
+        );
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring malformed attribute `bad\"attr` \
+                 (special `tdux:asp span title=\"x\" bad\"attr title=\"y\"`)",
+                "ignoring duplicate attribute `title` \
+                 (special `tdux:asp span title=\"x\" bad\"attr title=\"y\"`)",
+                "ignoring invalid element name `div\"><script` (special `tdux:asp div\"><script`)",
+                "dropping post-finish content (auto start tag <p>)",
+            ]
+        );
+    }
+
+    #[test]
+    fn direct_html() {
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:direct <!-- start -->")?;
+            s.text("a")?;
+            s.special("tdux:direct <iframe src=\"x.html\">  </iframe>")?;
+            s.text("b")?;
+            s.special("tdux:directLine <td>")?;
+            s.special("tdux:directStart")?;
+            s.special("tdux:directLine <svg>")?;
+            s.special("tdux:directLine   <rect width=\"1\"/>")?;
+            s.special("tdux:directLine </svg>")?;
+            s.special("tdux:directEnd")?;
+            s.text("c")?;
+            s.special("tdux:directEnd")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:direct <hr>")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            files["index.html"],
+            b"<html><body><!-- start -->a<iframe src=\"x.html\">  </iframe>b\
+              <svg>\n  <rect width=\"1\"/>\n</svg>c</body></html>"
+        );
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring special `tdux:directLine <td>` outside of tdux:directStart and \
+                 tdux:directEnd",
+                "ignoring unpaired tdux:directEnd special",
+                "dropping post-finish content (verbatim HTML)",
+            ]
+        );
+    }
+
+    #[test]
+    fn class_special() {
+        let mut engine = Spx2HtmlEngine::default();
+        let x = glyph_for('x');
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:class abstract")?;
+            s.special("tdux:class  wide \"x\" ")?;
+            s.special("tdux:as p")?;
+            s.text("b")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:as p")?;
+            s.text("c")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:class eq")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:class orphan")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.text("d")?;
+            s.special("tdux:class late")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:class last")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec![
+                "dropping class(es) `orphan` of tdux:class, which no element took",
+                "dropping class(es) `late` of tdux:class, which no element took",
+                "dropping class(es) `last` of tdux:class, which no element took",
+            ]
+        );
+
+        let html = String::from_utf8_lossy(&files["index.html"]);
+        assert!(html.starts_with(
+            "<html><body>a<p class=\"abstract wide &quot;x&quot;\">b</p><p>c</p>\
+             <span class=\"canvas canvas-inline eq\""
+        ));
+        assert_eq!(
+            String::from_utf8_lossy(&files["two.html"]),
+            "<html><body>d</body></html>"
+        );
+    }
+
+    #[test]
+    fn style_special() {
+        let mut engine = Spx2HtmlEngine::default();
+        let x = glyph_for('x');
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:style margin-left: 2em;")?;
+            s.special("tdux:style  font-family: \"A B\"")?;
+            s.special("tdux:class note")?;
+            s.special("tdux:as p")?;
+            s.text("b")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:style color: red")?;
+            s.special("tdux:asp div style=\"top: 0\" id=\"d\"")?;
+            s.special("tdux:ae div")?;
+            s.special("tdux:style opacity: 0.5")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:style color: blue")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec!["dropping style `color: blue` of tdux:style, which no element took"]
+        );
+
+        let html = String::from_utf8_lossy(&files["index.html"]);
+        assert!(html.starts_with(
+            "<html><body>a\
+             <p class=\"note\" style=\"margin-left: 2em; font-family: &quot;A B&quot;\">b</p>\
+             <div style=\"top: 0; color: red\" id=\"d\"></div>"
+        ));
+        assert!(html.contains("rem; opacity: 0.5\"><span class=\"ci\""));
+    }
+
+    #[test]
+    fn language_scopes() {
+        let mut io = simple_io();
+        io.add(
+            "lang.html",
+            "<html lang=\"{{ tduxLanguage }}\"><body>{{ tduxContent }}</body></html>",
+        );
+
+        let (result, status) = run_engine_in_memory(&mut Spx2HtmlEngine::default(), io, |s| {
+            s.special("tdux:setLanguage en-GB")?;
+            s.special("tdux:addTemplate lang.html")?;
+            s.special("tdux:setTemplate lang.html")?;
+            s.text("He said")?;
+            s.special("tdux:as p")?;
+            s.text("that")?;
+            s.special("tdux:setLanguage fr")?;
+            s.text("bonjour")?;
+            s.special("tdux:endLanguage")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:setLanguage x\"y")?;
+            s.special("tdux:endLanguage")?;
+            s.special("tdux:setLanguage de")?;
+            s.special("tdux:setLanguage de-CH")?;
+            s.text("Grüezi")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("Next")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = result.unwrap();
+        assert_eq!(
+            files["two.html"],
+            b"<html lang=\"en-GB\"><body>Next</body></html>"
+        );
+
+        let html = String::from_utf8(files["index.html"].clone()).unwrap();
+        assert_eq!(
+            html,
+            "<html lang=\"en-GB\"><body>He said<p>that<span lang=\"fr\">bonjour</span></p>\
+             <span lang=\"de\"><span lang=\"de-CH\">Grüezi</span></span></body></html>"
+        );
+
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring tdux:setLanguage special with invalid language tag `x\"y`",
+                "ignoring tdux:endLanguage special with no matching tdux:setLanguage",
+                "closing 2 language scope(s) with no tdux:endLanguage special at the end of `index.html`",
+            ]
+        );
+    }
+
+    #[test]
+    fn deferred_content() {
+        let mut io = simple_io();
+        io.add(
+            "notes.html",
+            "<body>{{ tduxContent }}<footer>{{ tduxDeferred.footnotes }}</footer></body>",
+        );
+
+        let x = glyph_for('x');
+        let (files, status) = run_engine_in_memory(&mut Spx2HtmlEngine::default(), io, |s| {
+            s.special("tdux:addTemplate notes.html")?;
+            s.special("tdux:setTemplate notes.html")?;
+            s.text("Body")?;
+            s.special("tdux:deferStart footnotes")?;
+            s.special("tdux:as p")?;
+            s.text("Note")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:deferEnd")?;
+            s.special("tdux:as p")?;
+            s.text("More")?;
+            s.special("tdux:ae p")?;
+            s.special("tdux:deferEnd")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("Two")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath three.html")?;
+            s.text("Three")?;
+            s.special("tdux:deferStart footnotes")?;
+            s.text("Unended")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring tdux:deferEnd special with no matching tdux:deferStart",
+                "ending the deferred stream `footnotes` with no tdux:deferEnd special at the end of `three.html`",
+            ]
+        );
+
+        let html = String::from_utf8_lossy(&files["index.html"]);
+        let (body, footer) = html.split_once("<footer>").unwrap();
+        assert_eq!(body, "<body>Body<p>More</p>");
+        assert!(
+            footer.starts_with("<p>Note <span class=\"canvas canvas-inline\""),
+            "{}",
+            footer
+        );
+        assert!(footer.ends_with("</p></footer></body>"), "{}", footer);
+
+        assert_eq!(files["two.html"], b"<body>Two<footer></footer></body>");
+        assert_eq!(
+            files["three.html"],
+            b"<body>Three<footer>Unended</footer></body>"
+        );
+
+        let (result, _status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.special("tdux:deferStart footnotes")?;
+                s.special("tdux:deferStart margin")
+            });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "cannot start deferring content to `margin` while deferring it to `footnotes`; \
+             tdux:deferStart doesn't nest"
+        );
+    }
+
+    #[test]
+    fn footnotes() {
+        let x = glyph_for('x');
+        let mut io = simple_io();
+        io.add(
+            "notes.html",
+            "{{ tduxContent }}<ol>{% for f in tduxFootnotes %}<li id=\"{{ f.id }}\">\
+             <a href=\"#{{ f.ref_id }}\">{{ f.mark }}</a> {{ f.html }}</li>{% endfor %}</ol>",
+        );
+
+        let (files, status) = run_engine_in_memory(&mut Spx2HtmlEngine::default(), io, |s| {
+            s.special("tdux:addTemplate notes.html")?;
+            s.special("tdux:setTemplate notes.html")?;
+            s.text("Main")?;
+            s.special("tdux:footnoteStart 1")?;
+            s.text("First")?;
+            s.special("tdux:footnoteStart 9")?;
+            s.special("tdux:footnoteEnd")?;
+            s.text("text")?;
+            s.special("tdux:footnoteStart *")?;
+            s.text("With")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:footnoteEnd")?;
+            s.special("tdux:footnoteEnd")?;
+            s.text("done")?;
+            s.special("tdux:emit")?;
+            s.text("Next")?;
+            s.special("tdux:footnoteStart a")?;
+            s.text("Unended")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring special `tdux:footnoteStart 9` inside of the footnote started with \
+                 tdux:footnoteStart",
+                "ignoring tdux:footnoteEnd special with no matching tdux:footnoteStart",
+                "ending the footnote `a` with no tdux:footnoteEnd special at the end of `two.html`",
+            ]
+        );
+
+        let html = String::from_utf8(files["index.html"].clone()).unwrap();
+        assert!(
+            html.starts_with(
+                "Main<sup><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup> text\
+                 <sup><a href=\"#fn-2\" id=\"fnref-2\">*</a></sup> done<ol>\
+                 <li id=\"fn-1\"><a href=\"#fnref-1\">1</a> First</li>\
+                 <li id=\"fn-2\"><a href=\"#fnref-2\">*</a> With <span class=\"canvas canvas-inline\""
+            ),
+            "{}",
+            html
+        );
+        assert!(html.ends_with("</span></li></ol>"), "{}", html);
+
+        // The numbering starts over in each file.
+        assert_eq!(
+            String::from_utf8_lossy(&files["two.html"]),
+            "Next<sup><a href=\"#fn-1\" id=\"fnref-1\">a</a></sup><ol>\
+             <li id=\"fn-1\"><a href=\"#fnref-1\">a</a> Unended</li></ol>"
+        );
+    }
+}
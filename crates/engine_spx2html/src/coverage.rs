@@ -0,0 +1,220 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Checking whether the document's fonts can render its text.
+//!
+//! A character that none of the fonts map to a glyph is rendered by the
+//! browser with some fallback font, if it's lucky, or as a "tofu" box
+//! otherwise. To let tooling catch such problems before publishing, the
+//! engine tallies the characters of the text that it emits and, when the
+//! content is finished, checks them against the character maps of the fonts.
+//! The resulting [`CoverageReport`] is delivered to the
+//! [`crate::progress::ProgressSink`], and can optionally be made available to
+//! templates with [`crate::Spx2HtmlEngine::uncovered_chars_variable`].
+//!
+//! Only text outside of canvases is considered, since the glyphs of canvases
+//! are drawn directly. Whitespace is ignored.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::html::FontLookup;
+
+/// How the document's fonts cover the characters of its text.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CoverageReport {
+    /// The number of distinct characters in the emitted text.
+    pub total_chars: usize,
+
+    /// The coverage of each font, in the order in which the fonts were
+    /// loaded.
+    pub families: Vec<FamilyCoverage>,
+
+    /// The characters of the emitted text that none of the fonts can render,
+    /// in order of their code points.
+    pub uncovered: Vec<UncoveredChar>,
+}
+
+/// How one font covers the characters of the document's text.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FamilyCoverage {
+    /// The file name of the font.
+    pub family: String,
+
+    /// The number of distinct characters of the emitted text that the font
+    /// can render.
+    pub covered: usize,
+}
+
+/// A character that none of the document's fonts can render.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct UncoveredChar {
+    /// The character.
+    pub character: char,
+
+    /// The code point of the character, in the form `U+1F600`.
+    pub codepoint: String,
+
+    /// How many times the character occurs in the emitted text.
+    pub count: usize,
+
+    /// The output path of the first file in which the character occurs.
+    pub first_path: String,
+
+    /// Whether the font of last resort, if one was configured with
+    /// [`crate::Spx2HtmlEngine::last_resort_font`], can render the
+    /// character.
+    pub rescued: bool,
+}
+
+/// Tallies the characters of the emitted text.
+#[derive(Debug, Default)]
+pub(crate) struct CharTally {
+    /// Characters in the file being built, with their counts.
+    page: HashMap<char, usize>,
+
+    /// Characters in the files emitted so far, with their counts and the
+    /// first file that they occurred in.
+    document: HashMap<char, (usize, String)>,
+}
+
+impl CharTally {
+    /// Tally the characters of a text run in the file being built.
+    pub fn add_text(&mut self, text: &str) {
+        for c in text.chars().filter(|c| !c.is_whitespace()) {
+            *self.page.entry(c).or_default() += 1;
+        }
+    }
+
+    /// Get the characters of the file being built.
+    pub fn page_chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.page.keys().copied()
+    }
+
+    /// Record that the file being built has been emitted at *path*.
+    pub fn finish_page(&mut self, path: &str) {
+        for (c, n) in self.page.drain() {
+            self.document
+                .entry(c)
+                .or_insert_with(|| (0, path.to_owned()))
+                .0 += n;
+        }
+    }
+
+    /// Check the tallied characters against the fonts. This must happen
+    /// before the font data are consumed.
+    pub fn report(&self, fonts: &FontLookup) -> CoverageReport {
+        let fds: Vec<_> = fonts.font_data.values().collect();
+
+        let families = fds
+            .iter()
+            .map(|fd| FamilyCoverage {
+                family: fd.basename().to_owned(),
+                covered: self.document.keys().filter(|c| fd.covers(**c)).count(),
+            })
+            .collect();
+
+        let mut uncovered: Vec<_> = self
+            .document
+            .iter()
+            .filter(|(c, _)| !fds.iter().any(|fd| fd.covers(**c)))
+            .map(|(c, (count, first_path))| UncoveredChar {
+                character: *c,
+                codepoint: format!("U+{:04X}", *c as u32),
+                count: *count,
+                first_path: first_path.clone(),
+                rescued: fonts.last_resort.as_ref().is_some_and(|fd| fd.covers(*c)),
+            })
+            .collect();
+        uncovered.sort_unstable_by_key(|u| u.character);
+
+        CoverageReport {
+            total_chars: self.document.len(),
+            families,
+            uncovered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{progress::ProgressSink, test_util::*, Spx2HtmlEngine};
+    use tectonic_errors::prelude::*;
+
+    #[test]
+    fn tally() {
+        let mut tally = CharTally::default();
+        tally.add_text("ab a");
+        tally.finish_page("one.html");
+        tally.add_text("bc");
+        tally.finish_page("two.html");
+        tally.add_text("never emitted");
+
+        let report = tally.report(&FontLookup::default());
+        assert_eq!(report.total_chars, 3);
+        assert!(report.families.is_empty());
+        assert_eq!(
+            report.uncovered[1],
+            UncoveredChar {
+                character: 'b',
+                codepoint: "U+0062".to_owned(),
+                count: 2,
+                first_path: "one.html".to_owned(),
+                rescued: false,
+            }
+        );
+        assert_eq!(report.uncovered[2].first_path, "two.html");
+    }
+
+    #[test]
+    fn uncovered_chars() {
+        #[derive(Clone, Default)]
+        struct CoverageProgress(std::sync::Arc<std::sync::Mutex<Option<CoverageReport>>>);
+
+        impl ProgressSink for CoverageProgress {
+            fn on_coverage_report(&mut self, report: &CoverageReport) -> Result<()> {
+                *self.0.lock().unwrap() = Some(report.clone());
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add(
+            "report.html",
+            "{% for c in tduxUncoveredChars %}{{ c.codepoint }} {{ c.count }} {{ c.first_path }};{% endfor %}",
+        );
+
+        let sink = CoverageProgress::default();
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .uncovered_chars_variable(true)
+            .progress_sink(Box::new(sink.clone()));
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello \u{1F600}")?;
+            s.special("tdux:setOutputPath one.html")?;
+            s.special("tdux:emit")?;
+            s.text("\u{1F600}\u{1F600} there")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:setTemplate report.html")?;
+            s.special("tdux:setOutputPath report.txt")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let report = sink.0.lock().unwrap().take().unwrap();
+        assert_eq!(report.total_chars, 8);
+        assert_eq!(report.families.len(), 1);
+        assert_eq!(report.families[0].covered, 7);
+        assert_eq!(report.uncovered.len(), 1);
+        assert_eq!(report.uncovered[0].character, '\u{1F600}');
+
+        let out = std::fs::read_to_string(tempdir.path().join("report.txt")).unwrap();
+        assert_eq!(out, "U+1F600 3 one.html;");
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Tracking which inputs each output file depends on.
+//!
+//! Tools that rebuild HTML output when its sources change, such as editors
+//! with a preview mode, need to know which inputs went into each page. The
+//! engine records, for every HTML output file, the template that it was
+//! rendered with, the fonts that its text and canvases use (including the
+//! main body font, whose `@font-face` rule every page gets), and the inputs
+//! copied with `tdux:provideFile` while it was being built. Inputs shared by
+//! many pages, like fonts, are listed for every page that uses them.
+//!
+//! The resulting map is delivered to
+//! [`crate::progress::ProgressSink::on_dependencies`] once processing is
+//! done, and can also be written out as a Makefile-style depfile with
+//! [`crate::Spx2HtmlEngine::depfile`]. The SPX input itself, which every
+//! output depends on, isn't listed.
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
+
+/// An input that an output file depends on.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct InputDependency {
+    /// The name of the input, as it was opened through the I/O layer.
+    pub name: String,
+
+    /// The SHA-256 digest of the input, in hexadecimal, if the I/O layer
+    /// computed one.
+    pub digest: Option<String>,
+}
+
+/// The inputs of an HTML output file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct OutputDependencies {
+    /// The path of the output file, relative to the output directory.
+    pub output: String,
+
+    /// The inputs that the file depends on, in order of their names.
+    pub inputs: Vec<InputDependency>,
+}
+
+/// Records the inputs of the output files.
+#[derive(Debug, Default)]
+pub(crate) struct DepTracker {
+    /// The digests of the inputs opened so far, keyed by name.
+    digests: HashMap<String, Option<String>>,
+
+    /// The inputs used by the output file being built.
+    page: BTreeSet<String>,
+
+    /// The dependencies of the output files emitted so far.
+    outputs: Vec<OutputDependencies>,
+}
+
+impl DepTracker {
+    /// Record that an input has been read, along with its digest.
+    pub fn note_input(&mut self, name: &str, digest: Option<String>) {
+        self.digests.insert(name.to_owned(), digest);
+    }
+
+    /// Record that the output file being built uses an input.
+    pub fn use_input(&mut self, name: &str) {
+        self.page.insert(name.to_owned());
+    }
+
+    /// Record that the output file being built has been emitted at *output*.
+    ///
+    /// If an earlier file was emitted at the same path, its dependencies are
+    /// replaced.
+    pub fn finish_page(&mut self, output: &str) {
+        let inputs = std::mem::take(&mut self.page)
+            .into_iter()
+            .map(|name| InputDependency {
+                digest: self.digests.get(&name).cloned().flatten(),
+                name,
+            })
+            .collect();
+
+        self.outputs.retain(|o| o.output != output);
+        self.outputs.push(OutputDependencies {
+            output: output.to_owned(),
+            inputs,
+        });
+    }
+
+    /// Get the dependencies of the output files, in the order in which they
+    /// were emitted.
+    pub fn outputs(&self) -> &[OutputDependencies] {
+        &self.outputs
+    }
+}
+
+/// Escape a file name for use in a Makefile rule.
+fn make_escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        match c {
+            ' ' | '#' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Render dependencies as Makefile rules, with the output files named
+/// relative to *out_base*.
+pub fn to_makefile(outputs: &[OutputDependencies], out_base: &Path) -> String {
+    let mut text = String::new();
+
+    for o in outputs {
+        text.push_str(&make_escape(&out_base.join(&o.output).to_string_lossy()));
+        text.push(':');
+
+        for i in &o.inputs {
+            text.push(' ');
+            text.push_str(&make_escape(&i.name));
+        }
+
+        text.push('\n');
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{progress::ProgressSink, test_util::*, Spx2HtmlEngine};
+    use std::sync::{Arc, Mutex};
+    use tectonic_errors::prelude::*;
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn makefile() {
+        let mut deps = DepTracker::default();
+        deps.note_input("page.html", None);
+        deps.use_input("page.html");
+        deps.use_input("my font$.otf");
+        deps.finish_page("index.html");
+        deps.use_input("other.html");
+        deps.finish_page("b/two.html");
+
+        assert_eq!(
+            to_makefile(deps.outputs(), Path::new("out")),
+            "out/index.html: my\\ font$$.otf page.html\nout/b/two.html: other.html\n"
+        );
+
+        // Re-emitting a file replaces its dependencies.
+        deps.finish_page("index.html");
+        assert_eq!(deps.outputs().len(), 2);
+        assert_eq!(deps.outputs()[1].output, "index.html");
+        assert!(deps.outputs()[1].inputs.is_empty());
+    }
+
+    #[test]
+    fn dependencies() {
+        struct DepsSink(Arc<Mutex<Vec<OutputDependencies>>>);
+
+        impl ProgressSink for DepsSink {
+            fn on_dependencies(&mut self, outputs: &[OutputDependencies]) -> Result<()> {
+                *self.0.lock().unwrap() = outputs.to_vec();
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let depfile = tempdir.path().join("deps.mk");
+        let out = tempdir.path().join("out");
+        let mut io = simple_io();
+        io.add("second.otf", test_font_data());
+        io.add("chapter.html", "{{ tduxContent }}");
+        io.add("logo.svg", "<svg/>");
+
+        let outputs = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .depfile(Some(depfile.clone()))
+            .progress_sink(Box::new(DepsSink(outputs.clone())));
+
+        let (result, _status) = run_engine_with(&mut engine, io, &out, |s| {
+            // The last font defined is the main body font, which every page
+            // depends on.
+            s.handle_define_native_font("second", 2, TEST_FONT_SIZE, 0, None, None, None, None)?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:addTemplate chapter.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:provideFile logo.svg img/logo.svg")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplate chapter.html")?;
+            s.special("tdux:setOutputPath chapters/two.html")?;
+            s.text_in(2, "World")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let outputs = outputs.lock().unwrap();
+        let summary: Vec<_> = outputs
+            .iter()
+            .map(|o| {
+                let names: Vec<_> = o.inputs.iter().map(|i| i.name.as_str()).collect();
+                format!("{}: {}", o.output, names.join(" "))
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "index.html: lmroman12-regular.otf logo.svg page.html second.otf",
+                "chapters/two.html: chapter.html second.otf",
+            ]
+        );
+        assert!(outputs[0].inputs.iter().all(|i| i.digest.is_some()));
+
+        let mk = std::fs::read_to_string(&depfile).unwrap();
+        assert_eq!(
+            mk,
+            format!(
+                "{}: lmroman12-regular.otf logo.svg page.html second.otf\n\
+                 {}: chapter.html second.otf\n",
+                out.join("index.html").display(),
+                out.join("chapters/two.html").display(),
+            )
+        );
+    }
+}
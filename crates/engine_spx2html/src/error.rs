@@ -0,0 +1,453 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Errors specific to the SPX-to-HTML engine.
+
+use tectonic_errors::Error;
+use thiserror::Error as ThisError;
+
+/// A boxed error that caused a higher-level failure.
+pub type BoxedSource = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Specific failures that can occur when converting SPX to HTML.
+///
+/// The engine's public APIs return the generic [`tectonic_errors::Error`]
+/// type, but failures that callers might want to handle specially are
+/// constructed as one of these variants. Use [`Spx2HtmlError::from_error`] to
+/// recover the variant from a generic error. The `Display` messages of the
+/// variants are stable.
+#[derive(ThisError, Debug)]
+#[non_exhaustive]
+pub enum Spx2HtmlError {
+    /// The input was not in SPX format.
+    #[error("file should be SPX format but got {filetype}")]
+    NotSpx {
+        /// A description of the format that was found.
+        filetype: String,
+    },
+
+    /// A `\special` could not be interpreted.
+    #[error("could not parse \\special `{raw}`")]
+    SpecialMalformed {
+        /// The contents of the special, lossily decoded.
+        raw: String,
+    },
+
+    /// An output path would have been written outside of the output
+    /// directory.
+    #[error("illegal output path `{path}`: {reason}")]
+    PathRejected {
+        /// The path requested by the document.
+        path: String,
+
+        /// Why the path was rejected.
+        reason: String,
+    },
+
+    /// Inputs referenced by the document could not be found.
+    #[error("{} referenced input(s) could not be found: {}", .missing.len(), .missing.join(", "))]
+    MissingInputs {
+        /// Descriptions of the missing inputs.
+        missing: Vec<String>,
+    },
+
+    /// A font file could not be loaded.
+    #[error("unable to load font `{name}`")]
+    FontLoad {
+        /// The name of the font file.
+        name: String,
+
+        /// The underlying error.
+        #[source]
+        source: BoxedSource,
+    },
+
+    /// An HTML template could not be compiled.
+    #[error("couldn't compile HTML template `{name}`")]
+    TemplateCompile {
+        /// The name of the template.
+        name: String,
+
+        /// The underlying error.
+        #[source]
+        source: tera::Error,
+    },
+
+    /// An HTML template was added more than once, with different contents.
+    #[error("HTML template `{name}` was added more than once with different contents")]
+    TemplateRedefined {
+        /// The name of the template.
+        name: String,
+    },
+
+    /// An HTML template could not be rendered.
+    #[error("failed to render HTML template `{name}` while creating `{page}`")]
+    TemplateRender {
+        /// The name of the template.
+        name: String,
+
+        /// The output path of the page being created.
+        page: String,
+
+        /// The underlying error.
+        #[source]
+        source: tera::Error,
+    },
+
+    /// Rendering an HTML template took too long.
+    #[error("rendering HTML template `{name}` while creating `{page}` took longer than the limit of {limit:?}")]
+    RenderTimeout {
+        /// The name of the template.
+        name: String,
+
+        /// The output path of the page being created.
+        page: String,
+
+        /// The time limit.
+        limit: std::time::Duration,
+    },
+
+    /// A rendered page was too large.
+    #[error("rendering HTML template `{name}` for `{page}` produced {size} bytes, more than the limit of {limit}")]
+    PageTooLarge {
+        /// The name of the template.
+        name: String,
+
+        /// The output path of the page being created.
+        page: String,
+
+        /// The size of the rendered page, in bytes.
+        size: usize,
+
+        /// The size limit, in bytes.
+        limit: usize,
+    },
+
+    /// A page with canvases doesn't include the CSS rules that they rely on.
+    #[error("`{page}` contains canvases, but template `{template}` includes neither `tduxRequiredCss` nor a link to `tdux-base.css`")]
+    MissingRequiredCss {
+        /// The output path of the page.
+        page: String,
+
+        /// The name of the template.
+        template: String,
+    },
+
+    /// A template referred to an asset that hasn't been provided.
+    #[error("template `{template}` references unknown asset `{path}`")]
+    UnknownAsset {
+        /// The path of the asset, relative to the output root.
+        path: String,
+
+        /// The name of the template.
+        template: String,
+    },
+
+    /// A template tried to inline an asset that is too large.
+    #[error(
+        "asset `{path}` inlined in template `{template}` is larger than the limit of {limit} bytes"
+    )]
+    AssetTooLarge {
+        /// The path of the asset, relative to the output root.
+        path: String,
+
+        /// The name of the template.
+        template: String,
+
+        /// The size limit, in bytes.
+        limit: u64,
+    },
+
+    /// Two different sources would provide the same output file.
+    #[error("output file `{path}` would be provided by both {first} and {second}")]
+    AssetConflict {
+        /// The path of the output file, relative to the output directory.
+        path: String,
+
+        /// A description of the first source.
+        first: String,
+
+        /// A description of the second source.
+        second: String,
+    },
+
+    /// A document tried to provide a file from a remote URL, but the
+    /// application hasn't enabled remote sources.
+    #[error("cannot fetch `{url}`: remote sources of tdux:provideFile must be enabled by the application with `Spx2HtmlEngine::remote_fetcher`")]
+    RemoteDisabled {
+        /// The URL.
+        url: String,
+    },
+
+    /// Fetching a remote URL failed.
+    #[error("failed to fetch `{url}`")]
+    RemoteFetch {
+        /// The URL.
+        url: String,
+
+        /// The underlying error.
+        #[source]
+        source: BoxedSource,
+    },
+
+    /// A remote URL provided more data than allowed.
+    #[error("fetching `{url}` produced {size} bytes, more than the limit of {limit}")]
+    RemoteTooLarge {
+        /// The URL.
+        url: String,
+
+        /// The size of the fetched contents, in bytes.
+        size: u64,
+
+        /// The size limit, in bytes.
+        limit: u64,
+    },
+
+    /// A font isn't listed in the asset manifest that the application asked
+    /// the engine to follow.
+    #[error("font `{name}` (face {face_index}) isn't listed in the precomputed asset manifest")]
+    FontNotInManifest {
+        /// The name of the font file.
+        name: String,
+
+        /// The index of the face within the file.
+        face_index: u32,
+    },
+
+    /// The same output file would have been written twice with different
+    /// contents.
+    #[error("output file `{path}` would be written more than once with different contents")]
+    OutputConflict {
+        /// The path of the output file, relative to the output directory.
+        path: String,
+    },
+
+    /// An output file already existed, and the application asked for
+    /// existing files not to be overwritten.
+    #[error("output file `{path}` already exists")]
+    OutputExists {
+        /// The path of the output file, relative to the output directory.
+        path: String,
+    },
+
+    /// The source of a `tdux:provideDirectory` special isn't a directory.
+    #[error("source `{path}` of tdux:provideDirectory isn't a directory")]
+    NotADirectory {
+        /// The path of the source, as given.
+        path: String,
+    },
+
+    /// A `tdux:deferStart` special appeared while content was already being
+    /// deferred.
+    #[error("cannot start deferring content to `{inner}` while deferring it to `{outer}`; tdux:deferStart doesn't nest")]
+    NestedDefer {
+        /// The name of the stream that was already open.
+        outer: String,
+
+        /// The name of the stream that the document tried to open.
+        inner: String,
+    },
+
+    /// The document declared, with `tdux:formatVersion`, that it needs a
+    /// newer version of the tdux format than the engine supports.
+    #[error("the document requires tdux format {required}, but this Tectonic supports format {supported}; a newer Tectonic is needed to process it")]
+    UnsupportedFormatVersion {
+        /// The version that the document declared.
+        required: u32,
+
+        /// The newest version that the engine supports.
+        supported: u32,
+    },
+
+    /// The document used the old name of a special that had already been
+    /// renamed in the version of the tdux format that it declared.
+    #[error("the special `{old}` was renamed to `{new}` in tdux format {since}, but the document declares format {declared}; use `{new}` instead")]
+    RenamedSpecial {
+        /// The old name of the special.
+        old: String,
+
+        /// The modern name of the special.
+        new: String,
+
+        /// The format version in which the special was renamed.
+        since: u32,
+
+        /// The format version that the document declared.
+        declared: u32,
+    },
+
+    /// The document ended without `tdux:contentFinished`, in strict mode.
+    #[error("the document ended without `tdux:contentFinished`, so its fonts weren't emitted")]
+    ContentNotFinished,
+
+    /// A problem that would otherwise draw a warning was found in strict
+    /// mode.
+    #[error("{message} (at byte {offset} of the SPX input)")]
+    StrictViolation {
+        /// A description of the problem.
+        message: String,
+
+        /// The position in the SPX input where the problem was found.
+        offset: u64,
+    },
+
+    /// Processing was cancelled by the application.
+    #[error("processing was cancelled")]
+    Cancelled,
+
+    /// Warnings were issued, and the application asked for them to be treated
+    /// as errors.
+    #[error("{count} warning(s) were issued, and warnings are fatal")]
+    FatalWarnings {
+        /// The number of warnings.
+        count: usize,
+    },
+
+    /// An I/O operation failed.
+    #[error("I/O error involving `{path}`")]
+    Io {
+        /// The path of the file involved.
+        path: String,
+
+        /// The underlying error.
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl Spx2HtmlError {
+    /// Find the engine-specific error underlying a generic error, if any.
+    ///
+    /// The error and its chain of sources are searched, and the outermost
+    /// match is returned.
+    pub fn from_error(err: &Error) -> Option<&Spx2HtmlError> {
+        err.chain().find_map(|e| e.downcast_ref::<Spx2HtmlError>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, EngineState, Spx2HtmlEngine, WarningCategories};
+    use tectonic_errors::prelude::*;
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn missing_inputs_reported_together() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let (result, _status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.handle_define_native_font(
+                "nosuchfont",
+                2,
+                TEST_FONT_SIZE,
+                0,
+                None,
+                None,
+                None,
+                None,
+            )?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:addTemplate nosuchpartial.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:setTemplate nosuchpage.html")?;
+            s.text("Hello")?;
+            unreachable!("emission should not begin");
+        });
+
+        let msg = result.unwrap_err().to_string();
+        assert!(msg.starts_with("3 referenced input(s)"), "{}", msg);
+        assert!(msg.contains("font `nosuchfont`"));
+        assert!(msg.contains("HTML template `nosuchpartial.html`"));
+        assert!(msg.contains("HTML template `nosuchpage.html`"));
+        assert!(!tempdir.path().join("index.html").exists());
+    }
+
+    #[test]
+    fn typed_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let (result, _status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:setOutputPath ../escaped.html")?;
+            s.special("tdux:emit")
+        });
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::PathRejected { path, .. }) if path == "../escaped.html"
+        ));
+        assert!(!tempdir.path().join("../escaped.html").exists());
+
+        let (result, _status) = run_engine(simple_io(), tempdir.path(), |s| {
+            s.special("tdux:setTemplate nosuchpage.html")?;
+            s.special("tdux:emit")
+        });
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::MissingInputs { missing }) if missing == &["HTML template `nosuchpage.html`"]
+        ));
+
+        let mut io = simple_io();
+        io.add("corrupt.otf", b"OTTO this is not really a font".to_vec());
+        let (result, _status) = run_engine(io, tempdir.path(), |s| {
+            s.handle_define_native_font("corrupt", 2, TEST_FONT_SIZE, 0, None, None, None, None)
+        });
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::FontLoad { name, .. }) if name == "corrupt.otf"
+        ));
+    }
+
+    #[test]
+    fn strict_warning_categories() {
+        fn events(s: &mut EngineState) -> Result<()> {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:setTemplateVariable title")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        }
+
+        // Problems in categories that aren't allowed are errors.
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .strict(true)
+            .strict_allow(WarningCategories::UNPAIRED_CANVASES);
+        let (result, status) = run_engine_in_memory(&mut engine, simple_io(), events);
+        let err = result.unwrap_err();
+
+        match Spx2HtmlError::from_error(&err) {
+            Some(Spx2HtmlError::StrictViolation { message, .. }) => {
+                assert_eq!(
+                    message,
+                    "ignoring malformed special `tdux:setTemplateVariable title`: \
+                     its `value` argument is empty"
+                );
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        assert_eq!(
+            status.warnings,
+            ["ignoring unpaired tdux:c[anvas]e[nd] special `tdux:ce math`"]
+        );
+
+        // Allowed categories remain warnings.
+        engine.strict_allow(
+            WarningCategories::UNPAIRED_CANVASES | WarningCategories::MALFORMED_SPECIALS,
+        );
+        let (result, status) = run_engine_in_memory(&mut engine, simple_io(), events);
+        let files = result.unwrap();
+        assert_eq!(files["index.html"], b"<html><body>Hello</body></html>");
+        assert_eq!(status.warnings.len(), 2, "{:?}", status.warnings);
+
+        assert!(WarningCategories::all().contains(WarningCategories::DROPPED_CONTENT));
+        assert!(!WarningCategories::empty().contains(WarningCategories::UNMAPPABLE_GLYPHS));
+    }
+}
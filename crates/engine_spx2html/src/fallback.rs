@@ -0,0 +1,919 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! The font of last resort that comes with the engine.
+//!
+//! With the `fallback-font` feature, the engine bundles a subset of DejaVu
+//! Sans covering basic Latin, Latin-1, punctuation, and common symbols (see
+//! `fonts/README.md` in the crate sources), which it uses as the font of last
+//! resort unless the application provides its own with
+//! [`crate::Spx2HtmlEngine::last_resort_font`]. Either way, the font isn't
+//! written to the output whole: once the content is finished, it's subset to
+//! the characters that it actually rescued, which are usually very few, and
+//! written as `tdux-fallback.woff2`.
+//!
+//! The subsetting only works for fonts with TrueType outlines, which the
+//! bundled one has. Other fonts of last resort are emitted whole, as without
+//! the feature. The WOFF2 encoding stores the tables without the optional
+//! `glyf` transform, which keeps this simple at the cost of a few percent of
+//! compression.
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    num::Wrapping,
+};
+use tectonic_errors::prelude::*;
+
+use crate::font::{opentype_checksum, GlyphId};
+
+/// The bundled font, a subset of DejaVu Sans, in WOFF2 format.
+const BUNDLED_WOFF2: &[u8] = include_bytes!("../fonts/tdux-fallback.woff2");
+
+/// The file name of the bundled font.
+pub const BUNDLED_NAME: &str = "tdux-fallback.woff2";
+
+/// The name of the file that the subset of the font of last resort is
+/// written to.
+pub const SUBSET_NAME: &str = "tdux-fallback.woff2";
+
+/// The ranges of characters of DejaVu Sans that the bundled font keeps. They
+/// only matter when it's regenerated, which is done by a test.
+#[cfg(test)]
+const BUNDLED_RANGES: &[(char, char)] = &[
+    ('\u{20}', '\u{7E}'),     // Basic Latin
+    ('\u{A0}', '\u{FF}'),     // Latin-1 Supplement
+    ('\u{2010}', '\u{205E}'), // General Punctuation
+    ('\u{20A0}', '\u{20C0}'), // Currency Symbols
+    ('\u{2100}', '\u{214F}'), // Letterlike Symbols
+    ('\u{2190}', '\u{21FF}'), // Arrows
+    ('\u{2200}', '\u{22FF}'), // Mathematical Operators
+    ('\u{25A0}', '\u{25FF}'), // Geometric Shapes
+    ('\u{2600}', '\u{26FF}'), // Miscellaneous Symbols
+    ('\u{2700}', '\u{27BF}'), // Dingbats
+];
+
+/// The tables of a subset font that are copied from the original unchanged.
+/// Those that refer to glyphs by their IDs, such as `GSUB`, are dropped, as
+/// are the hinting programs, bitmaps, and other extras. The `name` table is
+/// kept, since it carries the font's copyright and license notices.
+const COPIED_TABLES: &[&[u8; 4]] = &[b"OS/2", b"gasp", b"name"];
+
+/// Get the bundled font, decoded to an OpenType font.
+pub fn bundled_font() -> Result<Vec<u8>> {
+    from_woff2(BUNDLED_WOFF2)
+}
+
+/// Whether the font in *data* can be subset: it must be a single font, not a
+/// collection, with TrueType outlines.
+pub fn can_subset(data: &[u8]) -> bool {
+    match read_tables(data) {
+        Ok(tables) => tables.contains_key(b"glyf") && tables.contains_key(b"loca"),
+        Err(_) => false,
+    }
+}
+
+/// Write the `@font-face` rule for the subset of the font of last resort,
+/// whose CSS font family is *family*.
+pub fn write_face_css<W: std::fmt::Write>(family: &str, mut css: W) -> Result<()> {
+    writeln!(
+        css,
+        r#"@font-face {{
+  font-family: "{}";
+  src: url("{}") format("woff2");
+}}"#,
+        family, SUBSET_NAME
+    )?;
+    Ok(())
+}
+
+/// Get the tables of an OpenType font with TrueType outlines, keyed by tag.
+fn read_tables(data: &[u8]) -> Result<BTreeMap<[u8; 4], &[u8]>> {
+    ensure!(
+        data.len() >= 12 && BigEndian::read_u32(data) == 0x0001_0000,
+        "not an OpenType font with TrueType outlines"
+    );
+
+    let n_tables = BigEndian::read_u16(&data[4..]) as usize;
+    let mut tables = BTreeMap::new();
+
+    for i in 0..n_tables {
+        let record = a_ok_or!(
+            data.get(12 + 16 * i..28 + 16 * i);
+            ["truncated OpenType table directory"]
+        );
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&record[..4]);
+        let offset = BigEndian::read_u32(&record[8..]) as usize;
+        let len = BigEndian::read_u32(&record[12..]) as usize;
+        let table = a_ok_or!(
+            data.get(offset..offset + len);
+            ["truncated OpenType table `{}`", String::from_utf8_lossy(&tag)]
+        );
+        tables.insert(tag, table);
+    }
+
+    Ok(tables)
+}
+
+/// Get a table that a subset font needs.
+fn required<'a>(tables: &BTreeMap<[u8; 4], &'a [u8]>, tag: &[u8; 4]) -> Result<&'a [u8]> {
+    Ok(*a_ok_or!(
+        tables.get(tag);
+        ["OpenType font lacks the `{}` table", String::from_utf8_lossy(tag)]
+    ))
+}
+
+/// Subset a font with TrueType outlines to the glyphs that *mapping* maps
+/// characters to, along with the glyphs that they're composed of.
+///
+/// The glyphs are renumbered, so the character map of the subset only maps
+/// the characters of *mapping*, which must be sorted by character.
+pub fn subset(data: &[u8], mapping: &[(char, GlyphId)]) -> Result<Vec<u8>> {
+    let tables = read_tables(data)?;
+    let head = required(&tables, b"head")?;
+    let hhea = required(&tables, b"hhea")?;
+    let hmtx = required(&tables, b"hmtx")?;
+    let maxp = required(&tables, b"maxp")?;
+    let glyf = required(&tables, b"glyf")?;
+    let loca = required(&tables, b"loca")?;
+    ensure!(
+        head.len() >= 54 && hhea.len() >= 36 && maxp.len() >= 6,
+        "truncated OpenType header tables"
+    );
+
+    let n_glyphs = BigEndian::read_u16(&maxp[4..]) as usize;
+    let long_loca = BigEndian::read_i16(&head[50..]) != 0;
+
+    let glyph = |g: GlyphId| -> Result<&[u8]> {
+        let g = g as usize;
+        ensure!(g < n_glyphs, "glyph {} is out of range", g);
+
+        let (start, end) = if long_loca {
+            (
+                loca.get(4 * g..4 * g + 4).map(BigEndian::read_u32),
+                loca.get(4 * g + 4..4 * g + 8).map(BigEndian::read_u32),
+            )
+        } else {
+            (
+                loca.get(2 * g..2 * g + 2)
+                    .map(|b| 2 * BigEndian::read_u16(b) as u32),
+                loca.get(2 * g + 2..2 * g + 4)
+                    .map(|b| 2 * BigEndian::read_u16(b) as u32),
+            )
+        };
+
+        let (start, end) = match (start, end) {
+            (Some(s), Some(e)) if s <= e => (s as usize, e as usize),
+            _ => bail!("invalid `loca` entry for glyph {}", g),
+        };
+
+        Ok(a_ok_or!(
+            glyf.get(start..end);
+            ["`glyf` entry for glyph {} is out of bounds", g]
+        ))
+    };
+
+    // Choose the glyphs: `.notdef`, the mapped ones in order of their
+    // characters, and then any components of composite glyphs.
+
+    let mut old_ids = vec![0];
+    let mut new_ids = HashMap::new();
+    new_ids.insert(0, 0);
+
+    for (_, g) in mapping {
+        new_ids.entry(*g).or_insert_with(|| {
+            old_ids.push(*g);
+            old_ids.len() as GlyphId - 1
+        });
+    }
+
+    let mut i = 0;
+
+    while i < old_ids.len() {
+        for (_, component) in components(glyph(old_ids[i])?)?.0 {
+            new_ids.entry(component).or_insert_with(|| {
+                old_ids.push(component);
+                old_ids.len() as GlyphId - 1
+            });
+        }
+
+        i += 1;
+    }
+
+    // The outlines, with the components of composite glyphs renumbered.
+    // Browsers don't need the hinting instructions, which would take up much
+    // of the space.
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = Vec::new();
+
+    for old in &old_ids {
+        new_loca.write_u32::<BigEndian>(new_glyf.len() as u32)?;
+        let start = new_glyf.len();
+        new_glyf.extend_from_slice(&strip_instructions(glyph(*old)?)?);
+
+        for (offset, component) in components(&new_glyf[start..])?.0 {
+            let pos = start + offset + 2;
+            BigEndian::write_u16(&mut new_glyf[pos..pos + 2], new_ids[&component]);
+        }
+
+        while new_glyf.len() % 4 != 0 {
+            new_glyf.push(0);
+        }
+    }
+
+    new_loca.write_u32::<BigEndian>(new_glyf.len() as u32)?;
+
+    // The metrics.
+
+    let n_hmetrics = BigEndian::read_u16(&hhea[34..]) as usize;
+    ensure!(
+        n_hmetrics > 0 && hmtx.len() >= 4 * n_hmetrics + 2 * (n_glyphs - n_hmetrics),
+        "truncated `hmtx` table"
+    );
+    let mut new_hmtx = Vec::new();
+
+    for old in &old_ids {
+        let g = *old as usize;
+
+        if g < n_hmetrics {
+            new_hmtx.extend_from_slice(&hmtx[4 * g..4 * g + 4]);
+        } else {
+            let last = 4 * (n_hmetrics - 1);
+            new_hmtx.extend_from_slice(&hmtx[last..last + 2]);
+            let lsb = 4 * n_hmetrics + 2 * (g - n_hmetrics);
+            new_hmtx.extend_from_slice(&hmtx[lsb..lsb + 2]);
+        }
+    }
+
+    // The headers.
+
+    let mut new_head = head.to_vec();
+    BigEndian::write_i16(&mut new_head[50..], 1); // indexToLocFormat
+
+    let mut new_hhea = hhea.to_vec();
+    BigEndian::write_u16(&mut new_hhea[34..], old_ids.len() as u16); // numberOfHMetrics
+
+    let mut new_maxp = maxp.to_vec();
+    BigEndian::write_u16(&mut new_maxp[4..], old_ids.len() as u16); // numGlyphs
+
+    let mut new_tables = vec![
+        (*b"cmap", subset_cmap(mapping, &new_ids)?),
+        (*b"glyf", new_glyf),
+        (*b"head", new_head),
+        (*b"hhea", new_hhea),
+        (*b"hmtx", new_hmtx),
+        (*b"loca", new_loca),
+        (*b"maxp", new_maxp),
+    ];
+
+    // Version 3 of the `post` table has no glyph names, which would need
+    // renumbering.
+
+    if let Some(post) = tables.get(b"post").filter(|p| p.len() >= 32) {
+        let mut new_post = post[..32].to_vec();
+        BigEndian::write_u32(&mut new_post[..4], 0x0003_0000);
+        new_tables.push((*b"post", new_post));
+    }
+
+    for tag in COPIED_TABLES {
+        if let Some(table) = tables.get(*tag) {
+            new_tables.push((**tag, table.to_vec()));
+        }
+    }
+
+    Ok(write_sfnt(new_tables))
+}
+
+/// The flag of a component of a composite glyph that says that the glyph
+/// has instructions after its components.
+const WE_HAVE_INSTRUCTIONS: u16 = 0x0100;
+
+/// Get the components of a glyph, if it's a composite one, as the offsets of
+/// their records within the glyph data and the IDs of their glyphs, along
+/// with the offset of the end of the records.
+fn components(data: &[u8]) -> Result<(Vec<(usize, GlyphId)>, usize)> {
+    const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+    const WE_HAVE_A_SCALE: u16 = 0x0008;
+    const MORE_COMPONENTS: u16 = 0x0020;
+    const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+    const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+
+    let mut components = Vec::new();
+
+    if data.len() < 10 || BigEndian::read_i16(data) >= 0 {
+        return Ok((components, data.len()));
+    }
+
+    let mut pos = 10;
+
+    loop {
+        let record = a_ok_or!(
+            data.get(pos..pos + 4);
+            ["truncated composite glyph"]
+        );
+        let flags = BigEndian::read_u16(record);
+        components.push((pos, BigEndian::read_u16(&record[2..])));
+
+        pos += 4;
+        pos += if flags & ARG_1_AND_2_ARE_WORDS != 0 {
+            4
+        } else {
+            2
+        };
+
+        if flags & WE_HAVE_A_SCALE != 0 {
+            pos += 2;
+        } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+            pos += 4;
+        } else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+            pos += 8;
+        }
+
+        if flags & MORE_COMPONENTS == 0 {
+            return Ok((components, pos));
+        }
+    }
+}
+
+/// Strip the hinting instructions from a glyph.
+fn strip_instructions(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 10 {
+        return Ok(data.to_vec());
+    }
+
+    let n_contours = BigEndian::read_i16(data);
+
+    if n_contours >= 0 {
+        let pos = 10 + 2 * n_contours as usize;
+        let len = a_ok_or!(
+            data.get(pos..pos + 2);
+            ["truncated simple glyph"]
+        );
+        let rest = a_ok_or!(
+            data.get(pos + 2 + BigEndian::read_u16(len) as usize..);
+            ["truncated simple glyph"]
+        );
+
+        let mut stripped = data[..pos].to_vec();
+        stripped.extend_from_slice(&[0, 0]);
+        stripped.extend_from_slice(rest);
+        Ok(stripped)
+    } else {
+        let (records, end) = components(data)?;
+        let mut stripped = data[..end].to_vec();
+
+        for (offset, _) in records {
+            let flags = BigEndian::read_u16(&stripped[offset..]);
+            BigEndian::write_u16(&mut stripped[offset..], flags & !WE_HAVE_INSTRUCTIONS);
+        }
+
+        Ok(stripped)
+    }
+}
+
+/// Build the character map of a subset font: a format 4 subtable for the
+/// Basic Multilingual Plane, which all browsers understand, and a format 12
+/// one for all of the characters.
+fn subset_cmap(
+    mapping: &[(char, GlyphId)],
+    new_ids: &HashMap<GlyphId, GlyphId>,
+) -> Result<Vec<u8>> {
+    // Runs of consecutive characters mapped to consecutive glyphs, which
+    // are common since the glyphs are numbered in order of their characters.
+
+    let mut runs: Vec<(u32, u32, u32)> = Vec::new();
+
+    for (c, g) in mapping {
+        let (c, g) = (*c as u32, new_ids[g] as u32);
+
+        match runs.last_mut() {
+            Some((start, end, start_g)) if c == *end + 1 && g == *start_g + c - *start => {
+                *end = c;
+            }
+            _ => runs.push((c, c, g)),
+        }
+    }
+
+    let mut bmp: Vec<_> = runs
+        .iter()
+        .filter(|r| r.0 <= 0xFFFF)
+        .map(|&(start, end, g)| (start, end.min(0xFFFE), g))
+        .collect();
+    bmp.push((0xFFFF, 0xFFFF, 0));
+
+    let mut format4 = Vec::new();
+    let seg_count = bmp.len() as u16;
+    let search_range = 2 * (1 << (15 - seg_count.leading_zeros())) as u16;
+    format4.write_u16::<BigEndian>(4)?; // format
+    format4.write_u16::<BigEndian>(16 + 8 * seg_count)?; // length
+    format4.write_u16::<BigEndian>(0)?; // language
+    format4.write_u16::<BigEndian>(2 * seg_count)?;
+    format4.write_u16::<BigEndian>(search_range)?;
+    format4.write_u16::<BigEndian>(search_range.trailing_zeros() as u16 - 1)?; // entrySelector
+    format4.write_u16::<BigEndian>(2 * seg_count - search_range)?; // rangeShift
+
+    for (_, end, _) in &bmp {
+        format4.write_u16::<BigEndian>(*end as u16)?;
+    }
+
+    format4.write_u16::<BigEndian>(0)?; // reservedPad
+
+    for (start, _, _) in &bmp {
+        format4.write_u16::<BigEndian>(*start as u16)?;
+    }
+
+    for (start, _, g) in &bmp {
+        // The final segment maps 0xFFFF to glyph 0.
+        let delta = if *start == 0xFFFF {
+            1
+        } else {
+            g.wrapping_sub(*start) as u16
+        };
+        format4.write_u16::<BigEndian>(delta)?;
+    }
+
+    for _ in &bmp {
+        format4.write_u16::<BigEndian>(0)?; // idRangeOffset
+    }
+
+    let mut format12 = Vec::new();
+    format12.write_u16::<BigEndian>(12)?; // format
+    format12.write_u16::<BigEndian>(0)?; // reserved
+    format12.write_u32::<BigEndian>(16 + 12 * runs.len() as u32)?; // length
+    format12.write_u32::<BigEndian>(0)?; // language
+    format12.write_u32::<BigEndian>(runs.len() as u32)?;
+
+    for (start, end, g) in &runs {
+        format12.write_u32::<BigEndian>(*start)?;
+        format12.write_u32::<BigEndian>(*end)?;
+        format12.write_u32::<BigEndian>(*g)?;
+    }
+
+    let mut cmap = Vec::new();
+    cmap.write_u16::<BigEndian>(0)?; // version
+    cmap.write_u16::<BigEndian>(2)?; // numTables
+    cmap.write_u16::<BigEndian>(3)?; // Windows
+    cmap.write_u16::<BigEndian>(1)?; // Unicode BMP
+    cmap.write_u32::<BigEndian>(20)?;
+    cmap.write_u16::<BigEndian>(3)?; // Windows
+    cmap.write_u16::<BigEndian>(10)?; // Unicode full repertoire
+    cmap.write_u32::<BigEndian>(20 + format4.len() as u32)?;
+    cmap.extend_from_slice(&format4);
+    cmap.extend_from_slice(&format12);
+    Ok(cmap)
+}
+
+/// Assemble an OpenType font with TrueType outlines from its tables.
+///
+/// The checksum adjustment of the `head` table is recomputed.
+fn write_sfnt(mut tables: Vec<([u8; 4], Vec<u8>)>) -> Vec<u8> {
+    tables.sort_by_key(|t| t.0);
+
+    for (tag, data) in &mut tables {
+        if tag == b"head" && data.len() >= 12 {
+            BigEndian::write_u32(&mut data[8..12], 0);
+        }
+    }
+
+    let n_tables = tables.len() as u16;
+    let search_range = 16 * (1 << (15 - n_tables.leading_zeros())) as u16;
+
+    let mut font = Vec::new();
+    font.write_u32::<BigEndian>(0x0001_0000).unwrap();
+    font.write_u16::<BigEndian>(n_tables).unwrap();
+    font.write_u16::<BigEndian>(search_range).unwrap();
+    font.write_u16::<BigEndian>(search_range.trailing_zeros() as u16 - 4)
+        .unwrap();
+    font.write_u16::<BigEndian>(16 * n_tables - search_range)
+        .unwrap();
+
+    let mut offset = 12 + 16 * tables.len();
+    let mut head_offset = None;
+
+    for (tag, data) in &tables {
+        if tag == b"head" {
+            head_offset = Some(offset);
+        }
+
+        font.extend_from_slice(tag);
+        font.write_u32::<BigEndian>(opentype_checksum(data))
+            .unwrap();
+        font.write_u32::<BigEndian>(offset as u32).unwrap();
+        font.write_u32::<BigEndian>(data.len() as u32).unwrap();
+        offset += (data.len() + 3) & !3;
+    }
+
+    for (_, data) in &tables {
+        font.extend_from_slice(data);
+
+        while font.len() % 4 != 0 {
+            font.push(0);
+        }
+    }
+
+    if let Some(ofs) = head_offset {
+        let adjustment = Wrapping(0xB1B0AFBA) - Wrapping(opentype_checksum(&font));
+        BigEndian::write_u32(&mut font[ofs + 8..ofs + 12], adjustment.0);
+    }
+
+    font
+}
+
+/// The tags that WOFF2 table directory entries can refer to by their index.
+const WOFF2_KNOWN_TAGS: &[&[u8; 4]] = &[
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post", b"cvt ", b"fpgm",
+    b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT", b"EBLC", b"gasp", b"hdmx", b"kern",
+    b"LTSH", b"PCLT", b"VDMX", b"vhea", b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC",
+    b"JSTF", b"MATH", b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+    b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar", b"gvar", b"hsty",
+    b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop", b"trak", b"Zapf", b"Silf", b"Glat",
+    b"Gloc", b"Feat", b"Sill",
+];
+
+/// Append a number in the variable-length `UIntBase128` encoding of WOFF2.
+fn write_base128(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+
+    while rest != 0 {
+        bytes.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+
+    buf.extend(bytes.iter().rev());
+}
+
+/// Read a number in the `UIntBase128` encoding at *pos*, advancing it.
+fn read_base128(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut value = 0;
+
+    for _ in 0..5 {
+        let b = *a_ok_or!(
+            data.get(*pos);
+            ["truncated WOFF2 table directory"]
+        );
+        *pos += 1;
+        value = (value << 7) | (b & 0x7F) as usize;
+
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    bail!("invalid number in WOFF2 table directory")
+}
+
+/// Encode an OpenType font with TrueType outlines as WOFF2.
+///
+/// The tables are stored as they are, with the null transform for `glyf`
+/// and `loca`, and compressed together with Brotli.
+pub fn to_woff2(sfnt: &[u8]) -> Result<Vec<u8>> {
+    let tables = read_tables(sfnt)?;
+
+    let mut directory = Vec::new();
+    let mut stream = Vec::new();
+    let mut sfnt_size = 12 + 16 * tables.len();
+
+    for (tag, data) in &tables {
+        let known = WOFF2_KNOWN_TAGS.iter().position(|t| *t == tag);
+        // Transform version 3 is the null transform for `glyf` and `loca`,
+        // while version 0 is the null transform for everything else.
+        let transform = if tag == b"glyf" || tag == b"loca" {
+            3 << 6
+        } else {
+            0
+        };
+
+        match known {
+            Some(index) => directory.push(index as u8 | transform),
+            None => {
+                directory.push(63 | transform);
+                directory.extend_from_slice(tag);
+            }
+        }
+
+        write_base128(&mut directory, data.len() as u32);
+        stream.extend_from_slice(data);
+        sfnt_size += (data.len() + 3) & !3;
+    }
+
+    let mut compressed = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+        atry!(
+            writer.write_all(&stream);
+            ["failed to compress font data"]
+        );
+    }
+
+    let length = 48 + directory.len() + compressed.len();
+    let padded_length = (length + 3) & !3;
+
+    let mut woff2 = Vec::with_capacity(padded_length);
+    woff2.extend_from_slice(b"wOF2");
+    woff2.write_u32::<BigEndian>(0x0001_0000)?; // flavor
+    woff2.write_u32::<BigEndian>(padded_length as u32)?;
+    woff2.write_u16::<BigEndian>(tables.len() as u16)?;
+    woff2.write_u16::<BigEndian>(0)?; // reserved
+    woff2.write_u32::<BigEndian>(sfnt_size as u32)?; // totalSfntSize
+    woff2.write_u32::<BigEndian>(compressed.len() as u32)?;
+    woff2.write_u16::<BigEndian>(1)?; // majorVersion
+    woff2.write_u16::<BigEndian>(0)?; // minorVersion
+
+    for _ in 0..5 {
+        woff2.write_u32::<BigEndian>(0)?; // no metadata or private data
+    }
+
+    woff2.extend_from_slice(&directory);
+    woff2.extend_from_slice(&compressed);
+    woff2.resize(padded_length, 0);
+    Ok(woff2)
+}
+
+/// Decode a WOFF2 font whose tables are all stored with the null transform,
+/// as [`to_woff2`] writes them.
+pub fn from_woff2(woff2: &[u8]) -> Result<Vec<u8>> {
+    ensure!(
+        woff2.len() >= 48 && &woff2[..4] == b"wOF2",
+        "not a WOFF2 font"
+    );
+
+    let n_tables = BigEndian::read_u16(&woff2[12..]) as usize;
+    let compressed_len = BigEndian::read_u32(&woff2[20..]) as usize;
+    let mut pos = 48;
+    let mut entries = Vec::new();
+
+    for _ in 0..n_tables {
+        let flags = *a_ok_or!(
+            woff2.get(pos);
+            ["truncated WOFF2 table directory"]
+        );
+        pos += 1;
+
+        let tag = match flags & 63 {
+            63 => {
+                let tag = a_ok_or!(
+                    woff2.get(pos..pos + 4);
+                    ["truncated WOFF2 table directory"]
+                );
+                pos += 4;
+                let mut copy = [0; 4];
+                copy.copy_from_slice(tag);
+                copy
+            }
+            i => *WOFF2_KNOWN_TAGS[i as usize],
+        };
+
+        let null_transform = if &tag == b"glyf" || &tag == b"loca" {
+            3
+        } else {
+            0
+        };
+        ensure!(
+            flags >> 6 == null_transform,
+            "transformed WOFF2 table `{}` isn't supported",
+            String::from_utf8_lossy(&tag)
+        );
+
+        entries.push((tag, read_base128(woff2, &mut pos)?));
+    }
+
+    let compressed = a_ok_or!(
+        woff2.get(pos..pos + compressed_len);
+        ["truncated WOFF2 font data"]
+    );
+    let mut stream = Vec::new();
+    atry!(
+        brotli::Decompressor::new(compressed, 4096).read_to_end(&mut stream);
+        ["failed to decompress WOFF2 font data"]
+    );
+
+    let mut tables = Vec::new();
+    let mut offset = 0;
+
+    for (tag, len) in entries {
+        let data = a_ok_or!(
+            stream.get(offset..offset + len);
+            ["truncated WOFF2 font data"]
+        );
+        tables.push((tag, data.to_vec()));
+        offset += len;
+    }
+
+    Ok(write_sfnt(tables))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{font::FontData, test_util::*, EngineState, Spx2HtmlEngine};
+
+    fn bundled() -> FontData {
+        FontData::from_opentype(BUNDLED_NAME.to_owned(), bundled_font().unwrap(), 0).unwrap()
+    }
+
+    /// Get the glyphs that the bundled font maps *chars* to.
+    fn bundled_mapping(chars: &str) -> Vec<(char, GlyphId)> {
+        let fd = bundled();
+        let mut mapping: Vec<_> = chars.chars().map(|c| (c, fd.glyph(c).unwrap())).collect();
+        mapping.sort_unstable();
+        mapping
+    }
+
+    #[test]
+    fn bundled_coverage() {
+        let fd = bundled();
+        assert!(can_subset(fd.data()));
+
+        for c in "Az09.,;–—‘’“”…€™←→∀∑≠≤■☃✓é".chars() {
+            assert!(fd.covers(c), "{:?}", c);
+        }
+
+        assert!(!fd.covers('\u{1F600}'));
+        assert!(!fd.covers('α'));
+    }
+
+    #[test]
+    fn subsets() {
+        let orig = bundled();
+
+        // In DejaVu Sans, `é` is a composite of `e` and the acute accent.
+        let mapping = bundled_mapping("→é");
+        let data = subset(orig.data(), &mapping).unwrap();
+        assert!(data.len() < orig.data().len() / 8, "{} bytes", data.len());
+
+        let fd = FontData::from_opentype("subset.ttf".to_owned(), data.clone(), 0).unwrap();
+        assert!(fd.covers('→'));
+        assert!(fd.covers('é'));
+        assert!(!fd.covers('e'));
+
+        let tables = read_tables(&data).unwrap();
+        assert_eq!(BigEndian::read_u16(&tables[b"maxp"][4..]), 5);
+        assert!(!tables.contains_key(b"GSUB"));
+        assert!(tables.contains_key(b"name"));
+
+        // The components are renumbered along with the glyphs.
+        let glyph = fd.glyph('é').unwrap() as usize;
+        let loca = tables[b"loca"];
+        let start = BigEndian::read_u32(&loca[4 * glyph..]) as usize;
+        let end = BigEndian::read_u32(&loca[4 * glyph + 4..]) as usize;
+        let ids: Vec<_> = components(&tables[b"glyf"][start..end])
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|(_, g)| g)
+            .collect();
+        assert_eq!(ids, [3, 4]);
+
+        // The metrics are kept.
+        let metrics = |fd: &FontData, c| fd.lookup_metrics(fd.glyph(c).unwrap(), 65536).unwrap();
+        assert_eq!(metrics(&fd, '→').advance, metrics(&orig, '→').advance);
+        assert_eq!(metrics(&fd, 'é').lsb, metrics(&orig, 'é').lsb);
+
+        // The checksums hold.
+        assert_eq!(opentype_checksum(&data), 0xB1B0AFBA);
+    }
+
+    #[test]
+    fn strips_instructions() {
+        let glyph = [
+            0, 1, 0, 0, 0, 0, 0, 10, 0, 10, // header
+            0, 0, // endPtsOfContours
+            0, 3, 1, 2, 3, // instructions
+            1, 0, 0, // flags and coordinates
+        ];
+        assert_eq!(
+            strip_instructions(&glyph).unwrap(),
+            [0, 1, 0, 0, 0, 0, 0, 10, 0, 10, 0, 0, 0, 0, 1, 0, 0]
+        );
+
+        let composite = [
+            0xFF, 0xFF, 0, 0, 0, 0, 0, 10, 0, 10, // header
+            0x01, 0x21, 0, 7, 0, 0, 0, 0, // words, more components, instructions
+            0x00, 0x00, 0, 8, 0, 0, // bytes
+            0, 1, 0, // instructions
+        ];
+        assert_eq!(
+            strip_instructions(&composite).unwrap(),
+            [
+                0xFF, 0xFF, 0, 0, 0, 0, 0, 10, 0, 10, 0x00, 0x21, 0, 7, 0, 0, 0, 0, 0x00, 0x00, 0,
+                8, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn woff2_round_trip() {
+        let sfnt = subset(bundled().data(), &bundled_mapping("ABC→")).unwrap();
+        let woff2 = to_woff2(&sfnt).unwrap();
+        assert_eq!(&woff2[..4], b"wOF2");
+        assert_eq!(BigEndian::read_u32(&woff2[8..]) as usize, woff2.len());
+        assert_eq!(BigEndian::read_u32(&woff2[16..]) as usize, sfnt.len());
+        assert_eq!(woff2.len() % 4, 0);
+        assert!(woff2.len() < sfnt.len());
+
+        assert_eq!(from_woff2(&woff2).unwrap(), sfnt);
+    }
+
+    #[test]
+    fn base128() {
+        let mut buf = Vec::new();
+
+        for n in [0, 63, 0x3FFF, 0x4000, 0x0FFF_FFFF] {
+            write_base128(&mut buf, n);
+        }
+
+        assert_eq!(buf[..6], [0x00, 0x3F, 0xFF, 0x7F, 0x81, 0x80]);
+
+        let mut pos = 0;
+
+        for n in [0, 63, 0x3FFF, 0x4000, 0x0FFF_FFFF] {
+            assert_eq!(read_base128(&buf, &mut pos).unwrap(), n);
+        }
+
+        assert_eq!(pos, buf.len());
+    }
+
+    #[test]
+    fn engine_subsets_bundled() {
+        const SNOWMAN: char = '\u{2603}';
+
+        let mut io = TestIo::new();
+        io.add("page.html", "{{ tduxFontFaces | safe }}|{{ tduxContent }}");
+        io.add(
+            "report.html",
+            "{{ tduxMainBodyFontFamily }}|\
+             {% for c in tduxUncoveredChars %}{{ c.codepoint }} {{ c.rescued }};{% endfor %}",
+        );
+
+        let events = |s: &mut EngineState| {
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text(&format!("b{}\u{1F600}", SNOWMAN))?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:setTemplate report.html")?;
+            s.special("tdux:setOutputPath report.html")?;
+            s.special("tdux:emit")
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.uncovered_chars_variable(true);
+        let (files, status) = run_engine_in_memory(&mut engine, io.clone(), events);
+        let files = files.unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        let html = String::from_utf8(files["index.html"].clone()).unwrap();
+        assert!(!html.contains("tdux-fallback"));
+        let html = String::from_utf8(files["two.html"].clone()).unwrap();
+        assert!(html.contains("src: url(\"tdux-fallback.woff2\") format(\"woff2\")"));
+        assert_eq!(
+            files["report.html"],
+            b"tdux0, tdux-fallback|U+2603 true;U+1F600 false;"
+        );
+
+        // Only the rescued character is in the subset.
+        let woff2 = &files[SUBSET_NAME];
+        assert!(woff2.starts_with(b"wOF2"));
+        let fd =
+            FontData::from_opentype(SUBSET_NAME.to_owned(), from_woff2(woff2).unwrap(), 0).unwrap();
+        assert!(fd.covers(SNOWMAN));
+        assert!(!fd.covers('b'));
+        assert!(woff2.len() < 8192, "{}", woff2.len());
+
+        // The bundled font can be turned off.
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .uncovered_chars_variable(true)
+            .bundled_fallback_font(false);
+        let (files, _status) = run_engine_in_memory(&mut engine, io, events);
+        let files = files.unwrap();
+        assert!(!files.contains_key(SUBSET_NAME));
+        assert_eq!(files["report.html"], b"tdux0|U+2603 false;U+1F600 false;");
+    }
+
+    /// Regenerate the bundled font from the DejaVu Sans file named by the
+    /// `TDUX_FALLBACK_SOURCE` environment variable.
+    #[test]
+    #[ignore]
+    fn regenerate_bundled_font() {
+        let source = std::env::var("TDUX_FALLBACK_SOURCE").unwrap();
+        let data = std::fs::read(source).unwrap();
+        let fd = FontData::from_opentype("DejaVuSans.ttf".to_owned(), data.clone(), 0).unwrap();
+
+        let mapping: Vec<_> = BUNDLED_RANGES
+            .iter()
+            .flat_map(|(first, last)| *first..=*last)
+            .filter_map(|c| fd.glyph(c).map(|g| (c, g)))
+            .collect();
+
+        let woff2 = to_woff2(&subset(&data, &mapping).unwrap()).unwrap();
+        let dest = concat!(env!("CARGO_MANIFEST_DIR"), "/fonts/tdux-fallback.woff2");
+        std::fs::write(dest, woff2).unwrap();
+    }
+}
@@ -15,10 +15,14 @@ use pinot::{
     types::{FWord, Tag, UfWord},
     FontDataRef, TableProvider,
 };
-use std::{collections::HashMap, fmt::Write, num::Wrapping, path::Path};
+use std::{collections::HashMap, fmt::Write, num::Wrapping};
 use tectonic_errors::prelude::*;
+use tectonic_io_base::digest::{self, Digest, DigestData};
 
-use crate::FixedPoint;
+use crate::{
+    output::{OutputCategory, OutputTree},
+    FixedPoint,
+};
 
 /// A numerical identifier of a glyph in a font.
 pub type GlyphId = u16;
@@ -44,6 +48,9 @@ pub struct FontData {
     /// Information about how glyphs can be reverse-mapped to Unicode input
     gmap: HashMap<GlyphId, MapEntry>,
 
+    /// The glyphs that the character map maps characters to.
+    char_glyphs: HashMap<char, GlyphId>,
+
     units_per_em: UfWord,
 
     hmetrics: Vec<HorizontalMetrics>,
@@ -138,6 +145,23 @@ pub struct GlyphAlternateMapping {
     pub alternate_map_index: usize,
 }
 
+/// A variant font file written by [`FontData::emit_variants`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VariantFile {
+    /// The path of the file, relative to the output root.
+    pub path: String,
+
+    /// The index of the variant. These indices start at zero.
+    pub index: usize,
+
+    /// The characters mapped by the character map of the variant, with the
+    /// glyphs that they map to.
+    pub mappings: Vec<(char, GlyphId)>,
+
+    /// The size of the file, in bytes.
+    pub size: u64,
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct GlyphMetrics {
     /// Advance width in TeX units
@@ -153,6 +177,50 @@ pub struct GlyphMetrics {
     pub descent: FixedPoint,
 }
 
+/// The metrics of the characters of a font, separated from the rest of the
+/// font data so that they can be shared with templates.
+#[derive(Clone, Debug)]
+pub struct CharMetrics {
+    units_per_em: UfWord,
+    ascender: FWord,
+    descender: FWord,
+    hmetrics: Vec<HorizontalMetrics>,
+    glyphs: HashMap<char, GlyphId>,
+}
+
+/// The metrics of a character, in ems.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmMetrics {
+    /// The advance width.
+    pub advance: f32,
+
+    /// The left side bearing.
+    pub lsb: f32,
+
+    /// The ascent of the font.
+    pub ascent: f32,
+
+    /// The descent of the font. This value is typically negative.
+    pub descent: f32,
+}
+
+impl CharMetrics {
+    /// Look up the metrics of a character, if the font maps it directly to a
+    /// glyph.
+    pub fn lookup(&self, c: char) -> Option<EmMetrics> {
+        let glyph = self.glyphs.get(&c)?;
+        let hm = self.hmetrics.get(*glyph as usize)?;
+        let upem = self.units_per_em as f32;
+
+        Some(EmMetrics {
+            advance: hm.advance as f32 / upem,
+            lsb: hm.lsb as f32 / upem,
+            ascent: self.ascender as f32 / upem,
+            descent: self.descender as f32 / upem,
+        })
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 struct HorizontalMetrics {
     /// Advance width in font units
@@ -192,6 +260,7 @@ impl FontData {
         );
 
         let mut gmap = HashMap::new();
+        let mut char_glyphs = HashMap::new();
 
         for usv in valid_usvs() {
             let c = char::from_u32(usv).unwrap();
@@ -204,6 +273,7 @@ impl FontData {
             };
 
             gmap.insert(gidx, MapEntry::Direct(c));
+            char_glyphs.insert(c, gidx);
         }
 
         // Check for additional substitution-based mappings.
@@ -284,6 +354,7 @@ impl FontData {
             basename,
             buffer,
             gmap,
+            char_glyphs,
             units_per_em,
             hmetrics,
             ascender,
@@ -297,6 +368,12 @@ impl FontData {
     }
 
     /// Attempt to retrieve a mapping entry for the given glyph.
+    ///
+    /// The character map and the substitution tables are only walked when
+    /// the font is loaded, so this is a simple hash lookup that doesn't need
+    /// to be cached. Alternate mappings allocated with
+    /// [`Self::request_alternative`] are tracked separately and never change
+    /// the result.
     pub fn lookup_mapping(&self, glyph: GlyphId) -> Option<MapEntry> {
         self.gmap.get(&glyph).copied()
     }
@@ -311,7 +388,58 @@ impl FontData {
         self.baseline_factor
     }
 
+    /// Whether the font's character map maps a character to a glyph.
+    pub fn covers(&self, c: char) -> bool {
+        self.char_glyphs.contains_key(&c)
+    }
+
+    /// Get the glyph that the font's character map maps a character to.
+    #[cfg(feature = "fallback-font")]
+    pub fn glyph(&self, c: char) -> Option<GlyphId> {
+        self.char_glyphs.get(&c).copied()
+    }
+
+    /// Get the font file's data.
+    #[cfg(feature = "fallback-font")]
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Get the size of the font file, in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.buffer.len() as u64
+    }
+
+    /// Get the number of variants of the font with customized character maps
+    /// that will be emitted.
+    pub fn n_variants(&self) -> usize {
+        self.alternate_map_allocations
+            .values()
+            .map(|m| m.alternate_map_index + 1)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Get the file name of the font.
+    pub fn basename(&self) -> &str {
+        &self.basename
+    }
+
+    /// Get a copy of the character metrics of the font.
+    pub fn char_metrics(&self) -> CharMetrics {
+        CharMetrics {
+            units_per_em: self.units_per_em,
+            ascender: self.ascender,
+            descender: self.descender,
+            hmetrics: self.hmetrics.clone(),
+            glyphs: self.char_glyphs.clone(),
+        }
+    }
+
     /// Attempt to retrieve metrics information for the given glyph.
+    ///
+    /// The metrics are decoded when the font is loaded, so this only scales
+    /// them to the requested size.
     pub fn lookup_metrics(&self, glyph: GlyphId, tex_size: FixedPoint) -> Option<GlyphMetrics> {
         // As of Rust 1.45, the float-to-int cast saturates, which I think
         // is what we want here as a least-bad fallback. We don't want to
@@ -366,24 +494,44 @@ impl FontData {
         *map
     }
 
-    /// Emit customized fonts to the filesystem and compute
-    /// associated CSS for them. Consumes the object.
-    pub fn emit<W: Write>(self, out_base: &Path, base_facename: &str, mut css: W) -> Result<()> {
-        // Write the main font file.
+    /// Allocate alternative mappings decided beforehand, such as by an
+    /// earlier run, so that [`Self::request_alternative`] returns them for
+    /// their glyphs. Glyphs without such a mapping are allocated new ones
+    /// that don't clash with them.
+    pub fn pin_alternatives(&mut self, pins: &[(GlyphId, GlyphAlternateMapping)]) {
+        for (glyph, map) in pins {
+            self.alternate_map_allocations.insert(*glyph, *map);
+            let count = self.alternate_map_counts.entry(map.usv).or_default();
+            *count = (*count).max(map.alternate_map_index + 1);
+        }
+    }
 
-        let mut out_path = out_base.to_owned();
-        out_path.push(&self.basename);
-        atry!(
-            std::fs::write(&out_path, &self.buffer);
-            ["cannot write output file `{}`", out_path.display()]
-        );
+    /// Emit the unmodified font file to the output tree, unless *copy* is
+    /// false, and compute the associated CSS. The CSS refers to the file by
+    /// its name with *url_prefix* prepended.
+    ///
+    /// This doesn't depend on the glyphs that the document uses, so it can
+    /// happen as soon as the font is needed.
+    pub fn emit_base<W: Write>(
+        &self,
+        out: &OutputTree,
+        copy: bool,
+        url_prefix: &str,
+        base_facename: &str,
+        mut css: W,
+    ) -> Result<()> {
+        if copy {
+            out.write(&self.basename, &self.buffer, OutputCategory::Font)?;
+        }
 
-        // CSS for the main font.
-        //
         // We don't atry!() the write because I know that it's to a String,
         // which can panic but not Err.
 
-        let rel_url = utf8_percent_encode(&self.basename, CONTROLS).to_string();
+        let rel_url = format!(
+            "{}{}",
+            url_prefix,
+            utf8_percent_encode(&self.basename, CONTROLS)
+        );
 
         writeln!(
             css,
@@ -394,12 +542,39 @@ impl FontData {
             base_facename, rel_url
         )?;
 
+        Ok(())
+    }
+
+    /// Emit the customized variants of the font to the output tree and compute
+    /// associated CSS for them. Consumes the object. Returns descriptions of
+    /// the variant files.
+    ///
+    /// The variants can only be produced once all of the document's glyphs
+    /// are known. Pages may well be displayed before then, so the CSS asks
+    /// browsers to use `font-display: swap` for them.
+    ///
+    /// The variant fonts are assembled entirely in memory, and each file is
+    /// written exactly once through the atomic-write path. Variant files are
+    /// named after a digest of their contents, so that concurrent runs into the
+    /// same output directory that happen to allocate variants differently can
+    /// never clobber each other's files.
+    pub fn emit_variants<W: Write>(
+        &self,
+        out: &OutputTree,
+        base_facename: &str,
+        mut css: W,
+    ) -> Result<Vec<VariantFile>> {
         // Alternates until we're done
 
-        let mut buffer = self.buffer;
+        let n_variants = self.n_variants();
+        let mut buffer = self.buffer.clone();
         let orig_len = buffer.len();
+        let mut files = Vec::new();
 
-        for cur_map_index in 0.. {
+        // Pinned mappings (see `pin_alternatives`) may leave gaps in the
+        // indices.
+
+        for cur_map_index in 0..n_variants {
             let mut mappings = Vec::new();
 
             for (glyph, altmap) in &self.alternate_map_allocations {
@@ -409,7 +584,7 @@ impl FontData {
             }
 
             if mappings.is_empty() {
-                break;
+                continue;
             }
 
             // We have some alternates to emit!
@@ -439,15 +614,14 @@ impl FontData {
             let ofs = self.fontdata_head_offset as usize + 8;
             BigEndian::write_u32(&mut buffer[ofs..ofs + 4], chkadj.0);
 
-            // step 4: write new file
+            // step 4: write new file, naming it by its contents
+
+            let mut dc = digest::create();
+            dc.update(&buffer);
+            let digest = DigestData::from(dc).to_string();
 
-            out_path.pop();
-            let varname = format!("vg{}{}", cur_map_index, self.basename);
-            out_path.push(&varname);
-            atry!(
-                std::fs::write(&out_path, &buffer);
-                ["cannot write output file `{}`", out_path.display()]
-            );
+            let varname = format!("vg{}-{}", &digest[..16], self.basename);
+            out.write(&varname, &buffer, OutputCategory::Font)?;
 
             // step 5: update CSS
 
@@ -458,17 +632,64 @@ impl FontData {
                 r#"@font-face {{
   font-family: "{}vg{}";
   src: url("{}") format("opentype");
+  font-display: swap;
 }}"#,
                 base_facename, cur_map_index, rel_url
             )?;
+
+            files.push(VariantFile {
+                path: varname,
+                index: cur_map_index,
+                mappings,
+                size: buffer.len() as u64,
+            });
         }
 
         // All done!
 
-        Ok(())
+        Ok(files)
     }
 }
 
+/// Wrap the `@font-face` rules generated by [`FontData::emit_base`] and
+/// [`FontData::emit_variants`] in a `<style>` element for a page whose path
+/// to the output root is *rel_top*.
+///
+/// The rules refer to the font files relative to the output root, so their
+/// URLs are rewritten to be relative to the page instead. URLs that aren't
+/// relative, as with [`crate::Spx2HtmlEngine::font_url_prefix`], are left
+/// alone. Otherwise, the rules are unchanged.
+pub fn inline_faces(css: &str, rel_top: &str) -> String {
+    const SRC: &str = "src: url(\"";
+    let mut pieces = css.split(SRC);
+    let mut inlined = String::from("<style>\n");
+    inlined.push_str(pieces.next().unwrap_or_default());
+
+    for piece in pieces {
+        inlined.push_str(SRC);
+
+        if is_relative_url(piece) {
+            inlined.push_str(rel_top);
+        }
+
+        inlined.push_str(piece);
+    }
+
+    inlined.push_str("</style>");
+    inlined
+}
+
+/// Whether the URL at the start of *text* is a relative path, rather than an
+/// absolute path or one with a scheme or host.
+fn is_relative_url(text: &str) -> bool {
+    if text.starts_with('/') {
+        return false;
+    }
+
+    let first_segment = text.split(['/', '"']).next().unwrap_or_default();
+    !first_segment.contains(':')
+}
+
 fn load_ssty_mappings(
     map: &mut HashMap<GlyphId, MapEntry>,
     feat: &Feature,
@@ -545,7 +766,7 @@ fn valid_usvs() -> impl Iterator<Item = Usv> {
     (0..0xD800).chain(0xE000..0x11_0000)
 }
 
-fn opentype_checksum(data: &[u8]) -> u32 {
+pub(crate) fn opentype_checksum(data: &[u8]) -> u32 {
     let mut iter = data.chunks_exact(4);
     let cs: Wrapping<u32> = iter
         .by_ref()
@@ -587,3 +808,186 @@ fn append_simple_cmap(buf: &mut Vec<u8>, map: &[(char, GlyphId)]) {
         buf.write_u32::<BigEndian>(*gid as u32).unwrap(); // glyph id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::*, Spx2HtmlEngine};
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn merge_equivalent_fonts() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let (result, _status) = run_engine(simple_io(), tempdir.path(), |s| {
+            // The last-defined font sets the main body size, so font 1 is
+            // larger than the body text; fonts 2 and 3 are equivalent.
+            s.define_test_font(2, 10 * 65536)?;
+            s.define_test_font(3, 10 * 65536)?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text_in(2, "plain")?;
+            s.text_in(1, "three")?;
+            s.text_in(1, "word")?;
+            s.text_in(1, "phrase")?;
+            s.text_in(3, "more")?;
+            s.text_in(2, "plain")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(
+            html,
+            "<html><body>plain <span style=\"font-size: 1.2rem\">three word phrase</span> more plain</body></html>"
+        );
+    }
+
+    #[test]
+    fn fonts_precede_pages() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let out_base = tempdir.path().to_owned();
+        let mut io = simple_io();
+        io.add("faces.html", "{{ tduxFontFaces | safe }}");
+
+        let (result, _status) = run_engine(io, tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate faces.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+
+            // The font is written along with the first page, before the
+            // end of the content.
+            assert!(out_base.join("lmroman12-regular.otf").exists());
+            let faces = std::fs::read_to_string(out_base.join("index.html")).unwrap();
+            assert_eq!(faces.matches("@font-face").count(), 1);
+            assert!(faces.contains("url(\"lmroman12-regular.otf\")"));
+
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+    }
+
+    #[test]
+    fn inline_font_faces() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = TestIo::new();
+        io.add("faces.html", "{{ tduxFontFaces | safe }}");
+        io.add("inline.html", "{{ tduxFontFacesInline | safe }}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.inline_font_faces(true);
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:setTemplate faces.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplate inline.html")?;
+            s.special("tdux:setOutputPath top.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath ch/one.html")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let faces = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(faces.contains("src: url(\"lmroman12-regular.otf\")"));
+
+        // The rules are the same, except for the relative URLs.
+        let top = std::fs::read_to_string(tempdir.path().join("top.html")).unwrap();
+        assert_eq!(top, format!("<style>\n{}</style>", faces));
+        let nested = std::fs::read_to_string(tempdir.path().join("ch/one.html")).unwrap();
+        assert_eq!(nested, top.replace("url(\"lmroman12", "url(\"../lmroman12"));
+        assert_ne!(nested, top);
+    }
+
+    #[test]
+    fn font_url_prefix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("faces.html", "{{ tduxFontFaces | safe }}");
+        io.add("inline.html", "{{ tduxFontFacesInline | safe }}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .inline_font_faces(true)
+            .font_url_prefix(Some("https://cdn.example.com/fonts/"));
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:setTemplate faces.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplate inline.html")?;
+            s.special("tdux:setOutputPath ch/one.html")?;
+            s.text("World")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        let fonts: Vec<_> = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|n| n.ends_with(".otf"))
+            .collect();
+        assert!(fonts.is_empty(), "{:?}", fonts);
+
+        // The prefixed URLs aren't made relative to nested pages.
+        let url = format!(
+            "src: url(\"https://cdn.example.com/fonts/{}.otf\")",
+            TEST_FONT
+        );
+        let faces = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(faces.contains(&url), "{}", faces);
+        let nested = std::fs::read_to_string(tempdir.path().join("ch/one.html")).unwrap();
+        assert!(nested.contains(&url), "{}", nested);
+    }
+
+    #[test]
+    fn deterministic_font_faces() {
+        let mut io = simple_io();
+        io.add("faces.html", "{{ tduxFontFaces | safe }}");
+        io.add("second.otf", test_font_data());
+        io.add("third.otf", test_font_data());
+
+        let run = || {
+            let mut engine = Spx2HtmlEngine::default();
+            let (files, _status) = run_engine_in_memory(&mut engine, io.clone(), |s| {
+                for (name, num) in [("third.otf", 2), ("second.otf", 3)] {
+                    s.handle_define_native_font(
+                        name,
+                        num,
+                        TEST_FONT_SIZE,
+                        0,
+                        None,
+                        None,
+                        None,
+                        None,
+                    )?;
+                }
+
+                s.define_test_font(4, TEST_FONT_SIZE)?;
+                s.special("tdux:setTemplate faces.html")?;
+                s.text_in(3, "a")?;
+                s.text_in(2, "b")?;
+                s.text("c")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+            files.unwrap()
+        };
+
+        let first = run();
+        let faces = String::from_utf8(first["index.html"].clone()).unwrap();
+
+        // The rules follow the order in which the fonts were loaded.
+        let families: Vec<_> = faces
+            .lines()
+            .filter_map(|l| l.strip_prefix("  font-family: "))
+            .collect();
+        assert_eq!(families, ["\"tdux0\";", "\"tdux1\";", "\"tdux2\";"]);
+
+        for _ in 0..4 {
+            assert_eq!(run()["index.html"], first["index.html"]);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Handling specials defined by other projects.
+//!
+//! Projects building on the engine can define specials of their own, such
+//! as `tdux:myproj:figure ...`, by registering a [`SpecialHandler`] for a
+//! prefix with [`crate::Spx2HtmlEngine::register_special_handler`]. A
+//! special that the engine doesn't know itself is given to the first handler
+//! registered for a prefix that it starts with; specials that match no
+//! prefix are ignored, as always. Handlers can't take over the engine's own
+//! specials.
+//!
+//! A handler's specials count as content, like `tdux:emit`, so the engine
+//! has finished initializing by the time they're handled. Handlers act on the
+//! document through a [`SpecialContext`].
+
+use tectonic_errors::prelude::*;
+use tectonic_status_base::{tt_warning, StatusBackend};
+
+use crate::{Common, EmittingState};
+
+/// Handles specials on behalf of the application.
+///
+/// This is implemented for closures with the same signature as
+/// [`SpecialHandler::handle`].
+pub trait SpecialHandler: Send {
+    /// Handle a special.
+    ///
+    /// The *special* is its normalized text, including the prefix for which
+    /// the handler was registered. The position at which it appeared on the
+    /// page is (*x*, *y*), in TeX scaled points. Returning an error aborts
+    /// processing.
+    fn handle(&mut self, special: &str, x: i32, y: i32, cx: &mut SpecialContext) -> Result<()>;
+}
+
+impl<F> SpecialHandler for F
+where
+    F: FnMut(&str, i32, i32, &mut SpecialContext) -> Result<()> + Send,
+{
+    fn handle(&mut self, special: &str, x: i32, y: i32, cx: &mut SpecialContext) -> Result<()> {
+        self(special, x, y, cx)
+    }
+}
+
+/// What a [`SpecialHandler`] can do to the document.
+pub struct SpecialContext<'a, 'b> {
+    pub(crate) state: &'a mut EmittingState,
+    pub(crate) common: &'a mut Common<'b>,
+}
+
+impl<'a, 'b> SpecialContext<'a, 'b> {
+    /// Append markup to the content of the current output file, verbatim.
+    ///
+    /// Once the content is finished, the markup is dropped with a warning,
+    /// like any other content.
+    ///
+    /// In strict mode, dropping the markup is an error.
+    pub fn append_html(&mut self, html: &str) -> Result<()> {
+        if self.state.content_finished {
+            self.state
+                .warn_finished_content("markup from a special handler", self.common)
+        } else {
+            self.state.content.raw_html(html);
+            Ok(())
+        }
+    }
+
+    /// Set a template variable, like `tdux:setTemplateVariable`.
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.state.set_variable(name, Some(value));
+    }
+
+    /// Copy an input file to the output, like `tdux:provideFile`.
+    pub fn provide_file(&mut self, src_tex_path: &str, dest_path: &str) -> Result<()> {
+        self.state
+            .provide_file(src_tex_path, dest_path, self.common)
+    }
+
+    /// Issue a warning about the special.
+    pub fn warn(&mut self, message: &str) {
+        tt_warning!(self.common.status, "{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine};
+
+    #[test]
+    fn special_handlers() {
+        let mut io = simple_io();
+        io.add(
+            "page.html",
+            "<title>{{ figureTitle }}</title>{{ tduxContent }}",
+        );
+        io.add("fig.svg", "<svg/>");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .register_special_handler(
+                "tdux:myproj:hr",
+                Box::new(|_special: &str, _x, _y, cx: &mut SpecialContext| cx.append_html("<hr>")),
+            )
+            .register_special_handler(
+                "tdux:myproj:figure ",
+                Box::new(|special: &str, _x, _y, cx: &mut SpecialContext| {
+                    let title = special.strip_prefix("tdux:myproj:figure ").unwrap();
+                    cx.set_variable("figureTitle", title);
+                    cx.provide_file("fig.svg", "img/fig.svg")
+                }),
+            )
+            .register_special_handler(
+                "tdux:myproj:",
+                Box::new(|special: &str, _x, _y, _cx: &mut SpecialContext| {
+                    bail!("unexpected special `{}`", special)
+                }),
+            );
+
+        let (result, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:myproj:figure Results")?;
+            s.text("a")?;
+            s.special("tdux:myproj:hr")?;
+            s.text("b")?;
+            s.special("tdux:otherproj:hr")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = result.unwrap();
+        assert_eq!(status.warnings, Vec::<String>::new());
+        assert_eq!(
+            String::from_utf8_lossy(&files["index.html"]),
+            "<title>Results</title>a<hr>b"
+        );
+        assert_eq!(files["img/fig.svg"], b"<svg/>");
+
+        // Errors of handlers abort processing.
+        let (result, _status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.text("a")?;
+            s.special("tdux:myproj:oops")
+        });
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the handler of special `tdux:myproj:oops` failed"
+        );
+    }
+}
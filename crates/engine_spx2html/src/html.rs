@@ -0,0 +1,1865 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Rendering positioned glyphs to HTML.
+//!
+//! The engine uses these routines to convert the contents of "canvases" —
+//! regions of the document, such as equations, whose glyphs must be placed
+//! precisely — into HTML. They are exposed publicly so that other tools can
+//! generate the same markup for standalone formula snippets without running
+//! the full SPX pipeline.
+//!
+//! # Example
+//!
+//! ```
+//! use tectonic_engine_spx2html::html::{render_canvas, CanvasOptions, FontLookup, PositionedGlyph};
+//! # fn main() -> tectonic_errors::Result<()> {
+//! # let font_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../tests/assets/lmroman12-regular.otf");
+//! # let font_bytes = std::fs::read(font_path)?;
+//!
+//! // Register a font at 12pt, in TeX units (1pt = 65536 units):
+//! let mut fonts = FontLookup::default();
+//! fonts.add_font(1, "lmroman12-regular.otf", font_bytes, 0, 12 * 65536)?;
+//!
+//! // Glyph 30 of the font, placed at the canvas origin:
+//! let glyphs = [PositionedGlyph { font_num: 1, glyph: 30, dx: 0, dy: 0, color_rgba: None }];
+//!
+//! // Our "rem" unit is the size of the 12pt font:
+//! let options = CanvasOptions::new(1. / (12. * 65536.));
+//! let canvas = render_canvas(&mut fonts, &glyphs, &options)?;
+//! assert!(canvas.html.starts_with("<div class=\"canvas canvas-block tdux-avoid-break\""));
+//! # Ok(())
+//! # }
+//! ```
+
+use percent_encoding::{utf8_percent_encode, CONTROLS};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+use tectonic_errors::prelude::*;
+
+use crate::{
+    assets::FontPins,
+    content,
+    font::{FontData, MapEntry},
+    FixedPoint, FontInfo, FontRole,
+};
+
+/// A collection of fonts that can be used to render glyphs.
+///
+/// Fonts are identified by integer "font numbers", as in TeX. Several font
+/// numbers may refer to the same underlying font data at different sizes.
+#[derive(Debug, Default)]
+pub struct FontLookup {
+    /// Information about each font number.
+    pub(crate) fonts: HashMap<i32, FontInfo>,
+
+    /// Map from font name and face index to "font data key", which indexes
+    /// the actual font data.
+    font_data_keys: HashMap<(String, u32), usize>,
+
+    /// The loaded font data, in order of their keys, which is the order in
+    /// which they're emitted.
+    pub(crate) font_data: BTreeMap<usize, FontData>,
+
+    /// The key of the font data of the main body font, if known.
+    pub(crate) main_body_fd_key: Option<usize>,
+
+    /// Fallback font stacks, keyed by font file name, `body`, or `*`.
+    fallbacks: HashMap<String, String>,
+
+    /// The font of last resort, if any.
+    pub(crate) last_resort: Option<FontData>,
+
+    /// Font data keys and glyph mappings decided beforehand, if any.
+    pins: Option<FontPins>,
+}
+
+/// The CSS font family of the font of last resort.
+pub const LAST_RESORT_FAMILY: &str = "tdux-fallback";
+
+impl FontLookup {
+    /// Add a font to the lookup.
+    ///
+    /// The *name* identifies the font file; if the same name and face index
+    /// are added several times (e.g., at different sizes), the font data are
+    /// only parsed once. The *data* must be an OpenType font. The *size* is
+    /// expressed in TeX units.
+    pub fn add_font(
+        &mut self,
+        font_num: i32,
+        name: &str,
+        data: Vec<u8>,
+        face_index: u32,
+        size: FixedPoint,
+    ) -> Result<()> {
+        let basename = name.rsplit('/').next().unwrap();
+        let fd_key = self.add_font_data(name, basename, data, face_index)?;
+
+        self.fonts.insert(
+            font_num,
+            FontInfo {
+                role: FontRole::MainBody,
+                rel_url: utf8_percent_encode(basename, CONTROLS).to_string(),
+                fd_key,
+                size,
+                face_index,
+                color_rgba: None,
+                extend: None,
+                slant: None,
+                embolden: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Register font data, returning its data key. The data are only parsed if
+    /// this name and face index haven't been seen before.
+    pub(crate) fn add_font_data(
+        &mut self,
+        name: &str,
+        basename: &str,
+        data: Vec<u8>,
+        face_index: u32,
+    ) -> Result<usize> {
+        let id = (name.to_owned(), face_index);
+
+        if let Some(fd_key) = self.font_data_keys.get(&id) {
+            return Ok(*fd_key);
+        }
+
+        let fd_key = match &self.pins {
+            Some(pins) => match pins.key(name, face_index) {
+                Some(k) => k,
+                None => bail!("font `{}` isn't listed in the precomputed assets", name),
+            },
+            None => self.font_data_keys.len(),
+        };
+
+        let mut map = atry!(
+            FontData::from_opentype(basename.to_owned(), data, face_index);
+            ["unable to load glyph data from font `{}`", name]
+        );
+
+        if let Some(pins) = &self.pins {
+            map.pin_alternatives(pins.alternatives(fd_key));
+        }
+
+        self.font_data_keys.insert(id, fd_key);
+        self.font_data.insert(fd_key, map);
+        Ok(fd_key)
+    }
+
+    /// Use the font data keys and glyph mappings decided beforehand, such as
+    /// by an earlier run. Fonts that they don't cover can't be added.
+    pub(crate) fn pin(&mut self, pins: FontPins) {
+        self.pins = Some(pins);
+    }
+
+    /// Check whether a face of a font can be added, given the font data keys
+    /// decided beforehand, if any.
+    pub(crate) fn can_add(&self, name: &str, face_index: u32) -> bool {
+        self.pins
+            .as_ref()
+            .map(|p| p.key(name, face_index).is_some())
+            .unwrap_or(true)
+    }
+
+    /// Get the name of the input that a font's data were loaded from, and the
+    /// index of the face.
+    pub(crate) fn input(&self, fd_key: usize) -> Option<(&str, u32)> {
+        self.font_data_keys
+            .iter()
+            .find(|(_, k)| **k == fd_key)
+            .map(|((name, face_index), _)| (name.as_str(), *face_index))
+    }
+
+    /// Check whether a font number has been defined.
+    pub fn contains(&self, font_num: i32) -> bool {
+        self.fonts.contains_key(&font_num)
+    }
+
+    /// Set the fallback fonts to use alongside a font.
+    ///
+    /// The *family* is the file name of a font, such as
+    /// `lmroman12-regular.otf`; `body`, for the main body font; or `*`, for
+    /// all fonts without a more specific setting. The *stack* is a CSS font
+    /// family list, such as `"Noto Serif", serif`, that is appended to the
+    /// generated font family wherever it is used, so that browsers fall back
+    /// to suitable fonts for characters that the embedded fonts lack.
+    pub fn set_fallback(&mut self, family: &str, stack: &str) {
+        self.fallbacks.insert(family.to_owned(), stack.to_owned());
+    }
+
+    /// Set the font of last resort, whose file name is *name*.
+    pub(crate) fn set_last_resort_font(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        self.last_resort = Some(atry!(
+            FontData::from_opentype(name.to_owned(), data, 0);
+            ["unable to load glyph data from font `{}`", name]
+        ));
+        Ok(())
+    }
+
+    /// Get the CSS font family list for some font data, including any
+    /// fallbacks.
+    pub(crate) fn css_family(&self, fd_key: usize) -> String {
+        let mut family = css_family(fd_key, None, self.fallback(fd_key));
+
+        if self.last_resort.is_some() {
+            family.push_str(", ");
+            family.push_str(LAST_RESORT_FAMILY);
+        }
+
+        family
+    }
+
+    /// Get the fallback font stack for some font data, if there is one.
+    fn fallback(&self, fd_key: usize) -> Option<&str> {
+        let by_name = self
+            .font_data
+            .get(&fd_key)
+            .and_then(|fd| self.fallbacks.get(fd.basename()));
+        let by_role = if self.main_body_fd_key == Some(fd_key) {
+            self.fallbacks.get("body")
+        } else {
+            None
+        };
+
+        by_name
+            .or(by_role)
+            .or_else(|| self.fallbacks.get("*"))
+            .map(|s| s.as_str())
+    }
+}
+
+/// Format the CSS font family list for some font data, or for one of its
+/// alternate-mapped variants.
+fn css_family(fd_key: usize, variant: Option<usize>, fallback: Option<&str>) -> String {
+    let mut family = match variant {
+        Some(v) => format!("tdux{}vg{}", fd_key, v),
+        None => format!("tdux{}", fd_key),
+    };
+
+    if let Some(stack) = fallback {
+        family.push_str(", ");
+        family.push_str(stack);
+    }
+
+    family
+}
+
+/// A glyph to be drawn in a canvas.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PositionedGlyph {
+    /// The number of the font containing the glyph.
+    pub font_num: i32,
+
+    /// The glyph's ID in its font.
+    pub glyph: u16,
+
+    /// The horizontal position of the glyph's origin relative to the canvas
+    /// origin, in TeX units.
+    pub dx: i32,
+
+    /// The vertical position of the glyph's baseline relative to the canvas
+    /// baseline, in TeX units. Positive values are downwards.
+    pub dy: i32,
+
+    /// The color of the glyph, as RGBA, if it isn't the default.
+    pub color_rgba: Option<u32>,
+}
+
+/// A rule to be drawn in a canvas, such as a fraction bar or the vinculum of
+/// a radical.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PositionedRule {
+    /// The horizontal position of the left edge of the rule relative to the
+    /// canvas origin, in TeX units.
+    pub dx: i32,
+
+    /// The vertical position of the bottom edge of the rule relative to the
+    /// canvas baseline, in TeX units. Positive values are downwards.
+    pub dy: i32,
+
+    /// The width of the rule, in TeX units.
+    pub width: i32,
+
+    /// The height of the rule, in TeX units.
+    pub height: i32,
+
+    /// The color of the rule, as RGBA, if it isn't the default.
+    pub color_rgba: Option<u32>,
+}
+
+/// Bounds on the font sizes used to draw the glyphs of canvases, in rems.
+///
+/// Browsers often refuse to render text smaller than some minimum font size,
+/// which would break the positioning of small glyphs, since it assumes that
+/// the CSS box of each glyph is exactly as tall as its font size. If a canvas
+/// contains glyphs smaller than the minimum, all of its glyphs are drawn
+/// enlarged and the canvas contents are scaled back down with a CSS
+/// transform. Glyphs larger than the maximum are drawn at the maximum size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlyphSizeLimits {
+    /// The smallest font size that is used as-is.
+    pub min: f32,
+
+    /// The largest font size that is allowed.
+    pub max: f32,
+}
+
+impl Default for GlyphSizeLimits {
+    fn default() -> Self {
+        GlyphSizeLimits {
+            min: 0.375,
+            max: 24.,
+        }
+    }
+}
+
+/// Options controlling how a canvas is rendered.
+#[derive(Clone, Debug)]
+pub struct CanvasOptions {
+    rems_per_tex: f32,
+    pub(crate) inline: bool,
+    pub(crate) id: Option<String>,
+    pub(crate) classes: Option<String>,
+    pub(crate) style: Option<String>,
+    pub(crate) alt: Option<String>,
+    hide_unlabeled: bool,
+    size_limits: GlyphSizeLimits,
+    containment: bool,
+    xhtml: bool,
+}
+
+impl CanvasOptions {
+    /// Create a new set of options.
+    ///
+    /// The *rems_per_tex* parameter gives the scale of the output: the number
+    /// of CSS `rem` units corresponding to one TeX unit. This is usually the
+    /// inverse of the size of the main body font.
+    pub fn new(rems_per_tex: f32) -> Self {
+        CanvasOptions {
+            rems_per_tex,
+            inline: false,
+            id: None,
+            classes: None,
+            style: None,
+            alt: None,
+            hide_unlabeled: false,
+            size_limits: GlyphSizeLimits::default(),
+            containment: true,
+            xhtml: false,
+        }
+    }
+
+    /// Configure whether the canvas is rendered inline, aligned with the
+    /// baseline of its containing line of text.
+    ///
+    /// The default is false, in which case the canvas is rendered as a block.
+    pub fn inline(&mut self, inline: bool) -> &mut Self {
+        self.inline = inline;
+        self
+    }
+
+    /// Configure the `id` attribute of the canvas element.
+    ///
+    /// The default is None, in which case the element has no id.
+    pub fn id(&mut self, id: Option<&str>) -> &mut Self {
+        self.id = id.map(|s| s.to_owned());
+        self
+    }
+
+    /// Configure additional classes of the canvas element, separated by
+    /// spaces.
+    ///
+    /// The default is None, in which case the element only has the standard
+    /// classes.
+    pub fn classes(&mut self, classes: Option<&str>) -> &mut Self {
+        self.classes = classes.map(|s| s.to_owned());
+        self
+    }
+
+    /// Configure additional CSS declarations of the canvas element, which
+    /// follow the ones that position it.
+    ///
+    /// The default is None.
+    pub fn style(&mut self, style: Option<&str>) -> &mut Self {
+        self.style = style.map(|s| s.to_owned());
+        self
+    }
+
+    /// Configure the text alternative of the canvas, which describes it to
+    /// assistive technologies.
+    ///
+    /// If set, the canvas element gets `role="img"` and the text as its
+    /// `aria-label`, and the text is also included in a visually hidden
+    /// element, with the class [`CANVAS_ALT_CLASS`], for rendering without
+    /// CSS. The default is None.
+    pub fn alt(&mut self, alt: Option<&str>) -> &mut Self {
+        self.alt = alt.map(|s| s.to_owned());
+        self
+    }
+
+    /// Configure whether a canvas without a text alternative is hidden from
+    /// assistive technologies with `aria-hidden="true"`.
+    ///
+    /// Otherwise, screen readers read out the characters of its glyphs, in
+    /// whatever order they were drawn. The default is false.
+    pub fn hide_unlabeled(&mut self, hide: bool) -> &mut Self {
+        self.hide_unlabeled = hide;
+        self
+    }
+
+    /// Configure the bounds on the font sizes of glyphs.
+    ///
+    /// The default is [`GlyphSizeLimits::default`].
+    pub fn size_limits(&mut self, limits: GlyphSizeLimits) -> &mut Self {
+        self.size_limits = limits;
+        self
+    }
+
+    /// Configure whether the canvas element is fully pre-sized.
+    ///
+    /// When enabled, the element gets an `aspect-ratio` matching its
+    /// dimensions and `contain: layout size`, so that browsers can reserve
+    /// its space before the fonts of its glyphs have loaded, and nothing
+    /// inside it can shift the surrounding layout. Templates that style
+    /// canvases such that their contents need to affect the layout can turn
+    /// this off.
+    ///
+    /// The default is true.
+    pub fn containment(&mut self, enabled: bool) -> &mut Self {
+        self.containment = enabled;
+        self
+    }
+
+    /// Configure whether the canvas is serialized as well-formed XML.
+    ///
+    /// The default is false.
+    pub fn xhtml(&mut self, enabled: bool) -> &mut Self {
+        self.xhtml = enabled;
+        self
+    }
+}
+
+/// The bounding box of a canvas, in TeX units relative to its origin.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CanvasBounds {
+    /// The leftmost extent of the canvas contents.
+    pub x_min: i32,
+
+    /// The rightmost extent of the canvas contents.
+    pub x_max: i32,
+
+    /// The topmost extent of the canvas contents.
+    pub y_min: i32,
+
+    /// The bottommost extent of the canvas contents.
+    pub y_max: i32,
+}
+
+/// The dimensions of a rendered canvas element, in rems.
+///
+/// The serialized names of the fields are part of the layout sidecar format
+/// (see [`crate::Spx2HtmlEngine::layout_sidecars`]), so they're pinned
+/// explicitly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct CanvasSize {
+    /// The width of the element's content box.
+    #[serde(rename = "width")]
+    pub width: f32,
+
+    /// The height of the element.
+    #[serde(rename = "height")]
+    pub height: f32,
+
+    /// The left padding of the element, which accounts for glyphs that
+    /// extend to the left of the canvas origin.
+    #[serde(rename = "padding_left")]
+    pub padding_left: f32,
+
+    /// For inline canvases, the `vertical-align` setting that puts the
+    /// canvas baseline on the baseline of the surrounding text.
+    #[serde(rename = "vertical_align")]
+    pub vertical_align: Option<f32>,
+}
+
+/// A glyph drawn in a rendered canvas, with its position within the canvas
+/// element in rems.
+///
+/// As with [`CanvasSize`], the serialized names of the fields are pinned.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DrawnGlyph {
+    /// The character used to draw the glyph.
+    #[serde(rename = "character")]
+    pub character: char,
+
+    /// The CSS font family used to draw the glyph.
+    #[serde(rename = "font_family")]
+    pub font_family: String,
+
+    /// The distance from the top of the canvas to the top of the glyph box.
+    #[serde(rename = "top")]
+    pub top: f32,
+
+    /// The distance from the canvas origin to the glyph origin.
+    #[serde(rename = "left")]
+    pub left: f32,
+
+    /// The font size.
+    #[serde(rename = "size")]
+    pub size: f32,
+}
+
+/// An alternate-mapped font variant that a rendered canvas relies upon.
+///
+/// Glyphs that don't correspond directly to a Unicode character are drawn
+/// using variant fonts with customized character maps. The CSS font family of
+/// the variant is `tdux{fd_key}vg{alternate_map_index}`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct UsedVariant {
+    /// The key of the font data underlying the variant.
+    pub fd_key: usize,
+
+    /// The index of the alternate map used by the variant.
+    pub alternate_map_index: usize,
+}
+
+/// The result of rendering a canvas.
+#[derive(Clone, Debug)]
+pub struct RenderedCanvas {
+    /// The HTML of the canvas.
+    pub html: String,
+
+    /// The alternate-mapped font variants used by the canvas, in order of
+    /// first use.
+    pub used_variants: Vec<UsedVariant>,
+
+    /// The bounding box of the canvas contents.
+    pub bounds: CanvasBounds,
+
+    /// The dimensions of the canvas element.
+    pub size: CanvasSize,
+
+    /// The glyphs drawn in the canvas, in the order in which they were
+    /// given.
+    pub glyphs: Vec<DrawnGlyph>,
+
+    /// The factor by which the glyphs were enlarged because some of them
+    /// were smaller than the minimum size, or 1 if they weren't.
+    pub scale: f32,
+
+    /// The number of glyphs that were reduced to the maximum size.
+    pub n_capped: usize,
+
+    /// Glyphs that could not be reverse-mapped to Unicode, and were therefore
+    /// omitted from the output.
+    pub unmapped: Vec<PositionedGlyph>,
+}
+
+/// The class of every canvas element.
+pub const CANVAS_CLASS: &str = "canvas";
+
+/// The class of inline canvases, such as inline math.
+pub const CANVAS_INLINE_CLASS: &str = "canvas-inline";
+
+/// The class of block canvases, such as display math.
+pub const CANVAS_BLOCK_CLASS: &str = "canvas-block";
+
+/// The class of the element wrapping the glyphs of a canvas that has been
+/// drawn larger and scaled down.
+pub const CANVAS_SCALED_CLASS: &str = "canvas-scaled";
+
+/// The class of the visually hidden element holding the text alternative of a
+/// canvas.
+pub const CANVAS_ALT_CLASS: &str = "canvas-alt";
+
+/// The class of each glyph drawn in a canvas.
+pub const GLYPH_CLASS: &str = "ci";
+
+/// The class of each rule drawn in a canvas.
+pub const RULE_CLASS: &str = "cr";
+
+/// The name of the stylesheet holding [`required_css`], written to the output
+/// root when enabled with [`crate::Spx2HtmlEngine::base_css_file`].
+pub const REQUIRED_CSS_FILE: &str = "tdux-base.css";
+
+/// Get the CSS rules that the markup of canvases relies on.
+///
+/// Canvases position their glyphs absolutely, relative to the canvas, and
+/// size each glyph's box by its font size, which only works out with
+/// `line-height: 1`. Pages with canvases must therefore include these rules,
+/// either from the `tduxRequiredCss` template variable or by linking to
+/// [`REQUIRED_CSS_FILE`]. Templates can add rules of their own on top of
+/// them, such as margins around block canvases. The rules for printed output
+/// are separate; see the `tduxPrintCss` template variable.
+pub fn required_css() -> String {
+    format!(
+        "\
+.{canvas} {{ position: relative; line-height: 1; white-space: nowrap; }}
+.{inline} {{ display: inline-block; }}
+.{block} {{ display: block; }}
+.{glyph} {{ position: absolute; white-space: pre; }}
+.{rule} {{ position: absolute; background-color: currentColor; }}
+.{alt} {{ position: absolute; width: 1px; height: 1px; overflow: hidden; clip: rect(0 0 0 0); }}
+",
+        canvas = CANVAS_CLASS,
+        inline = CANVAS_INLINE_CLASS,
+        block = CANVAS_BLOCK_CLASS,
+        glyph = GLYPH_CLASS,
+        rule = RULE_CLASS,
+        alt = CANVAS_ALT_CLASS,
+    )
+}
+
+/// Render a set of positioned glyphs into an HTML canvas.
+///
+/// The glyphs are drawn as absolutely positioned `<span>` elements inside a
+/// container that is sized to the overall bounding box of the glyphs. Glyphs
+/// that need alternate-mapped font variants will have those variants allocated
+/// in *fonts*.
+pub fn render_canvas(
+    fonts: &mut FontLookup,
+    glyphs: &[PositionedGlyph],
+    options: &CanvasOptions,
+) -> Result<RenderedCanvas> {
+    render_canvas_with_rules(fonts, glyphs, &[], options)
+}
+
+/// Render a set of positioned glyphs and rules into an HTML canvas.
+///
+/// This is like [`render_canvas`], but the canvas also contains rules, such
+/// as the bars of fractions. They're drawn after the glyphs, as absolutely
+/// positioned `<span>` elements filled with their color, and count towards
+/// the bounding box of the canvas. Rules with a nonpositive width or height
+/// are skipped, like TeX does.
+pub fn render_canvas_with_rules(
+    fonts: &mut FontLookup,
+    glyphs: &[PositionedGlyph],
+    rules: &[PositionedRule],
+    options: &CanvasOptions,
+) -> Result<RenderedCanvas> {
+    let rules: Vec<_> = rules
+        .iter()
+        .filter(|r| r.width > 0 && r.height > 0)
+        .collect();
+    let rems_per_tex = options.rems_per_tex;
+    let limits = options.size_limits;
+    let max_size_tex = (limits.max / rems_per_tex) as FixedPoint;
+
+    // First pass: get overall bounds of all the glyphs from their metrics.
+    // We need to gather this information first because as we emit glyphs we
+    // have to specify their positions relative to the edges of the
+    // containing canvas box, and the size of that box is defined by the
+    // extents of all of the glyphs it contains. The bounds are measured in
+    // TeX units.
+
+    let mut first = true;
+    let mut x_min_tex = 0;
+    let mut x_max_tex = 0;
+    let mut y_min_tex = 0;
+    let mut y_max_tex = 0;
+    let mut min_size_tex = max_size_tex;
+    let mut n_capped = 0;
+
+    for gi in glyphs {
+        let fi = a_ok_or!(
+            fonts.fonts.get(&gi.font_num);
+            ["undeclared font {} in canvas", gi.font_num]
+        );
+
+        if fi.size > max_size_tex {
+            n_capped += 1;
+        }
+
+        let size_tex = std::cmp::min(fi.size, max_size_tex);
+        min_size_tex = std::cmp::min(min_size_tex, size_tex);
+
+        let fd = fonts.font_data.get_mut(&fi.fd_key).unwrap();
+        let gm = fd.lookup_metrics(gi.glyph, size_tex);
+
+        if let Some(gm) = gm {
+            // to check: RTL correctness
+            let xmin = gi.dx - gm.lsb;
+            let xmax = gi.dx + gm.advance;
+            let ymin = gi.dy - gm.ascent;
+            let ymax = gi.dy - gm.descent; // note: descent is negative
+
+            if first {
+                x_min_tex = xmin;
+                x_max_tex = xmax;
+                y_min_tex = ymin;
+                y_max_tex = ymax;
+                first = false;
+            } else {
+                x_min_tex = std::cmp::min(x_min_tex, xmin);
+                x_max_tex = std::cmp::max(x_max_tex, xmax);
+                y_min_tex = std::cmp::min(y_min_tex, ymin);
+                y_max_tex = std::cmp::max(y_max_tex, ymax);
+            }
+        }
+    }
+
+    for ri in &rules {
+        let xmin = ri.dx;
+        let xmax = ri.dx + ri.width;
+        let ymin = ri.dy - ri.height;
+        let ymax = ri.dy;
+
+        if first {
+            x_min_tex = xmin;
+            x_max_tex = xmax;
+            y_min_tex = ymin;
+            y_max_tex = ymax;
+            first = false;
+        } else {
+            x_min_tex = std::cmp::min(x_min_tex, xmin);
+            x_max_tex = std::cmp::max(x_max_tex, xmax);
+            y_min_tex = std::cmp::min(y_min_tex, ymin);
+            y_max_tex = std::cmp::max(y_max_tex, ymax);
+        }
+    }
+
+    // Now that we have that information, we can lay out the individual
+    // glyphs.
+    //
+    // A resource I found very helpful:
+    // https://iamvdo.me/en/blog/css-font-metrics-line-height-and-vertical-align
+
+    // If some glyphs are too small, draw everything bigger and scale it back
+    // down as a whole, so that browser minimum font sizes don't kick in.
+
+    let min_size = min_size_tex as f32 * rems_per_tex;
+    let scale = if min_size > 0. && min_size < limits.min {
+        limits.min / min_size
+    } else {
+        1.
+    };
+
+    let mut inner_content = String::default();
+    let mut used_variants = Vec::new();
+    let mut unmapped = Vec::new();
+    let mut drawn = Vec::new();
+
+    for gi in glyphs {
+        let fi = fonts.fonts.get(&gi.font_num).unwrap();
+
+        // The size of the font being used for this glyph, in rems; that is,
+        // relative to the main body font.
+        let rel_size = std::cmp::min(fi.size, max_size_tex) as f32 * rems_per_tex;
+        let fallback = fonts.fallback(fi.fd_key).map(|s| s.to_owned());
+        let fd = fonts.font_data.get_mut(&fi.fd_key).unwrap();
+        let mc = fd.lookup_mapping(gi.glyph);
+
+        if let Some(mc) = mc {
+            // Sometimes we need to render a glyph in one of our input fonts
+            // that isn't directly associated with a specific Unicode
+            // character. For instance, in math, we may need to draw a big
+            // integral sign, but the Unicode integral character maps to a
+            // small one. The way we handle this is by *creating new fonts*
+            // with custom character map tables that *do* map Unicode
+            // characters directly to the specific glyphs we want.
+
+            let (mut ch, need_alt) = match mc {
+                MapEntry::Direct(c) => (c, false),
+                MapEntry::SubSuperScript(c, _) => (c, true),
+                MapEntry::MathGrowingVariant(c, _, _) => (c, true),
+            };
+
+            let font_fam = if need_alt {
+                let map = fd.request_alternative(gi.glyph, ch);
+                ch = map.usv;
+
+                let variant = UsedVariant {
+                    fd_key: fi.fd_key,
+                    alternate_map_index: map.alternate_map_index,
+                };
+
+                if !used_variants.contains(&variant) {
+                    used_variants.push(variant);
+                }
+
+                css_family(
+                    fi.fd_key,
+                    Some(map.alternate_map_index),
+                    fallback.as_deref(),
+                )
+            } else {
+                css_family(fi.fd_key, None, fallback.as_deref())
+            };
+
+            // dy gives the target position of this glyph's baseline
+            // relative to the canvas's baseline. For our `position:
+            // absolute` layout, we have to convert that into the distance
+            // between the top of this glyph's box and the top of the
+            // overall canvas box (or bottom/bottom).
+            //
+            // In order to do this, we need to know the size of this glyph's
+            // box according to CSS, and the position of the glyph's
+            // baseline within that box.
+            //
+            // The baseline position is straightforward: it is given by what
+            // we call the font's "baseline factor". This is true no matter
+            // the specific size of the CSS box relative to the font
+            // rendering size, due to the way in which the drawn glyph is
+            // centered vertically within its CSS box.
+            //
+            // The CSS glyph box height can be funky: it depends on the
+            // font-size setting, font metrics (not just ascender/descender
+            // but "line gap") and `line-height` setting in "exciting" ways.
+            // One convenient approach is to set `line-height: 1` in the
+            // container, in which case the box height is the `font-size`
+            // setting.
+
+            let top_rem =
+                (-y_min_tex + gi.dy) as f32 * rems_per_tex - fd.baseline_factor() * rel_size;
+
+            let glyph = DrawnGlyph {
+                character: ch,
+                font_family: font_fam,
+                top: top_rem,
+                left: gi.dx as f32 * rems_per_tex,
+                size: rel_size,
+            };
+
+            let mut character = glyph.character.to_string();
+
+            if options.xhtml {
+                character = content::escape_text(&character).into_owned();
+            }
+
+            let color = gi
+                .color_rgba
+                .map(|c| format!("; color: #{:08x}", c))
+                .unwrap_or_default();
+
+            write!(
+                inner_content,
+                "<span class=\"{}\" style=\"top: {}rem; left: {}rem; font-size: {}rem; font-family: {}{}\">{}</span>",
+                GLYPH_CLASS,
+                glyph.top * scale,
+                glyph.left * scale,
+                glyph.size * scale,
+                tera::escape_html(&glyph.font_family),
+                color,
+                character
+            )
+            .unwrap();
+            drawn.push(glyph);
+        } else {
+            unmapped.push(*gi);
+        }
+    }
+
+    for ri in &rules {
+        let color = ri
+            .color_rgba
+            .map(|c| format!("; background-color: #{:08x}", c))
+            .unwrap_or_default();
+
+        write!(
+            inner_content,
+            "<span class=\"{}\" style=\"top: {}rem; left: {}rem; width: {}rem; height: {}rem{}\"></span>",
+            RULE_CLASS,
+            (ri.dy - ri.height - y_min_tex) as f32 * rems_per_tex * scale,
+            ri.dx as f32 * rems_per_tex * scale,
+            ri.width as f32 * rems_per_tex * scale,
+            ri.height as f32 * rems_per_tex * scale,
+            color
+        )
+        .unwrap();
+    }
+
+    let (element, layout_class, vertical_align) = if options.inline {
+        // A numerical vertical-align setting positions the bottom edge of
+        // this block relative to the containing line's baseline. This is
+        // the best (only?) way to make sure that this block's baseline
+        // lines up with that of its container.
+        (
+            "span",
+            CANVAS_INLINE_CLASS.to_owned(),
+            Some(-y_max_tex as f32 * rems_per_tex),
+        )
+    } else {
+        (
+            "div",
+            format!("{} {}", CANVAS_BLOCK_CLASS, content::AVOID_BREAK_CLASS),
+            None,
+        )
+    };
+
+    let size = CanvasSize {
+        width: (x_max_tex - x_min_tex) as f32 * rems_per_tex,
+        height: (y_max_tex - y_min_tex) as f32 * rems_per_tex,
+        padding_left: -x_min_tex as f32 * rems_per_tex,
+        vertical_align,
+    };
+
+    let mut html = String::default();
+
+    write!(
+        html,
+        "<{} class=\"{} {}",
+        element, CANVAS_CLASS, layout_class
+    )
+    .unwrap();
+
+    if let Some(classes) = options.classes.as_ref() {
+        write!(html, " {}", tera::escape_html(classes)).unwrap();
+    }
+
+    html.push('"');
+
+    if let Some(id) = options.id.as_ref() {
+        write!(html, " id=\"{}\"", tera::escape_html(id)).unwrap();
+    }
+
+    if let Some(alt) = options.alt.as_ref() {
+        write!(
+            html,
+            " role=\"img\" aria-label=\"{}\"",
+            tera::escape_html(alt)
+        )
+        .unwrap();
+    } else if options.hide_unlabeled {
+        html.push_str(" aria-hidden=\"true\"");
+    }
+
+    write!(
+        html,
+        " style=\"width: {}rem; height: {}rem; padding-left: {}rem",
+        size.width, size.height, size.padding_left,
+    )
+    .unwrap();
+
+    if options.containment {
+        if size.width > 0. && size.height > 0. {
+            write!(html, "; aspect-ratio: {} / {}", size.width, size.height).unwrap();
+        }
+
+        html.push_str("; contain: layout size");
+    }
+
+    if let Some(va) = size.vertical_align {
+        write!(html, "; vertical-align: {}rem", va).unwrap();
+    }
+
+    if let Some(style) = options.style.as_ref() {
+        write!(html, "; {}", tera::escape_html(style)).unwrap();
+    }
+
+    html.push_str("\">");
+
+    if scale != 1. {
+        write!(
+            html,
+            "<{} class=\"{}\" style=\"position: absolute; left: 0; top: 0; width: {}rem; height: {}rem; transform: scale({}); transform-origin: 0 0\">",
+            element,
+            CANVAS_SCALED_CLASS,
+            (size.width + size.padding_left) * scale,
+            size.height * scale,
+            1. / scale,
+        )
+        .unwrap();
+        html.push_str(&inner_content);
+        write!(html, "</{}>", element).unwrap();
+    } else {
+        html.push_str(&inner_content);
+    }
+
+    if let Some(alt) = options.alt.as_ref() {
+        write!(
+            html,
+            "<span class=\"{}\">{}</span>",
+            CANVAS_ALT_CLASS,
+            content::escape_text(alt)
+        )
+        .unwrap();
+    }
+
+    write!(html, "</{}>", element).unwrap();
+
+    Ok(RenderedCanvas {
+        html,
+        used_variants,
+        bounds: CanvasBounds {
+            x_min: x_min_tex,
+            x_max: x_max_tex,
+            y_min: y_min_tex,
+            y_max: y_max_tex,
+        },
+        size,
+        glyphs: drawn,
+        scale,
+        n_capped,
+        unmapped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, EngineState, LayoutDetail, Spx2HtmlEngine, Spx2HtmlError};
+    use tectonic_xdv::XdvEvents;
+
+    fn test_lookup() -> FontLookup {
+        let mut fonts = FontLookup::default();
+        fonts
+            .add_font(1, TEST_FONT, test_font_data(), 0, TEST_FONT_SIZE)
+            .unwrap();
+        fonts
+    }
+
+    #[test]
+    fn simple_canvas() {
+        let mut fonts = test_lookup();
+        let x = glyph_for('x');
+        let glyphs = [
+            PositionedGlyph {
+                font_num: 1,
+                glyph: x,
+                dx: 0,
+                dy: 0,
+                color_rgba: None,
+            },
+            PositionedGlyph {
+                font_num: 1,
+                glyph: x,
+                dx: TEST_FONT_SIZE,
+                dy: 0,
+                color_rgba: None,
+            },
+        ];
+
+        let mut options = CanvasOptions::new(1. / TEST_FONT_SIZE as f32);
+        options.inline(true);
+        let rc = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+
+        assert!(rc.html.starts_with("<span class=\"canvas canvas-inline\""));
+        assert_eq!(rc.html.matches("<span class=\"ci\"").count(), 2);
+        assert_eq!(rc.html.matches(">x</span>").count(), 2);
+        assert!(rc.used_variants.is_empty());
+        assert!(rc.unmapped.is_empty());
+        assert!(rc.bounds.x_max > TEST_FONT_SIZE);
+        assert!(rc.bounds.y_min < 0);
+    }
+
+    #[test]
+    fn undeclared_font() {
+        let mut fonts = test_lookup();
+        let glyphs = [PositionedGlyph {
+            font_num: 2,
+            glyph: 1,
+            dx: 0,
+            dy: 0,
+            color_rgba: None,
+        }];
+
+        let options = CanvasOptions::new(1.);
+        assert!(render_canvas(&mut fonts, &glyphs, &options).is_err());
+    }
+
+    #[test]
+    fn fallbacks() {
+        let mut fonts = test_lookup();
+        fonts.set_fallback("*", "serif");
+        fonts.set_fallback(TEST_FONT, "\"Noto Serif\", serif");
+
+        let glyphs = [PositionedGlyph {
+            font_num: 1,
+            glyph: glyph_for('x'),
+            dx: 0,
+            dy: 0,
+            color_rgba: None,
+        }];
+
+        let options = CanvasOptions::new(1. / TEST_FONT_SIZE as f32);
+        let rc = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+        assert!(rc
+            .html
+            .contains("font-family: tdux0, &quot;Noto Serif&quot;, serif\">x</span>"));
+        assert_eq!(fonts.css_family(0), "tdux0, \"Noto Serif\", serif");
+    }
+
+    #[test]
+    fn layout_data() {
+        let mut fonts = test_lookup();
+        let glyphs = [PositionedGlyph {
+            font_num: 1,
+            glyph: glyph_for('x'),
+            dx: TEST_FONT_SIZE,
+            dy: 0,
+            color_rgba: None,
+        }];
+
+        let mut options = CanvasOptions::new(1. / TEST_FONT_SIZE as f32);
+        options.inline(true).id(Some("c<1>"));
+        let rc = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+
+        assert!(rc
+            .html
+            .starts_with("<span class=\"canvas canvas-inline\" id=\"c&lt;1&gt;\" style=\"width: "));
+        assert!(rc.size.vertical_align.unwrap() < 0.);
+        assert!(rc.html.contains(&format!(
+            "; vertical-align: {}rem\">",
+            rc.size.vertical_align.unwrap()
+        )));
+
+        assert_eq!(rc.glyphs.len(), 1);
+        let g = &rc.glyphs[0];
+        assert_eq!(g.character, 'x');
+        assert_eq!(g.left, 1.);
+        assert_eq!(g.size, 1.);
+        assert!(rc.html.contains(&format!(
+            "top: {}rem; left: 1rem; font-size: 1rem; font-family: {}\">x</span>",
+            g.top, g.font_family
+        )));
+    }
+
+    #[test]
+    fn extreme_sizes() {
+        let x = glyph_for('x');
+        let mut fonts = test_lookup();
+        fonts
+            .add_font(2, TEST_FONT, test_font_data(), 0, 2 * 65536)
+            .unwrap();
+        fonts
+            .add_font(3, TEST_FONT, test_font_data(), 0, 200 * 65536)
+            .unwrap();
+
+        let glyphs = |font_num| {
+            [
+                PositionedGlyph {
+                    font_num,
+                    glyph: x,
+                    dx: 0,
+                    dy: 0,
+                    color_rgba: None,
+                },
+                PositionedGlyph {
+                    font_num: 1,
+                    glyph: x,
+                    dx: TEST_FONT_SIZE,
+                    dy: 0,
+                    color_rgba: None,
+                },
+            ]
+        };
+
+        let mut options = CanvasOptions::new(1. / TEST_FONT_SIZE as f32);
+        options.size_limits(GlyphSizeLimits { min: 0.5, max: 10. });
+
+        // At 2pt, the glyphs are drawn three times larger and scaled down,
+        // while the reported layout stays the same.
+        let plain = render_canvas(&mut fonts, &glyphs(1), &options).unwrap();
+        assert_eq!(plain.scale, 1.);
+        assert!(!plain.html.contains("canvas-scaled"));
+
+        let small = render_canvas(&mut fonts, &glyphs(2), &options).unwrap();
+        assert!((small.scale - 3.).abs() < 1e-3);
+        assert_eq!(small.n_capped, 0);
+        assert!(small.html.contains("transform: scale(0.333"));
+        assert_eq!(small.glyphs[1].size, 1.);
+        assert!(small.html.contains(&format!(
+            "left: {}rem; font-size: {}rem",
+            small.scale, small.scale
+        )));
+        assert!(small.glyphs[0].size < 0.5);
+
+        for rc in [&plain, &small] {
+            for g in &rc.glyphs {
+                assert!(g.top >= -1e-3 && g.top + g.size <= rc.size.height + 1e-3);
+            }
+        }
+
+        // At 200pt, the glyph is reduced to the maximum size, and the box
+        // follows suit.
+        let big = render_canvas(&mut fonts, &glyphs(3), &options).unwrap();
+        assert_eq!(big.scale, 1.);
+        assert_eq!(big.n_capped, 1);
+        assert_eq!(big.glyphs[0].size, 10.);
+        assert!(big.html.contains("font-size: 10rem"));
+
+        options.size_limits(GlyphSizeLimits {
+            min: 0.5,
+            max: 100.,
+        });
+        let uncapped = render_canvas(&mut fonts, &glyphs(3), &options).unwrap();
+        assert_eq!(uncapped.n_capped, 0);
+        assert!((big.size.height / uncapped.size.height - 0.6).abs() < 1e-3);
+
+        for g in &big.glyphs {
+            assert!(g.top >= -1e-3 && g.top + g.size <= big.size.height + 1e-3);
+        }
+    }
+
+    #[test]
+    fn containment() {
+        let mut fonts = test_lookup();
+        let glyphs = [PositionedGlyph {
+            font_num: 1,
+            glyph: glyph_for('x'),
+            dx: 0,
+            dy: 0,
+            color_rgba: None,
+        }];
+
+        let mut options = CanvasOptions::new(1. / TEST_FONT_SIZE as f32);
+        let rc = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+        assert!(rc.size.width > 0. && rc.size.height > 0.);
+        assert!(rc.html.contains(&format!(
+            "; aspect-ratio: {} / {}; contain: layout size\">",
+            rc.size.width, rc.size.height
+        )));
+
+        options.inline(true);
+        let rc = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+        assert!(rc.html.contains("; contain: layout size; vertical-align: "));
+
+        options.containment(false);
+        let rc = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+        assert!(!rc.html.contains("aspect-ratio"));
+        assert!(!rc.html.contains("contain:"));
+    }
+
+    #[test]
+    fn rules() {
+        let mut fonts = test_lookup();
+        let x = glyph_for('x');
+        let glyphs = [
+            PositionedGlyph {
+                font_num: 1,
+                glyph: x,
+                dx: 0,
+                dy: -TEST_FONT_SIZE,
+                color_rgba: None,
+            },
+            PositionedGlyph {
+                font_num: 1,
+                glyph: x,
+                dx: 0,
+                dy: TEST_FONT_SIZE,
+                color_rgba: None,
+            },
+        ];
+        let bar = PositionedRule {
+            dx: -TEST_FONT_SIZE / 4,
+            dy: 0,
+            width: 2 * TEST_FONT_SIZE,
+            height: TEST_FONT_SIZE / 24,
+            color_rgba: None,
+        };
+        let empty = PositionedRule {
+            width: 0,
+            color_rgba: Some(0xff0000ff),
+            ..bar
+        };
+
+        let rems_per_tex = 1. / TEST_FONT_SIZE as f32;
+        let options = CanvasOptions::new(rems_per_tex);
+        let without = render_canvas(&mut fonts, &glyphs, &options).unwrap();
+        let rc = render_canvas_with_rules(&mut fonts, &glyphs, &[bar, empty], &options).unwrap();
+
+        // The bar is wider than the glyphs.
+        assert_eq!(rc.bounds.x_min, -TEST_FONT_SIZE / 4);
+        assert_eq!(rc.bounds.x_max, 7 * TEST_FONT_SIZE / 4);
+        assert_eq!(rc.bounds.y_min, without.bounds.y_min);
+        assert_eq!(rc.size.padding_left, 0.25);
+
+        let top = (-TEST_FONT_SIZE / 24 - rc.bounds.y_min) as f32 * rems_per_tex;
+        assert!(rc.html.ends_with(&format!(
+            "<span class=\"cr\" style=\"top: {}rem; left: -0.25rem; width: 2rem; height: {}rem\"></span></div>",
+            top,
+            (TEST_FONT_SIZE / 24) as f32 * rems_per_tex
+        )));
+        assert_eq!(rc.html.matches("class=\"cr\"").count(), 1);
+
+        // Rules alone make a canvas, too.
+        let rc = render_canvas_with_rules(&mut fonts, &[], &[bar], &options).unwrap();
+        assert_eq!(rc.size.width, 2.);
+        assert_eq!(rc.bounds.y_min, -TEST_FONT_SIZE / 24);
+        assert_eq!(rc.bounds.y_max, 0);
+    }
+
+    #[test]
+    fn required_rules() {
+        assert_eq!(
+            required_css(),
+            ".canvas { position: relative; line-height: 1; white-space: nowrap; }
+.canvas-inline { display: inline-block; }
+.canvas-block { display: block; }
+.ci { position: absolute; white-space: pre; }
+.cr { position: absolute; background-color: currentColor; }
+.canvas-alt { position: absolute; width: 1px; height: 1px; overflow: hidden; clip: rect(0 0 0 0); }
+"
+        );
+    }
+
+    #[test]
+    fn font_fallbacks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("family.txt", "{{ tduxMainBodyFontFamily }}");
+
+        // The font variables are only available after the content is
+        // finished.
+        fn events(s: &mut EngineState) -> Result<()> {
+            simple_events(s)?;
+            s.special("tdux:setTemplate family.txt")?;
+            s.special("tdux:setOutputPath family.txt")?;
+            s.special("tdux:emit")
+        }
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .font_fallback("body", "serif")
+            .bundled_fallback_font(false);
+        let (result, status) = run_engine_with(&mut engine, io.clone(), tempdir.path(), |s| {
+            s.special("tdux:setFontFallback body")?;
+            s.special("tdux:setFontFallback * x; color: red")?;
+            events(s)
+        });
+        result.unwrap();
+        assert_eq!(status.warnings.len(), 2, "{:?}", status.warnings);
+
+        let css = std::fs::read_to_string(tempdir.path().join("family.txt")).unwrap();
+        assert_eq!(css, "tdux0, serif");
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special(&format!(
+                "tdux:setFontFallback {}.otf \"Noto Serif\", serif",
+                TEST_FONT
+            ))?;
+            events(s)
+        });
+        result.unwrap();
+
+        let css = std::fs::read_to_string(tempdir.path().join("family.txt")).unwrap();
+        assert_eq!(css, "tdux0, \"Noto Serif\", serif");
+    }
+
+    #[test]
+    fn layout_sidecars() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut engine = Spx2HtmlEngine::default();
+        engine.layout_sidecars(Some(LayoutDetail::Glyphs));
+        let x = glyph_for('x');
+
+        let (result, _status) = run_engine_with(&mut engine, simple_io(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+
+            for (kind, x0) in [("math", 1000), ("dmath", 2000)] {
+                s.handle_special(x0, 500, format!("tdux:cs {}", kind).as_bytes())?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[x0], &[500])?;
+                s.special(&format!("tdux:ce {}", kind))?;
+            }
+
+            s.special("tdux:emit")?;
+            s.text("b")?;
+            s.handle_special(0, 0, b"tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(html.contains("id=\"tdux-canvas-0\""));
+        assert!(html.contains("id=\"tdux-canvas-1\""));
+
+        let json = std::fs::read_to_string(tempdir.path().join("index.html.layout.json")).unwrap();
+        let layout: tera::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(layout["page"], "index.html");
+        let canvases = layout["canvases"].as_array().unwrap();
+        assert_eq!(canvases.len(), 2);
+        assert_eq!(canvases[0]["id"], "tdux-canvas-0");
+        assert_eq!(canvases[0]["kind"], "math");
+        assert_eq!(canvases[0]["x"], 1000);
+        assert_eq!(canvases[0]["y"], 500);
+        assert!(canvases[0]["vertical_align"].is_number());
+        assert_eq!(canvases[0]["glyphs"][0]["character"], "x");
+        assert_eq!(canvases[1]["kind"], "dmath");
+        assert!(canvases[1]["vertical_align"].is_null());
+
+        // The field names are part of the sidecar format.
+        let mut keys: Vec<_> = canvases[0].as_object().unwrap().keys().collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "glyphs",
+                "height",
+                "id",
+                "kind",
+                "padding_left",
+                "vertical_align",
+                "width",
+                "x",
+                "y"
+            ]
+        );
+        let mut keys: Vec<_> = canvases[0]["glyphs"][0]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["character", "font_family", "left", "size", "top"]);
+
+        // Ids count from zero on each page.
+        let json = std::fs::read_to_string(tempdir.path().join("two.html.layout.json")).unwrap();
+        assert!(json.contains("\"tdux-canvas-0\""));
+    }
+
+    #[test]
+    fn extreme_glyph_sizes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut engine = Spx2HtmlEngine::default();
+        engine.glyph_size_limits(0.5, 10.);
+        let x = glyph_for('x');
+
+        let (result, status) = run_engine_with(&mut engine, simple_io(), tempdir.path(), |s| {
+            s.define_test_font(2, 2 * 65536)?;
+            s.define_test_font(3, 200 * 65536)?;
+            // The last font defined is taken to be the main body font.
+            s.define_test_font(4, TEST_FONT_SIZE)?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+
+            for font_num in [2, 3] {
+                s.special("tdux:cs dmath")?;
+                s.handle_text_and_glyphs(font_num, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce dmath")?;
+            }
+
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.notes.len(), 1, "{:?}", status.notes);
+        assert!(status.notes[0].contains("smaller than 0.5rem"));
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert!(
+            status.warnings[0].contains("1 glyph(s) in a `dmath` canvas were larger than 10rem")
+        );
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html.matches("class=\"canvas-scaled\"").count(), 1);
+        assert!(html.contains("font-size: 10rem"));
+    }
+
+    #[test]
+    fn required_css_delivery() {
+        let x = glyph_for('x');
+        let mut io = simple_io();
+        io.add(
+            "styled.html",
+            "<style>{{ tduxRequiredCss }}</style>{{ tduxContent }}",
+        );
+        io.add(
+            "linked.html",
+            "<link rel=\"stylesheet\" href=\"{{ tduxRelTop }}tdux-base.css\">{{ tduxContent }}",
+        );
+
+        let events = |template: &'static str| {
+            move |s: &mut EngineState| {
+                s.special(&format!("tdux:addTemplate {}", template))?;
+                s.special(&format!("tdux:setTemplate {}", template))?;
+                s.text("a")?;
+                s.handle_special(0, 0, b"tdux:cs math")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce math")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            }
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.strict(true).base_css_file(true);
+
+        let (files, _status) = run_engine_in_memory(&mut engine, io.clone(), events("styled.html"));
+        let files = files.unwrap();
+        let css = required_css();
+        assert_eq!(files["tdux-base.css"], css.as_bytes());
+        let page = std::str::from_utf8(&files["index.html"]).unwrap();
+        assert!(page.starts_with(&format!("<style>{}</style>", css)));
+        assert!(page.contains("<span class=\"canvas canvas-inline\""));
+
+        let (files, _status) = run_engine_in_memory(&mut engine, io.clone(), events("linked.html"));
+        files.unwrap();
+
+        // In strict mode, pages with canvases must include the rules.
+        let (files, _status) = run_engine_in_memory(&mut engine, io, events("page.html"));
+        assert!(matches!(
+            Spx2HtmlError::from_error(&files.unwrap_err()),
+            Some(Spx2HtmlError::MissingRequiredCss { page, .. }) if page == "index.html"
+        ));
+    }
+
+    #[test]
+    fn body_font_size() {
+        let mut io = simple_io();
+        io.add("second.otf", test_font_data());
+
+        // TeX may define the fonts in either order, and the last one is taken
+        // to be the main body font unless the size is set explicitly.
+        let run = |engine: &mut Spx2HtmlEngine, fonts: [(&'static str, i32, i32); 2], pin: bool| {
+            let x = glyph_for('x');
+            let (files, status) = run_engine_in_memory(engine, io.clone(), move |s| {
+                for (name, num, size) in fonts {
+                    s.handle_define_native_font(name, num, size, 0, None, None, None, None)?;
+                }
+
+                if pin {
+                    s.special(&format!("tdux:setBodyFontSize {}", 10 * 65536))?;
+                }
+
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("a")?;
+                s.handle_special(0, 0, b"tdux:cs math")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce math")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+            let files = files.unwrap();
+            let layout: tera::Value =
+                serde_json::from_slice(&files["index.html.layout.json"]).unwrap();
+            (layout["canvases"][0].clone(), status)
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.layout_sidecars(Some(LayoutDetail::Canvases));
+        let body_last = [("second.otf", 2, 8 * 65536), (TEST_FONT, 3, 10 * 65536)];
+        let body_first = [(TEST_FONT, 3, 10 * 65536), ("second.otf", 2, 8 * 65536)];
+
+        let (unpinned_last, _status) = run(&mut engine, body_last, false);
+        assert_eq!(engine.body_font_size_used(), Some(10 * 65536));
+        let (unpinned_first, _status) = run(&mut engine, body_first, false);
+        assert_eq!(engine.body_font_size_used(), Some(8 * 65536));
+        assert_ne!(unpinned_first["width"], unpinned_last["width"]);
+
+        // The special pins the size.
+        let (pinned, status) = run(&mut engine, body_first, true);
+        assert_eq!(engine.body_font_size_used(), Some(10 * 65536));
+        assert_eq!(pinned, unpinned_last);
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        // So does the engine option, though the special takes precedence.
+        engine.body_font_size(Some(10 * 65536));
+        let (pinned, _status) = run(&mut engine, body_first, false);
+        assert_eq!(pinned, unpinned_last);
+        engine.body_font_size(Some(8 * 65536));
+        let (pinned, _status) = run(&mut engine, body_first, true);
+        assert_eq!(pinned, unpinned_last);
+        assert_eq!(engine.body_font_size_used(), Some(10 * 65536));
+
+        // It can't change once content has been emitted.
+        let (result, status) = run_engine_in_memory(&mut engine, io.clone(), |s| {
+            s.special("tdux:setBodyFontSize 1ex")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:setBodyFontSize 655360")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+        assert_eq!(status.warnings.len(), 2, "{:?}", status.warnings);
+        assert!(status.warnings[0].contains("malformatted tdux:setBodyFontSize special `1ex`"));
+        assert!(status.warnings[1].contains("can't change once content has been emitted"));
+    }
+
+    #[test]
+    fn images() {
+        const SVG: &str = "<svg xmlns=\"http://www.w3.org/2000/svg\"><!-- as is --></svg>";
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        let mut io = simple_io();
+        io.add("plot.svg", SVG);
+        io.add("photo.png", PNG);
+        io.add("my photo.png", PNG);
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special(&format!("tdux:img plot.svg img/plot.svg {} 0", 12 * 65536))?;
+            s.special("tdux:img photo.png ./img/photo.png 393216 786432 \"A & B\"")?;
+            s.special("tdux:img photo.png img/photo.png wide 10")?;
+            s.special("tdux:setOutputPath ch/one/index.html")?;
+            s.special("tdux:emit")?;
+            s.text("b")?;
+            s.special("tdux:img photo.png img/photo.png 0 0")?;
+            s.special(
+                "tdux:img \"my photo.png\" \"img/my photo.png\" 0 0 \"A \\\"quoted\\\" photo\"",
+            )?;
+            s.special("tdux:img photo.png img/photo.png 0 0 \"unterminated")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec![
+                "ignoring malformatted tdux:img special `photo.png img/photo.png wide 10`: \
+                 its size `wide` isn't a non-negative integer",
+                "ignoring malformatted tdux:img special \
+                 `photo.png img/photo.png 0 0 \"unterminated`: \
+                 the quoted argument `\"unterminated` isn't terminated",
+            ]
+        );
+
+        // The image files are copied as they are.
+        assert_eq!(files["img/plot.svg"], SVG.as_bytes());
+        assert_eq!(files["img/photo.png"], PNG);
+        assert_eq!(files["img/my photo.png"], PNG);
+
+        assert_eq!(
+            String::from_utf8_lossy(&files["ch/one/index.html"]),
+            "<html><body>a\
+             <img src=\"../../img&#x2F;plot.svg\" alt=\"\" style=\"width: 1rem; height: 0rem\">\
+             <img src=\"../../img&#x2F;photo.png\" alt=\"A &amp; B\" style=\"width: 0.5rem; height: 1rem\">\
+             </body></html>"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&files["index.html"]),
+            "<html><body>b\
+             <img src=\"img&#x2F;photo.png\" alt=\"\" style=\"width: 0rem; height: 0rem\">\
+             <img src=\"img&#x2F;my photo.png\" alt=\"A &quot;quoted&quot; photo\" \
+             style=\"width: 0rem; height: 0rem\">\
+             </body></html>"
+        );
+    }
+
+    #[test]
+    fn fraction_bars() {
+        let x = glyph_for('x');
+        let pt = TEST_FONT_SIZE / 12;
+        let (files, status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Half:")?;
+                // Rules outside of canvases are ignored.
+                s.handle_rule(0, 0, pt, 10 * pt)?;
+                s.special("tdux:cs dmath")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[-8 * pt])?;
+                s.special("color push rgb 1 0 0")?;
+                s.handle_rule(-pt, -3 * pt, pt / 2, 8 * pt)?;
+                s.special("color pop")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[8 * pt])?;
+                s.special("tdux:ce dmath")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+        let files = files.unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        let html = String::from_utf8_lossy(&files["index.html"]);
+        assert_eq!(html.matches("class=\"cr\"").count(), 1, "{}", html);
+        assert!(
+            html.contains("width: 0.6666667rem; height: 0.041666668rem; background-color: #ff0000ff\"></span>"),
+            "{}",
+            html
+        );
+
+        // The bar is drawn between the numerator and the denominator.
+        let tops: Vec<f32> = html
+            .split("top: ")
+            .skip(1)
+            .map(|s| s.split("rem").next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(tops.len(), 3);
+        assert!(tops[0] < tops[2] && tops[2] < tops[1], "{:?}", tops);
+    }
+
+    #[test]
+    fn nested_canvases() {
+        let x = glyph_for('x');
+        let pt = TEST_FONT_SIZE / 12;
+
+        let run = |nested: bool| {
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Equation:")?;
+                s.handle_special(10 * pt, 20 * pt, b"tdux:cs dmath")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[11 * pt], &[20 * pt])?;
+
+                if nested {
+                    s.handle_special(14 * pt, 26 * pt, b"tdux:cs math")?;
+                }
+
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[15 * pt], &[24 * pt])?;
+                s.handle_rule(14 * pt, 25 * pt, pt / 2, 3 * pt)?;
+
+                if nested {
+                    s.special("tdux:ce math")?;
+                }
+
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[20 * pt], &[20 * pt])?;
+                s.special("tdux:ce dmath")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            })
+        };
+
+        let (flat, _status) = run(false);
+        let (nested, status) = run(true);
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+        assert_eq!(
+            String::from_utf8_lossy(&nested.unwrap()["index.html"]),
+            String::from_utf8_lossy(&flat.unwrap()["index.html"])
+        );
+
+        // The kind of canvas being ended is checked.
+        let (files, status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Equation:")?;
+                s.special("tdux:cs dmath")?;
+                s.special("tdux:cs math")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce dmath")?;
+                s.special("tdux:ce dmath")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+        assert_eq!(
+            status.warnings,
+            ["tdux:c[anvas]e[nd] special `tdux:ce dmath` ends a canvas of kind `math`"]
+        );
+        let html = String::from_utf8_lossy(&files.unwrap()["index.html"]).into_owned();
+        assert_eq!(html.matches("class=\"canvas ").count(), 1);
+        assert_eq!(html.matches("class=\"ci\"").count(), 1);
+    }
+
+    #[test]
+    fn canvas_alt() {
+        let x = glyph_for('x');
+
+        let run = |engine: &mut Spx2HtmlEngine| {
+            let (files, status) = run_engine_in_memory(engine, simple_io(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("a")?;
+                s.special("tdux:canvasAlt the \"x\" & <y>")?;
+                s.special("tdux:cs math")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce math")?;
+                s.special("tdux:cs dmath")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce dmath")?;
+                s.special("tdux:canvasAlt x")?;
+                s.special("tdux:cs mathml-math")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+                s.special("tdux:ce mathml-math")?;
+                s.special("tdux:canvasAlt orphan")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+            (
+                String::from_utf8(files.unwrap()["index.html"].clone()).unwrap(),
+                status,
+            )
+        };
+
+        let (html, status) = run(&mut Spx2HtmlEngine::default());
+        assert!(
+            html.contains(
+                "<span class=\"canvas canvas-inline\" role=\"img\" \
+                 aria-label=\"the &quot;x&quot; &amp; &lt;y&gt;\" style=\""
+            ),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<span class=\"canvas-alt\">the \"x\" &amp; &lt;y&gt;</span></span>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<div class=\"canvas canvas-block"),
+            "{}",
+            html
+        );
+        assert!(html.contains("alttext=\"x\""), "{}", html);
+        assert_eq!(html.matches("aria-label").count(), 1);
+        assert!(!html.contains("aria-hidden"), "{}", html);
+        assert_eq!(
+            status.warnings,
+            ["dropping text alternative `orphan` of tdux:canvasAlt, which no canvas took"]
+        );
+
+        // Optionally, the canvas without a text alternative is hidden.
+        let (html, _) = run(Spx2HtmlEngine::default().hide_unlabeled_canvases(true));
+        assert_eq!(html.matches("aria-hidden=\"true\"").count(), 1);
+        assert!(
+            html.contains(
+                "<div class=\"canvas canvas-block tdux-avoid-break\" aria-hidden=\"true\" style="
+            ),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn last_resort_font() {
+        const SNOWMAN: char = '\u{2603}';
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = TestIo::new();
+        io.add("page.html", "{{ tduxFontFaces | safe }}|{{ tduxContent }}");
+        io.add(
+            "report.html",
+            "{{ tduxMainBodyFontFamily }}|\
+             {% for c in tduxUncoveredChars %}{{ c.codepoint }} {{ c.rescued }};{% endfor %}",
+        );
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .uncovered_chars_variable(true)
+            .last_resort_font(Some(("fallback.otf", remapped_test_font('x', SNOWMAN))));
+
+        let (result, status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:emit")?;
+            assert!(!tempdir.path().join("fallback.otf").exists());
+
+            s.special("tdux:setOutputPath two.html")?;
+            s.text(&format!("b{}\u{1F600}", SNOWMAN))?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:setTemplate report.html")?;
+            s.special("tdux:setOutputPath report.html")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        // The font is only emitted once it's needed.
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(!html.contains("tdux-fallback"));
+        let html = std::fs::read_to_string(tempdir.path().join("two.html")).unwrap();
+        assert!(html.contains("font-family: \"tdux-fallback\";\n  src: url(\"fallback.otf\")"));
+        assert!(tempdir.path().join("fallback.otf").exists());
+
+        let html = std::fs::read_to_string(tempdir.path().join("report.html")).unwrap();
+        assert_eq!(html, "tdux0, tdux-fallback|U+2603 true;U+1F600 false;");
+    }
+}
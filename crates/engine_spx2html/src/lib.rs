@@ -4,1025 +4,6121 @@
 #![deny(missing_docs)]
 
 //! Convert Tectonic’s SPX format to HTML.
-
-use percent_encoding::{utf8_percent_encode, CONTROLS};
+//!
+//! ## Concurrency
+//!
+//! Multiple engine runs may safely target the same output directory at the
+//! same time. Every output file — HTML pages, copied assets, and fonts,
+//! including the variant fonts with customized character maps — is fully
+//! assembled in memory and then written through a uniquely-named temporary
+//! file that is atomically renamed into place. Readers therefore never observe
+//! partially-written files, and concurrent writers never interleave their
+//! data. Variant font files are named after a digest of their contents, as
+//! `vg{digest}-{font file}`, rather than after their CSS font families, such
+//! as `tdux3vg0`: the families depend on the order in which a run comes
+//! across fonts and glyphs, so two related documents sharing an output
+//! directory could give the same family name to different variants, while a
+//! digest names the same variant the same way in every run, and different
+//! variants differently. When two runs write the same path with different
+//! contents (e.g. two documents both writing `index.html`), the last writer
+//! wins. No temporary directories are used.
+//!
+//! ## Output order
+//!
+//! Output files are written as soon as possible, so that a server previewing
+//! the output directory can start serving pages while the conversion is still
+//! running. In particular, the file of each font is written just before the
+//! first page that uses it, and the template variable `tduxFontFaces` holds the
+//! `@font-face` rules of the fonts written so far. The variant fonts with
+//! customized character maps can only be written once the whole document has
+//! been seen, at `tdux:contentFinished`; their rules use `font-display: swap`
+//! so that pages displayed in the meantime don't wait on them. Likewise, a page
+//! that links to an anchor further on in the document is held back until
+//! `tdux:contentFinished`, when all of the anchors are known.
+//!
+//! ## Tables of contents
+//!
+//! `tdux:tocEntry <level> <anchor> <title>` adds an entry to a table of
+//! contents that covers the whole document, which every output file gets as
+//! the template variable `tduxToc`: a list of objects with an integer
+//! `level`, the `title` text, and the `url` of the anchor, relative to the
+//! file being rendered, so that templates can nest the entries as they like.
+//! Since the entries that have been given so far are the ones that a file
+//! gets, they should be given up front, before the first file is emitted,
+//! like LaTeX typesets its table of contents from the `.toc` file of the
+//! previous run. Their anchors may be in any output file, though: like other
+//! links, an entry whose anchor hasn't been emitted yet makes the file hold
+//! back until `tdux:contentFinished`, when its URL is resolved.
+//!
+//! ## Footnotes
+//!
+//! `tdux:footnoteStart <mark>` ... `tdux:footnoteEnd` collect the content in
+//! between, which may include canvases, as a footnote of the output file.
+//! The footnote's call is left in its place: the mark, superscripted, as a
+//! link to `#fn-N`, with the id `fnref-N`, where N counts the footnotes of
+//! the file from 1. Templates get the footnotes as `tduxFootnotes`, a list
+//! of objects with the `mark`, the `html` of the content, the `id` that the
+//! footnote should have, and the `ref_id` of its call, for a link back.
+//!
+//! ## Format versions
+//!
+//! The vocabulary of specials is versioned. A document should say which
+//! version it was written for with `tdux:formatVersion <n>`, before its
+//! content; processing it fails if that's newer than [`FORMAT_VERSION`], the
+//! version that the engine supports. Once a document has declared its
+//! version, the first use of each unknown `tdux:` special draws a warning,
+//! rather than being ignored silently, since it's probably a typo.
+//!
+//! Specials that have been renamed keep working under their old names. The
+//! old names draw a warning in documents that don't declare their version,
+//! nothing in documents declaring a version from before the renaming, and
+//! an error in the others. Version 2 renamed `tdux:directBegin` to
+//! `tdux:directStart` and `tdux:fontFallback` to `tdux:setFontFallback`.
+//!
+//! ## Memory use
+//!
+//! Each output file is rendered as soon as it's emitted, and its content is
+//! released once it has been written, so that the content of only one file
+//! is in memory at a time. What's kept for the whole run is the font data,
+//! the outline of the document's headings (which templates get in full for
+//! every file, as `tduxOutline`), and some bookkeeping per output file.
+//! Documents that are very large should therefore be split into many output
+//! files, with `tdux:emit`, rather than be emitted as one. With a render time
+//! limit, see [`Spx2HtmlEngine::render_time_limit`], the template variables
+//! are copied for the rendering thread, which doubles the memory needed for
+//! the content of each file while it is rendered.
+//!
+//! The exception is the output files that link to anchors that haven't been
+//! emitted yet, which are held back until the content is finished. If many
+//! links point forward, the low-memory mode, see
+//! [`Spx2HtmlEngine::spill_threshold`], bounds the memory that they take by
+//! spilling them to temporary files. The statistics of each run record the
+//! peak number of bytes of rendered files in memory, and how many were
+//! spilled.
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, CONTROLS};
+use serde::Serialize;
 use std::{
-    collections::HashMap,
-    fmt::Write as FmtWrite,
-    fs::File,
-    io::{Read, Write},
+    borrow::Cow,
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    io::{Read, Seek, SeekFrom, Write},
+    ops::{BitOr, BitOrAssign},
     path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use tectonic_bridge_core::DriverHooks;
 use tectonic_errors::prelude::*;
-use tectonic_io_base::OpenResult;
-use tectonic_status_base::{tt_warning, StatusBackend};
+use tectonic_io_base::{
+    digest::{self, Digest, DigestData},
+    InputFeatures, InputHandle, InputOrigin, OpenResult,
+};
+use tectonic_status_base::{tt_note, tt_warning, StatusBackend};
 use tectonic_xdv::{FileType, XdvEvents, XdvParser};
 
-use crate::font::{FontData, MapEntry};
+use crate::{
+    assets::{Assets, CopySource, FontPins},
+    color::ColorStack,
+    compat::{Compat, Deprecation},
+    content::{CellAlign, ContentSink, Destination, HeadingInfo, TextStyle},
+    coverage::CharTally,
+    deps::DepTracker,
+    handlers::{SpecialContext, SpecialHandler},
+    html::{
+        CanvasOptions, CanvasSize, DrawnGlyph, FontLookup, GlyphSizeLimits, PositionedGlyph,
+        PositionedRule,
+    },
+    outpath::OutputPathPattern,
+    output::{OutputPolicy, OutputTree},
+    overlay::CollectingStatus,
+    pdf::LinkTarget,
+    plan::PlanRecorder,
+    progress::{Phase, PositionTrackingReader, ProgressSink, TemplateInfo},
+    remote::{RemoteCache, RemoteFetcher, DEFAULT_MAX_REMOTE_SIZE},
+    render::RenderLimits,
+    spill::SpillArea,
+    stats::{ProcessingStats, RenderStats},
+    usage::UsageTally,
+    variables::VariableScopes,
+    xref::{CrossRefs, HeldPage},
+};
 
+mod assets;
+mod color;
+mod compat;
+mod content;
+pub mod coverage;
+pub mod deps;
+mod error;
+#[cfg(feature = "fallback-font")]
+mod fallback;
 mod font;
+pub mod handlers;
+pub mod html;
+pub mod mathml;
+mod metrics;
+mod outpath;
+mod output;
+mod overlay;
+mod pdf;
+pub mod plan;
+pub mod progress;
+pub mod remote;
+mod render;
+mod specials;
+mod spill;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
+pub mod usage;
+mod variables;
+mod xref;
+
+pub use crate::{
+    assets::{AssetManifest, AssetOrigin, FontFace, ManifestEntry, MANIFEST_VERSION},
+    compat::FORMAT_VERSION,
+    error::Spx2HtmlError,
+    output::{EmittedFile, EmittedOutputs, OutputCategory, OutputSink, PostProcessor, ZipSink},
+};
 
 /// An engine that converts SPX to HTML.
 #[derive(Default)]
-pub struct Spx2HtmlEngine {}
-
-impl Spx2HtmlEngine {
-    /// Process SPX into HTML.
-    ///
-    /// Because this driver will, in the generic case, produce a tree of HTML
-    /// output files that are not going to be used as a basis for any subsequent
-    /// engine stages, it outputs directly to disk (via `out_base`) rather than
-    /// using the I/O layer. I don't like hardcoding use of the filesystem, but
-    /// I don't want to build up some extra abstraction layer right now.
-    pub fn process_to_filesystem(
-        &mut self,
-        hooks: &mut dyn DriverHooks,
-        status: &mut dyn StatusBackend,
-        spx: &str,
-        out_base: &Path,
-    ) -> Result<()> {
-        let mut input = hooks.io().input_open_name(spx, status).must_exist()?;
-
-        {
-            let state = EngineState::new(hooks, status, out_base);
-            let state = XdvParser::process_with_seeks(&mut input, state)?;
-            state.finished()?;
-        }
-
-        let (name, digest_opt) = input.into_name_digest();
-        hooks.event_input_closed(name, digest_opt, status);
-        Ok(())
-    }
-}
-
-struct EngineState<'a> {
-    common: Common<'a>,
-    state: State,
+pub struct Spx2HtmlEngine {
+    options: Options,
+    progress: Option<Box<dyn ProgressSink>>,
+    remote_fetcher: Option<Box<dyn RemoteFetcher>>,
+    post_processor: Option<Box<dyn PostProcessor>>,
+    special_handlers: Vec<(String, Box<dyn SpecialHandler>)>,
+
+    /// The size of the main body font used by the last run.
+    body_font_size_used: Option<FixedPoint>,
+
+    /// The statistics of the last run.
+    stats: Option<ProcessingStats>,
 }
 
-struct Common<'a> {
-    hooks: &'a mut dyn DriverHooks,
-    status: &'a mut dyn StatusBackend,
-    out_base: &'a Path,
-}
-
-impl<'a> EngineState<'a> {
-    pub fn new(
-        hooks: &'a mut dyn DriverHooks,
-        status: &'a mut dyn StatusBackend,
-        out_base: &'a Path,
-    ) -> Self {
-        Self {
-            common: Common {
-                hooks,
-                status,
-                out_base,
-            },
-            state: State::Initializing(InitializationState::default()),
-        }
+impl fmt::Debug for Spx2HtmlEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spx2HtmlEngine")
+            .field("options", &self.options)
+            .field("progress", &self.progress.is_some())
+            .field("remote_fetcher", &self.remote_fetcher.is_some())
+            .field("post_processor", &self.post_processor.is_some())
+            .field(
+                "special_handlers",
+                &self
+                    .special_handlers
+                    .iter()
+                    .map(|(prefix, _)| prefix)
+                    .collect::<Vec<_>>(),
+            )
+            .field("body_font_size_used", &self.body_font_size_used)
+            .field("stats", &self.stats)
+            .finish()
     }
 }
 
-#[derive(Debug)]
-#[allow(clippy::large_enum_variant)]
-enum State {
-    /// This variant is needed to implement state changes.
-    Invalid,
-    Initializing(InitializationState),
-    Emitting(EmittingState),
+/// Settings that control the behavior of the engine.
+#[derive(Debug, Default)]
+struct Options {
+    auto_sections: bool,
+    heading_ids: Option<SlugStyle>,
+    heading_anchor_links: bool,
+    output: OutputPolicy,
+    render_limits: RenderLimits,
+    spill_threshold: Option<usize>,
+    dev_overlay: bool,
+    emission_plans: bool,
+    strict: bool,
+    strict_allowed: WarningCategories,
+    fatal_warnings: bool,
+    font_fallbacks: Vec<(String, String)>,
+    preserve_control_chars: bool,
+    no_space_after: String,
+    no_space_before: String,
+    uncovered_chars_variable: bool,
+    inline_font_faces: bool,
+    no_font_copies: bool,
+    font_url_prefix: Option<String>,
+    body_font_size: Option<FixedPoint>,
+    base_css_file: bool,
+    last_resort_font: Option<(String, Vec<u8>)>,
+    no_bundled_fallback_font: bool,
+    search_provided_files: bool,
+    layout_sidecars: Option<LayoutDetail>,
+    glyph_size_limits: GlyphSizeLimits,
+    no_canvas_containment: bool,
+    hide_unlabeled_canvases: bool,
+    variables: Vec<(String, tera::Value)>,
+    templates: Vec<(String, String)>,
+    assets: Vec<(String, AssetSource)>,
+    max_remote_size: Option<u64>,
+    post_process_assets: bool,
+    depfile: Option<PathBuf>,
+    asset_manifest: Option<PathBuf>,
+    precomputed_assets: Option<AssetManifest>,
+    font_usage_report: Option<PathBuf>,
+    report_stats: bool,
+    default_output_path: Option<String>,
+    url_style: UrlStyle,
+    serialization: Serialization,
+    output_selection: OutputSelection,
+    precompress: CompressionKinds,
+    cancellation: Option<Arc<AtomicBool>>,
 }
 
-impl<'a> EngineState<'a> {
-    pub fn finished(mut self) -> Result<()> {
-        if let State::Emitting(mut s) = self.state {
-            if !s.current_content.is_empty() {
-                s.finish_file(&mut self.common)?;
-            }
-        }
-
-        Ok(())
+impl Options {
+    /// Whether control characters in text are kept as character references.
+    fn keeps_control_chars(&self) -> bool {
+        self.preserve_control_chars && self.serialization == Serialization::Html
     }
-}
-
-impl<'a> XdvEvents for EngineState<'a> {
-    type Error = Error;
-
-    fn handle_header(&mut self, filetype: FileType, _comment: &[u8]) -> Result<()> {
-        if filetype != FileType::Spx {
-            bail!("file should be SPX format but got {}", filetype);
-        }
 
-        Ok(())
+    /// Whether the document's unmodified font files are written to the
+    /// output.
+    fn copies_fonts(&self) -> bool {
+        !self.no_font_copies && self.font_url_prefix.is_none()
     }
 
-    fn handle_special(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<()> {
-        let contents = atry!(std::str::from_utf8(contents); ["could not parse \\special as UTF-8"]);
-
-        if contents == "tdux:emit" || contents.starts_with("tdux:provideFile") {
-            self.state.ensure_initialized()?;
+    /// Get the font of last resort, as its file name and data: the one
+    /// configured by the application if there is one, and otherwise the
+    /// bundled one, if it's available and enabled.
+    fn last_resort_font(&self) -> Result<Option<(String, Vec<u8>)>> {
+        if let Some(font) = &self.last_resort_font {
+            return Ok(Some(font.clone()));
         }
 
-        match &mut self.state {
-            State::Invalid => panic!("invalid spx2html state leaked"),
-            State::Initializing(s) => s.handle_special(contents, &mut self.common),
-            State::Emitting(s) => s.handle_special(x, y, contents, &mut self.common),
+        #[cfg(feature = "fallback-font")]
+        if !self.no_bundled_fallback_font {
+            let data = fallback::bundled_font()?;
+            return Ok(Some((fallback::BUNDLED_NAME.to_owned(), data)));
         }
-    }
 
-    fn handle_text_and_glyphs(
-        &mut self,
-        font_num: i32,
-        text: &str,
-        _width: i32,
-        glyphs: &[u16],
-        x: &[i32],
-        y: &[i32],
-    ) -> Result<()> {
-        self.state.ensure_initialized()?;
+        Ok(None)
+    }
+}
 
-        match &mut self.state {
-            State::Invalid => panic!("invalid spx2html state leaked"),
-            State::Initializing(_) => unreachable!(),
-            State::Emitting(s) => {
-                s.handle_text_and_glyphs(font_num, text, glyphs, x, y, &mut self.common)
-            }
-        }
+/// How non-ASCII text is handled when deriving element ids from text.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SlugStyle {
+    /// Transliterate the text to ASCII, so that `Ärger über Öl` becomes
+    /// `arger-uber-ol`.
+    Ascii,
 
-        Ok(())
-    }
+    /// Keep non-ASCII letters and digits, lowercased, so that `Ärger über Öl`
+    /// becomes `ärger-über-öl`.
+    Unicode,
+}
 
-    fn handle_define_native_font(
-        &mut self,
-        name: &str,
-        font_num: i32,
-        size: i32,
-        face_index: u32,
-        color_rgba: Option<u32>,
-        extend: Option<u32>,
-        slant: Option<u32>,
-        embolden: Option<u32>,
-    ) -> Result<(), Self::Error> {
-        match &mut self.state {
-            State::Invalid => panic!("invalid spx2html state leaked"),
-            State::Initializing(s) => s.handle_define_native_font(
-                name,
-                font_num,
-                size,
-                face_index,
-                color_rgba,
-                extend,
-                slant,
-                embolden,
-                &mut self.common,
-            ),
-            _ => Ok(()),
-        }
-    }
+/// The source of an asset provided by the application driving the engine.
+pub enum AssetSource {
+    /// The contents of a file on the filesystem.
+    Path(PathBuf),
 
-    fn handle_glyph_run(
-        &mut self,
-        font_num: i32,
-        glyphs: &[u16],
-        x: &[i32],
-        y: &[i32],
-    ) -> Result<(), Self::Error> {
-        self.state.ensure_initialized()?;
+    /// Fixed contents.
+    Bytes(Vec<u8>),
 
-        match &mut self.state {
-            State::Invalid => panic!("invalid spx2html state leaked"),
-            State::Initializing(_) => unreachable!(),
-            State::Emitting(s) => s.handle_glyph_run(font_num, glyphs, x, y, &mut self.common),
-        }
-    }
+    /// The contents of a stream, which is read when processing starts.
+    Reader(Box<dyn Read + Send>),
 }
 
-impl State {
-    fn ensure_initialized(&mut self) -> Result<()> {
-        // Is this the least-bad way to do this??
-        let mut work = std::mem::replace(self, State::Invalid);
-
-        if let State::Initializing(s) = work {
-            work = State::Emitting(s.initialization_finished()?);
+impl fmt::Debug for AssetSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetSource::Path(p) => f.debug_tuple("Path").field(p).finish(),
+            AssetSource::Bytes(b) => write!(f, "Bytes({} bytes)", b.len()),
+            AssetSource::Reader(_) => f.write_str("Reader"),
         }
-
-        std::mem::swap(self, &mut work);
-        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct InitializationState {
-    templates: HashMap<String, String>,
-    next_template_path: String,
-    next_output_path: String,
-    fonts: HashMap<i32, FontInfo>,
-    main_body_font_size: FixedPoint,
-    font_data_keys: HashMap<(String, u32), usize>,
-    font_data: HashMap<usize, FontData>,
-    variables: HashMap<String, String>,
+/// How the engine serializes the markup that it generates.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Serialization {
+    /// Write HTML.
+    #[default]
+    Html,
+
+    /// Write markup that is also well-formed XML, as XHTML requires: element
+    /// names are lowercased, void elements such as `<br />` are self-closed,
+    /// and text is escaped.
+    Xhtml,
 }
 
-impl Default for InitializationState {
-    fn default() -> Self {
-        InitializationState {
-            templates: Default::default(),
-            next_template_path: Default::default(),
-            next_output_path: "index.html".to_owned(),
-            fonts: Default::default(),
-            main_body_font_size: 0,
-            font_data_keys: Default::default(),
-            font_data: Default::default(),
-            variables: Default::default(),
-        }
-    }
+/// How the URLs of output files are formed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlStyle {
+    /// Pages are emitted at their output paths, and URLs name the files
+    /// explicitly, as in `guide.html`.
+    #[default]
+    Html,
+
+    /// A page with output path `guide.html` is emitted as `guide/index.html`,
+    /// and URLs point to its directory, as in `guide/`.
+    Directory,
+
+    /// Pages are emitted at their output paths, but URLs omit the `.html`
+    /// extension, as in `guide`. This relies on the web server to find the
+    /// file.
+    Bare,
 }
 
-impl InitializationState {
-    #[allow(clippy::too_many_arguments)]
-    fn handle_define_native_font(
-        &mut self,
-        name: &str,
-        font_num: i32,
-        size: FixedPoint,
-        face_index: u32,
-        color_rgba: Option<u32>,
-        extend: Option<u32>,
-        slant: Option<u32>,
-        embolden: Option<u32>,
-        common: &mut Common,
-    ) -> Result<()> {
-        if self.fonts.contains_key(&font_num) {
-            // Should we override the definition or something?
-            return Ok(());
-        }
-
-        // TODO: often there are multiple font_nums with the same "name". We
-        // only need to copy the file once.
+/// Which output files are written.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputSelection {
+    /// Write all output files.
+    #[default]
+    All,
 
-        let io = common.hooks.io();
-        let mut texpath = String::default();
-        let mut ih = None;
+    /// Write everything but the HTML pages and their layout sidecars: the
+    /// fonts, the files provided with `tdux:provideFile`, and so on.
+    AssetsOnly,
 
-        for ext in &["", ".otf"] {
-            texpath = format!("{}{}", name, ext);
+    /// Only write the HTML pages and their layout sidecars.
+    PagesOnly,
 
-            match io.input_open_name(&texpath, common.status) {
-                OpenResult::Ok(h) => {
-                    ih = Some(h);
-                    break;
-                }
+    /// Write none of the files produced from the document, only those at
+    /// paths chosen by the application, such as [`Spx2HtmlEngine::depfile`].
+    Nothing,
+}
 
-                OpenResult::NotAvailable => continue,
+/// What happens when an output file already exists in the output directory.
+///
+/// Files that were already written by the same run are always replaced, as
+/// when a page is emitted twice at the same path.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverwritePolicy {
+    /// Replace the existing file.
+    #[default]
+    Always,
+
+    /// Fail with [`Spx2HtmlError::OutputExists`], so that documents sharing
+    /// an output directory can't clobber each other's files.
+    ErrorIfExists,
+
+    /// Replace the existing file, unless it already has the contents that
+    /// would be written, in which case it's left alone, along with its
+    /// modification time. This suits incremental rebuilds of static sites.
+    SkipIfIdentical,
+}
 
-                OpenResult::Err(e) => return Err(e),
-            };
-        }
+/// Which compressed variants of the output files are written.
+///
+/// Static web servers can serve a compressed variant of a file in place of
+/// the original, to clients that accept it. Each variant is written next to
+/// its original, with an extra extension: `index.html.gz`, say. The HTML
+/// pages, their layout sidecars, and `tdux-base.css` are compressed if any
+/// kind of compression is enabled; the fonts and the files provided with
+/// `tdux:provideFile` or [`Spx2HtmlEngine::provide_asset`] only if asked,
+/// since they're often compressed already.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompressionKinds {
+    /// Write gzip-compressed variants, ending in `.gz`.
+    pub gzip: bool,
+
+    /// Write Brotli-compressed variants, ending in `.br`.
+    #[cfg(feature = "brotli")]
+    pub brotli: bool,
+
+    /// Also compress the font files.
+    pub fonts: bool,
+
+    /// Also compress the provided files.
+    pub assets: bool,
+}
 
-        let mut ih = a_ok_or!(ih;
-            ["failed to find a font file associated with the name `{}`", name]
-        );
+/// Categories of problems that [`Spx2HtmlEngine::strict`] mode treats as
+/// errors unless they're allowed with [`Spx2HtmlEngine::strict_allow`].
+///
+/// Categories are combined with `|`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WarningCategories(u32);
 
-        let mut contents = Vec::new();
-        atry!(
-            ih.read_to_end(&mut contents);
-            ["unable to read input font file `{}`", &texpath]
-        );
-        let (name, digest_opt) = ih.into_name_digest();
-        common
-            .hooks
-            .event_input_closed(name.clone(), digest_opt, common.status);
+impl WarningCategories {
+    /// Specials with missing or malformed arguments, or out of place, and
+    /// semantic elements that aren't recognized.
+    pub const MALFORMED_SPECIALS: Self = WarningCategories(1 << 0);
 
-        let mut out_path = common.out_base.to_owned();
-        let basename = texpath.rsplit('/').next().unwrap();
-        out_path.push(basename);
+    /// Glyphs in canvases that can't be mapped back to characters.
+    pub const UNMAPPABLE_GLYPHS: Self = WarningCategories(1 << 1);
 
-        {
-            let mut out_file = atry!(
-                File::create(&out_path);
-                ["cannot open output file `{}`", out_path.display()]
-            );
+    /// Canvas ends without a matching canvas start.
+    pub const UNPAIRED_CANVASES: Self = WarningCategories(1 << 2);
 
-            atry!(
-                out_file.write_all(&contents);
-                ["cannot write output file `{}`", out_path.display()]
-            );
-        }
+    /// Content that is dropped because it came after `tdux:contentFinished`,
+    /// or because no `tdux:emit` followed it.
+    pub const DROPPED_CONTENT: Self = WarningCategories(1 << 3);
 
-        let fd_key = (name, face_index);
-        let next_id = self.font_data_keys.len();
-        let fd_key = *self.font_data_keys.entry(fd_key).or_insert(next_id);
+    /// No categories.
+    pub const fn empty() -> Self {
+        WarningCategories(0)
+    }
 
-        if fd_key == next_id {
-            let map = atry!(
-                FontData::from_opentype(basename.to_owned(), contents, face_index);
-                ["unable to load glyph data from font `{}`", texpath]
-            );
-            self.font_data.insert(fd_key, map);
-        }
+    /// All of the categories.
+    pub const fn all() -> Self {
+        WarningCategories(0b1111)
+    }
 
-        // TODO: actually handle font roles. Here we intentionally overwrite
-        // main_body_font_size with every new font because when we're scanning
-        // the postamble, the last font is the main body font. In my one
-        // example.
-        self.main_body_font_size = size;
+    /// Check whether all of the categories in *other* are in this set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
 
-        let info = FontInfo {
-            role: FontRole::MainBody,
-            rel_url: utf8_percent_encode(basename, CONTROLS).to_string(),
-            fd_key,
-            size,
-            face_index,
-            color_rgba,
-            extend,
-            slant,
-            embolden,
-        };
+impl BitOr for WarningCategories {
+    type Output = Self;
 
-        self.fonts.insert(font_num, info);
-        Ok(())
+    fn bitor(self, other: Self) -> Self {
+        WarningCategories(self.0 | other.0)
     }
+}
 
-    fn handle_special(&mut self, contents: &str, common: &mut Common) -> Result<()> {
-        if let Some(texpath) = contents.strip_prefix("tdux:addTemplate ") {
-            self.handle_add_template(texpath, common)
-        } else if let Some(texpath) = contents.strip_prefix("tdux:setTemplate ") {
-            self.handle_set_template(texpath, common)
-        } else if let Some(texpath) = contents.strip_prefix("tdux:setOutputPath ") {
-            self.handle_set_output_path(texpath, common)
-        } else if let Some(remainder) = contents.strip_prefix("tdux:setTemplateVariable ") {
-            self.handle_set_template_variable(remainder, common)
-        } else if let Some(_remainder) = contents.strip_prefix("tdux:provideFile ") {
-            tt_warning!(common.status, "ignoring too-soon tdux:provideFile special");
-            Ok(())
-        } else {
-            Ok(())
-        }
+impl BitOrAssign for WarningCategories {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
     }
+}
 
-    fn handle_add_template(&mut self, texpath: &str, common: &mut Common) -> Result<()> {
-        let mut ih = atry!(
-            common.hooks.io().input_open_name(texpath, common.status).must_exist();
-            ["unable to open input HTML template `{}`", texpath]
-        );
+/// How much detail goes into layout sidecar files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LayoutDetail {
+    /// Describe the placement and size of each canvas.
+    Canvases,
 
-        let mut contents = String::new();
-        atry!(
-            ih.read_to_string(&mut contents);
-            ["unable to read input HTML template `{}`", texpath]
-        );
+    /// Also describe the placement of each glyph within each canvas.
+    Glyphs,
+}
 
-        self.templates.insert(texpath.to_owned(), contents);
+impl Spx2HtmlEngine {
+    /// Configure whether `<section>` elements are generated automatically
+    /// from headings.
+    ///
+    /// When enabled, opening an `h1`–`h6` element with `tdux:as` closes any
+    /// automatically generated sections of the same or a deeper level, then
+    /// opens a new `<section>` containing the heading and the content that
+    /// follows it. Generated sections still open at the end of an output file
+    /// are closed. Headings placed directly inside a `section` element opened
+    /// by the document itself are not wrapped again.
+    ///
+    /// The default is false.
+    pub fn auto_sections(&mut self, enabled: bool) -> &mut Self {
+        self.options.auto_sections = enabled;
+        self
+    }
 
-        let (name, digest_opt) = ih.into_name_digest();
-        common
-            .hooks
-            .event_input_closed(name, digest_opt, common.status);
-        Ok(())
+    /// Configure whether `id` attributes are generated for headings.
+    ///
+    /// If *style* is not None, every `h1`–`h6` element opened with `tdux:as`
+    /// is given an `id` derived from its text content, with `-2`, `-3`, etc.
+    /// appended as needed to keep the ids unique within each output file. If
+    /// the heading is wrapped in an automatically generated section (see
+    /// [`Self::auto_sections`]), the section carries the id instead, so that
+    /// the id is never duplicated. The *style* determines how non-ASCII text
+    /// is slugified.
+    ///
+    /// The headings of each output file, and their ids, are made available
+    /// to the HTML template as `tduxHeadings`, a list of objects with
+    /// `level`, `id`, and `text` fields. The headings of all of the files
+    /// emitted so far, including the current one, are available as
+    /// `tduxOutline`, whose objects also have a `path` field giving the
+    /// output path of the file containing the heading, and a `url` field
+    /// giving its URL in the style chosen with [`Self::url_style`].
+    ///
+    /// The default is None.
+    pub fn heading_ids(&mut self, style: Option<SlugStyle>) -> &mut Self {
+        self.options.heading_ids = style;
+        self
     }
 
-    fn handle_set_template(&mut self, texpath: &str, _common: &mut Common) -> Result<()> {
-        self.next_template_path = texpath.to_owned();
-        Ok(())
+    /// Configure whether headings with generated ids get permalink markers.
+    ///
+    /// When enabled along with [`Self::heading_ids`], the markup `<a
+    /// class="tdux-anchor" href="#ID">#</a>` is appended inside of each
+    /// heading that was given an id, so that templates can style it as a
+    /// clickable permalink.
+    ///
+    /// The default is false.
+    pub fn heading_anchor_links(&mut self, enabled: bool) -> &mut Self {
+        self.options.heading_anchor_links = enabled;
+        self
     }
 
-    fn handle_set_output_path(&mut self, texpath: &str, _common: &mut Common) -> Result<()> {
-        self.next_output_path = texpath.to_owned();
-        Ok(())
+    /// Set the Unix permissions of output files.
+    ///
+    /// The permissions are set explicitly, so they don't depend on the umask
+    /// of the process or the permissions of the files that outputs are copied
+    /// from. This setting has no effect on other platforms.
+    ///
+    /// The default is `0o644`.
+    pub fn output_file_mode(&mut self, mode: u32) -> &mut Self {
+        self.options.output.file_mode = mode;
+        self
     }
 
-    fn handle_set_template_variable(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
-        if let Some((varname, varval)) = remainder.split_once(' ') {
-            self.variables.insert(varname.to_owned(), varval.to_owned());
-        } else {
-            tt_warning!(
-                common.status,
-                "ignoring malformatted tdux:setTemplateVariable special `{}`",
-                remainder
+    /// Set the Unix permissions of output directories that the engine
+    /// creates.
+    ///
+    /// Like [`Self::output_file_mode`], this has no effect on other
+    /// platforms.
+    ///
+    /// The default is `0o755`.
+    pub fn output_dir_mode(&mut self, mode: u32) -> &mut Self {
+        self.options.output.dir_mode = mode;
+        self
+    }
+
+    /// Set the Unix user and group IDs that should own output files and the
+    /// directories that the engine creates.
+    ///
+    /// This is useful when running as root inside a container that writes to
+    /// a bind-mounted directory. Changing ownership generally requires
+    /// privileges. This setting has no effect on other platforms.
+    ///
+    /// The default is None, which leaves ownership alone.
+    pub fn output_owner(&mut self, owner: Option<(u32, u32)>) -> &mut Self {
+        self.options.output.owner = owner;
+        self
+    }
+
+    /// Choose what happens when an output file already exists in the output
+    /// directory.
+    ///
+    /// The policy applies to all of the files written to the output
+    /// directory: pages, fonts, stylesheets, and provided files. Files at
+    /// paths chosen by the application that lie outside of it, such as a
+    /// depfile, are always replaced. See [`OverwritePolicy`].
+    ///
+    /// The default is [`OverwritePolicy::Always`].
+    pub fn overwrite_policy(&mut self, policy: OverwritePolicy) -> &mut Self {
+        self.options.output.overwrite = policy;
+        self
+    }
+
+    /// Set the maximum time that rendering the template of a single page may
+    /// take.
+    ///
+    /// If the limit is exceeded, processing fails with an error naming the
+    /// template and the page. Because Tera can't interrupt a render in
+    /// progress, when a limit is set each page is rendered on a helper thread,
+    /// which is abandoned if it exceeds the limit. If *limit* is None, there is
+    /// no limit and pages are rendered on the calling thread.
+    ///
+    /// The default is two minutes.
+    pub fn render_time_limit(&mut self, limit: Option<Duration>) -> &mut Self {
+        self.options.render_limits.time = limit;
+        self
+    }
+
+    /// Set the maximum size of a single rendered page, in bytes.
+    ///
+    /// If the limit is exceeded, processing fails before the page is written.
+    /// If *limit* is None, there is no limit.
+    ///
+    /// The default is 256 MiB.
+    pub fn max_page_size(&mut self, limit: Option<usize>) -> &mut Self {
+        self.options.render_limits.size = limit;
+        self
+    }
+
+    /// Bound the memory taken by output files that are held back, in bytes.
+    ///
+    /// Files that link to anchors that haven't been emitted yet are held
+    /// back until the content is finished. In this low-memory mode, once the
+    /// held files in memory add up to more than *threshold* bytes, further
+    /// ones are spilled to temporary files, which are read back one at a time
+    /// at the end. The statistics of the run, see
+    /// [`Self::processing_stats`], say how many files were spilled. If
+    /// *threshold* is None, held files are always kept in memory.
+    ///
+    /// The default is None.
+    pub fn spill_threshold(&mut self, threshold: Option<usize>) -> &mut Self {
+        self.options.spill_threshold = threshold;
+        self
+    }
+
+    /// Configure whether pages include an overlay listing the warnings
+    /// generated while producing them.
+    ///
+    /// This is intended for development builds. When enabled, each output
+    /// file gets a fixed-position `<details class="tdux-warnings">` element
+    /// listing the warnings and errors reported since the previous file was
+    /// emitted, along with the offsets in the SPX input where they arose. At
+    /// most 50 entries are listed. The overlay is inserted after the
+    /// template is rendered, just before `</body>`, so templates need no
+    /// changes, and it is never included in `tduxContent`.
+    ///
+    /// The default is false.
+    pub fn dev_overlay(&mut self, enabled: bool) -> &mut Self {
+        self.options.dev_overlay = enabled;
+        self
+    }
+
+    /// Configure whether the engine records how it decided what went into
+    /// each output file.
+    ///
+    /// When enabled, the events that affected each output file, such as
+    /// changes to its path, template, and template variables, and additions
+    /// to its content, are recorded in order. The resulting
+    /// [`plan::EmissionPlan`]s are delivered to the progress sink, and listed
+    /// in the development overlay if [`Self::dev_overlay`] is enabled too.
+    ///
+    /// The default is false.
+    pub fn emission_plans(&mut self, enabled: bool) -> &mut Self {
+        self.options.emission_plans = enabled;
+        self
+    }
+
+    /// Configure whether questionable input is an error rather than a
+    /// warning.
+    ///
+    /// When enabled, adding the same template twice with different contents
+    /// is an error. Otherwise, it draws a warning, and the template that was
+    /// added last is used. Also when enabled, it's an error for a page with
+    /// canvases to include neither the `tduxRequiredCss` variable nor a link
+    /// to `tdux-base.css`, since the canvases will be garbled without the
+    /// rules that they provide, and it's an error for a document with content
+    /// to end without `tdux:contentFinished`, rather than a warning.
+    ///
+    /// Strict mode also turns the warnings about the problems in
+    /// [`WarningCategories`] into errors, except for the categories allowed
+    /// with [`Self::strict_allow`]. The errors are
+    /// [`Spx2HtmlError::StrictViolation`]s, which give the position in the
+    /// SPX input where the problem was found.
+    ///
+    /// The default is false.
+    pub fn strict(&mut self, enabled: bool) -> &mut Self {
+        self.options.strict = enabled;
+        self
+    }
+
+    /// Configure which categories of problems remain warnings in strict mode.
+    ///
+    /// This has no effect unless [`Self::strict`] is enabled.
+    ///
+    /// The default is [`WarningCategories::empty`].
+    pub fn strict_allow(&mut self, categories: WarningCategories) -> &mut Self {
+        self.options.strict_allowed = categories;
+        self
+    }
+
+    /// Configure whether processing fails if any warnings were issued.
+    ///
+    /// Unlike [`Self::strict`], this doesn't change how anything is handled:
+    /// processing runs to completion, so that all of the warnings are
+    /// reported, and then fails with [`Spx2HtmlError::FatalWarnings`]. Output
+    /// files written along the way are left in place.
+    ///
+    /// The default is false.
+    pub fn fatal_warnings(&mut self, enabled: bool) -> &mut Self {
+        self.options.fatal_warnings = enabled;
+        self
+    }
+
+    /// Add fallback fonts to the CSS font family of a font.
+    ///
+    /// Without fallbacks, characters that the embedded fonts can't display
+    /// are drawn in whatever font the browser picks, which may clash with the
+    /// rest of the document. The *family* is the file name of a font, such
+    /// as `lmroman12-regular.otf`; `body`, for the main body font; or `*`,
+    /// for all fonts without a more specific setting. The *stack* is a CSS
+    /// font family list such as `"Noto Serif", serif`. It is appended to the
+    /// generated font family in the text styles, in canvases, and in the
+    /// `tduxMainBodyFontFamily` template variable.
+    ///
+    /// Documents can set fallbacks too, using the `tdux:setFontFallback`
+    /// special, which takes the same two arguments. Those override the
+    /// settings made here.
+    pub fn font_fallback(&mut self, family: &str, stack: &str) -> &mut Self {
+        self.options
+            .font_fallbacks
+            .push((family.to_owned(), stack.to_owned()));
+        self
+    }
+
+    /// Configure whether control characters in text are kept as numeric
+    /// character references.
+    ///
+    /// Text sometimes contains stray control characters, such as form feeds
+    /// from verbatim input, that aren't allowed in HTML. By default, all
+    /// control characters other than tab and newline are removed from text.
+    /// When this option is enabled, they are written as character references
+    /// such as `&#xC;` instead, which can help with tracking down where they
+    /// came from. Either way, a warning summarizing the characters found is
+    /// issued for each output file that contained any. This option has no
+    /// effect with [`Serialization::Xhtml`].
+    ///
+    /// The default is false.
+    pub fn preserve_control_chars(&mut self, enabled: bool) -> &mut Self {
+        self.options.preserve_control_chars = enabled;
+        self
+    }
+
+    /// Add characters after which no space is inserted between text runs.
+    ///
+    /// Consecutive text runs are normally separated by a space. No space is
+    /// inserted, however, if the first run ends with one of a set of
+    /// characters that, by default, contains opening brackets, opening
+    /// quotation marks, and en and em dashes. This adds the characters of
+    /// *chars* to that set, which can be useful for languages with other
+    /// punctuation conventions.
+    pub fn no_space_after(&mut self, chars: &str) -> &mut Self {
+        self.options.no_space_after.push_str(chars);
+        self
+    }
+
+    /// Add characters before which no space is inserted between text runs.
+    ///
+    /// This is the counterpart of [`Self::no_space_after`]: no space is
+    /// inserted if the second run starts with one of a set of characters that,
+    /// by default, contains closing brackets, closing quotation marks, en and
+    /// em dashes, and the punctuation `,.;:!?%…`. This adds the characters of
+    /// *chars* to that set.
+    pub fn no_space_before(&mut self, chars: &str) -> &mut Self {
+        self.options.no_space_before.push_str(chars);
+        self
+    }
+
+    /// Configure whether templates are told about characters that the fonts
+    /// can't render.
+    ///
+    /// When the content is finished, the characters of the emitted text are
+    /// checked against the character maps of the document's fonts, and the
+    /// results are reported to the progress sink as a
+    /// [`coverage::CoverageReport`]. If this option is enabled, the
+    /// characters that none of the fonts cover are also made available to
+    /// templates rendered after that point as `tduxUncoveredChars`, a list of
+    /// objects with `character`, `codepoint`, `count`, and `first_path`
+    /// fields, so that a build report page can list them.
+    ///
+    /// The default is false.
+    pub fn uncovered_chars_variable(&mut self, enabled: bool) -> &mut Self {
+        self.options.uncovered_chars_variable = enabled;
+        self
+    }
+
+    /// Configure whether templates get the `@font-face` rules of the fonts
+    /// as a ready-made `<style>` element.
+    ///
+    /// The `tduxFontFaces` variable holds rules whose font URLs are relative
+    /// to the output root, which is what a stylesheet at the root needs.
+    /// Templates that can't easily link to such a stylesheet can enable this
+    /// option and use `tduxFontFacesInline` instead: the same rules, wrapped
+    /// in a `<style>` element, with their URLs made relative to the page
+    /// being rendered.
+    ///
+    /// The default is false.
+    pub fn inline_font_faces(&mut self, enabled: bool) -> &mut Self {
+        self.options.inline_font_faces = enabled;
+        self
+    }
+
+    /// Configure whether the document's font files are copied to the output.
+    ///
+    /// Applications that serve many documents set in the same fonts may
+    /// prefer to provide the font files once, at the output root, themselves.
+    /// When disabled, the `@font-face` rules still refer to the font files,
+    /// but the files aren't written. The variant fonts with customized
+    /// character maps are specific to the document, so they're written
+    /// regardless, as is the font of last resort.
+    ///
+    /// The default is true.
+    pub fn copy_fonts(&mut self, enabled: bool) -> &mut Self {
+        self.options.no_font_copies = !enabled;
+        self
+    }
+
+    /// Refer to the document's font files at an external URL prefix, rather
+    /// than copying them to the output.
+    ///
+    /// This suits applications that host their web fonts elsewhere, such as
+    /// on a CDN. The `@font-face` rules refer to each font file by the prefix
+    /// followed by the percent-encoded file name, so the prefix should
+    /// usually end with a slash. As with [`Self::copy_fonts`], the variant
+    /// fonts with customized character maps and the font of last resort are
+    /// still written to the output, and referred to relative to it.
+    ///
+    /// The default is None, in which case the font files are referred to
+    /// relative to the output root.
+    pub fn font_url_prefix(&mut self, prefix: Option<&str>) -> &mut Self {
+        self.options.font_url_prefix = prefix.map(|p| p.to_owned());
+        self
+    }
+
+    /// Configure whether the CSS rules that canvases rely on are written to
+    /// `tdux-base.css` at the output root.
+    ///
+    /// Templates get the same rules in the `tduxRequiredCss` variable, for
+    /// inclusion in a `<style>` element; this option is for templates that
+    /// would rather link to a stylesheet. See [`html::required_css`].
+    ///
+    /// The default is false.
+    pub fn base_css_file(&mut self, enabled: bool) -> &mut Self {
+        self.options.base_css_file = enabled;
+        self
+    }
+
+    /// Configure a font of last resort, for characters that none of the
+    /// document's fonts can render.
+    ///
+    /// The font, given as the file name and the data of an OpenType font, is
+    /// appended to the generated CSS font family lists as `tdux-fallback`.
+    /// Its `@font-face` rule is only added to `tduxFontFaces` once an output
+    /// file contains a character that it covers and the document's fonts
+    /// don't. Such characters are marked as rescued in the
+    /// [`coverage::CoverageReport`]. The font is written to the output
+    /// directory whole, under its own file name, unless the crate is built
+    /// with the `fallback-font` feature and the font has TrueType outlines:
+    /// then it's subset to the rescued characters when the content is
+    /// finished, and written as `tdux-fallback.woff2`.
+    ///
+    /// The default is None, in which case the bundled font is used if there
+    /// is one (see [`Self::bundled_fallback_font`]), and characters that the
+    /// document's fonts can't render are left to the browser otherwise.
+    pub fn last_resort_font(&mut self, font: Option<(&str, Vec<u8>)>) -> &mut Self {
+        self.options.last_resort_font = font.map(|(name, data)| (name.to_owned(), data));
+        self
+    }
+
+    /// Use the font of last resort that comes with the engine.
+    ///
+    /// With the `fallback-font` feature, the crate bundles a subset of DejaVu
+    /// Sans covering basic Latin, Latin-1, punctuation, arrows, mathematical
+    /// operators, and other common symbols, which is used as the font of
+    /// last resort unless the application configures one with
+    /// [`Self::last_resort_font`]. Setting this to false leaves characters
+    /// that the document's fonts can't render to the browser instead, which
+    /// may show them as boxes, rather than adding a font to the output that
+    /// the document didn't ask for. Without the feature, there's no bundled
+    /// font, and this has no effect.
+    ///
+    /// The default is true.
+    pub fn bundled_fallback_font(&mut self, enabled: bool) -> &mut Self {
+        self.options.no_bundled_fallback_font = !enabled;
+        self
+    }
+
+    /// Pin the size of the main body font, in TeX scaled points (1/65536 of
+    /// a point).
+    ///
+    /// Sizes in the HTML output, such as those of canvases and of text set in
+    /// other fonts, are expressed in rems, relative to this size. Left to
+    /// itself, the engine takes it to be the size of the last font that the
+    /// document defines, which is usually the main body font, but depends on
+    /// the order in which TeX happened to define the fonts. Documents can set
+    /// it with the `tdux:setBodyFontSize` special, which takes precedence over
+    /// this. See also [`Self::body_font_size_used`].
+    ///
+    /// The default is None.
+    pub fn body_font_size(&mut self, size: Option<i32>) -> &mut Self {
+        self.options.body_font_size = size;
+        self
+    }
+
+    /// Get the newest version of the tdux format that the engine supports,
+    /// [`FORMAT_VERSION`].
+    ///
+    /// Documents declare the version that they were written for with
+    /// `tdux:formatVersion <n>`, and fail to process if it's newer than this,
+    /// so the TeX support files should declare the version that they're
+    /// generated for.
+    pub fn format_version(&self) -> u32 {
+        FORMAT_VERSION
+    }
+
+    /// Get the size of the main body font used by the last successful run, in
+    /// TeX scaled points.
+    ///
+    /// This is the size that rems correspond to in the output; see
+    /// [`Self::body_font_size`]. It's None if nothing has been processed yet,
+    /// or if the last document never got as far as emitting content.
+    pub fn body_font_size_used(&self) -> Option<i32> {
+        self.body_font_size_used
+    }
+
+    /// Configure whether the template search path applies to the sources of
+    /// `tdux:provideFile` and `tdux:provideDirectory`.
+    ///
+    /// Documents can name directories with the `tdux:templateSearchPath`
+    /// special. If a template isn't found under the name given by the
+    /// document, each of those directories is tried in turn. When this option
+    /// is enabled, the same goes for files copied with `tdux:provideFile`,
+    /// and for the directories copied with `tdux:provideDirectory`.
+    ///
+    /// The default is false.
+    pub fn search_provided_files(&mut self, enabled: bool) -> &mut Self {
+        self.options.search_provided_files = enabled;
+        self
+    }
+
+    /// Configure whether layout sidecar files are written.
+    ///
+    /// Client-side tools, such as annotation layers, may need to know where
+    /// the canvases of a page ended up. When this option is enabled, each
+    /// canvas element gets an id of the form `tdux-canvas-N`, counting from
+    /// zero on each page, and every HTML output file is accompanied by a file
+    /// with `.layout.json` appended to its name. The sidecar lists the
+    /// canvases of the page with their ids, kinds, the TeX coordinates of
+    /// their origins, and their dimensions in rems. With
+    /// [`LayoutDetail::Glyphs`], it also lists the character, font family,
+    /// and placement of each glyph. The data are the same ones used to
+    /// generate the HTML.
+    ///
+    /// The sidecars are pretty-printed JSON with a fixed order of object
+    /// keys, so that changes to them can be reviewed with ordinary diffs. Existing
+    /// fields won't be renamed or removed, but new ones may be added, so
+    /// readers should ignore fields that they don't recognize.
+    ///
+    /// The default is None, in which case no sidecars are written.
+    pub fn layout_sidecars(&mut self, detail: Option<LayoutDetail>) -> &mut Self {
+        self.options.layout_sidecars = detail;
+        self
+    }
+
+    /// Configure the range of font sizes, in rems, at which canvas glyphs are
+    /// drawn as-is.
+    ///
+    /// Canvases containing glyphs smaller than *min* are drawn enlarged and
+    /// scaled down with a CSS transform, so that browser minimum font sizes
+    /// can't distort their layout. Glyphs larger than *max* are drawn at that
+    /// size instead, with a warning. See [`html::GlyphSizeLimits`] for the
+    /// defaults.
+    pub fn glyph_size_limits(&mut self, min: f32, max: f32) -> &mut Self {
+        self.options.glyph_size_limits = GlyphSizeLimits { min, max };
+        self
+    }
+
+    /// Configure whether canvas elements are fully pre-sized to prevent
+    /// layout shifts.
+    ///
+    /// See [`html::CanvasOptions::containment`] for details. Templates that
+    /// need the overflow of canvases to affect the layout can disable this.
+    ///
+    /// The default is true.
+    pub fn canvas_containment(&mut self, enabled: bool) -> &mut Self {
+        self.options.no_canvas_containment = !enabled;
+        self
+    }
+
+    /// Configure whether canvases without a text alternative are hidden from
+    /// assistive technologies.
+    ///
+    /// A canvas gets a text alternative from a preceding `tdux:canvasAlt
+    /// <text>` special, which gives its element `role="img"` and the text as
+    /// its `aria-label`. If this is enabled, the elements of other canvases
+    /// get `aria-hidden="true"`, for documents that describe their math some
+    /// other way. MathML canvases are never hidden, since their markup is
+    /// accessible by itself.
+    ///
+    /// The default is false.
+    pub fn hide_unlabeled_canvases(&mut self, enabled: bool) -> &mut Self {
+        self.options.hide_unlabeled_canvases = enabled;
+        self
+    }
+
+    /// Configure how the URLs of output files are formed.
+    ///
+    /// The style affects where pages are emitted and the `url` fields of the
+    /// entries of `tduxOutline` (see [`Self::heading_ids`]). Output paths
+    /// ending with a slash, whether set with `tdux:setOutputPath` or generated
+    /// from a pattern, get `index.html` appended in every style. Templates can
+    /// refer to the URL of the current page as `tduxCurrentUrl`, and the style
+    /// itself as `tduxUrlStyle`, which is `html`, `directory`, or `bare`. All
+    /// of these URLs are relative to the output root, so links between pages
+    /// should be prefixed with `tduxRelTop`. The URL of a top-level
+    /// `index.html` is `./` in the directory and bare styles.
+    ///
+    /// The default is [`UrlStyle::Html`].
+    pub fn url_style(&mut self, style: UrlStyle) -> &mut Self {
+        self.options.url_style = style;
+        self
+    }
+
+    /// Choose how the markup that the engine generates is serialized.
+    ///
+    /// With [`Serialization::Xhtml`], everything that the engine writes into
+    /// `tduxContent` is well-formed XML, for packaging as EPUB and the like:
+    /// the elements opened with `tdux:as`, `tdux:asp`, and `tdux:asSemantic`,
+    /// the elements that the engine generates around content, canvases, and
+    /// text. Since XML can't represent most control characters, even as
+    /// character references, they're always removed from text in this mode;
+    /// see [`Self::preserve_control_chars`]. The markup of the templates, and
+    /// any that the document passes through verbatim, is up to their authors.
+    ///
+    /// The default is [`Serialization::Html`].
+    pub fn serialization(&mut self, serialization: Serialization) -> &mut Self {
+        self.options.serialization = serialization;
+        self
+    }
+
+    /// Set a template variable on behalf of the application.
+    ///
+    /// This allows values that don't belong in the TeX source, such as the
+    /// base URL of a website or the revision being built, to be passed to
+    /// templates. This may be called repeatedly; if a variable is set more
+    /// than once, the last value wins. Values set by the document with
+    /// `tdux:setTemplateVariable` take precedence over these.
+    pub fn set_variable(&mut self, name: &str, value: tera::Value) -> &mut Self {
+        self.options.variables.push((name.to_owned(), value));
+        self
+    }
+
+    /// Add a template on behalf of the application.
+    ///
+    /// The template is available under *name* just as if the document had
+    /// added it with `tdux:addTemplate`, without the need for it to exist as a
+    /// file. The first template added this way is used to render pages if the
+    /// document never chooses one with `tdux:setTemplate`.
+    pub fn add_template(&mut self, name: &str, contents: &str) -> &mut Self {
+        self.options
+            .templates
+            .push((name.to_owned(), contents.to_owned()));
+        self
+    }
+
+    /// Provide an asset on behalf of the application.
+    ///
+    /// The asset is written to *dest_path*, relative to the output directory,
+    /// when initialization finishes, and is available to templates like the
+    /// files copied by the document with `tdux:provideFile`. It's an error for
+    /// the document to provide another file at the same path.
+    pub fn provide_asset(&mut self, dest_path: &str, source: AssetSource) -> &mut Self {
+        self.options.assets.push((dest_path.to_owned(), source));
+        self
+    }
+
+    /// Allow the document to provide files from remote URLs, fetching them
+    /// with *fetcher*.
+    ///
+    /// Without a fetcher, a `tdux:provideFile` special whose source is an
+    /// `http://` or `https://` URL is an error. See [`remote`] for details.
+    pub fn remote_fetcher(&mut self, fetcher: Box<dyn RemoteFetcher>) -> &mut Self {
+        self.remote_fetcher = Some(fetcher);
+        self
+    }
+
+    /// Post-process the HTML pages with *processor* before they're written.
+    ///
+    /// The processor is given the path of each page, relative to the output
+    /// root, and its rendered contents, which it can transform in place, to
+    /// minify them or rewrite their links, say. If it returns an error,
+    /// processing fails with an error naming the page. See
+    /// [`Self::post_process_assets`] to have it transform other files too.
+    pub fn post_processor(&mut self, processor: Box<dyn PostProcessor>) -> &mut Self {
+        self.post_processor = Some(processor);
+        self
+    }
+
+    /// Handle the specials starting with *prefix*, such as `tdux:myproj:`,
+    /// with *handler*.
+    ///
+    /// This lets other projects define specials of their own. The engine's
+    /// own specials are never given to handlers, and if several handlers'
+    /// prefixes match a special, the one registered first gets it. See
+    /// [`handlers`] for details.
+    pub fn register_special_handler(
+        &mut self,
+        prefix: &str,
+        handler: Box<dyn SpecialHandler>,
+    ) -> &mut Self {
+        self.special_handlers.push((prefix.to_owned(), handler));
+        self
+    }
+
+    /// Configure whether the post-processor also transforms the files provided
+    /// with `tdux:provideFile` or [`Self::provide_asset`].
+    ///
+    /// The default is false.
+    pub fn post_process_assets(&mut self, enabled: bool) -> &mut Self {
+        self.options.post_process_assets = enabled;
+        self
+    }
+
+    /// Set the maximum size of a file fetched from a remote URL, in bytes.
+    ///
+    /// If the limit is exceeded, processing fails with an error naming the
+    /// URL. The default is [`remote::DEFAULT_MAX_REMOTE_SIZE`].
+    pub fn max_remote_size(&mut self, limit: u64) -> &mut Self {
+        self.options.max_remote_size = Some(limit);
+        self
+    }
+
+    /// Read the contents of the assets whose sources are streams, so that
+    /// they can be used more than once.
+    fn read_asset_streams(&mut self) -> Result<()> {
+        for (dest, source) in &mut self.options.assets {
+            if let AssetSource::Reader(r) = source {
+                let mut contents = Vec::new();
+                r.read_to_end(&mut contents)
+                    .map_err(|e| Spx2HtmlError::Io {
+                        path: dest.clone(),
+                        source: e,
+                    })?;
+                *source = AssetSource::Bytes(contents);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a Makefile-style file listing the inputs of each output file.
+    ///
+    /// When processing is done, a rule is written to *path* for each HTML
+    /// output file, naming the inputs that it depends on, so that generic
+    /// build tools can tell when the output is out of date. See [`deps`] for
+    /// what's included. If *path* is None, no such file is written.
+    ///
+    /// Unlike the output paths requested by documents, *path* isn't confined
+    /// to the output directory, since it's chosen by the application: it may
+    /// be absolute, or relative to the current directory, and may contain
+    /// `..` components. This is also the case for [`Self::asset_manifest`].
+    /// It's still an error for it to coincide with another output file.
+    ///
+    /// The default is None.
+    pub fn depfile(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.options.depfile = path;
+        self
+    }
+
+    /// Write a JSON file listing the assets provided to the output.
+    ///
+    /// When processing is done, an object is written to *path* with the
+    /// `version` of the format, [`MANIFEST_VERSION`], and an array of
+    /// `entries`: one for each file provided with `tdux:provideFile`,
+    /// `tdux:provideDirectory`, `tdux:provideData` or
+    /// [`Self::provide_asset`], and each font file written by the engine, in
+    /// order of their paths. Each entry gives the `path` of the file,
+    /// relative to the output root, and its `origin`: `application`;
+    /// `engine`, for `tdux-base.css`; `copy` or `directory_copy`, with the
+    /// `src` input; `remote_copy`, with the `url` and the SHA-256 `digest` of
+    /// its contents; `data`, with the `digest` of the data; `font_file`, with
+    /// the `src` input and the `faces` used, each with its `index` and
+    /// generated CSS `family`; or `font_variant`, with the `family` of the
+    /// face that it derives from, its `index`, and the `glyphs` that its
+    /// character map maps to, each paired with its character. Version 0 of
+    /// the format, written by earlier versions of the engine, is just the
+    /// array of entries. Font files that aren't copied (see [`Self::copy_fonts`] and
+    /// [`Self::font_url_prefix`]) aren't listed. If *path* is None, no such
+    /// file is written. The *path* can be anywhere, as with
+    /// [`Self::depfile`]. The contents can be read back as an
+    /// [`AssetManifest`].
+    ///
+    /// The default is None.
+    pub fn asset_manifest(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.options.asset_manifest = path;
+        self
+    }
+
+    /// Follow the decisions about fonts recorded in the asset manifest of an
+    /// earlier run.
+    ///
+    /// The engine normally names the CSS font families of the document's
+    /// fonts, and allocates the customized character maps of their variants,
+    /// in the order in which it comes across them. When *manifest* is given,
+    /// as read from the file written with [`Self::asset_manifest`], the
+    /// fonts and glyph mappings listed in it are treated the same way as
+    /// they were in the run that wrote it, so that a second pass over a
+    /// related document produces the same HTML for them. If the manifest
+    /// lists any font faces, it's an error for the document to use a face
+    /// that it doesn't list. Since variant font files are named after their
+    /// contents, their paths come out the same too.
+    ///
+    /// Manifests in any version of the format can be used, including newer
+    /// ones than [`MANIFEST_VERSION`]: their entries and fields that this
+    /// version of the engine doesn't know about are ignored.
+    ///
+    /// The default is None.
+    pub fn precomputed_assets(&mut self, manifest: Option<AssetManifest>) -> &mut Self {
+        self.options.precomputed_assets = manifest;
+        self
+    }
+
+    /// Write a JSON report on how the document uses its fonts.
+    ///
+    /// When the content is finished, the [`usage::FontUsageReport`] is
+    /// written to *path*, which can be anywhere, as with [`Self::depfile`].
+    /// If *path* is None, no such file is written, but the report is still
+    /// delivered to the progress sink.
+    ///
+    /// The default is None.
+    pub fn font_usage_report(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.options.font_usage_report = path;
+        self
+    }
+
+    /// Configure whether a one-line summary of the [`stats::ProcessingStats`]
+    /// is reported as a note at the end of each run.
+    ///
+    /// The statistics are available from [`Self::processing_stats`] either
+    /// way. The default is false.
+    pub fn report_stats(&mut self, enabled: bool) -> &mut Self {
+        self.options.report_stats = enabled;
+        self
+    }
+
+    /// Get the statistics of the last run.
+    ///
+    /// It's None if nothing has been processed yet. If the last run failed,
+    /// only the [`stats::ProcessingStats::rendering`] statistics are filled
+    /// in, so that it can be seen whether a render hit one of the limits.
+    pub fn processing_stats(&self) -> Option<&ProcessingStats> {
+        self.stats.as_ref()
+    }
+
+    /// Set the output path of the pages emitted before any
+    /// `tdux:setOutputPath` special.
+    ///
+    /// The default is `index.html`.
+    pub fn default_output_path(&mut self, path: &str) -> &mut Self {
+        self.options.default_output_path = Some(path.to_owned());
+        self
+    }
+
+    /// Choose which output files are written.
+    ///
+    /// Incremental builds may only need to regenerate the HTML pages, or only
+    /// the fonts and other assets. Either way, the whole document is still
+    /// processed, so that inputs are read and closed, dependencies are
+    /// tracked, and errors are detected as usual; the output files that
+    /// weren't selected just aren't written. Assets that aren't written can
+    /// still be inlined into pages with `inline_asset`.
+    ///
+    /// The default is [`OutputSelection::All`].
+    pub fn output_selection(&mut self, selection: OutputSelection) -> &mut Self {
+        self.options.output_selection = selection;
+        self
+    }
+
+    /// Choose which compressed variants of the output files are written
+    /// alongside them. See [`CompressionKinds`].
+    ///
+    /// The variants are listed among the [`EmittedOutputs`], in the category
+    /// of their originals. The default is to write none.
+    pub fn precompress(&mut self, kinds: CompressionKinds) -> &mut Self {
+        self.options.precompress = kinds;
+        self
+    }
+
+    /// Register a sink that will receive progress updates.
+    ///
+    /// See [`progress::ProgressSink`] for the updates that are provided.
+    pub fn progress_sink(&mut self, sink: Box<dyn ProgressSink>) -> &mut Self {
+        self.progress = Some(sink);
+        self
+    }
+
+    /// Allow processing to be cancelled by setting *token*, from another
+    /// thread or from a progress callback.
+    ///
+    /// The engine checks the token before handling each special, run of text,
+    /// and run of glyphs, and before writing each page. Once it's set,
+    /// processing fails with [`Spx2HtmlError::Cancelled`]. The files written
+    /// up to that point are left in place; each of them is complete, since
+    /// output files are written atomically.
+    ///
+    /// The default is None, in which case processing can't be cancelled.
+    pub fn cancellation_token(&mut self, token: Option<Arc<AtomicBool>>) -> &mut Self {
+        self.options.cancellation = token;
+        self
+    }
+
+    /// Process SPX into HTML.
+    ///
+    /// Because this driver will, in the generic case, produce a tree of HTML
+    /// output files that are not going to be used as a basis for any subsequent
+    /// engine stages, it outputs directly to disk (via `out_base`) rather than
+    /// using the I/O layer. Use [`Self::process_to_memory`] to avoid the
+    /// filesystem altogether, [`Self::process_to_driver_io`] to go through
+    /// the I/O layer after all, or [`Self::process_to_sink`] to store the
+    /// output some other way.
+    ///
+    /// Returns a listing of the files written to the output directory, so
+    /// that build tools can upload, clean, or cache them.
+    pub fn process_to_filesystem(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+        out_base: &Path,
+    ) -> Result<EmittedOutputs> {
+        let out = OutputTree::directory(out_base, self.options.output.clone());
+        self.process(hooks, status, spx, out.clone(), Pass::Process)?;
+        Ok(out.emitted())
+    }
+
+    /// Process SPX read from *spx* into HTML, writing to the filesystem.
+    ///
+    /// This is like [`Self::process_to_filesystem`], but for SPX that the
+    /// application already has at hand, such as in memory after running the
+    /// previous engine stage, so that it needn't be staged in the I/O layer.
+    /// The reader must provide the whole SPX file, from its start. The SPX
+    /// isn't opened through the I/O layer, so the driver hooks aren't told
+    /// about it, but the fonts, templates, and other inputs are opened as
+    /// usual.
+    pub fn process_reader_to_filesystem<R: Read + Seek>(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        mut spx: R,
+        out_base: &Path,
+    ) -> Result<EmittedOutputs> {
+        let input_size = atry!(
+            spx.seek(SeekFrom::End(0));
+            ["failed to determine the size of the SPX input"]
+        );
+
+        let out = OutputTree::directory(out_base, self.options.output.clone());
+        self.process_stream(
+            hooks,
+            status,
+            spx,
+            Some(input_size),
+            out.clone(),
+            Pass::Process,
+        )?;
+        Ok(out.emitted())
+    }
+
+    /// Process SPX into HTML, keeping the output files in memory.
+    ///
+    /// The files are returned keyed by their paths relative to the output
+    /// root, such as `index.html` or `chapters/two.html`. Output paths are
+    /// checked just as they are by [`Self::process_to_filesystem`], and the
+    /// same errors result. The settings that control how files are created,
+    /// such as [`Self::output_file_mode`], have no effect.
+    pub fn process_to_memory(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+    ) -> Result<HashMap<String, Vec<u8>>> {
+        let out = OutputTree::memory();
+        self.process(hooks, status, spx, out.clone(), Pass::Process)?;
+        Ok(out.take_files())
+    }
+
+    /// Process SPX into HTML, handing the output files to *sink*.
+    ///
+    /// This lets applications store the output however they like, such as in
+    /// an archive. Output paths are checked just as they are by
+    /// [`Self::process_to_filesystem`], and the sink is only given their
+    /// normalized forms, relative to the output root. The settings that
+    /// control how files are created, such as [`Self::output_file_mode`],
+    /// have no effect. [`OutputSink::finish`] is called if processing
+    /// succeeds.
+    pub fn process_to_sink(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+        sink: Box<dyn OutputSink>,
+    ) -> Result<()> {
+        self.process(hooks, status, spx, OutputTree::sink(sink), Pass::Process)?;
+        Ok(())
+    }
+
+    /// Process SPX into HTML, storing the output files in a ZIP archive
+    /// written to *writer*.
+    ///
+    /// The archive holds the same files, at the same paths, as the output
+    /// directory of [`Self::process_to_filesystem`] would. See [`ZipSink`]
+    /// for the details. Returns the writer once the archive is complete, and
+    /// a listing of the files in it.
+    pub fn process_to_zip<W: Write + Seek + Send + 'static>(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+        writer: W,
+    ) -> Result<(W, EmittedOutputs)> {
+        let finished = Arc::new(Mutex::new(None));
+        let out = OutputTree::sink(Box::new(ZipSink::new(writer, finished.clone())));
+        self.process(hooks, status, spx, out.clone(), Pass::Process)?;
+        let writer = finished.lock().unwrap().take().unwrap();
+        Ok((writer, out.emitted()))
+    }
+
+    /// Process SPX into HTML, writing the output files through the I/O layer
+    /// of *hooks*.
+    ///
+    /// Each file is opened with the I/O provider's `output_open_name`, at
+    /// its path relative to the output root, and the hooks are told about it
+    /// with `event_output_closed` once it's written, just like the outputs
+    /// of the other engines. This lets drivers capture or redirect the HTML
+    /// output along with everything else. The files are kept in memory until
+    /// processing has succeeded, and then written in order of their paths.
+    /// Output paths are checked just as they are by
+    /// [`Self::process_to_filesystem`]. The settings that control how files
+    /// are created, such as [`Self::output_file_mode`], have no effect.
+    ///
+    /// Returns a listing of the files written.
+    pub fn process_to_driver_io(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+    ) -> Result<EmittedOutputs> {
+        let out = OutputTree::memory();
+        self.process(hooks, status, spx, out.clone(), Pass::Process)?;
+        let mut files: Vec<_> = out.take_files().into_iter().collect();
+        files.sort();
+
+        for (path, data) in files {
+            write_to_driver_io(hooks, status, &path, &data)?;
+        }
+
+        Ok(out.emitted())
+    }
+
+    /// Scan SPX for the assets that processing it would produce, without
+    /// rendering its pages.
+    ///
+    /// Large documents can be processed in two passes: a scan that settles
+    /// which fonts, font variants, and other assets the document needs, and
+    /// then the processing proper, with the returned description passed to
+    /// [`Self::precomputed_assets`]. The second pass then names the fonts and
+    /// allocates the variants just as the scan did, so its output is the same
+    /// as if it had been processed in one go. The scan reads the document's
+    /// inputs and reports its warnings as usual, but no output files are
+    /// written, nor are the files configured with [`Self::depfile`],
+    /// [`Self::asset_manifest`], and [`Self::font_usage_report`].
+    pub fn process_for_assets(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+    ) -> Result<AssetManifest> {
+        let outcome = self.process(hooks, status, spx, OutputTree::memory(), Pass::Scan)?;
+        Ok(outcome.assets)
+    }
+
+    /// Check SPX without writing any output.
+    ///
+    /// This is meant for continuous integration. The document is processed
+    /// just as by [`Self::process_to_filesystem`]: its templates are compiled
+    /// and rendered, the files that it provides are read, and so on, and
+    /// problems surface as the same errors and warnings. But no output files
+    /// are written, nor are the files configured with [`Self::depfile`],
+    /// [`Self::asset_manifest`], and [`Self::font_usage_report`].
+    ///
+    /// Returns the number of HTML pages that processing would produce.
+    pub fn validate(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+    ) -> Result<usize> {
+        let outcome = self.process(hooks, status, spx, OutputTree::memory(), Pass::Validate)?;
+        Ok(outcome.n_pages)
+    }
+
+    fn process(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+        out: OutputTree,
+        pass: Pass,
+    ) -> Result<Outcome> {
+        let mut input = hooks.io().input_open_name(spx, status).must_exist()?;
+        let input_size = input.get_size().ok().map(|n| n as u64);
+        let outcome = self.process_stream(hooks, status, &mut input, input_size, out, pass)?;
+
+        let (name, digest_opt) = input.into_name_digest();
+        hooks.event_input_closed(name, digest_opt, status);
+        Ok(outcome)
+    }
+
+    /// Process the SPX read from *spx*, which needn't be at its start.
+    fn process_stream<R: Read + Seek>(
+        &mut self,
+        hooks: &mut dyn DriverHooks,
+        status: &mut dyn StatusBackend,
+        spx: R,
+        input_size: Option<u64>,
+        out: OutputTree,
+        pass: Pass,
+    ) -> Result<Outcome> {
+        self.read_asset_streams()?;
+
+        let render_stats = Rc::new(Cell::new(RenderStats::default()));
+
+        let result = {
+            let mut state = EngineState::new(
+                hooks,
+                status,
+                &self.options,
+                borrow_progress_sink(&mut self.progress),
+                borrow_remote_fetcher(&mut self.remote_fetcher),
+                borrow_post_processor(&mut self.post_processor),
+                out.clone(),
+            );
+            state.common.input_size = input_size;
+            state.common.render_stats = render_stats.clone();
+            state.common.pass = pass;
+            state.common.special_handlers = &mut self.special_handlers;
+            let reader = PositionTrackingReader::new(spx, state.common.input_pos.clone());
+
+            if pass != Pass::Process {
+                out.select(OutputSelection::Nothing);
+            }
+
+            XdvParser::process_with_seeks(reader, state).and_then(|s| s.finished())
+        };
+
+        let outcome = match result {
+            Ok(o) => o,
+            Err(e) => {
+                self.stats = Some(ProcessingStats {
+                    rendering: render_stats.get(),
+                    ..Default::default()
+                });
+                return Err(e);
+            }
+        };
+
+        out.finish()?;
+        self.body_font_size_used = outcome.body_font_size;
+        self.stats = Some(outcome.stats.clone());
+        Ok(outcome)
+    }
+}
+
+/// A kind of pass over a document.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Pass {
+    /// Produce the output.
+    Process,
+
+    /// Only find out which assets the document needs, without rendering its
+    /// pages. See [`Spx2HtmlEngine::process_for_assets`].
+    Scan,
+
+    /// Render the output, but don't write it. See
+    /// [`Spx2HtmlEngine::validate`].
+    Validate,
+}
+
+/// What a pass over a document found.
+#[derive(Debug)]
+struct Outcome {
+    /// The description of the document's assets.
+    assets: AssetManifest,
+
+    /// The number of distinct HTML pages produced.
+    n_pages: usize,
+
+    /// The size of the main body font, if the document got as far as
+    /// emitting content.
+    body_font_size: Option<FixedPoint>,
+
+    /// The statistics of the run.
+    stats: ProcessingStats,
+}
+
+/// Reborrow an optional boxed progress sink.
+///
+/// (We can't just use `Option::as_deref_mut()` because the trait object
+/// lifetime then can't be shortened.)
+fn borrow_progress_sink(
+    progress: &mut Option<Box<dyn ProgressSink>>,
+) -> Option<&mut dyn ProgressSink> {
+    match progress {
+        Some(p) => Some(p.as_mut()),
+        None => None,
+    }
+}
+
+/// Reborrow an optional boxed remote fetcher, like [`borrow_progress_sink`].
+fn borrow_remote_fetcher(
+    fetcher: &mut Option<Box<dyn RemoteFetcher>>,
+) -> Option<&mut dyn RemoteFetcher> {
+    match fetcher {
+        Some(f) => Some(f.as_mut()),
+        None => None,
+    }
+}
+
+/// Reborrow an optional boxed post-processor, like [`borrow_progress_sink`].
+fn borrow_post_processor(
+    processor: &mut Option<Box<dyn PostProcessor>>,
+) -> Option<&mut dyn PostProcessor> {
+    match processor {
+        Some(p) => Some(p.as_mut()),
+        None => None,
+    }
+}
+
+/// Write an output file through the I/O layer of *hooks*, and tell them about
+/// it once it's closed.
+fn write_to_driver_io(
+    hooks: &mut dyn DriverHooks,
+    status: &mut dyn StatusBackend,
+    path: &str,
+    data: &[u8],
+) -> Result<()> {
+    let mut handle = match hooks.io().output_open_name(path) {
+        OpenResult::Ok(h) => h,
+        OpenResult::NotAvailable => {
+            bail!("the I/O layer can't create output file `{}`", path)
+        }
+        OpenResult::Err(e) => return Err(e),
+    };
+
+    atry!(
+        handle.write_all(data).and_then(|_| handle.flush());
+        ["failed to write output file `{}`", path]
+    );
+
+    let (name, digest) = handle.into_name_digest();
+    hooks.event_output_closed(name, digest, status);
+    Ok(())
+}
+
+struct EngineState<'a> {
+    common: Common<'a>,
+    state: State,
+}
+
+struct Common<'a> {
+    hooks: &'a mut dyn DriverHooks,
+    status: CollectingStatus<'a>,
+    options: &'a Options,
+    progress: Option<&'a mut dyn ProgressSink>,
+    fetcher: Option<&'a mut dyn RemoteFetcher>,
+    post_processor: Option<&'a mut dyn PostProcessor>,
+    out: OutputTree,
+
+    /// The current position in the SPX input, for progress reporting and
+    /// diagnostics.
+    input_pos: Rc<Cell<u64>>,
+
+    /// The size of the SPX input, if known.
+    input_size: Option<u64>,
+
+    /// The statistics of the rendering of the templates. They're shared with
+    /// the engine so that they survive a run that fails, in particular one
+    /// that fails because a render hit a limit.
+    render_stats: Rc<Cell<RenderStats>>,
+
+    /// The translation of deprecated specials.
+    compat: Compat,
+
+    /// The directories in which templates are looked for if they aren't
+    /// found under their literal names, in order.
+    template_search_path: Vec<String>,
+
+    /// The emission plan of the output file being built.
+    plan: PlanRecorder,
+
+    /// The contents of the remote URLs fetched so far.
+    remote: RemoteCache,
+
+    /// The inputs of the output files.
+    deps: DepTracker,
+
+    /// The kind of pass over the document.
+    pass: Pass,
+
+    /// The handlers of the specials defined by the application, with their
+    /// prefixes.
+    special_handlers: &'a mut [(String, Box<dyn SpecialHandler>)],
+}
+
+impl<'a> Common<'a> {
+    /// Check whether a special is one that a handler registered by the
+    /// application might handle.
+    fn has_special_handler(&self, contents: &str) -> bool {
+        self.special_handlers
+            .iter()
+            .any(|(prefix, _)| contents.starts_with(prefix.as_str()))
+    }
+
+    /// Warn about a problem, or, in strict mode, fail because of it unless its
+    /// category is allowed.
+    fn warn_or_fail(&mut self, category: WarningCategories, message: fmt::Arguments) -> Result<()> {
+        if self.options.strict && !self.options.strict_allowed.contains(category) {
+            return Err(Spx2HtmlError::StrictViolation {
+                message: message.to_string(),
+                offset: self.input_pos.get(),
+            }
+            .into());
+        }
+
+        tt_warning!(self.status, "{}", message);
+        Ok(())
+    }
+
+    /// Fail if the application has asked for processing to be cancelled.
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.options.cancellation {
+            Some(token) if token.load(Ordering::Relaxed) => Err(Spx2HtmlError::Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Let the post-processor, if there is one, transform an output file
+    /// before it's written.
+    fn post_process(&mut self, rel_path: &str, data: &mut Vec<u8>) -> Result<()> {
+        if let Some(processor) = self.post_processor.as_mut() {
+            atry!(
+                processor.process(rel_path, data);
+                ["failed to post-process output file `{}`", rel_path]
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Write a file provided with `tdux:provideFile` or by the application,
+    /// post-processing it if so configured.
+    fn write_asset(&mut self, dest_path: &str, mut contents: Vec<u8>) -> Result<()> {
+        if self.options.post_process_assets {
+            let normalized = self.out.resolve(dest_path)?;
+            self.post_process(&normalized, &mut contents)?;
+        }
+
+        self.out
+            .write(dest_path, &contents, OutputCategory::CopiedAsset)?;
+        Ok(())
+    }
+
+    /// Deliver an update to the progress sink, if there is one.
+    ///
+    /// Errors are reported as warnings rather than propagated.
+    fn progress<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut dyn ProgressSink) -> Result<()>,
+    {
+        if let Some(sink) = self.progress.as_mut() {
+            if let Err(e) = f(&mut **sink) {
+                tt_warning!(self.status, "progress reporting callback failed"; e);
+            }
+        }
+    }
+
+    /// Open an input that must exist.
+    ///
+    /// If it doesn't, the error notes the *kind* of input.
+    fn open_input(&mut self, name: &str, kind: &str) -> Result<InputHandle> {
+        match self.hooks.io().input_open_name(name, &mut self.status) {
+            OpenResult::Ok(h) => Ok(h),
+            OpenResult::NotAvailable => Err(Spx2HtmlError::MissingInputs {
+                missing: vec![format!("{} `{}`", kind, name)],
+            }
+            .into()),
+            OpenResult::Err(e) => Err(e),
+        }
+    }
+
+    /// Get the names under which an input is looked for: its literal name,
+    /// and then, unless it's absolute, its name in each of the directories of
+    /// the template search path.
+    fn search_candidates(&self, name: &str) -> Vec<String> {
+        let mut candidates = vec![name.to_owned()];
+
+        if !name.starts_with('/') {
+            for dir in &self.template_search_path {
+                let candidate = format!("{}/{}", dir, name);
+
+                if !candidates.contains(&candidate) {
+                    candidates.push(candidate);
+                }
+            }
+        }
+
+        candidates
+    }
+
+    /// Open an input, using the template search path.
+    ///
+    /// Returns the name under which the input was found along with its
+    /// handle, or None if none of the candidates exist.
+    fn search_input(&mut self, name: &str) -> Result<Option<(String, InputHandle)>> {
+        for candidate in self.search_candidates(name) {
+            match self
+                .hooks
+                .io()
+                .input_open_name(&candidate, &mut self.status)
+            {
+                OpenResult::Ok(h) => return Ok(Some((candidate, h))),
+                OpenResult::NotAvailable => {}
+                OpenResult::Err(e) => return Err(e),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Describe an input that couldn't be found with [`Self::search_input`],
+    /// listing the candidates that were tried if there were several.
+    fn describe_missing(&self, name: &str, kind: &str) -> String {
+        let candidates = self.search_candidates(name);
+
+        if candidates.len() == 1 {
+            format!("{} `{}`", kind, name)
+        } else {
+            format!("{} `{}` (tried `{}`)", kind, name, candidates.join("`, `"))
+        }
+    }
+
+    /// Open an input that must exist, using the template search path.
+    ///
+    /// Returns the name under which the input was found along with its
+    /// handle.
+    fn open_searched_input(&mut self, name: &str, kind: &str) -> Result<(String, InputHandle)> {
+        match self.search_input(name)? {
+            Some(found) => Ok(found),
+            None => Err(Spx2HtmlError::MissingInputs {
+                missing: vec![self.describe_missing(name, kind)],
+            }
+            .into()),
+        }
+    }
+
+    /// Read all of an opened input, found at *src_path*, noting it as a
+    /// dependency.
+    fn read_input(&mut self, src_path: String, mut ih: InputHandle) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        ih.read_to_end(&mut contents)
+            .map_err(|e| Spx2HtmlError::Io {
+                path: src_path,
+                source: e,
+            })?;
+
+        let (name, digest_opt) = ih.into_name_digest();
+        self.deps
+            .note_input(&name, digest_opt.map(|d| d.to_string()));
+        self.deps.use_input(&name);
+        self.hooks
+            .event_input_closed(name, digest_opt, &mut self.status);
+        Ok(contents)
+    }
+
+    /// Handle a `tdux:templateSearchPath` special.
+    fn add_template_search_dir(&mut self, dir: &str) -> Result<()> {
+        let dir = dir.trim().trim_end_matches('/');
+
+        if dir.is_empty() {
+            self.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!("ignoring empty tdux:templateSearchPath special"),
+            )?;
+        } else if !self.template_search_path.iter().any(|d| d == dir) {
+            self.template_search_path.push(dir.to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> EngineState<'a> {
+    pub fn new(
+        hooks: &'a mut dyn DriverHooks,
+        status: &'a mut dyn StatusBackend,
+        options: &'a Options,
+        progress: Option<&'a mut dyn ProgressSink>,
+        fetcher: Option<&'a mut dyn RemoteFetcher>,
+        post_processor: Option<&'a mut dyn PostProcessor>,
+        out: OutputTree,
+    ) -> Self {
+        let input_pos = Rc::new(Cell::new(0));
+        out.select(options.output_selection);
+        out.precompress(options.precompress);
+
+        let mut common = Common {
+            hooks,
+            status: CollectingStatus::new(status, options.dev_overlay, input_pos.clone()),
+            options,
+            progress,
+            fetcher,
+            post_processor,
+            out,
+            input_pos,
+            input_size: None,
+            render_stats: Default::default(),
+            compat: Compat::default(),
+            template_search_path: Vec::new(),
+            plan: PlanRecorder::new(options.emission_plans),
+            remote: RemoteCache::default(),
+            deps: DepTracker::default(),
+            pass: Pass::Process,
+            special_handlers: &mut [],
+        };
+
+        common.progress(|p| p.on_phase_change(Phase::Initializing));
+
+        let mut init = InitializationState::default();
+
+        for (family, stack) in &options.font_fallbacks {
+            init.fonts.set_fallback(family, stack);
+        }
+
+        if let Some(pins) = options.precomputed_assets.as_ref().and_then(FontPins::new) {
+            init.fonts.pin(pins);
+        }
+
+        for (name, contents) in &options.templates {
+            init.templates.insert(name.clone(), contents.clone());
+            init.driver_templates.insert(name.clone());
+        }
+
+        if let Some((name, _)) = options.templates.first() {
+            init.next_template_path = name.clone();
+        }
+
+        if let Some(path) = &options.default_output_path {
+            init.next_output_path = path.clone();
+        }
+
+        Self {
+            common,
+            state: State::Initializing(init),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+enum State {
+    /// This variant is needed to implement state changes.
+    Invalid,
+    Initializing(InitializationState),
+    Emitting(EmittingState),
+}
+
+impl<'a> EngineState<'a> {
+    /// Finish processing, once the input has been handled, returning what
+    /// the pass found.
+    ///
+    /// If the document started emitting content but didn't finish it with
+    /// `tdux:contentFinished`, as can happen if its TeX support code crashed,
+    /// the pending content and the fonts are emitted anyway, with a warning,
+    /// so that the output is complete. In strict mode, that's an error
+    /// instead.
+    pub fn finished(mut self) -> Result<Outcome> {
+        let mut clean = false;
+
+        if let State::Emitting(s) = &mut self.state {
+            if !s.content.is_empty() {
+                s.finish_file(false, &mut self.common)?;
+            }
+
+            clean = s.content_finished;
+
+            if !clean {
+                if self.common.options.strict {
+                    return Err(Spx2HtmlError::ContentNotFinished.into());
+                }
+
+                tt_warning!(
+                    self.common.status,
+                    "the document ended without `tdux:contentFinished`; \
+                     finishing its content anyway, but the output may be incomplete"
+                );
+                s.content_finished(&mut self.common)?;
+            }
+
+            s.drop_pending_data(&mut self.common);
+            s.copy_pending_assets(&mut self.common)?;
+        }
+
+        let outputs = self.common.deps.outputs();
+        let outcome = match &mut self.state {
+            State::Emitting(s) => {
+                let mut stats = std::mem::take(&mut s.stats);
+                stats.pages = s.n_emitted;
+                stats.peak_resident_bytes = s.spill.peak_resident() as u64;
+                stats.spilled_files = s.spill.n_spilled();
+                stats.rendering = self.common.render_stats.get();
+
+                Outcome {
+                    assets: s.assets.manifest(),
+                    n_pages: s.emitted_paths.len(),
+                    body_font_size: Some(s.body_font_size),
+                    stats,
+                }
+            }
+            State::Initializing(s) => {
+                let mut stats = std::mem::take(&mut s.stats);
+                stats.fonts = s.fonts.font_data.len();
+
+                Outcome {
+                    assets: AssetManifest::default(),
+                    n_pages: 0,
+                    body_font_size: None,
+                    stats,
+                }
+            }
+            State::Invalid => panic!("invalid spx2html state leaked"),
+        };
+        let writes = self.common.pass == Pass::Process;
+
+        if let (true, Some(path)) = (writes, &self.common.options.depfile) {
+            let text = deps::to_makefile(outputs, self.common.out.base());
+            self.common
+                .out
+                .write_trusted(path, text.as_bytes(), false)?;
+        }
+
+        if let (true, Some(path)) = (writes, &self.common.options.asset_manifest) {
+            let text = outcome.assets.to_json()?;
+            self.common
+                .out
+                .write_trusted(path, text.as_bytes(), false)?;
+        }
+
+        // A scan doesn't know the pages' inputs.
+
+        if let (false, Some(sink)) = (
+            self.common.pass == Pass::Scan,
+            self.common.progress.as_mut(),
+        ) {
+            if let Err(e) = sink.on_dependencies(outputs) {
+                tt_warning!(self.common.status, "progress reporting callback failed"; e);
+            }
+        }
+
+        let done = self
+            .common
+            .input_size
+            .unwrap_or_else(|| self.common.input_pos.get());
+        let total = self.common.input_size;
+        self.common.progress(|p| p.on_bytes_processed(done, total));
+
+        let count = self.common.status.warning_count();
+
+        if self.common.options.fatal_warnings && count > 0 {
+            return Err(Spx2HtmlError::FatalWarnings { count }.into());
+        }
+
+        if self.common.options.report_stats {
+            tt_note!(self.common.status, "{}", outcome.stats);
+        }
+
+        self.common.progress(|p| p.on_completed(clean));
+        self.common.progress(|p| p.on_phase_change(Phase::Done));
+        Ok(outcome)
+    }
+
+    /// Handle `tdux:formatVersion`, given its argument.
+    fn handle_format_version(&mut self, version: &str) -> Result<()> {
+        let version = match version.parse::<u32>() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                return self.common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring tdux:formatVersion `{}`, which isn't a positive integer",
+                        version
+                    ),
+                );
+            }
+        };
+
+        if !self.common.compat.declare_format_version(version) {
+            return Err(Spx2HtmlError::UnsupportedFormatVersion {
+                required: version,
+                supported: FORMAT_VERSION,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> XdvEvents for EngineState<'a> {
+    type Error = Error;
+
+    fn handle_header(&mut self, filetype: FileType, _comment: &[u8]) -> Result<()> {
+        if filetype != FileType::Spx {
+            return Err(Spx2HtmlError::NotSpx {
+                filetype: filetype.to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> Result<()> {
+        let done = self.common.input_pos.get();
+        let total = self.common.input_size;
+        self.common.progress(|p| p.on_bytes_processed(done, total));
+        Ok(())
+    }
+
+    fn handle_special(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<()> {
+        self.common.check_cancelled()?;
+
+        let contents =
+            std::str::from_utf8(contents).map_err(|_| Spx2HtmlError::SpecialMalformed {
+                raw: String::from_utf8_lossy(contents).into_owned(),
+            })?;
+
+        let (contents, deprecation) = self.common.compat.translate(contents, compat::DEPRECATED);
+        let contents = contents.as_ref();
+
+        match deprecation {
+            Deprecation::Accept => {}
+
+            Deprecation::Warn(r) => {
+                tt_warning!(
+                    self.common.status,
+                    "the special `{}` is deprecated; use `{}` instead",
+                    r.old,
+                    r.new
+                );
+            }
+
+            Deprecation::Reject(r) => {
+                return Err(Spx2HtmlError::RenamedSpecial {
+                    old: r.old.to_owned(),
+                    new: r.new.to_owned(),
+                    since: r.since,
+                    declared: self.common.compat.format_version().unwrap_or_default(),
+                }
+                .into());
+            }
+        }
+
+        let contents = match specials::normalize(contents) {
+            Ok(c) => c,
+            Err(problem) => {
+                return self.common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring malformed special `{}`: {}",
+                        contents.trim_end(),
+                        problem
+                    ),
+                );
+            }
+        };
+        let contents = contents.as_ref();
+
+        self.common.plan.note_special(contents);
+
+        if let Some(version) = contents.strip_prefix("tdux:formatVersion ") {
+            return self.handle_format_version(version);
+        }
+
+        let name = contents.split(' ').next().unwrap_or_default();
+
+        if name.starts_with("tdux:")
+            && !specials::is_known(name)
+            && !self.common.has_special_handler(contents)
+        {
+            if let (true, Some(version)) = (
+                self.common.compat.unknown_special(name),
+                self.common.compat.format_version(),
+            ) {
+                self.common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring unknown special `{}`, which isn't part of tdux format {}; \
+                         further instances won't be reported",
+                        name, version
+                    ),
+                )?;
+            }
+        }
+
+        // Table structure and semantic markup are content, so they can only
+        // be handled once we've started emitting.
+        if contents == "tdux:emit"
+            || contents == "tdux:emitIfChanged"
+            || is_table_special(contents)
+            || contents.starts_with("tdux:asp ")
+            || contents.starts_with("tdux:asSemantic ")
+            || contents.starts_with("tdux:aeSemantic ")
+            || contents == "tdux:printBreakBefore"
+            || is_direct_special(contents)
+            || contents.starts_with("tdux:anchor ")
+            || contents.starts_with("tdux:refStart ")
+            || contents.starts_with("tdux:hrefStart ")
+            || contents.starts_with("tdux:img ")
+            || contents.starts_with("pdf:dest ")
+            || contents.starts_with("pdf:bann")
+            || contents.starts_with("tdux:class ")
+            || contents.starts_with("tdux:style ")
+            || contents.starts_with("tdux:canvasAlt ")
+            || contents.starts_with("tdux:deferStart ")
+            || contents.starts_with("tdux:footnoteStart ")
+            || self.common.has_special_handler(contents)
+        {
+            self.state.ensure_initialized(&mut self.common)?;
+        }
+
+        match &mut self.state {
+            State::Invalid => panic!("invalid spx2html state leaked"),
+            State::Initializing(s) => s.handle_special(contents, &mut self.common),
+            State::Emitting(s) => s.handle_special(x, y, contents, &mut self.common),
+        }
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        font_num: i32,
+        text: &str,
+        _width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        self.common.check_cancelled()?;
+        self.state.ensure_initialized(&mut self.common)?;
+
+        match &mut self.state {
+            State::Invalid => panic!("invalid spx2html state leaked"),
+            State::Initializing(_) => unreachable!(),
+            State::Emitting(s) => {
+                s.handle_text_and_glyphs(font_num, text, glyphs, x, y, &mut self.common)
+            }
+        }
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        name: &str,
+        font_num: i32,
+        size: i32,
+        face_index: u32,
+        color_rgba: Option<u32>,
+        extend: Option<u32>,
+        slant: Option<u32>,
+        embolden: Option<u32>,
+    ) -> Result<(), Self::Error> {
+        match &mut self.state {
+            State::Invalid => panic!("invalid spx2html state leaked"),
+            State::Initializing(s) => s.handle_define_native_font(
+                name,
+                font_num,
+                size,
+                face_index,
+                color_rgba,
+                extend,
+                slant,
+                embolden,
+                &mut self.common,
+            ),
+            State::Emitting(s) => s.handle_define_native_font(
+                name,
+                font_num,
+                size,
+                face_index,
+                color_rgba,
+                extend,
+                slant,
+                embolden,
+                &mut self.common,
+            ),
+        }
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.common.check_cancelled()?;
+        self.state.ensure_initialized(&mut self.common)?;
+
+        match &mut self.state {
+            State::Invalid => panic!("invalid spx2html state leaked"),
+            State::Initializing(_) => unreachable!(),
+            State::Emitting(s) => s.handle_glyph_run(font_num, glyphs, x, y, &mut self.common),
+        }
+    }
+
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<(), Self::Error> {
+        self.common.check_cancelled()?;
+
+        // Rules outside of canvases are ignored, so there's no need to
+        // initialize for them.
+        match &mut self.state {
+            State::Invalid => panic!("invalid spx2html state leaked"),
+            State::Initializing(_) => Ok(()),
+            State::Emitting(s) => {
+                s.handle_rule(x, y, height, width);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl State {
+    fn ensure_initialized(&mut self, common: &mut Common) -> Result<()> {
+        // Is this the least-bad way to do this??
+        let mut work = std::mem::replace(self, State::Invalid);
+
+        if let State::Initializing(mut s) = work {
+            let deferred = std::mem::take(&mut s.deferred_specials);
+            let mut emitting = s.initialization_finished(common)?;
+            common.progress(|p| p.on_phase_change(Phase::Emitting));
+            emitting.provide_driver_assets(common)?;
+
+            if !deferred.is_empty() {
+                let mut names = Vec::new();
+
+                for special in &deferred {
+                    if !names.contains(&special.name()) {
+                        names.push(special.name());
+                    }
+                }
+
+                tt_note!(
+                    common.status,
+                    "handling {} {} request(s) deferred until the end of initialization",
+                    deferred.len(),
+                    names.join(", ")
+                );
+            }
+
+            // They're handled in the order in which they were given, since
+            // they may depend on each other.
+            for special in &deferred {
+                match special {
+                    DeferredSpecial::ProvideFile(remainder) => {
+                        emitting.handle_provide_file(remainder, common)?
+                    }
+                    DeferredSpecial::ProvideDirectory(remainder) => {
+                        emitting.handle_provide_directory(remainder, common)?
+                    }
+                    DeferredSpecial::LinkedAsset(kind, remainder) => {
+                        emitting.handle_add_linked_asset(*kind, remainder, common)?
+                    }
+                    DeferredSpecial::ProvideData(remainder, last) => {
+                        emitting.handle_provide_data(remainder, *last, common)?
+                    }
+                    DeferredSpecial::TocEntry(remainder) => {
+                        emitting.handle_toc_entry(remainder, common)?
+                    }
+                }
+            }
+
+            work = State::Emitting(emitting);
+        }
+
+        std::mem::swap(self, &mut work);
+        Ok(())
+    }
+}
+
+/// A special seen during initialization that can only be handled once it's
+/// finished, with its arguments.
+#[derive(Debug)]
+enum DeferredSpecial {
+    /// `tdux:provideFile`.
+    ProvideFile(String),
+
+    /// `tdux:provideDirectory`.
+    ProvideDirectory(String),
+
+    /// `tdux:addCss` or `tdux:addJs`.
+    LinkedAsset(LinkedAssetKind, String),
+
+    /// `tdux:provideDataChunk` or, if the flag is set, `tdux:provideData`.
+    ProvideData(String, bool),
+
+    /// `tdux:tocEntry`.
+    TocEntry(String),
+}
+
+impl DeferredSpecial {
+    /// Get the name of the special.
+    fn name(&self) -> &'static str {
+        match self {
+            DeferredSpecial::ProvideFile(_) => "tdux:provideFile",
+            DeferredSpecial::ProvideDirectory(_) => "tdux:provideDirectory",
+            DeferredSpecial::LinkedAsset(kind, _) => kind.special(),
+            DeferredSpecial::ProvideData(_, false) => "tdux:provideDataChunk",
+            DeferredSpecial::ProvideData(_, true) => "tdux:provideData",
+            DeferredSpecial::TocEntry(_) => "tdux:tocEntry",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InitializationState {
+    /// The templates, in order of their names, so that compilation errors are
+    /// reported consistently.
+    templates: BTreeMap<String, String>,
+
+    /// The templates whose current contents were added by the application.
+    driver_templates: HashSet<String>,
+
+    next_template_path: String,
+
+    /// A template selected with `tdux:setTemplateOnce`, to be used for the
+    /// next output file only.
+    once_template_path: Option<String>,
+
+    next_output_path: String,
+    output_path_pattern: Option<OutputPathPattern>,
+    fonts: FontLookup,
+    main_body_font_size: FixedPoint,
+    main_body_fd_key: usize,
+
+    /// The size of the main body font set with `tdux:setBodyFontSize`, which
+    /// overrides the one inferred from the font definitions.
+    body_font_size: Option<FixedPoint>,
+
+    /// The template variables set with `tdux:setTemplateVariable`, or None
+    /// for the ones unset with `tdux:unsetTemplateVariable`, which hides any
+    /// value set by the driver.
+    variables: HashMap<String, Option<String>>,
+
+    /// The variable scopes opened with `tdux:pushVariableScope`.
+    variable_scopes: VariableScopes<Option<String>>,
+
+    /// The metadata of the first output file, set with `tdux:setPageMeta`.
+    page_meta: BTreeMap<String, String>,
+
+    /// The colors pushed with the specials of the `color` package.
+    colors: ColorStack,
+
+    /// The language of the document, set with `tdux:setLanguage`.
+    language: Option<String>,
+
+    /// Templates selected with `tdux:setTemplate` or `tdux:setTemplateOnce`
+    /// during initialization, whose existence is checked before emission
+    /// begins.
+    referenced_templates: Vec<String>,
+
+    /// Descriptions of inputs that couldn't be found. Rather than failing on
+    /// the first one, we report them all together once initialization is
+    /// finished.
+    missing_inputs: Vec<String>,
+
+    /// The specials seen during initialization that can only be handled
+    /// once it's finished, in order.
+    deferred_specials: Vec<DeferredSpecial>,
+
+    /// The statistics gathered so far, which carry over into emission.
+    stats: ProcessingStats,
+}
+
+impl Default for InitializationState {
+    fn default() -> Self {
+        InitializationState {
+            templates: Default::default(),
+            driver_templates: Default::default(),
+            next_template_path: Default::default(),
+            once_template_path: None,
+            next_output_path: "index.html".to_owned(),
+            output_path_pattern: None,
+            fonts: Default::default(),
+            main_body_font_size: 0,
+            main_body_fd_key: 0,
+            body_font_size: None,
+            variables: Default::default(),
+            variable_scopes: Default::default(),
+            page_meta: Default::default(),
+            colors: Default::default(),
+            language: None,
+            referenced_templates: Default::default(),
+            missing_inputs: Default::default(),
+            deferred_specials: Default::default(),
+            stats: Default::default(),
+        }
+    }
+}
+
+/// Load the file of a font and add it to *fonts* as number *font_num*.
+///
+/// Returns the key of the font's data, or None if its file can't be found.
+#[allow(clippy::too_many_arguments)]
+fn load_font(
+    fonts: &mut FontLookup,
+    name: &str,
+    font_num: i32,
+    size: FixedPoint,
+    face_index: u32,
+    color_rgba: Option<u32>,
+    extend: Option<u32>,
+    slant: Option<u32>,
+    embolden: Option<u32>,
+    common: &mut Common,
+) -> Result<Option<usize>> {
+    // TODO: often there are multiple font_nums with the same "name". We
+    // only need to copy the file once.
+
+    let io = common.hooks.io();
+    let mut texpath = String::default();
+    let mut ih = None;
+
+    for ext in &["", ".otf"] {
+        texpath = format!("{}{}", name, ext);
+
+        match io.input_open_name(&texpath, &mut common.status) {
+            OpenResult::Ok(h) => {
+                ih = Some(h);
+                break;
+            }
+
+            OpenResult::NotAvailable => continue,
+
+            OpenResult::Err(e) => return Err(e),
+        };
+    }
+
+    let mut ih = match ih {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+
+    let mut contents = Vec::new();
+    ih.read_to_end(&mut contents)
+        .map_err(|e| Spx2HtmlError::Io {
+            path: texpath.clone(),
+            source: e,
+        })?;
+    let (name, digest_opt) = ih.into_name_digest();
+    common
+        .deps
+        .note_input(&name, digest_opt.map(|d| d.to_string()));
+    common
+        .hooks
+        .event_input_closed(name.clone(), digest_opt, &mut common.status);
+
+    if !fonts.can_add(&name, face_index) {
+        return Err(Spx2HtmlError::FontNotInManifest { name, face_index }.into());
+    }
+
+    let basename = texpath.rsplit('/').next().unwrap();
+
+    if common.options.copies_fonts() {
+        common
+            .out
+            .write(basename, &contents, OutputCategory::Font)?;
+    }
+
+    let fd_key = fonts
+        .add_font_data(&name, basename, contents, face_index)
+        .map_err(|e| Spx2HtmlError::FontLoad {
+            name: name.clone(),
+            source: e.into(),
+        })?;
+
+    let info = FontInfo {
+        role: FontRole::MainBody,
+        rel_url: utf8_percent_encode(basename, CONTROLS).to_string(),
+        fd_key,
+        size,
+        face_index,
+        color_rgba,
+        extend,
+        slant,
+        embolden,
+    };
+
+    fonts.fonts.insert(font_num, info);
+    common.progress(|p| p.on_font_defined(&name));
+    Ok(Some(fd_key))
+}
+
+impl InitializationState {
+    #[allow(clippy::too_many_arguments)]
+    fn handle_define_native_font(
+        &mut self,
+        name: &str,
+        font_num: i32,
+        size: FixedPoint,
+        face_index: u32,
+        color_rgba: Option<u32>,
+        extend: Option<u32>,
+        slant: Option<u32>,
+        embolden: Option<u32>,
+        common: &mut Common,
+    ) -> Result<()> {
+        if self.fonts.contains(font_num) {
+            // Should we override the definition or something?
+            return Ok(());
+        }
+
+        let fd_key = match load_font(
+            &mut self.fonts,
+            name,
+            font_num,
+            size,
+            face_index,
+            color_rgba,
+            extend,
+            slant,
+            embolden,
+            common,
+        )? {
+            Some(k) => k,
+            None => {
+                self.missing_inputs.push(format!("font `{}`", name));
+                return Ok(());
+            }
+        };
+
+        // TODO: actually handle font roles. Here we intentionally overwrite
+        // main_body_font_size with every new font because when we're scanning
+        // the postamble, the last font is the main body font. In my one
+        // example. Documents that run into trouble can pin the size with
+        // `tdux:setBodyFontSize`.
+        self.main_body_font_size = size;
+        self.main_body_fd_key = fd_key;
+        self.fonts.main_body_fd_key = Some(fd_key);
+        Ok(())
+    }
+
+    fn handle_special(&mut self, contents: &str, common: &mut Common) -> Result<()> {
+        self.stats.add_special(contents);
+
+        if let Some(texpath) = contents.strip_prefix("tdux:addTemplate ") {
+            self.handle_add_template(&decode_path(texpath), common)
+        } else if let Some(texpath) = contents.strip_prefix("tdux:setTemplate ") {
+            self.handle_set_template(&decode_path(texpath), false, common)
+        } else if let Some(texpath) = contents.strip_prefix("tdux:setTemplateOnce ") {
+            self.handle_set_template(&decode_path(texpath), true, common)
+        } else if let Some(texpath) = contents.strip_prefix("tdux:setOutputPath ") {
+            self.handle_set_output_path(&decode_path(texpath), common)
+        } else if let Some(pattern) = contents.strip_prefix("tdux:setOutputPathPattern ") {
+            self.output_path_pattern = parse_output_path_pattern(pattern, common)?;
+            Ok(())
+        } else if let Some(remainder) = contents.strip_prefix("tdux:setTemplateVariable ") {
+            self.handle_set_template_variable(remainder, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:unsetTemplateVariable ") {
+            if let Some(varname) = parse_unset_template_variable(remainder, common)? {
+                self.set_variable(&varname, None);
+            }
+            Ok(())
+        } else if contents == "tdux:pushVariableScope" {
+            self.variable_scopes.push();
+            Ok(())
+        } else if contents == "tdux:popVariableScope" {
+            match self.variable_scopes.pop() {
+                Some(saved) => {
+                    for (varname, value) in saved {
+                        match value {
+                            Some(value) => self.variables.insert(varname, value),
+                            None => self.variables.remove(&varname),
+                        };
+                    }
+                    Ok(())
+                }
+                None => warn_unmatched_variable_scope(common),
+            }
+        } else if let Some(remainder) = contents.strip_prefix("tdux:setPageMeta ") {
+            handle_set_page_meta(&mut self.page_meta, remainder);
+            Ok(())
+        } else if let Some(args) = contents.strip_prefix("color ") {
+            handle_color_special(&mut self.colors, args, common)
+        } else if let Some(tag) = contents.strip_prefix("tdux:setLanguage ") {
+            if check_language_tag(tag, common)? {
+                self.language = Some(tag.to_owned());
+            }
+            Ok(())
+        } else if let Some(size) = contents.strip_prefix("tdux:setBodyFontSize ") {
+            if let Some(size) = parse_body_font_size(size, common)? {
+                self.body_font_size = Some(size);
+            }
+            Ok(())
+        } else if let Some(remainder) = contents.strip_prefix("tdux:setFontFallback ") {
+            handle_font_fallback(&mut self.fonts, remainder, common)
+        } else if let Some(dir) = contents.strip_prefix("tdux:templateSearchPath ") {
+            common.add_template_search_dir(dir)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideFile ") {
+            // We can't copy files until initialization is finished, so save
+            // these for later.
+            self.defer(DeferredSpecial::ProvideFile(remainder.to_owned()))
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideDirectory ") {
+            self.defer(DeferredSpecial::ProvideDirectory(remainder.to_owned()))
+        } else if let Some((kind, remainder)) = LinkedAssetKind::parse_special(contents) {
+            self.defer(DeferredSpecial::LinkedAsset(kind, remainder.to_owned()))
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideDataChunk ") {
+            self.defer(DeferredSpecial::ProvideData(remainder.to_owned(), false))
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideData ") {
+            self.defer(DeferredSpecial::ProvideData(remainder.to_owned(), true))
+        } else if let Some(remainder) = contents.strip_prefix("tdux:tocEntry ") {
+            // Links can't be made until initialization is finished either.
+            self.defer(DeferredSpecial::TocEntry(remainder.to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Save a special to be handled once initialization is finished.
+    fn defer(&mut self, special: DeferredSpecial) -> Result<()> {
+        self.deferred_specials.push(special);
+        Ok(())
+    }
+
+    fn handle_add_template(&mut self, texpath: &str, common: &mut Common) -> Result<()> {
+        let (resolved, mut ih) = match atry!(
+            common.search_input(texpath);
+            ["unable to open input HTML template `{}`", texpath]
+        ) {
+            Some(found) => found,
+            None => {
+                self.missing_inputs
+                    .push(common.describe_missing(texpath, "HTML template"));
+                return Ok(());
+            }
+        };
+
+        let mut contents = String::new();
+        ih.read_to_string(&mut contents)
+            .map_err(|e| Spx2HtmlError::Io {
+                path: resolved.clone(),
+                source: e,
+            })?;
+
+        if resolved != texpath {
+            tt_note!(
+                common.status,
+                "using `{}` for HTML template `{}`",
+                resolved,
+                texpath
+            );
+        }
+
+        // If specials come from several sources, the same template may be
+        // added more than once. That's harmless unless the contents differ,
+        // in which case the last one added wins.
+
+        if self
+            .templates
+            .get(&resolved)
+            .is_some_and(|t| *t != contents)
+        {
+            let err = Spx2HtmlError::TemplateRedefined {
+                name: resolved.clone(),
+            };
+
+            if common.options.strict {
+                return Err(err.into());
+            }
+
+            tt_warning!(common.status, "{}; using the one added last", err);
+        }
+
+        self.driver_templates.remove(&resolved);
+        self.templates.insert(resolved, contents);
+
+        let (name, digest_opt) = ih.into_name_digest();
+        common
+            .hooks
+            .event_input_closed(name, digest_opt, &mut common.status);
+        Ok(())
+    }
+
+    fn handle_set_template(
+        &mut self,
+        texpath: &str,
+        once: bool,
+        _common: &mut Common,
+    ) -> Result<()> {
+        if once {
+            self.once_template_path = Some(texpath.to_owned());
+        } else {
+            self.next_template_path = texpath.to_owned();
+            self.once_template_path = None;
+        }
+
+        if !self.referenced_templates.iter().any(|t| t == texpath) {
+            self.referenced_templates.push(texpath.to_owned());
+        }
+
+        Ok(())
+    }
+
+    fn handle_set_output_path(&mut self, texpath: &str, _common: &mut Common) -> Result<()> {
+        self.next_output_path = texpath.to_owned();
+        self.output_path_pattern = None;
+        Ok(())
+    }
+
+    fn handle_set_template_variable(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
+        match specials::split_args(remainder, 2) {
+            Ok(args) => {
+                self.set_variable(&args[0], Some(args[1].to_string()));
+                Ok(())
+            }
+            Err(problem) => common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:setTemplateVariable special `{}`: {}",
+                    remainder, problem
+                ),
+            ),
+        }
+    }
+
+    /// Set or unset a template variable, saving its previous state in the
+    /// innermost variable scope.
+    fn set_variable(&mut self, varname: &str, value: Option<String>) {
+        let variables = &self.variables;
+        self.variable_scopes
+            .save(varname, || variables.get(varname).cloned());
+        self.variables.insert(varname.to_owned(), value);
+    }
+
+    fn initialization_finished(mut self, common: &mut Common) -> Result<EmittingState> {
+        // Make sure that the templates that we know we'll need actually exist,
+        // so that we don't fail partway through a long emission. This only
+        // probes for their existence; they're read when they're used.
+
+        for texpath in std::mem::take(&mut self.referenced_templates) {
+            if !self.templates.contains_key(&texpath) && common.search_input(&texpath)?.is_none() {
+                self.missing_inputs
+                    .push(common.describe_missing(&texpath, "HTML template"));
+            }
+        }
+
+        if !self.missing_inputs.is_empty() {
+            return Err(Spx2HtmlError::MissingInputs {
+                missing: self.missing_inputs,
+            }
+            .into());
+        }
+
+        if let Some((name, data)) = common.options.last_resort_font()? {
+            self.fonts.set_last_resort_font(&name, data)?;
+        }
+
+        // All of our templates are added in raw form, so there's no need to
+        // point Tera at any filesystem location. Tera compiles them all in one
+        // go, so parse them individually first to learn which one is bad in
+        // case of a syntax error.
+
+        for (name, text) in &self.templates {
+            if let Err(e) = tera::Template::new(name, None, text) {
+                return Err(Spx2HtmlError::TemplateCompile {
+                    name: name.clone(),
+                    source: e,
+                }
+                .into());
+            }
+        }
+
+        let mut tera = tera::Tera::default();
+
+        tera.add_raw_templates(self.templates.iter()).map_err(|e| {
+            Spx2HtmlError::TemplateCompile {
+                name: self
+                    .templates
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                source: e,
+            }
+        })?;
+
+        let mut inventory: Vec<_> = self
+            .templates
+            .iter()
+            .map(|(name, text)| {
+                let mut dc = digest::create();
+                dc.update(text.as_bytes());
+                TemplateInfo {
+                    name: name.clone(),
+                    digest: DigestData::from(dc).to_string(),
+                }
+            })
+            .collect();
+        inventory.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        common.progress(|p| p.on_templates_compiled(&inventory));
+
+        let assets = Assets::new(common.out.clone());
+        assets::register_functions(&mut tera, &assets);
+        let body_font_size = self
+            .body_font_size
+            .or(common.options.body_font_size)
+            .unwrap_or(self.main_body_font_size);
+        let rems_per_tex = 1.0 / (body_font_size as f32);
+        metrics::register_function(&mut tera, metrics::collect(&self.fonts, rems_per_tex));
+
+        // Set up the context.
+
+        let mut context = tera::Context::default();
+
+        for (varname, varvalue) in &common.options.variables {
+            context.insert(varname, varvalue);
+        }
+
+        for (varname, varvalue) in self.variables {
+            match varvalue {
+                Some(varvalue) => context.insert(varname, &varvalue),
+                None => {
+                    context.remove(&varname);
+                }
+            }
+        }
+
+        // The scopes still open carry over, but in terms of the context. A
+        // variable that the document hadn't touched reverts to the driver's
+        // value.
+
+        let variable_scopes = self.variable_scopes.map(|varname, saved| match saved {
+            Some(value) => value.map(tera::Value::String),
+            None => common
+                .options
+                .variables
+                .iter()
+                .rev()
+                .find(|(n, _)| n == varname)
+                .map(|(_, value)| value.clone()),
+        });
+
+        context.insert("tduxPrintCss", content::PRINT_CSS);
+        context.insert("tduxRequiredCss", &html::required_css());
+        context.insert("tduxUrlStyle", &common.options.url_style);
+
+        if let Some(language) = &self.language {
+            context.insert("tduxLanguage", language);
+        }
+
+        let templates = &self.templates;
+        let driver_templates = self
+            .driver_templates
+            .into_iter()
+            .map(|name| {
+                let contents = templates[&name].clone();
+                (name, contents)
+            })
+            .collect();
+
+        // All done!
+
+        Ok(EmittingState {
+            tera: Arc::new(Mutex::new(tera)),
+            driver_templates,
+            context,
+            variable_scopes,
+            assets,
+            css_files: Vec::new(),
+            js_files: Vec::new(),
+            pending_data: HashMap::new(),
+            fonts: self.fonts,
+            body_font_size,
+            rems_per_tex,
+            main_body_fd_key: self.main_body_fd_key,
+            next_template_path: self.next_template_path,
+            once_template_path: self.once_template_path,
+            next_output_path: self.next_output_path,
+            output_path_pattern: self.output_path_pattern,
+            explicit_output_path: false,
+            n_emitted: 0,
+            emitted_paths: HashSet::new(),
+            content: ContentSink::new(common.options),
+            outline: Vec::new(),
+            toc: Vec::new(),
+            page_fonts: BTreeSet::new(),
+            emitted_fonts: HashSet::new(),
+            chars: CharTally::default(),
+            usage: UsageTally::default(),
+            layout: Vec::new(),
+            font_faces: String::new(),
+            variant_faces_start: 0,
+            last_resort_emitted: false,
+            #[cfg(feature = "fallback-font")]
+            last_resort_chars: BTreeSet::new(),
+            page_has_canvas: false,
+            current_canvas: None,
+            direct_lines: None,
+            content_finished: false,
+            content_finished_warning_issued: false,
+            content_reset: false,
+            no_template_warning_issued: false,
+            page_meta: self.page_meta,
+            colors: self.colors,
+            xrefs: Default::default(),
+            spill: SpillArea::new(common.options.spill_threshold),
+            open_hrefs: 0,
+            open_languages: 0,
+            deferring: None,
+            footnote_mark: None,
+            footnotes: Vec::new(),
+            deferred_streams: BTreeSet::new(),
+            pending_classes: Vec::new(),
+            pending_styles: Vec::new(),
+            pending_alt: None,
+            pdf_link: None,
+            pdf_ignored: Default::default(),
+            stats: self.stats,
+        })
+    }
+}
+
+/// Parse a list of `name=value` attributes, where the values may be enclosed
+/// in double or single quotes. Malformed and duplicated attributes are
+/// skipped, and descriptions of the problems are returned along with the
+/// attributes.
+fn parse_attributes(text: &str) -> (Vec<(String, String)>, Vec<String>) {
+    let mut attrs = Vec::new();
+    let mut problems = Vec::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let eq = rest[..end].find('=');
+
+        let (name, value, remainder) = match eq {
+            None => (&rest[..end], None, &rest[end..]),
+
+            Some(eq) => {
+                let after = &rest[eq + 1..];
+
+                let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'');
+
+                if let Some(quote) = quote {
+                    let quoted = &after[1..];
+
+                    match quoted.find(quote) {
+                        Some(close) => (&rest[..eq], Some(&quoted[..close]), &quoted[close + 1..]),
+                        None => {
+                            problems
+                                .push(format!("unterminated quoted value in attribute `{}`", rest));
+                            break;
+                        }
+                    }
+                } else {
+                    (&rest[..eq], Some(&rest[eq + 1..end]), &rest[end..])
+                }
+            }
+        };
+
+        match value {
+            Some(_) if attrs.iter().any(|(n, _)| n == name) => {
+                problems.push(format!("ignoring duplicate attribute `{}`", name))
+            }
+            Some(v) if content::is_attribute_name(name) => {
+                attrs.push((name.to_owned(), v.to_owned()))
+            }
+            _ => problems.push(format!(
+                "ignoring malformed attribute `{}`",
+                &rest[..rest.len() - remainder.len()]
+            )),
+        }
+
+        rest = remainder.trim_start();
+    }
+
+    (attrs, problems)
+}
+
+/// Percent-decode a path given in a special, which is how paths can contain
+/// spaces. Sequences that aren't valid UTF-8 are replaced.
+fn decode_path(path: &str) -> Cow<'_, str> {
+    percent_decode_str(path).decode_utf8_lossy()
+}
+
+/// Decode the standard base64 encoding of the payload of a
+/// `tdux:provideData` special. Whitespace is ignored, and the padding at the
+/// end is optional.
+///
+/// Returns a description of the problem if the text isn't valid base64.
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+
+    base64::decode(&text).map_err(|e| match e {
+        base64::DecodeError::InvalidByte(_, byte) => {
+            format!("`{}` isn't a base64 digit", byte as char)
+        }
+        base64::DecodeError::InvalidLength => "it's truncated".to_owned(),
+        base64::DecodeError::InvalidLastSymbol(_, byte) => {
+            format!("its last digit `{}` has bits left over", byte as char)
+        }
+    })
+}
+
+/// Check the tag of a `tdux:setLanguage` special, which should be a BCP 47
+/// language tag like `en` or `fr-CA`. This only checks that it consists of
+/// ASCII letters, digits, and hyphens.
+///
+/// Returns false if the special should be ignored.
+fn check_language_tag(tag: &str, common: &mut Common) -> Result<bool> {
+    if tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Ok(true);
+    }
+
+    common.warn_or_fail(
+        WarningCategories::MALFORMED_SPECIALS,
+        format_args!(
+            "ignoring tdux:setLanguage special with invalid language tag `{}`",
+            tag
+        ),
+    )?;
+    Ok(false)
+}
+
+/// Collect the paths of the files underneath the directory *dir*, relative
+/// to the directory given to `tdux:provideDirectory` and prefixed with
+/// *prefix*, in sorted order.
+///
+/// Symbolic links and names that aren't valid UTF-8 are skipped with a
+/// warning.
+fn collect_directory_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<String>,
+    common: &mut Common,
+) -> Result<()> {
+    let io_error = |e| Spx2HtmlError::Io {
+        path: dir.display().to_string(),
+        source: e,
+    };
+
+    let mut entries = std::fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .map_err(io_error)?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(n) => {
+                tt_warning!(
+                    common.status,
+                    "tdux:provideDirectory is skipping `{}`, whose name isn't valid UTF-8",
+                    dir.join(n).display()
+                );
+                continue;
+            }
+        };
+
+        let rel_path = format!("{}{}", prefix, name);
+        let file_type = entry.file_type().map_err(io_error)?;
+
+        if file_type.is_symlink() {
+            tt_warning!(
+                common.status,
+                "tdux:provideDirectory is skipping `{}`, which is a symbolic link",
+                entry.path().display()
+            );
+        } else if file_type.is_dir() {
+            collect_directory_files(&entry.path(), &format!("{}/", rel_path), files, common)?;
+        } else {
+            files.push(rel_path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle `tdux:setPageMeta`, whose arguments are a key and a value, which
+/// may contain spaces.
+fn handle_set_page_meta(page_meta: &mut BTreeMap<String, String>, remainder: &str) {
+    // Normalization guarantees that both arguments are there.
+    if let Some((key, value)) = remainder.split_once(' ') {
+        page_meta.insert(key.to_owned(), value.to_owned());
+    }
+}
+
+/// Parse the name of the variable of a `tdux:unsetTemplateVariable` special,
+/// which may be quoted. Unsetting a variable that isn't set does nothing.
+fn parse_unset_template_variable(remainder: &str, common: &mut Common) -> Result<Option<String>> {
+    match specials::split_args(remainder, 1) {
+        Ok(mut args) => Ok(args.pop().map(Cow::into_owned)),
+        Err(problem) => {
+            common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:unsetTemplateVariable special `{}`: {}",
+                    remainder, problem
+                ),
+            )?;
+            Ok(None)
+        }
+    }
+}
+
+/// Warn about a `tdux:popVariableScope` with no open scope to pop.
+fn warn_unmatched_variable_scope(common: &mut Common) -> Result<()> {
+    common.warn_or_fail(
+        WarningCategories::MALFORMED_SPECIALS,
+        format_args!(
+            "ignoring tdux:popVariableScope special with no matching tdux:pushVariableScope"
+        ),
+    )
+}
+
+/// Handle a `color` special of the standard `color` package, whose arguments
+/// are `push <spec>` or `pop`.
+fn handle_color_special(colors: &mut ColorStack, args: &str, common: &mut Common) -> Result<()> {
+    match colors.handle(args) {
+        Ok(()) => Ok(()),
+        Err(problem) => common.warn_or_fail(
+            WarningCategories::MALFORMED_SPECIALS,
+            format_args!("ignoring special `color {}`: {}", args, problem),
+        ),
+    }
+}
+
+/// Parse the argument of `tdux:setBodyFontSize`, a positive size in TeX
+/// scaled points, warning if it's malformed.
+fn parse_body_font_size(text: &str, common: &mut Common) -> Result<Option<FixedPoint>> {
+    match text.parse::<FixedPoint>() {
+        Ok(size) if size > 0 => Ok(Some(size)),
+        _ => {
+            common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:setBodyFontSize special `{}`",
+                    text
+                ),
+            )?;
+            Ok(None)
+        }
+    }
+}
+
+/// Handle `tdux:setFontFallback`, whose arguments are a font family key and a
+/// CSS font family list.
+fn handle_font_fallback(
+    fonts: &mut FontLookup,
+    remainder: &str,
+    common: &mut Common,
+) -> Result<()> {
+    let (family, stack) = match remainder.split_once(' ') {
+        Some((f, s)) if !s.trim().is_empty() => (f, s.trim()),
+        _ => {
+            return common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:setFontFallback special `{}`",
+                    remainder
+                ),
+            );
+        }
+    };
+
+    // The stack ends up in CSS declarations, so don't let it break out of
+    // them.
+    if stack.contains([';', '{', '}', '<', '>', '\\']) {
+        return common.warn_or_fail(
+            WarningCategories::MALFORMED_SPECIALS,
+            format_args!(
+                "ignoring font fallback stack `{}` for `{}`: it contains characters not allowed in a font family list",
+                stack,
+                family
+            ),
+        );
+    }
+
+    fonts.set_fallback(family, stack);
+    Ok(())
+}
+
+/// Check whether a special is one of the ones that define table structure.
+fn is_table_special(contents: &str) -> bool {
+    let name = contents.split(' ').next().unwrap_or_default();
+
+    matches!(
+        name,
+        "tdux:tableStart"
+            | "tdux:tableEnd"
+            | "tdux:rowStart"
+            | "tdux:rowEnd"
+            | "tdux:cellStart"
+            | "tdux:cellEnd"
+    )
+}
+
+/// The kinds of files that templates link to automatically.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LinkedAssetKind {
+    /// A stylesheet registered with `tdux:addCss`, listed in `tduxCssFiles`.
+    Css,
+
+    /// A script registered with `tdux:addJs`, listed in `tduxJsFiles`.
+    Js,
+}
+
+impl LinkedAssetKind {
+    /// Identify a `tdux:addCss` or `tdux:addJs` special, returning the kind
+    /// of file that it registers and its arguments.
+    fn parse_special(contents: &str) -> Option<(Self, &str)> {
+        if let Some(args) = contents.strip_prefix("tdux:addCss ") {
+            Some((LinkedAssetKind::Css, args))
+        } else {
+            contents
+                .strip_prefix("tdux:addJs ")
+                .map(|args| (LinkedAssetKind::Js, args))
+        }
+    }
+
+    /// The name of the special that registers this kind of file.
+    fn special(self) -> &'static str {
+        match self {
+            LinkedAssetKind::Css => "tdux:addCss",
+            LinkedAssetKind::Js => "tdux:addJs",
+        }
+    }
+}
+
+/// Decode the source path of a `tdux:provideFile` special or the like, which
+/// is left alone if it's a remote URL.
+fn decode_source_path(arg: &str) -> Cow<'_, str> {
+    if remote::is_remote(arg) {
+        Cow::Borrowed(arg)
+    } else {
+        decode_path(arg)
+    }
+}
+
+/// Check whether a special is one of the ones that pass HTML through
+/// verbatim.
+fn is_direct_special(contents: &str) -> bool {
+    let name = contents.split(' ').next().unwrap_or_default();
+
+    matches!(
+        name,
+        "tdux:direct" | "tdux:directStart" | "tdux:directLine" | "tdux:directEnd"
+    )
+}
+
+/// Parse the argument of a `tdux:setOutputPathPattern` special, warning and
+/// returning None if it's invalid.
+fn parse_output_path_pattern(text: &str, common: &mut Common) -> Result<Option<OutputPathPattern>> {
+    match OutputPathPattern::parse(text) {
+        Ok(p) => Ok(Some(p)),
+        Err(e) => {
+            common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!("ignoring invalid output path pattern: {}", e),
+            )?;
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EmittingState {
+    tera: Arc<Mutex<tera::Tera>>,
+
+    /// The templates added by the application, which don't exist as inputs.
+    driver_templates: HashMap<String, String>,
+
+    context: tera::Context,
+
+    /// The variable scopes opened with `tdux:pushVariableScope`.
+    variable_scopes: VariableScopes<tera::Value>,
+
+    assets: Assets,
+
+    /// The output paths of the stylesheets and scripts registered with
+    /// `tdux:addCss` and `tdux:addJs`, in order, relative to the output root.
+    css_files: Vec<String>,
+    js_files: Vec<String>,
+
+    /// The base64 payloads given so far with `tdux:provideDataChunk`, keyed
+    /// by their normalized destination paths.
+    pending_data: HashMap<String, String>,
+
+    fonts: FontLookup,
+
+    /// The size of the main body font, which rems correspond to.
+    body_font_size: FixedPoint,
+
+    rems_per_tex: f32,
+    main_body_fd_key: usize,
+    next_template_path: String,
+    once_template_path: Option<String>,
+    next_output_path: String,
+    output_path_pattern: Option<OutputPathPattern>,
+    explicit_output_path: bool,
+    n_emitted: usize,
+    emitted_paths: HashSet<String>,
+    content: ContentSink,
+    outline: Vec<OutlineEntry>,
+
+    /// The table of contents given with `tdux:tocEntry`, which templates get
+    /// in full for every file, as `tduxToc`.
+    toc: Vec<TocEntry>,
+    page_fonts: BTreeSet<usize>,
+    emitted_fonts: HashSet<usize>,
+    chars: CharTally,
+    usage: UsageTally,
+    layout: Vec<CanvasLayout>,
+    font_faces: String,
+
+    /// The length of `font_faces` before the rules of the variant fonts were
+    /// added at `tdux:contentFinished`. The variants are emitted anew each
+    /// time that the content is finished, so `tdux:resetContent` drops their
+    /// rules.
+    variant_faces_start: usize,
+
+    last_resort_emitted: bool,
+
+    /// The characters that the subset of the font of last resort must cover.
+    #[cfg(feature = "fallback-font")]
+    last_resort_chars: BTreeSet<char>,
+
+    page_has_canvas: bool,
+    current_canvas: Option<CanvasState>,
+    direct_lines: Option<Vec<String>>,
+
+    /// The metadata of the output file being built, set with
+    /// `tdux:setPageMeta`.
+    page_meta: BTreeMap<String, String>,
+
+    /// The colors pushed with the specials of the `color` package.
+    colors: ColorStack,
+
+    xrefs: CrossRefs,
+
+    /// Where the output files held back by `xrefs` are kept.
+    spill: SpillArea,
+
+    /// The number of links started with `tdux:hrefStart` that haven't been
+    /// ended yet.
+    open_hrefs: usize,
+
+    /// The number of language scopes started with `tdux:setLanguage` that
+    /// haven't been ended yet.
+    open_languages: usize,
+
+    /// The name of the stream that content is being deferred to with
+    /// `tdux:deferStart`, if any.
+    deferring: Option<String>,
+
+    /// The mark of the footnote being collected with `tdux:footnoteStart`,
+    /// if any.
+    footnote_mark: Option<String>,
+
+    /// The footnotes of the output file being built.
+    footnotes: Vec<Footnote>,
+
+    /// The names of all of the streams that content has been deferred to so
+    /// far, which every later output file gets in `tduxDeferred`.
+    deferred_streams: BTreeSet<String>,
+
+    /// Class names set with `tdux:class`, for the next element started with
+    /// `tdux:as` or canvas started with `tdux:cs`.
+    pending_classes: Vec<String>,
+
+    /// CSS declarations set with `tdux:style`, for the next element started
+    /// with `tdux:as` or `tdux:asp` or canvas started with `tdux:cs`.
+    pending_styles: Vec<String>,
+
+    /// The text alternative set with `tdux:canvasAlt`, for the next canvas
+    /// started with `tdux:cs`.
+    pending_alt: Option<String>,
+
+    /// Whether a `pdf:bann` link annotation is open, and if so, whether it
+    /// produced an `<a>` element.
+    pdf_link: Option<bool>,
+
+    /// The kinds of things in `pdf:bann` link annotations that have been
+    /// ignored with a warning.
+    pdf_ignored: HashSet<String>,
+
+    content_finished: bool,
+    content_finished_warning_issued: bool,
+
+    /// Whether the content has been reset with `tdux:resetContent`, so that
+    /// it may be finished more than once.
+    content_reset: bool,
+
+    no_template_warning_issued: bool,
+    stats: ProcessingStats,
+}
+
+/// A heading in the document outline, which covers all of the output files
+/// emitted so far.
+#[derive(Debug, Serialize)]
+struct OutlineEntry {
+    level: u8,
+    id: String,
+    text: String,
+    path: String,
+    url: String,
+}
+
+impl OutlineEntry {
+    fn new(heading: &HeadingInfo, path: &str, url: &str) -> Self {
+        OutlineEntry {
+            level: heading.level,
+            id: heading.id.clone(),
+            text: heading.text.clone(),
+            path: path.to_owned(),
+            url: url.to_owned(),
+        }
+    }
+}
+
+/// A footnote of an output file, collected between `tdux:footnoteStart` and
+/// `tdux:footnoteEnd`.
+#[derive(Debug, Serialize)]
+struct Footnote {
+    mark: String,
+    html: String,
+
+    /// The id that the footnote should have, which its call links to.
+    id: String,
+
+    /// The id of the call, which the footnote should link back to.
+    ref_id: String,
+}
+
+/// An entry of the table of contents, given with `tdux:tocEntry`.
+#[derive(Debug, Serialize)]
+struct TocEntry {
+    level: i32,
+    title: String,
+
+    /// The placeholder URL of the entry's anchor, which is resolved relative
+    /// to each output file that it's rendered into.
+    url: String,
+}
+
+/// The layout of a canvas, as recorded in layout sidecars. The serialized
+/// names of the fields are pinned, since they're part of the sidecar format.
+#[derive(Debug, Serialize)]
+struct CanvasLayout {
+    #[serde(rename = "id")]
+    id: String,
+    #[serde(rename = "kind")]
+    kind: String,
+    #[serde(rename = "x")]
+    x: i32,
+    #[serde(rename = "y")]
+    y: i32,
+    #[serde(flatten)]
+    size: CanvasSize,
+    #[serde(rename = "glyphs", skip_serializing_if = "Option::is_none")]
+    glyphs: Option<Vec<DrawnGlyph>>,
+}
+
+/// The contents of a canvas, or of a canvas nested inside of it.
+#[derive(Debug)]
+struct CanvasScope {
+    kind: String,
+    x0: i32,
+    y0: i32,
+
+    /// The glyphs, positioned relative to the origin of this scope.
+    glyphs: Vec<PositionedGlyph>,
+
+    /// The rules, positioned likewise.
+    rules: Vec<PositionedRule>,
+}
+
+impl CanvasScope {
+    fn new(kind: &str, x0: i32, y0: i32) -> Self {
+        CanvasScope {
+            kind: kind.to_owned(),
+            x0,
+            y0,
+            glyphs: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Merge the contents of a nested scope into this one, repositioning
+    /// them relative to the origin of this scope.
+    fn fold(&mut self, child: CanvasScope) {
+        let dx = child.x0 - self.x0;
+        let dy = child.y0 - self.y0;
+
+        self.glyphs
+            .extend(child.glyphs.into_iter().map(|g| PositionedGlyph {
+                dx: g.dx + dx,
+                dy: g.dy + dy,
+                ..g
+            }));
+        self.rules
+            .extend(child.rules.into_iter().map(|r| PositionedRule {
+                dx: r.dx + dx,
+                dy: r.dy + dy,
+                ..r
+            }));
+    }
+}
+
+#[derive(Debug)]
+struct CanvasState {
+    classes: Option<String>,
+    style: Option<String>,
+    alt: Option<String>,
+
+    /// The outermost canvas, which is the one that gets rendered.
+    outer: CanvasScope,
+
+    /// The canvases started inside of the outermost one that haven't been
+    /// ended yet, innermost last. Each one's contents are folded into its
+    /// parent when it ends.
+    nested: Vec<CanvasScope>,
+}
+
+impl CanvasState {
+    fn new(
+        kind: &str,
+        classes: Option<String>,
+        style: Option<String>,
+        alt: Option<String>,
+        x0: i32,
+        y0: i32,
+    ) -> Self {
+        CanvasState {
+            classes,
+            style,
+            alt,
+            outer: CanvasScope::new(kind, x0, y0),
+            nested: Vec::new(),
+        }
+    }
+
+    /// Get the innermost open scope, to which content is added.
+    fn innermost(&mut self) -> &mut CanvasScope {
+        self.nested.last_mut().unwrap_or(&mut self.outer)
+    }
+
+    /// End the innermost nested scope, folding it into its parent. Returns
+    /// false if there's none, in which case the outermost canvas is ending.
+    fn end_nested(&mut self) -> bool {
+        match self.nested.pop() {
+            Some(child) => {
+                self.innermost().fold(child);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl EmittingState {
+    fn warn_finished_content(&mut self, detail: &str, common: &mut Common) -> Result<()> {
+        self.stats.dropped_content += 1;
+
+        if !self.content_finished_warning_issued {
+            common.warn_or_fail(
+                WarningCategories::DROPPED_CONTENT,
+                format_args!("dropping post-finish content ({})", detail),
+            )?;
+            self.content_finished_warning_issued = true;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a font definition seen after emission has started.
+    ///
+    /// The fonts are normally all defined in the postamble, which is read
+    /// first, and the definitions within the pages just repeat them. A new font
+    /// can only show up here if the SPX stream is malformed, but it can still
+    /// be used, if not as the main body font.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_define_native_font(
+        &mut self,
+        name: &str,
+        font_num: i32,
+        size: FixedPoint,
+        face_index: u32,
+        color_rgba: Option<u32>,
+        extend: Option<u32>,
+        slant: Option<u32>,
+        embolden: Option<u32>,
+        common: &mut Common,
+    ) -> Result<()> {
+        if self.fonts.contains(font_num) {
+            return Ok(());
+        }
+
+        if self.content_finished {
+            self.warn_finished_content(&format!("definition of font `{}`", name), common)?;
+            return Ok(());
+        }
+
+        tt_warning!(
+            common.status,
+            "font `{}` was defined after content started; it can't be the main body font, \
+             and templates can't look up its metrics",
+            name
+        );
+
+        let loaded = load_font(
+            &mut self.fonts,
+            name,
+            font_num,
+            size,
+            face_index,
+            color_rgba,
+            extend,
+            slant,
+            embolden,
+            common,
+        )?;
+
+        if loaded.is_none() {
+            return Err(Spx2HtmlError::MissingInputs {
+                missing: vec![format!("font `{}`", name)],
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Handle a `tdux:addTemplate` seen after emission has started.
+    ///
+    /// The template can't take part in the checks done when initialization
+    /// finishes, but it can still be used.
+    fn handle_add_template(&mut self, texpath: &str, common: &mut Common) -> Result<()> {
+        tt_warning!(
+            common.status,
+            "HTML template `{}` was added after content started; \
+             this usually means that the SPX stream is malformed",
+            texpath
+        );
+
+        let (resolved, mut ih) = common.open_searched_input(texpath, "HTML template")?;
+
+        let mut contents = String::new();
+        ih.read_to_string(&mut contents)
+            .map_err(|e| Spx2HtmlError::Io {
+                path: resolved.clone(),
+                source: e,
+            })?;
+
+        let (name, digest_opt) = ih.into_name_digest();
+        common
+            .hooks
+            .event_input_closed(name, digest_opt, &mut common.status);
+
+        render::lock(&self.tera)
+            .add_raw_template(&resolved, &contents)
+            .map_err(|e| Spx2HtmlError::TemplateCompile {
+                name: resolved.clone(),
+                source: e,
+            })?;
+        self.driver_templates.remove(&resolved);
+        Ok(())
+    }
+
+    fn handle_special(
+        &mut self,
+        x: i32,
+        y: i32,
+        contents: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        self.stats.add_special(contents);
+
+        if let Some(texpath) = contents.strip_prefix("tdux:addTemplate ") {
+            self.handle_add_template(&decode_path(texpath), common)
+        } else if let Some(element) = contents.strip_prefix("tdux:as ") {
+            if self.content_finished {
+                self.warn_finished_content(&format!("auto start tag <{}>", element), common)?;
+            } else {
+                let classes = self.take_pending_classes();
+                let style = self.take_pending_style();
+                let mut attrs = Vec::new();
+
+                if let Some(classes) = classes.as_deref() {
+                    attrs.push(("class", classes));
+                }
+
+                if let Some(style) = style.as_deref() {
+                    attrs.push(("style", style));
+                }
+
+                let problems = if attrs.is_empty() {
+                    self.content.open_tag(element)
+                } else {
+                    self.content.open_tag_with_attrs(element, &attrs)
+                };
+
+                for problem in problems {
+                    tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                }
+            }
+            Ok(())
+        } else if let Some(style) = contents.strip_prefix("tdux:style ") {
+            if self.content_finished {
+                self.warn_finished_content("style declarations", common)?;
+            } else {
+                let style = style.trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+
+                if !style.is_empty() {
+                    self.pending_styles.push(style.to_owned());
+                }
+            }
+            Ok(())
+        } else if let Some(alt) = contents.strip_prefix("tdux:canvasAlt ") {
+            if self.content_finished {
+                self.warn_finished_content("canvas text alternative", common)?;
+            } else {
+                self.pending_alt = Some(alt.to_owned());
+            }
+            Ok(())
+        } else if let Some(names) = contents.strip_prefix("tdux:class ") {
+            if self.content_finished {
+                self.warn_finished_content("class names", common)?;
+            } else {
+                self.pending_classes
+                    .extend(names.split_whitespace().map(|s| s.to_owned()));
+            }
+            Ok(())
+        } else if let Some(args) = contents.strip_prefix("tdux:asp ") {
+            if self.content_finished {
+                let element = args.split(' ').next().unwrap_or_default();
+                self.warn_finished_content(&format!("auto start tag <{}>", element), common)?;
+            } else {
+                for problem in self.handle_start_with_attrs(args) {
+                    tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                }
+            }
+            Ok(())
+        } else if let Some(element) = contents.strip_prefix("tdux:ae ") {
+            if self.content_finished {
+                self.warn_finished_content(&format!("auto end tag </{}>", element), common)?;
+            } else {
+                for problem in self.content.close_tag(element) {
+                    tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                }
+            }
+            Ok(())
+        } else if let Some(args) = contents.strip_prefix("tdux:asSemantic ") {
+            if self.content_finished {
+                self.warn_finished_content("semantic start tag", common)?;
+            } else {
+                for problem in self.handle_semantic_start(args) {
+                    tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                }
+            }
+            Ok(())
+        } else if let Some(alias) = contents.strip_prefix("tdux:aeSemantic ") {
+            if self.content_finished {
+                self.warn_finished_content("semantic end tag", common)?;
+            } else if let Some(element) = content::semantic_element(alias.trim()) {
+                for problem in self.content.close_tag(element) {
+                    tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                }
+            } else {
+                common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring unrecognized semantic element `{}` (special `{}`)",
+                        alias, contents
+                    ),
+                )?;
+            }
+            Ok(())
+        } else if contents == "tdux:printBreakBefore" {
+            if self.content_finished {
+                self.warn_finished_content("print break", common)?;
+            } else {
+                self.content.print_break();
+            }
+            Ok(())
+        } else if let Some(kind) = contents.strip_prefix("tdux:cs ") {
+            if self.content_finished {
+                self.warn_finished_content("canvas start", common)?;
+            } else if let Some(canvas) = self.current_canvas.as_mut() {
+                canvas.nested.push(CanvasScope::new(kind, x, y));
+            } else {
+                let classes = self.take_pending_classes();
+                let style = self.take_pending_style();
+                let alt = self.pending_alt.take();
+                self.current_canvas = Some(CanvasState::new(kind, classes, style, alt, x, y));
+            }
+            Ok(())
+        } else if let Some(kind) = contents.strip_prefix("tdux:ce ") {
+            if self.content_finished {
+                self.warn_finished_content("canvas end", common)?;
+            } else if let Some(canvas) = self.current_canvas.as_mut() {
+                let open_kind = canvas.innermost().kind.clone();
+                let nested = canvas.end_nested();
+
+                if kind != open_kind {
+                    common.warn_or_fail(
+                        WarningCategories::UNPAIRED_CANVASES,
+                        format_args!(
+                            "tdux:c[anvas]e[nd] special `{}` ends a canvas of kind `{}`",
+                            contents, open_kind
+                        ),
+                    )?;
+                }
+
+                if !nested {
+                    self.handle_end_canvas(common)?;
+                }
+            } else {
+                common.warn_or_fail(
+                    WarningCategories::UNPAIRED_CANVASES,
+                    format_args!(
+                        "ignoring unpaired tdux:c[anvas]e[nd] special `{}`",
+                        contents
+                    ),
+                )?;
+            }
+            Ok(())
+        } else if is_direct_special(contents) {
+            self.handle_direct_special(contents, common)
+        } else if let Some(name) = contents.strip_prefix("tdux:anchor ") {
+            self.add_anchor(name, contents, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:tocEntry ") {
+            self.handle_toc_entry(remainder, common)
+        } else if let Some(name) = contents.strip_prefix("tdux:refStart ") {
+            self.start_link(LinkTarget::Destination(name.to_owned()), contents, common)
+        } else if contents == "tdux:refEnd" {
+            if self.content_finished {
+                self.warn_finished_content("link end", common)?;
+            } else {
+                for problem in self.content.close_tag("a") {
+                    tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                }
+            }
+            Ok(())
+        } else if let Some(url) = contents.strip_prefix("tdux:hrefStart ") {
+            if !self.content_finished {
+                self.open_hrefs += 1;
+            }
+
+            self.start_link(LinkTarget::Url(url.to_owned()), contents, common)
+        } else if contents == "tdux:hrefEnd" {
+            if self.content_finished {
+                self.warn_finished_content("link end", common)?;
+            } else if self.open_hrefs == 0 {
+                common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!("ignoring tdux:hrefEnd special with no matching tdux:hrefStart"),
+                )?;
+            } else {
+                self.open_hrefs -= 1;
+
+                // If the document closed an element that the link was inside
+                // of, the link was closed along with it.
+                if self.content.is_open("a") {
+                    for problem in self.content.close_tag("a") {
+                        tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                    }
+                }
+            }
+            Ok(())
+        } else if let Some(stream) = contents.strip_prefix("tdux:deferStart ") {
+            if self.content_finished {
+                return self.warn_finished_content("deferred content start", common);
+            }
+
+            if self.footnote_mark.is_some() {
+                return self.warn_in_footnote(contents, common);
+            }
+
+            if let Some(outer) = &self.deferring {
+                return Err(Spx2HtmlError::NestedDefer {
+                    outer: outer.clone(),
+                    inner: stream.to_owned(),
+                }
+                .into());
+            }
+
+            self.content
+                .set_destination(Destination::Region(stream.to_owned()));
+            self.deferred_streams.insert(stream.to_owned());
+            self.deferring = Some(stream.to_owned());
+            Ok(())
+        } else if contents == "tdux:deferEnd" {
+            if self.content_finished {
+                self.warn_finished_content("deferred content end", common)?;
+            } else if self.footnote_mark.is_some() {
+                self.warn_in_footnote(contents, common)?;
+            } else if self.deferring.take().is_some() {
+                self.content.set_destination(Destination::Main);
+            } else {
+                common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!("ignoring tdux:deferEnd special with no matching tdux:deferStart"),
+                )?;
+            }
+            Ok(())
+        } else if let Some(mark) = contents.strip_prefix("tdux:footnoteStart ") {
+            if self.content_finished {
+                self.warn_finished_content("footnote start", common)?;
+            } else if self.footnote_mark.is_some() {
+                self.warn_in_footnote(contents, common)?;
+            } else {
+                self.start_footnote(mark);
+            }
+            Ok(())
+        } else if contents == "tdux:footnoteEnd" {
+            if self.content_finished {
+                self.warn_finished_content("footnote end", common)?;
+            } else if self.footnote_mark.is_some() {
+                self.end_footnote();
+            } else {
+                common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring tdux:footnoteEnd special with no matching tdux:footnoteStart"
+                    ),
+                )?;
+            }
+            Ok(())
+        } else if let Some(tag) = contents.strip_prefix("tdux:setLanguage ") {
+            self.start_language(tag, contents, common)
+        } else if contents == "tdux:endLanguage" {
+            if self.content_finished {
+                self.warn_finished_content("language scope end", common)?;
+            } else if self.open_languages == 0 {
+                common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring tdux:endLanguage special with no matching tdux:setLanguage"
+                    ),
+                )?;
+            } else {
+                self.open_languages -= 1;
+
+                if self.content.is_open("span") {
+                    for problem in self.content.close_tag("span") {
+                        tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                    }
+                }
+            }
+            Ok(())
+        } else if let Some(args) = contents.strip_prefix("pdf:dest ") {
+            match pdf::parse_dest(args) {
+                Some(name) => self.add_anchor(&name, contents, common),
+                None => common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!("ignoring malformatted pdf:dest special `{}`", contents),
+                ),
+            }
+        } else if let Some(args) = contents.strip_prefix("pdf:bann") {
+            self.handle_pdf_link_start(args, contents, common)
+        } else if contents == "pdf:eann" {
+            match self.pdf_link.take() {
+                Some(true) => {
+                    if self.content.is_open("a") {
+                        for problem in self.content.close_tag("a") {
+                            tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+                        }
+                    }
+                    Ok(())
+                }
+                Some(false) => Ok(()),
+                None => common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!("ignoring pdf:eann special with no matching pdf:bann"),
+                ),
+            }
+        } else if let Some(problems) = self.handle_table_special(contents, common)? {
+            for problem in problems {
+                tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+            }
+            Ok(())
+        } else if contents == "tdux:emit" {
+            self.finish_file(false, common)
+        } else if contents == "tdux:emitIfChanged" {
+            self.finish_file(true, common)
+        } else if let Some(texpath) = contents.strip_prefix("tdux:setTemplate ") {
+            self.next_template_path = decode_path(texpath).into_owned();
+            self.once_template_path = None;
+            Ok(())
+        } else if let Some(texpath) = contents.strip_prefix("tdux:setTemplateOnce ") {
+            self.once_template_path = Some(decode_path(texpath).into_owned());
+            Ok(())
+        } else if let Some(texpath) = contents.strip_prefix("tdux:setOutputPath ") {
+            self.next_output_path = decode_path(texpath).into_owned();
+            self.explicit_output_path = true;
+            Ok(())
+        } else if let Some(pattern) = contents.strip_prefix("tdux:setOutputPathPattern ") {
+            self.output_path_pattern = parse_output_path_pattern(pattern, common)?;
+            self.explicit_output_path = false;
+            Ok(())
+        } else if let Some(remainder) = contents.strip_prefix("tdux:setTemplateVariable ") {
+            self.handle_set_template_variable(remainder, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:unsetTemplateVariable ") {
+            if let Some(varname) = parse_unset_template_variable(remainder, common)? {
+                self.set_variable(&varname, None);
+            }
+            Ok(())
+        } else if contents == "tdux:pushVariableScope" {
+            self.variable_scopes.push();
+            Ok(())
+        } else if contents == "tdux:popVariableScope" {
+            match self.variable_scopes.pop() {
+                Some(saved) => {
+                    for (varname, value) in saved {
+                        match value {
+                            Some(value) => self.context.insert(varname, &value),
+                            None => {
+                                self.context.remove(&varname);
+                            }
+                        }
+                    }
+                    Ok(())
+                }
+                None => warn_unmatched_variable_scope(common),
+            }
+        } else if let Some(remainder) = contents.strip_prefix("tdux:setPageMeta ") {
+            handle_set_page_meta(&mut self.page_meta, remainder);
+            Ok(())
+        } else if let Some(args) = contents.strip_prefix("color ") {
+            handle_color_special(&mut self.colors, args, common)
+        } else if let Some(size) = contents.strip_prefix("tdux:setBodyFontSize ") {
+            match parse_body_font_size(size, common)? {
+                Some(size) if size != self.body_font_size => tt_warning!(
+                    common.status,
+                    "ignoring special `{}`: the body font size can't change once \
+                     content has been emitted",
+                    contents
+                ),
+                _ => {}
+            }
+            Ok(())
+        } else if let Some(remainder) = contents.strip_prefix("tdux:setFontFallback ") {
+            handle_font_fallback(&mut self.fonts, remainder, common)
+        } else if let Some(dir) = contents.strip_prefix("tdux:templateSearchPath ") {
+            common.add_template_search_dir(dir)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideFile ") {
+            self.handle_provide_file(remainder, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideDirectory ") {
+            self.handle_provide_directory(remainder, common)
+        } else if let Some((kind, remainder)) = LinkedAssetKind::parse_special(contents) {
+            self.handle_add_linked_asset(kind, remainder, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideDataChunk ") {
+            self.handle_provide_data(remainder, false, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:provideData ") {
+            self.handle_provide_data(remainder, true, common)
+        } else if let Some(remainder) = contents.strip_prefix("tdux:img ") {
+            if self.content_finished {
+                self.warn_finished_content("image", common)
+            } else {
+                self.handle_image(remainder, common)
+            }
+        } else if contents == "tdux:contentFinished" {
+            if self.content_finished {
+                common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring tdux:contentFinished special: the content is already finished"
+                    ),
+                )
+            } else {
+                self.content_finished(common)
+            }
+        } else if contents == "tdux:resetContent" {
+            self.reset_content(common)
+        } else {
+            self.handle_custom_special(x, y, contents, common)
+        }
+    }
+
+    /// Give a special that the engine doesn't know to the handler registered
+    /// for it, if any.
+    fn handle_custom_special(
+        &mut self,
+        x: i32,
+        y: i32,
+        contents: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        let handlers = std::mem::take(&mut common.special_handlers);
+        let result = match handlers
+            .iter_mut()
+            .find(|(prefix, _)| contents.starts_with(prefix.as_str()))
+        {
+            Some((_, handler)) => {
+                let mut cx = SpecialContext {
+                    state: self,
+                    common,
+                };
+                handler.handle(contents, x, y, &mut cx)
+            }
+            None => Ok(()),
+        };
+        common.special_handlers = handlers;
+
+        atry!(
+            result;
+            ["the handler of special `{}` failed", contents]
+        );
+        Ok(())
+    }
+
+    /// Handle `tdux:asp`, whose arguments are an element name followed by
+    /// optional `name=value` attributes, as for `tdux:asSemantic`.
+    ///
+    /// An invalid element name would break the markup, so the special is
+    /// ignored in that case. Malformed attributes are left out.
+    fn handle_start_with_attrs(&mut self, args: &str) -> Vec<String> {
+        let (element, rest) = args.split_once(' ').unwrap_or((args, ""));
+
+        if !content::is_element_name(element) {
+            return vec![format!("ignoring invalid element name `{}`", element)];
+        }
+
+        let (mut attrs, mut problems) = parse_attributes(rest);
+
+        if let Some(style) = self.take_pending_style() {
+            match attrs.iter_mut().find(|(n, _)| n == "style") {
+                Some((_, value)) => *value = format!("{}; {}", value, style),
+                None => attrs.push(("style".to_owned(), style)),
+            }
+        }
+
+        let attrs: Vec<_> = attrs
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect();
+        problems.extend(self.content.open_tag_with_attrs(element, &attrs));
+        problems
+    }
+
+    /// Handle `tdux:asSemantic`, whose arguments are a semantic alias followed
+    /// by optional `name=value` attributes. Values containing spaces can be
+    /// enclosed in double or single quotes.
+    fn handle_semantic_start(&mut self, args: &str) -> Vec<String> {
+        let args = args.trim_start();
+        let (alias, rest) = args.split_once(' ').unwrap_or((args, ""));
+
+        let element = match content::semantic_element(alias) {
+            Some(e) => e,
+            None => {
+                return vec![format!(
+                    "ignoring unrecognized semantic element `{}`",
+                    alias
+                )]
+            }
+        };
+
+        let (attrs, mut problems) = parse_attributes(rest);
+        let attrs: Vec<_> = attrs
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect();
+        problems.extend(self.content.open_tag_with_attrs(element, &attrs));
+        problems
+    }
+
+    /// Handle `tdux:direct`, which adds HTML to the content verbatim, and
+    /// `tdux:directStart`, `tdux:directLine`, and `tdux:directEnd`, which do
+    /// the same for a fragment too long for one special. The lines of a
+    /// fragment are joined with newlines.
+    fn handle_direct_special(&mut self, contents: &str, common: &mut Common) -> Result<()> {
+        if self.content_finished {
+            return self.warn_finished_content("verbatim HTML", common);
+        }
+
+        let (name, html) = contents.split_once(' ').unwrap_or((contents, ""));
+
+        match name {
+            "tdux:direct" => self.content.raw_html(html),
+
+            "tdux:directStart" => {
+                if self.direct_lines.replace(Vec::new()).is_some() {
+                    common.warn_or_fail(
+                        WarningCategories::MALFORMED_SPECIALS,
+                        format_args!(
+                            "dropping the HTML fragment left unfinished by a previous \
+                             tdux:directStart special"
+                        ),
+                    )?;
+                }
+            }
+
+            "tdux:directLine" => match self.direct_lines.as_mut() {
+                Some(lines) => lines.push(html.to_owned()),
+                None => common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring special `{}` outside of tdux:directStart and tdux:directEnd",
+                        contents
+                    ),
+                )?,
+            },
+
+            _ => match self.direct_lines.take() {
+                Some(lines) => self.content.raw_html(&lines.join("\n")),
+                None => common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!("ignoring unpaired tdux:directEnd special"),
+                )?,
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Handle the specials that define table structure.
+    ///
+    /// Returns None if the special isn't one of them, and otherwise a list of
+    /// problems to warn about.
+    fn handle_table_special(
+        &mut self,
+        contents: &str,
+        common: &mut Common,
+    ) -> Result<Option<Vec<String>>> {
+        if !is_table_special(contents) {
+            return Ok(None);
+        }
+
+        let (name, args) = contents.split_once(' ').unwrap_or((contents, ""));
+
+        if self.content_finished {
+            self.warn_finished_content("table structure", common)?;
+            return Ok(Some(Vec::new()));
+        }
+
+        Ok(Some(match name {
+            "tdux:tableStart" => {
+                self.content.table_start();
+                Vec::new()
+            }
+            "tdux:tableEnd" => self.content.table_end(),
+            "tdux:rowStart" => self.content.table_row_start(),
+            "tdux:rowEnd" => self.content.table_row_end(),
+            "tdux:cellEnd" => self.content.table_cell_end(),
+            _ => {
+                // The cell start takes an optional alignment and an optional
+                // column span, in either order.
+                let mut align = None;
+                let mut colspan = None;
+                let mut problems = Vec::new();
+
+                for arg in args.split_whitespace() {
+                    if let Ok(n) = arg.parse::<u32>() {
+                        colspan = Some(n);
+                    } else if let Some(a) = CellAlign::parse(arg) {
+                        align = Some(a);
+                    } else {
+                        problems.push(format!(
+                            "ignoring unrecognized table cell setting `{}`",
+                            arg
+                        ));
+                    }
+                }
+
+                problems.extend(self.content.table_cell_start(align, colspan));
+                problems
+            }
+        }))
+    }
+
+    fn handle_set_template_variable(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
+        match specials::split_args(remainder, 2) {
+            Ok(args) => {
+                self.set_variable(&args[0], Some(&args[1]));
+                Ok(())
+            }
+            Err(problem) => common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:setTemplateVariable special `{}`: {}",
+                    remainder, problem
+                ),
+            ),
+        }
+    }
+
+    /// Set or unset a template variable, saving its previous value in the
+    /// innermost variable scope.
+    fn set_variable(&mut self, varname: &str, value: Option<&str>) {
+        let context = &self.context;
+        self.variable_scopes
+            .save(varname, || context.get(varname).cloned());
+
+        match value {
+            Some(value) => self.context.insert(varname, value),
+            None => {
+                self.context.remove(varname);
+            }
+        }
+    }
+
+    /// Write out the assets provided by the application, and the base
+    /// stylesheet if it's enabled.
+    fn provide_driver_assets(&mut self, common: &mut Common) -> Result<()> {
+        if common.options.base_css_file {
+            self.assets
+                .claim(html::REQUIRED_CSS_FILE, AssetOrigin::Engine)?;
+            common.out.write(
+                html::REQUIRED_CSS_FILE,
+                html::required_css().as_bytes(),
+                OutputCategory::Stylesheet,
+            )?;
+            self.assets.add(html::REQUIRED_CSS_FILE);
+        }
+
+        for (dest_path, source) in &common.options.assets {
+            self.assets.claim(dest_path, AssetOrigin::Application)?;
+            common.out.resolve(dest_path)?;
+
+            let source = match source {
+                AssetSource::Path(p) => CopySource::Path(p.clone()),
+                AssetSource::Bytes(b) => CopySource::Bytes(b.clone()),
+                AssetSource::Reader(_) => {
+                    bail!("the stream providing asset `{}` was never read", dest_path)
+                }
+            };
+
+            self.assets.defer(dest_path, source);
+        }
+
+        Ok(())
+    }
+
+    /// Make the copies of the assets that have been queued.
+    ///
+    /// This is the one place where provided files are read and written,
+    /// whether they were requested during initialization, in the middle of
+    /// the document, or after `tdux:contentFinished`. It happens before each
+    /// page is rendered, so that its template can use the assets, and at the
+    /// end of the run.
+    fn copy_pending_assets(&mut self, common: &mut Common) -> Result<()> {
+        for copy in self.assets.take_pending() {
+            let dest_path = &copy.dest;
+
+            let contents = match copy.source {
+                CopySource::Input(src_tex_path) => {
+                    let (src_tex_path, ih) = if common.options.search_provided_files {
+                        common.open_searched_input(&src_tex_path, "provideFile source")?
+                    } else {
+                        let ih = common.open_input(&src_tex_path, "provideFile source")?;
+                        (src_tex_path, ih)
+                    };
+
+                    common.read_input(src_tex_path, ih)?
+                }
+
+                CopySource::DirectoryFile(src_path) => {
+                    let ih = match common
+                        .hooks
+                        .io()
+                        .input_open_name(&src_path, &mut common.status)
+                    {
+                        OpenResult::Ok(h) => h,
+                        OpenResult::NotAvailable => {
+                            let file =
+                                std::fs::File::open(&src_path).map_err(|e| Spx2HtmlError::Io {
+                                    path: src_path.clone(),
+                                    source: e,
+                                })?;
+                            InputHandle::new(src_path.clone(), file, InputOrigin::Filesystem)
+                        }
+                        OpenResult::Err(e) => return Err(e),
+                    };
+
+                    common.read_input(src_path, ih)?
+                }
+
+                CopySource::Path(p) => std::fs::read(&p).map_err(|e| Spx2HtmlError::Io {
+                    path: p.display().to_string(),
+                    source: e,
+                })?,
+
+                CopySource::Bytes(b) => b,
+            };
+
+            common.write_asset(dest_path, contents)?;
+            self.assets.add(dest_path);
+        }
+
+        Ok(())
+    }
+
+    fn handle_provide_file(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
+        match specials::split_args(remainder, 2) {
+            Ok(args) => self.provide_file(
+                &decode_source_path(&args[0]),
+                &decode_path(&args[1]),
+                common,
+            ),
+            Err(problem) => common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:provideFile special `{}`: {}",
+                    remainder, problem
+                ),
+            ),
+        }
+    }
+
+    /// Handle a `tdux:addCss` or `tdux:addJs` special, which provides a file
+    /// like `tdux:provideFile` and adds it to the files that templates link
+    /// to.
+    fn handle_add_linked_asset(
+        &mut self,
+        kind: LinkedAssetKind,
+        remainder: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        let args = match specials::split_args(remainder, 2) {
+            Ok(args) => args,
+            Err(problem) => {
+                return common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring malformatted {} special `{}`: {}",
+                        kind.special(),
+                        remainder,
+                        problem
+                    ),
+                );
+            }
+        };
+
+        let dest_path = decode_path(&args[1]);
+        self.provide_file(&decode_source_path(&args[0]), &dest_path, common)?;
+
+        // Registering the same file again keeps its original position.
+
+        let dest_path = assets::normalize(&dest_path);
+        let files = match kind {
+            LinkedAssetKind::Css => &mut self.css_files,
+            LinkedAssetKind::Js => &mut self.js_files,
+        };
+
+        if !files.contains(&dest_path) {
+            files.push(dest_path);
+        }
+
+        Ok(())
+    }
+
+    /// Handle a `tdux:provideDataChunk` special, or a `tdux:provideData`
+    /// special if *last* is true.
+    ///
+    /// The base64 payloads of the chunks are collected until a
+    /// `tdux:provideData` special for the same destination supplies the last
+    /// of it, when the whole payload is decoded and written.
+    fn handle_provide_data(
+        &mut self,
+        remainder: &str,
+        last: bool,
+        common: &mut Common,
+    ) -> Result<()> {
+        let special = if last {
+            "tdux:provideData"
+        } else {
+            "tdux:provideDataChunk"
+        };
+
+        let args = match specials::split_args(remainder, 2) {
+            Ok(args) => args,
+            Err(problem) => {
+                return common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring malformatted {} special `{}`: {}",
+                        special, remainder, problem
+                    ),
+                );
+            }
+        };
+
+        let dest_path = assets::normalize(&decode_path(&args[0]));
+        let payload = self.pending_data.entry(dest_path.clone()).or_default();
+        payload.push_str(&args[1]);
+
+        if !last {
+            return Ok(());
+        }
+
+        let payload = self.pending_data.remove(&dest_path).unwrap_or_default();
+
+        let contents = match decode_base64(&payload) {
+            Ok(c) => c,
+            Err(problem) => {
+                return common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "not providing `{}` with tdux:provideData: its data isn't valid base64, \
+                         since {}",
+                        dest_path, problem
+                    ),
+                );
+            }
+        };
+
+        let mut dc = digest::create();
+        dc.update(&contents);
+        let origin = AssetOrigin::Data {
+            digest: DigestData::from(dc).to_string(),
+        };
+
+        if !self.assets.claim(&dest_path, origin)? {
+            return Ok(());
+        }
+
+        common.out.resolve(&dest_path)?;
+        self.assets.defer(&dest_path, CopySource::Bytes(contents));
+        Ok(())
+    }
+
+    /// Handle a `tdux:tocEntry` special, adding an entry to the table of
+    /// contents.
+    fn handle_toc_entry(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
+        let parsed = specials::split_args(remainder, 3).and_then(|args| {
+            let level = args[0]
+                .parse::<i32>()
+                .map_err(|_| format!("its level `{}` isn't an integer", args[0]))?;
+            Ok((level, args))
+        });
+
+        let (level, args) = match parsed {
+            Ok(parsed) => parsed,
+            Err(problem) => {
+                return common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring malformatted tdux:tocEntry special `{}`: {}",
+                        remainder, problem
+                    ),
+                );
+            }
+        };
+
+        self.toc.push(TocEntry {
+            level,
+            title: args[2].clone().into_owned(),
+            url: self.xrefs.placeholder(&args[1]),
+        });
+        Ok(())
+    }
+
+    /// Drop the payloads of `tdux:provideDataChunk` specials that no
+    /// `tdux:provideData` special finished, with a warning.
+    fn drop_pending_data(&mut self, common: &mut Common) {
+        let mut dest_paths: Vec<_> = self.pending_data.drain().map(|(path, _)| path).collect();
+        dest_paths.sort();
+
+        for dest_path in dest_paths {
+            tt_warning!(
+                common.status,
+                "not providing `{}`: its tdux:provideDataChunk data was never finished with \
+                 tdux:provideData",
+                dest_path
+            );
+        }
+    }
+
+    fn handle_provide_directory(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
+        match specials::split_args(remainder, 2) {
+            Ok(args) => {
+                self.provide_directory(&decode_path(&args[0]), &decode_path(&args[1]), common)
+            }
+            Err(problem) => common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!(
+                    "ignoring malformatted tdux:provideDirectory special `{}`: {}",
+                    remainder, problem
+                ),
+            ),
+        }
+    }
+
+    /// Take the class names set with `tdux:class`, joined with spaces, if
+    /// there are any.
+    fn take_pending_classes(&mut self) -> Option<String> {
+        if self.pending_classes.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.pending_classes).join(" "))
+        }
+    }
+
+    /// Take the CSS declarations set with `tdux:style`, joined with
+    /// semicolons, if there are any.
+    fn take_pending_style(&mut self) -> Option<String> {
+        if self.pending_styles.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.pending_styles).join("; "))
+        }
+    }
+
+    /// Drop any class names, styles, and text alternatives set with
+    /// `tdux:class`, `tdux:style`, and `tdux:canvasAlt` that no element has
+    /// taken, with a warning.
+    fn drop_pending_attributes(&mut self, common: &mut Common) {
+        if let Some(classes) = self.take_pending_classes() {
+            tt_warning!(
+                common.status,
+                "dropping class(es) `{}` of tdux:class, which no element took",
+                classes
             );
         }
 
+        if let Some(style) = self.take_pending_style() {
+            tt_warning!(
+                common.status,
+                "dropping style `{}` of tdux:style, which no element took",
+                style
+            );
+        }
+
+        if let Some(alt) = self.pending_alt.take() {
+            tt_warning!(
+                common.status,
+                "dropping text alternative `{}` of tdux:canvasAlt, which no canvas took",
+                alt
+            );
+        }
+    }
+
+    /// Add an anchor with the given name, for `tdux:anchor` or `pdf:dest`.
+    fn add_anchor(&mut self, name: &str, contents: &str, common: &mut Common) -> Result<()> {
+        if self.content_finished {
+            self.warn_finished_content("anchor", common)
+        } else {
+            if let Some(id) = self.xrefs.add_anchor(name) {
+                self.content.anchor(&id);
+            } else {
+                tt_warning!(
+                    common.status,
+                    "ignoring special `{}`: there's already an anchor by that name",
+                    contents
+                );
+            }
+            Ok(())
+        }
+    }
+
+    /// Start a link, for `tdux:refStart`, `tdux:hrefStart`, or `pdf:bann`.
+    fn start_link(
+        &mut self,
+        target: LinkTarget,
+        contents: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        if self.content_finished {
+            return self.warn_finished_content("link start", common);
+        }
+
+        let href = match target {
+            LinkTarget::Destination(name) => self.xrefs.placeholder(&name),
+            LinkTarget::Url(url) => url,
+        };
+
+        for problem in self.content.open_tag_with_attrs("a", &[("href", &href)]) {
+            tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+        }
+
+        Ok(())
+    }
+
+    /// Take the content deferred to each stream with `tdux:deferStart`, for
+    /// `tduxDeferred`. Streams that content was deferred to for earlier files
+    /// are included, empty, so that templates can refer to them
+    /// unconditionally.
+    fn take_deferred(&mut self) -> BTreeMap<String, String> {
+        let content = &mut self.content;
+
+        self.deferred_streams
+            .iter()
+            .map(|name| {
+                let html = content.take_destination(Destination::Region(name.clone()));
+                (name.clone(), html)
+            })
+            .collect()
+    }
+
+    /// Start a footnote with the given mark, for `tdux:footnoteStart`.
+    ///
+    /// The call of the footnote, a superscripted link to it, is added to the
+    /// content, and the following content is diverted to the footnote until
+    /// `tdux:footnoteEnd`.
+    fn start_footnote(&mut self, mark: &str) {
+        let n = self.footnotes.len() + 1;
+        self.content.text_html(&format!(
+            "<sup><a href=\"#fn-{}\" id=\"fnref-{}\">{}</a></sup>",
+            n,
+            n,
+            content::escape_text(mark)
+        ));
+        self.content.set_destination(Destination::Footnotes);
+        self.footnote_mark = Some(mark.to_owned());
+    }
+
+    /// End the footnote being collected, if any, adding it to the footnotes
+    /// of the output file and returning to the content that it interrupted.
+    fn end_footnote(&mut self) {
+        let mark = match self.footnote_mark.take() {
+            Some(m) => m,
+            None => return,
+        };
+
+        let html = self.content.take_destination(Destination::Footnotes);
+        let resumed = match &self.deferring {
+            Some(stream) => Destination::Region(stream.clone()),
+            None => Destination::Main,
+        };
+        self.content.set_destination(resumed);
+
+        let n = self.footnotes.len() + 1;
+        self.footnotes.push(Footnote {
+            mark,
+            html,
+            id: format!("fn-{}", n),
+            ref_id: format!("fnref-{}", n),
+        });
+    }
+
+    /// Warn about a special that can't be used inside of a footnote.
+    fn warn_in_footnote(&mut self, contents: &str, common: &mut Common) -> Result<()> {
+        common.warn_or_fail(
+            WarningCategories::MALFORMED_SPECIALS,
+            format_args!(
+                "ignoring special `{}` inside of the footnote started with tdux:footnoteStart",
+                contents
+            ),
+        )
+    }
+
+    /// Start a language scope, for `tdux:setLanguage`.
+    ///
+    /// Scopes nest, so that a quotation in another language can be marked
+    /// inside of a paragraph.
+    fn start_language(&mut self, tag: &str, contents: &str, common: &mut Common) -> Result<()> {
+        if self.content_finished {
+            return self.warn_finished_content("language scope start", common);
+        }
+
+        if !check_language_tag(tag, common)? {
+            return Ok(());
+        }
+
+        for problem in self.content.open_tag_with_attrs("span", &[("lang", tag)]) {
+            tt_warning!(common.status, "{} (special `{}`)", problem, contents);
+        }
+
+        self.open_languages += 1;
+        Ok(())
+    }
+
+    /// Handle `pdf:bann`, which starts a link annotation of hyperref.
+    ///
+    /// What the annotation says about the appearance of the link is ignored,
+    /// with one warning for each kind of thing.
+    fn handle_pdf_link_start(
+        &mut self,
+        args: &str,
+        contents: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        // Links don't nest.
+        if self.pdf_link.take() == Some(true) && self.content.is_open("a") {
+            self.content.close_tag("a");
+        }
+
+        self.pdf_link = Some(false);
+
+        let link = match pdf::parse_link(args) {
+            Some(l) => l,
+            None => {
+                return common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!("ignoring malformatted pdf:bann special `{}`", contents),
+                );
+            }
+        };
+
+        for kind in link.ignored {
+            if self.pdf_ignored.insert(kind.clone()) {
+                tt_warning!(
+                    common.status,
+                    "ignoring {} in pdf:bann link annotations; further instances won't be reported",
+                    kind
+                );
+            }
+        }
+
+        if let Some(target) = link.target {
+            if !self.content_finished {
+                self.pdf_link = Some(true);
+            }
+
+            self.start_link(target, contents, common)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle `tdux:img`, whose arguments are the source and destination
+    /// paths of an image file, its width and height in TeX scaled points, and
+    /// optionally its alt text. They're split with [`specials::split_args`].
+    ///
+    /// The file is copied as with `tdux:provideFile`, whatever its format.
+    fn handle_image(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
+        // The alt text is optional, so it's there if the last of four
+        // arguments is really two.
+        let args = match specials::split_args(remainder, 4) {
+            Ok(args) if args[3].contains(' ') => specials::split_args(remainder, 5),
+            args => args,
+        };
+
+        let parsed = args.and_then(|args| {
+            let size = |arg: &str| match arg.parse::<FixedPoint>() {
+                Ok(s) if s >= 0 => Ok(s),
+                _ => Err(format!("its size `{}` isn't a non-negative integer", arg)),
+            };
+            let width = size(&args[2])?;
+            let height = size(&args[3])?;
+            Ok((width, height, args))
+        });
+
+        let (width, height, args) = match parsed {
+            Ok(parsed) => parsed,
+            Err(problem) => {
+                return common.warn_or_fail(
+                    WarningCategories::MALFORMED_SPECIALS,
+                    format_args!(
+                        "ignoring malformatted tdux:img special `{}`: {}",
+                        remainder, problem
+                    ),
+                );
+            }
+        };
+
+        let src_tex_path = decode_source_path(&args[0]);
+        let dest_path = decode_path(&args[1]);
+        let alt = args.get(4).map(AsRef::as_ref).unwrap_or_default();
+
+        self.provide_file(&src_tex_path, &dest_path, common)?;
+
+        // The URL of the image depends on the path of the output file, which
+        // isn't known yet.
+
+        let src = format!(
+            "{}{}",
+            self.xrefs.top_placeholder(),
+            common.out.resolve(&dest_path)?
+        );
+        let style = format!(
+            "width: {}rem; height: {}rem",
+            width as f32 * self.rems_per_tex,
+            height as f32 * self.rems_per_tex
+        );
+
+        self.content
+            .void_element("img", &[("src", &src), ("alt", alt), ("style", &style)]);
+        Ok(())
+    }
+
+    /// Queue the copy of the input *src_tex_path* to the output at
+    /// *dest_path*. It's made by [`Self::copy_pending_assets`].
+    fn provide_file(
+        &mut self,
+        src_tex_path: &str,
+        dest_path: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        // The same request may appear more than once, e.g. if it was deferred
+        // during initialization and then seen again in the main stream.
+
+        if remote::is_remote(src_tex_path) {
+            return self.provide_remote_file(src_tex_path, dest_path, common);
+        }
+
+        let origin = AssetOrigin::Copy {
+            src: src_tex_path.to_owned(),
+        };
+
+        if !self.assets.claim(dest_path, origin)? {
+            return Ok(());
+        }
+
+        common.out.resolve(dest_path)?;
+        self.assets
+            .defer(dest_path, CopySource::Input(src_tex_path.to_owned()));
         Ok(())
     }
 
-    fn initialization_finished(self) -> Result<EmittingState> {
-        // Tera requires that we give it a filesystem path to look for
-        // templates, even if we're going to be adding all of our templates
-        // later. So I guess we have to create an empty tempdir.
+    /// Queue the copies of every file underneath the directory *src_dir* to
+    /// the output, under *dest_dir*.
+    ///
+    /// The I/O layer can't list directories, so the source must be a real
+    /// directory, relative to the working directory or, if provided files are
+    /// searched for, to the template search path. It's listed now, and the
+    /// files are opened through the I/O layer if it has them, and read from
+    /// the filesystem otherwise, when the copies are made. Symbolic links are
+    /// skipped.
+    fn provide_directory(
+        &mut self,
+        src_dir: &str,
+        dest_dir: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        let candidates = if common.options.search_provided_files {
+            common.search_candidates(src_dir)
+        } else {
+            vec![src_dir.to_owned()]
+        };
 
-        let tempdir = atry!(
-            tempfile::Builder::new().prefix("tectonic_tera_workaround").tempdir();
-            ["couldn't create empty temporary directory for Tera"]
-        );
+        let root = match candidates.into_iter().find(|c| Path::new(c).is_dir()) {
+            Some(r) => r,
+            None => {
+                return Err(Spx2HtmlError::NotADirectory {
+                    path: src_dir.to_owned(),
+                }
+                .into())
+            }
+        };
 
-        let mut p = PathBuf::from(tempdir.path());
-        p.push("*");
+        let mut files = Vec::new();
+        collect_directory_files(Path::new(&root), "", &mut files, common)?;
 
-        let p = a_ok_or!(
-            p.to_str();
-            ["couldn't convert Tera temporary directory name to UTF8 as required"]
-        );
+        let root = root.trim_end_matches('/');
+        let dest_dir = dest_dir.trim_end_matches('/');
 
-        let mut tera = atry!(
-            tera::Tera::parse(p);
-            ["couldn't initialize Tera templating engine in temporary directory `{}`", p]
-        );
+        for rel_path in files {
+            let src_path = format!("{}/{}", root, rel_path);
+            let dest_path = format!("{}/{}", dest_dir, rel_path);
 
-        atry!(
-            tera.add_raw_templates(self.templates.iter());
-            ["couldn't compile Tera templates"]
-        );
+            let origin = AssetOrigin::DirectoryCopy {
+                src: src_path.clone(),
+            };
 
-        // Set up the context.
+            if !self.assets.claim(&dest_path, origin)? {
+                continue;
+            }
 
-        let mut context = tera::Context::default();
+            common.out.resolve(&dest_path)?;
+            self.assets
+                .defer(&dest_path, CopySource::DirectoryFile(src_path));
+        }
 
-        for (varname, varvalue) in self.variables {
-            context.insert(varname, &varvalue);
+        Ok(())
+    }
+
+    /// Handle a `tdux:provideFile` special whose source is a remote URL.
+    fn provide_remote_file(
+        &mut self,
+        url: &str,
+        dest_path: &str,
+        common: &mut Common,
+    ) -> Result<()> {
+        let limit = common
+            .options
+            .max_remote_size
+            .unwrap_or(DEFAULT_MAX_REMOTE_SIZE);
+        let (fetched, fresh) = common
+            .remote
+            .get(common.fetcher.as_deref_mut(), url, limit)?;
+
+        if fresh {
+            tt_note!(
+                common.status,
+                "fetched `{}` for tdux:provideFile: {} bytes with SHA-256 digest {}",
+                url,
+                fetched.contents.len(),
+                fetched.digest
+            );
         }
 
-        // All done!
+        let origin = AssetOrigin::RemoteCopy {
+            url: url.to_owned(),
+            digest: fetched.digest.clone(),
+        };
 
-        Ok(EmittingState {
-            tera,
-            context,
-            fonts: self.fonts,
-            rems_per_tex: 1.0 / (self.main_body_font_size as f32),
-            font_data: self.font_data,
-            next_template_path: self.next_template_path,
-            next_output_path: self.next_output_path,
-            current_content: String::default(),
-            current_canvas: None,
-            content_finished: false,
-            content_finished_warning_issued: false,
-        })
+        if !self.assets.claim(dest_path, origin)? {
+            return Ok(());
+        }
+
+        common.deps.note_input(url, Some(fetched.digest.clone()));
+        common.deps.use_input(url);
+
+        common.out.resolve(dest_path)?;
+        self.assets
+            .defer(dest_path, CopySource::Bytes(fetched.contents.clone()));
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-struct EmittingState {
-    tera: tera::Tera,
-    context: tera::Context,
-    fonts: HashMap<i32, FontInfo>,
-    rems_per_tex: f32,
-    font_data: HashMap<usize, FontData>,
-    next_template_path: String,
-    next_output_path: String,
-    current_content: String,
-    current_canvas: Option<CanvasState>,
-    content_finished: bool,
-    content_finished_warning_issued: bool,
-}
+    fn handle_text_and_glyphs(
+        &mut self,
+        font_num: i32,
+        text: &str,
+        glyphs: &[u16],
+        xs: &[i32],
+        ys: &[i32],
+        common: &mut Common,
+    ) -> Result<()> {
+        if self.content_finished {
+            return self.warn_finished_content(&format!("text `{}`", text), common);
+        }
 
-#[derive(Debug)]
-struct CanvasState {
-    kind: String,
-    depth: usize,
-    x0: i32,
-    y0: i32,
-    glyphs: Vec<GlyphInfo>,
-}
+        self.note_font_use(font_num, glyphs.len());
+        let color_rgba = self.glyph_color(font_num);
 
-impl CanvasState {
-    fn new(kind: &str, x0: i32, y0: i32) -> Self {
-        CanvasState {
-            kind: kind.to_owned(),
-            depth: 1,
-            x0,
-            y0,
-            glyphs: Vec::new(),
+        if let Some(c) = self.current_canvas.as_mut().map(CanvasState::innermost) {
+            self.stats.canvas_glyphs += glyphs.len() as u64;
+
+            for i in 0..glyphs.len() {
+                c.glyphs.push(PositionedGlyph {
+                    dx: xs[i] - c.x0,
+                    dy: ys[i] - c.y0,
+                    glyph: glyphs[i],
+                    font_num,
+                    color_rgba,
+                });
+            }
+        } else {
+            if self.next_template_path.is_empty()
+                && self.once_template_path.is_none()
+                && !self.no_template_warning_issued
+            {
+                tt_warning!(
+                    common.status,
+                    "text `{}` was added before any template was chosen with tdux:setTemplate; \
+                     this usually means that macros are running in the wrong order",
+                    text
+                );
+                self.no_template_warning_issued = true;
+            }
+
+            let style = self.text_style(font_num);
+            self.chars.add_text(text);
+            self.content.text(style, text);
+            common.plan.note_text(text);
         }
+
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-struct GlyphInfo {
-    dx: i32,
-    dy: i32,
-    font_num: i32,
-    glyph: u16,
-}
+    /// Record that the page being built uses the specified font.
+    fn note_font_use(&mut self, font_num: i32, n_glyphs: usize) {
+        if let Some(fi) = self.fonts.fonts.get(&font_num) {
+            self.page_fonts.insert(fi.fd_key);
+            self.usage.add_glyphs(fi.fd_key, n_glyphs);
+        }
+    }
 
-impl EmittingState {
-    fn warn_finished_content(&mut self, detail: &str, common: &mut Common) {
-        if !self.content_finished_warning_issued {
-            tt_warning!(common.status, "dropping post-finish content ({})", detail);
-            self.content_finished_warning_issued = true;
+    /// Get the color of glyphs in the specified font: the current color of
+    /// the `color` package, if any, or else the color of the font.
+    fn glyph_color(&self, font_num: i32) -> Option<u32> {
+        self.colors
+            .current()
+            .or_else(|| self.fonts.fonts.get(&font_num)?.color_rgba)
+    }
+
+    /// Compute the normalized style of text in the specified font.
+    ///
+    /// Text in undeclared fonts is treated as plain body text.
+    fn text_style(&self, font_num: i32) -> TextStyle {
+        let fi = match self.fonts.fonts.get(&font_num) {
+            Some(fi) => fi,
+            None => return TextStyle::default(),
+        };
+
+        let family = if fi.fd_key == self.main_body_fd_key {
+            None
+        } else {
+            Some(self.fonts.css_family(fi.fd_key))
+        };
+
+        let size_millirem = (fi.size as f32 * self.rems_per_tex * 1000.).round() as i32;
+        let size_millirem = if size_millirem == 1000 {
+            None
+        } else {
+            Some(size_millirem)
+        };
+
+        TextStyle {
+            family,
+            size_millirem,
+            color_rgba: self.colors.current().or(fi.color_rgba),
         }
     }
 
-    fn handle_special(
+    fn handle_glyph_run(
         &mut self,
-        x: i32,
-        y: i32,
-        contents: &str,
+        font_num: i32,
+        glyphs: &[u16],
+        xs: &[i32],
+        ys: &[i32],
         common: &mut Common,
     ) -> Result<()> {
-        if let Some(element) = contents.strip_prefix("tdux:as ") {
-            if self.content_finished {
-                self.warn_finished_content(&format!("auto start tag <{}>", element), common);
-            } else {
-                self.current_content.push('<');
-                self.current_content.push_str(element);
-                self.current_content.push('>');
-            }
-            Ok(())
-        } else if let Some(element) = contents.strip_prefix("tdux:ae ") {
-            if self.content_finished {
-                self.warn_finished_content(&format!("auto end tag </{}>", element), common);
-            } else {
-                self.current_content.push('<');
-                self.current_content.push('/');
-                self.current_content.push_str(element);
-                self.current_content.push('>');
-            }
-            Ok(())
-        } else if let Some(kind) = contents.strip_prefix("tdux:cs ") {
-            if self.content_finished {
-                self.warn_finished_content("canvas start", common);
-            } else if let Some(canvas) = self.current_canvas.as_mut() {
-                canvas.depth += 1;
-            } else {
-                self.current_canvas = Some(CanvasState::new(kind, x, y));
+        if self.content_finished {
+            self.warn_finished_content("glyph run", common)?;
+            return Ok(());
+        }
+
+        self.note_font_use(font_num, glyphs.len());
+        let color_rgba = self.glyph_color(font_num);
+
+        if let Some(c) = self.current_canvas.as_mut().map(CanvasState::innermost) {
+            self.stats.canvas_glyphs += glyphs.len() as u64;
+
+            for i in 0..glyphs.len() {
+                c.glyphs.push(PositionedGlyph {
+                    dx: xs[i] - c.x0,
+                    dy: ys[i] - c.y0,
+                    glyph: glyphs[i],
+                    font_num,
+                    color_rgba,
+                });
             }
-            Ok(())
-        } else if let Some(_kind) = contents.strip_prefix("tdux:ce ") {
-            if self.content_finished {
-                self.warn_finished_content("canvas end", common);
-            } else if let Some(canvas) = self.current_canvas.as_mut() {
-                canvas.depth -= 1;
-                if canvas.depth == 0 {
-                    self.handle_end_canvas(common)?;
-                }
-            } else {
+        } else {
+            tt_warning!(common.status, "TODO HANDLE glyph_run OUTSIDE OF CANVAS");
+        }
+
+        Ok(())
+    }
+
+    /// Handle a rule. Rules are only drawn inside of canvases, where they
+    /// make up the bars of fractions and the like; others are ignored.
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) {
+        let color_rgba = self.colors.current();
+
+        if let Some(c) = self.current_canvas.as_mut().map(CanvasState::innermost) {
+            c.rules.push(PositionedRule {
+                dx: x - c.x0,
+                dy: y - c.y0,
+                width,
+                height,
+                color_rgba,
+            });
+        }
+    }
+
+    fn handle_end_canvas(&mut self, common: &mut Common) -> Result<()> {
+        let CanvasState {
+            classes,
+            style,
+            alt,
+            outer: canvas,
+            ..
+        } = self.current_canvas.take().unwrap();
+        common.plan.note_canvas(&canvas.kind);
+
+        let (inline, mathml) = match canvas.kind.as_ref() {
+            "math" => (true, false),
+            "dmath" => (false, false),
+            "mathml-math" => (true, true),
+            "mathml-dmath" => (false, true),
+            _ => (false, false),
+        };
+
+        if mathml {
+            let mut options = CanvasOptions::new(self.rems_per_tex);
+            options
+                .inline(inline)
+                .classes(classes.as_deref())
+                .style(style.as_deref())
+                .alt(alt.as_deref());
+            let rendered = mathml::render_mathml(&self.fonts, &canvas.glyphs, &options)?;
+            self.warn_unmapped_glyphs(&rendered.unmapped, common)?;
+
+            if !canvas.rules.is_empty() {
                 tt_warning!(
                     common.status,
-                    "ignoring unpaired tdux:c[anvas]e[nd] special `{}`",
-                    contents
+                    "dropping {} rule(s) in a `{}` canvas, which MathML can't represent",
+                    canvas.rules.len(),
+                    canvas.kind
                 );
             }
-            Ok(())
-        } else if contents == "tdux:emit" {
-            self.finish_file(common)
-        } else if let Some(texpath) = contents.strip_prefix("tdux:setTemplate ") {
-            self.next_template_path = texpath.to_owned();
-            Ok(())
-        } else if let Some(texpath) = contents.strip_prefix("tdux:setOutputPath ") {
-            self.next_output_path = texpath.to_owned();
-            Ok(())
-        } else if let Some(remainder) = contents.strip_prefix("tdux:setTemplateVariable ") {
-            self.handle_set_template_variable(remainder, common)
-        } else if let Some(remainder) = contents.strip_prefix("tdux:provideFile ") {
-            self.handle_provide_file(remainder, common)
-        } else if contents == "tdux:contentFinished" {
-            self.content_finished(common)
-        } else {
-            Ok(())
+
+            self.content.canvas(&rendered.html);
+            return Ok(());
+        }
+
+        self.page_has_canvas = true;
+
+        let id = common
+            .options
+            .layout_sidecars
+            .map(|_| format!("tdux-canvas-{}", self.layout.len()));
+
+        let mut options = CanvasOptions::new(self.rems_per_tex);
+        options
+            .inline(inline)
+            .id(id.as_deref())
+            .classes(classes.as_deref())
+            .style(style.as_deref())
+            .alt(alt.as_deref())
+            .hide_unlabeled(common.options.hide_unlabeled_canvases)
+            .size_limits(common.options.glyph_size_limits)
+            .containment(!common.options.no_canvas_containment)
+            .xhtml(common.options.serialization == Serialization::Xhtml);
+        let rendered = html::render_canvas_with_rules(
+            &mut self.fonts,
+            &canvas.glyphs,
+            &canvas.rules,
+            &options,
+        )?;
+
+        if rendered.scale != 1. {
+            tt_note!(
+                common.status,
+                "a `{}` canvas contains glyphs smaller than {}rem; it has been drawn {}x larger \
+                 and scaled down",
+                canvas.kind,
+                common.options.glyph_size_limits.min,
+                rendered.scale
+            );
+        }
+
+        if rendered.n_capped > 0 {
+            tt_warning!(
+                common.status,
+                "{} glyph(s) in a `{}` canvas were larger than {}rem and have been shrunk",
+                rendered.n_capped,
+                canvas.kind,
+                common.options.glyph_size_limits.max
+            );
+        }
+
+        self.warn_unmapped_glyphs(&rendered.unmapped, common)?;
+        self.content.canvas(&rendered.html);
+
+        if let Some(id) = id {
+            self.layout.push(CanvasLayout {
+                id,
+                kind: canvas.kind,
+                x: canvas.x0,
+                y: canvas.y0,
+                size: rendered.size,
+                glyphs: if common.options.layout_sidecars == Some(LayoutDetail::Glyphs) {
+                    Some(rendered.glyphs)
+                } else {
+                    None
+                },
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Warn about the glyphs of a canvas that couldn't be reverse-mapped.
+    fn warn_unmapped_glyphs(
+        &self,
+        unmapped: &[PositionedGlyph],
+        common: &mut Common,
+    ) -> Result<()> {
+        for gi in unmapped {
+            let fi = self.fonts.fonts.get(&gi.font_num).unwrap();
+            common.warn_or_fail(
+                WarningCategories::UNMAPPABLE_GLYPHS,
+                format_args!(
+                    "unable to reverse-map glyph {} in font `{}` (face {})",
+                    gi.glyph, fi.rel_url, fi.face_index
+                ),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Determine the path of the output file being emitted.
+    ///
+    /// If an output path pattern is in effect, and no explicit output path
+    /// has been set since the previous emission, the pattern is resolved.
+    fn resolve_output_path(&mut self, common: &mut Common) -> String {
+        let pattern = match self.output_path_pattern.as_ref() {
+            Some(p) if !self.explicit_output_path => p,
+            _ => return self.next_output_path.clone(),
+        };
+
+        let style = common.options.heading_ids.unwrap_or(SlugStyle::Ascii);
+        let slug = self
+            .content
+            .last_heading_text()
+            .map(|t| content::slugify(t, style));
+        let context = &self.context;
+
+        let (path, problems) = pattern.resolve(self.n_emitted, slug.as_deref(), |name| {
+            context.get(name).map(|v| match v {
+                tera::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            })
+        });
+
+        for problem in problems {
+            tt_warning!(
+                common.status,
+                "output path pattern problem for `{}`: {}; using the file number instead",
+                path,
+                problem
+            );
+        }
+
+        path
+    }
+
+    /// Render and write the output file being built.
+    ///
+    /// If *if_changed* is true, as for `tdux:emitIfChanged`, an existing file
+    /// with the same contents is left alone.
+    fn finish_file(&mut self, if_changed: bool, common: &mut Common) -> Result<()> {
+        common.check_cancelled()?;
+
+        // Prep the output path
+
+        self.n_emitted += 1;
+        let output_path = self.resolve_output_path(common);
+        self.explicit_output_path = false;
+
+        let style = common.options.url_style;
+        let normalized = common
+            .out
+            .resolve(&output::page_file(style, &output_path))?;
+        let url = output::page_url(style, &normalized);
+        let n_levels = normalized.split('/').count();
+
+        let rewrite = !self.emitted_paths.insert(normalized.clone());
+
+        if rewrite {
+            tt_warning!(
+                common.status,
+                "HTML output path `{}` has already been emitted; overwriting it",
+                normalized
+            );
+        }
+
+        self.context.insert("tduxCurrentOutputPath", &normalized);
+        self.context.insert("tduxCurrentUrl", &url);
+
+        if let Some(mark) = &self.footnote_mark {
+            tt_warning!(
+                common.status,
+                "ending the footnote `{}` with no tdux:footnoteEnd special at the end of `{}`",
+                mark,
+                normalized
+            );
+            self.end_footnote();
         }
-    }
 
-    fn handle_set_template_variable(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
-        if let Some((varname, varval)) = remainder.split_once(' ') {
-            self.context.insert(varname, varval);
-        } else {
+        if let Some(stream) = self.deferring.take() {
             tt_warning!(
                 common.status,
-                "ignoring malformatted tdux:setTemplateVariable special `{}`",
-                remainder
+                "ending the deferred stream `{}` with no tdux:deferEnd special at the end of `{}`",
+                stream,
+                normalized
             );
+            self.content.set_destination(Destination::Main);
         }
 
-        Ok(())
-    }
+        if self.open_hrefs > 0 {
+            tt_warning!(
+                common.status,
+                "closing {} link(s) with no tdux:hrefEnd special at the end of `{}`",
+                self.open_hrefs,
+                normalized
+            );
 
-    fn handle_provide_file(&mut self, remainder: &str, common: &mut Common) -> Result<()> {
-        let (src_tex_path, dest_path) = match remainder.split_once(' ') {
-            Some(t) => t,
-            None => {
-                tt_warning!(
-                    common.status,
-                    "ignoring malformatted tdux:provideFile special `{}`",
-                    remainder
-                );
-                return Ok(());
+            while self.open_hrefs > 0 && self.content.is_open("a") {
+                self.content.close_tag("a");
+                self.open_hrefs -= 1;
             }
-        };
-
-        // Set up input?
-
-        let mut ih = atry!(
-            common.hooks.io().input_open_name(src_tex_path, common.status).must_exist();
-            ["unable to open provideFile source `{}`", &src_tex_path]
-        );
 
-        // Set up output? TODO: create parent directories!
+            self.open_hrefs = 0;
+        }
 
-        let mut out_path = common.out_base.to_owned();
+        if self.open_languages > 0 {
+            tt_warning!(
+                common.status,
+                "closing {} language scope(s) with no tdux:endLanguage special at the end of `{}`",
+                self.open_languages,
+                normalized
+            );
 
-        for piece in dest_path.split('/') {
-            if piece.is_empty() {
-                continue;
+            while self.open_languages > 0 && self.content.is_open("span") {
+                self.content.close_tag("span");
+                self.open_languages -= 1;
             }
 
-            if piece == ".." {
-                bail!(
-                    "illegal provideFile dest path `{}`: it contains a `..` component",
-                    &dest_path
-                );
-            }
+            self.open_languages = 0;
+        }
 
-            let as_path = Path::new(piece);
+        self.drop_pending_attributes(common);
 
-            if as_path.is_absolute() || as_path.has_root() {
-                bail!(
-                    "illegal provideFile path `{}`: it contains an absolute/rooted component",
-                    &dest_path,
-                );
+        // A hyperref link that continues onto the next page of the PDF
+        // continues into the next file, but we can't have that.
+        if self.pdf_link == Some(true) {
+            if self.content.is_open("a") {
+                self.content.close_tag("a");
             }
 
-            out_path.push(piece);
+            self.pdf_link = Some(false);
         }
 
-        // Copy!
+        self.context.insert("tduxContent", &self.content.take());
+        let deferred = self.take_deferred();
+        self.context.insert("tduxDeferred", &deferred);
+        self.context
+            .insert("tduxFootnotes", &std::mem::take(&mut self.footnotes));
+        self.xrefs.finish_page(&normalized);
 
-        {
-            let mut out_file = atry!(
-                File::create(&out_path);
-                ["cannot open output file `{}`", out_path.display()]
+        for len in self.content.take_long_runs() {
+            tt_warning!(
+                common.status,
+                "a text run in `{}` is {} bytes long; it has been broken into lines, \
+                 but this may indicate a problem with the document",
+                normalized,
+                len
             );
+        }
 
-            atry!(
-                std::io::copy(&mut ih, &mut out_file);
-                ["cannot copy to output file `{}`", out_path.display()]
-            );
+        self.emit_last_resort_font(common)?;
+        self.chars.finish_page(&normalized);
+        self.report_control_chars(&normalized, common);
+        let headings = self.content.take_headings();
+        self.outline.extend(
+            headings
+                .iter()
+                .map(|h| OutlineEntry::new(h, &normalized, &url)),
+        );
+        self.context.insert("tduxHeadings", &headings);
+        self.context.insert("tduxOutline", &self.outline);
+        self.context.insert("tduxToc", &self.toc);
+        self.context
+            .insert("tduxPageMeta", &std::mem::take(&mut self.page_meta));
+
+        let mut rel_top = String::default();
+
+        for _ in 1..n_levels {
+            rel_top.push_str("../");
         }
 
-        // All done.
+        // A template selected with `tdux:setTemplateOnce` only applies to this
+        // file.
 
-        let (name, digest_opt) = ih.into_name_digest();
-        common
-            .hooks
-            .event_input_closed(name, digest_opt, common.status);
+        let template_name = self
+            .once_template_path
+            .take()
+            .unwrap_or_else(|| self.next_template_path.clone());
 
-        Ok(())
-    }
+        self.context.insert("tduxRelTop", &rel_top);
+        self.assets.set_page(&rel_top, &template_name);
 
-    fn handle_text_and_glyphs(
-        &mut self,
-        font_num: i32,
-        text: &str,
-        glyphs: &[u16],
-        xs: &[i32],
-        ys: &[i32],
-        common: &mut Common,
-    ) {
-        if self.content_finished {
-            self.warn_finished_content(&format!("text `{}`", text), common);
-            return;
+        for (name, files) in [
+            ("tduxCssFiles", &self.css_files),
+            ("tduxJsFiles", &self.js_files),
+        ] {
+            let urls: Vec<_> = files.iter().map(|f| format!("{}{}", rel_top, f)).collect();
+            self.context.insert(name, &urls);
         }
 
-        if let Some(c) = self.current_canvas.as_mut() {
-            for i in 0..glyphs.len() {
-                c.glyphs.push(GlyphInfo {
-                    dx: xs[i] - c.x0,
-                    dy: ys[i] - c.y0,
-                    glyph: glyphs[i],
-                    font_num,
-                });
-            }
-        } else {
-            if !self.current_content.is_empty() && !self.current_content.ends_with('>') {
-                self.current_content.push(' ');
-            }
+        // Write out the fonts that the page uses before the page itself, so
+        // that anyone watching the output directory never sees a page whose
+        // fonts are missing.
 
-            self.current_content.push_str(text);
+        for fd_key in &self.page_fonts {
+            self.usage.add_page(*fd_key, &normalized);
         }
-    }
 
-    fn handle_glyph_run(
-        &mut self,
-        font_num: i32,
-        glyphs: &[u16],
-        xs: &[i32],
-        ys: &[i32],
-        common: &mut Common,
-    ) -> Result<()> {
-        if self.content_finished {
-            self.warn_finished_content("glyph run", common);
-            return Ok(());
-        }
+        self.page_fonts.insert(self.main_body_fd_key);
+        let fd_keys: Vec<_> = std::mem::take(&mut self.page_fonts).into_iter().collect();
+        self.emit_base_fonts(&fd_keys, common)?;
+        self.context.insert("tduxFontFaces", &self.font_faces);
 
-        if let Some(c) = self.current_canvas.as_mut() {
-            for i in 0..glyphs.len() {
-                c.glyphs.push(GlyphInfo {
-                    dx: xs[i] - c.x0,
-                    dy: ys[i] - c.y0,
-                    glyph: glyphs[i],
-                    font_num,
-                });
+        for fd_key in &fd_keys {
+            if let Some((name, _)) = self.fonts.input(*fd_key) {
+                common.deps.use_input(name);
             }
-        } else {
-            tt_warning!(common.status, "TODO HANDLE glyph_run OUTSIDE OF CANVAS");
         }
 
-        Ok(())
-    }
+        if common.options.inline_font_faces {
+            self.context.insert(
+                "tduxFontFacesInline",
+                &font::inline_faces(&self.font_faces, &rel_top),
+            );
+        }
 
-    fn handle_end_canvas(&mut self, common: &mut Common) -> Result<()> {
-        let mut canvas = self.current_canvas.take().unwrap();
+        // When scanning for assets, the page itself isn't needed.
 
-        if !self.current_content.is_empty() && !self.current_content.ends_with('>') {
-            self.current_content.push(' ');
+        if common.pass == Pass::Scan {
+            self.context.remove("tduxContent");
+            self.page_has_canvas = false;
+            self.layout.clear();
+            return Ok(());
         }
 
-        let inline = match canvas.kind.as_ref() {
-            "math" => true,
-            "dmath" => false,
-            _ => false,
-        };
+        // Read in the template. Let's not cache it, in case someone wants to do
+        // something fancy with rewriting it.
 
-        // First pass: get overall bounds of all the glyphs from their metrics.
-        // We need to gather this information first because as we emit glyphs we
-        // have to specify their positions relative to the edges of the
-        // containing canvas box, and the size of that box is defined by the
-        // extents of all of the glyphs it contains. The bounds are measured in
-        // TeX units.
-
-        let mut first = true;
-        let mut x_min_tex = 0;
-        let mut x_max_tex = 0;
-        let mut y_min_tex = 0;
-        let mut y_max_tex = 0;
-
-        for gi in &canvas.glyphs[..] {
-            let fi = a_ok_or!(
-                self.fonts.get(&gi.font_num);
-                ["undeclared font {} in canvas", gi.font_num]
-            );
+        let (template_path, template) =
+            if let Some(contents) = self.driver_templates.get(&template_name) {
+                (template_name, contents.clone())
+            } else {
+                let (template_path, mut ih) =
+                    common.open_searched_input(&template_name, "HTML template")?;
+
+                let mut template = String::new();
+                ih.read_to_string(&mut template)
+                    .map_err(|e| Spx2HtmlError::Io {
+                        path: template_path.clone(),
+                        source: e,
+                    })?;
+
+                let (name, digest_opt) = ih.into_name_digest();
+                common
+                    .deps
+                    .note_input(&name, digest_opt.map(|d| d.to_string()));
+                common.deps.use_input(&name);
+                common
+                    .hooks
+                    .event_input_closed(name, digest_opt, &mut common.status);
+                (template_path, template)
+            };
 
-            let fd = self.font_data.get_mut(&fi.fd_key).unwrap();
-            let gm = fd.lookup_metrics(gi.glyph, fi.size);
-
-            if let Some(gm) = gm {
-                // to check: RTL correctness
-                let xmin = gi.dx - gm.lsb;
-                let xmax = gi.dx + gm.advance;
-                let ymin = gi.dy - gm.ascent;
-                let ymax = gi.dy - gm.descent; // note: descent is negative
-
-                if first {
-                    x_min_tex = xmin;
-                    x_max_tex = xmax;
-                    y_min_tex = ymin;
-                    y_max_tex = ymax;
-                    first = false;
-                } else {
-                    x_min_tex = std::cmp::min(x_min_tex, xmin);
-                    x_max_tex = std::cmp::max(x_max_tex, xmax);
-                    y_min_tex = std::cmp::min(y_min_tex, ymin);
-                    y_max_tex = std::cmp::max(y_max_tex, ymax);
-                }
+        // Copy the assets that the template may need.
+
+        self.copy_pending_assets(common)?;
+
+        // Ready to render!
+
+        let mut render_stats = common.render_stats.get();
+        let result = render::render(
+            &self.tera,
+            template,
+            &self.context,
+            &template_path,
+            &normalized,
+            &common.options.render_limits,
+            &mut render_stats,
+        );
+        common.render_stats.set(render_stats);
+        let mut rendered = result?;
+
+        // Don't hold on to the content until the next file is emitted: for
+        // large documents, it can be the biggest thing in memory.
+        self.context.remove("tduxContent");
+
+        // Pages with canvases are garbled without the rules that they rely
+        // on. Templates may well get them from a stylesheet of their own, so
+        // we can only insist on the standard ones in strict mode.
+
+        if std::mem::take(&mut self.page_has_canvas)
+            && common.options.strict
+            && !rendered.contains(&html::required_css())
+            && !rendered.contains(html::REQUIRED_CSS_FILE)
+        {
+            return Err(Spx2HtmlError::MissingRequiredCss {
+                page: normalized,
+                template: template_path,
             }
+            .into());
         }
 
-        // Now that we have that information, we can lay out the individual
-        // glyphs.
-        //
-        // A resource I found very helpful:
-        // https://iamvdo.me/en/blog/css-font-metrics-line-height-and-vertical-align
+        let plan = common.plan.finish(&normalized, &template_path);
 
-        let mut inner_content = String::default();
+        if let Some(diagnostics) = common.status.take() {
+            overlay::insert(&mut rendered, &overlay::render(&diagnostics, plan.as_ref()));
+        }
 
-        for gi in canvas.glyphs.drain(..) {
-            let fi = self.fonts.get(&gi.font_num).unwrap();
+        // Save it, unless it links to anchors that haven't been emitted yet.
+        // An earlier file at the same path that was held back is superseded.
 
-            // The size of the font being used for this glyph, in rems; that is,
-            // relative to the main body font.
-            let rel_size = fi.size as f32 * self.rems_per_tex;
-            let fd = self.font_data.get_mut(&fi.fd_key).unwrap();
-            let mc = fd.lookup_mapping(gi.glyph);
+        if let Some(page) = self.xrefs.drop_held(&normalized) {
+            self.spill.discard(page.html);
+        }
 
-            if let Some(mc) = mc {
-                // Sometimes we need to render a glyph in one of our input fonts
-                // that isn't directly associated with a specific Unicode
-                // character. For instance, in math, we may need to draw a big
-                // integral sign, but the Unicode integral character maps to a
-                // small one. The way we handle this is by *creating new fonts*
-                // with custom character map tables that *do* map Unicode
-                // characters directly to the specific glyphs we want.
+        self.spill.note_resident(rendered.len());
+        let (html, unresolved) = self.xrefs.resolve(&rendered, &normalized, style);
 
-                let (mut ch, need_alt) = match mc {
-                    MapEntry::Direct(c) => (c, false),
-                    MapEntry::SubSuperScript(c, _) => (c, true),
-                    MapEntry::MathGrowingVariant(c, _, _) => (c, true),
-                };
+        if unresolved.is_empty() {
+            self.write_page(&normalized, html, rewrite, if_changed, common)?;
+        } else {
+            self.xrefs.hold(HeldPage {
+                path: normalized.clone(),
+                html: self.spill.set_aside(rendered)?,
+                rewrite,
+                if_changed,
+            });
+        }
 
-                let font_fam = if need_alt {
-                    let map = fd.request_alternative(gi.glyph, ch);
-                    ch = map.usv;
-                    format!("tdux{}vg{}", fi.fd_key, map.alternate_map_index)
-                } else {
-                    format!("tdux{}", fi.fd_key)
-                };
+        self.write_layout_sidecar(&normalized, if_changed, common)?;
+        common.deps.finish_page(&normalized);
 
-                // dy gives the target position of this glyph's baseline
-                // relative to the canvas's baseline. For our `position:
-                // absolute` layout, we have to convert that into the distance
-                // between the top of this glyph's box and the top of the
-                // overall canvas box (or bottom/bottom).
-                //
-                // In order to do this, we need to know the size of this glyph's
-                // box according to CSS, and the position of the glyph's
-                // baseline within that box.
-                //
-                // The baseline position is straightforward: it is given by what
-                // we call the font's "baseline factor". This is true no matter
-                // the specific size of the CSS box relative to the font
-                // rendering size, due to the way in which the drawn glyph is
-                // centered vertically within its CSS box.
-                //
-                // The CSS glyph box height can be funky: it depends on the
-                // font-size setting, font metrics (not just ascender/descender
-                // but "line gap") and `line-height` setting in "exciting" ways.
-                // One convenient approach is to set `line-height: 1` in the
-                // container, in which case the box height is the `font-size`
-                // setting.
-
-                let top_rem = (-y_min_tex + gi.dy) as f32 * self.rems_per_tex
-                    - fd.baseline_factor() * rel_size;
-
-                write!(
-                    inner_content,
-                    "<span class=\"ci\" style=\"top: {}rem; left: {}rem; font-size: {}rem; font-family: {}\">{}</span>",
-                    top_rem,
-                    gi.dx as f32 * self.rems_per_tex,
-                    rel_size,
-                    font_fam,
-                    ch
-                )
-                .unwrap();
-            } else {
-                tt_warning!(
-                    common.status,
-                    "unable to reverse-map glyph {} in font `{}` (face {})",
-                    gi.glyph,
-                    fi.rel_url,
-                    fi.face_index
-                );
-            }
+        let index = self.n_emitted - 1;
+        common.progress(|p| p.on_page_emitted(&normalized, index, &template_path));
+
+        if let Some(plan) = plan {
+            common.progress(|p| p.on_emission_plan(&plan));
         }
 
-        let (element, layout_class, valign) = if inline {
-            // A numerical vertical-align setting positions the bottom edge of
-            // this block relative to the containing line's baseline. This is
-            // the best (only?) way to make sure that this block's baseline
-            // lines up with that of its container.
-            (
-                "span",
-                "canvas-inline",
-                format!(
-                    "; vertical-align: {}rem",
-                    -y_max_tex as f32 * self.rems_per_tex
-                ),
-            )
+        Ok(())
+    }
+
+    /// Post-process and write a rendered HTML file.
+    fn write_page(
+        &mut self,
+        page: &str,
+        html: String,
+        rewrite: bool,
+        if_changed: bool,
+        common: &mut Common,
+    ) -> Result<()> {
+        let mut html = html.into_bytes();
+        common.post_process(page, &mut html)?;
+
+        if if_changed {
+            common
+                .out
+                .write_page_if_changed(page, &html, OutputCategory::Html, rewrite)?;
         } else {
-            ("div", "canvas-block", "".to_owned())
-        };
+            common
+                .out
+                .write_page(page, &html, OutputCategory::Html, rewrite)?;
+        }
 
-        write!(
-            self.current_content,
-            "<{} class=\"canvas {}\" style=\"width: {}rem; height: {}rem; padding-left: {}rem{}\">",
-            element,
-            layout_class,
-            (x_max_tex - x_min_tex) as f32 * self.rems_per_tex,
-            (y_max_tex - y_min_tex) as f32 * self.rems_per_tex,
-            -x_min_tex as f32 * self.rems_per_tex,
-            valign,
-        )
-        .unwrap();
-        self.current_content.push_str(&inner_content);
-        write!(self.current_content, "</{}>", element).unwrap();
         Ok(())
     }
 
-    fn finish_file(&mut self, common: &mut Common) -> Result<()> {
-        // Prep the output path
-
-        let mut out_path = common.out_base.to_owned();
-        let mut n_levels = 0;
+    /// Write the output files that were held back because they link to
+    /// anchors that hadn't been emitted, now that all anchors are known.
+    fn write_held_pages(&mut self, common: &mut Common) -> Result<()> {
+        // Spilled files are read back one at a time.
 
-        for piece in self.next_output_path.split('/') {
-            if piece.is_empty() {
-                continue;
-            }
+        for page in self.xrefs.take_held() {
+            let held = self.spill.take_back(page.html)?;
+            self.spill.note_resident(held.len());
+            let (html, unresolved) =
+                self.xrefs
+                    .resolve(&held, &page.path, common.options.url_style);
 
-            if piece == ".." {
-                bail!(
-                    "illegal HTML output path `{}`: it contains a `..` component",
-                    &self.next_output_path
+            for name in unresolved {
+                tt_warning!(
+                    common.status,
+                    "`{}` links to anchor `{}`, which was never emitted",
+                    page.path,
+                    name
                 );
             }
 
-            let as_path = Path::new(piece);
+            self.write_page(&page.path, html, page.rewrite, page.if_changed, common)?;
+        }
 
-            if as_path.is_absolute() || as_path.has_root() {
-                bail!(
-                    "illegal HTML output path `{}`: it contains an absolute/rooted component",
-                    &self.next_output_path
-                );
-            }
+        Ok(())
+    }
+
+    /// Write the layout sidecar of an output file, if they're enabled. Like
+    /// the file, it may be left alone if it hasn't changed.
+    fn write_layout_sidecar(
+        &mut self,
+        page: &str,
+        if_changed: bool,
+        common: &mut Common,
+    ) -> Result<()> {
+        let layout = std::mem::take(&mut self.layout);
 
-            out_path.push(piece);
-            n_levels += 1;
+        if common.options.layout_sidecars.is_none() {
+            return Ok(());
         }
 
-        self.context.insert("tduxContent", &self.current_content);
+        let mut sidecar = tera::Map::new();
+        sidecar.insert("page".into(), page.into());
+        sidecar.insert(
+            "canvases".into(),
+            atry!(
+                tera::to_value(&layout);
+                ["failed to serialize the layout of `{}`", page]
+            ),
+        );
+
+        let json = format!("{:#}\n", tera::Value::Object(sidecar));
+        let path = format!("{}.layout.json", page);
 
-        if n_levels < 2 {
-            self.context.insert("tduxRelTop", "");
+        if if_changed {
+            common.out.write_page_if_changed(
+                &path,
+                json.as_bytes(),
+                OutputCategory::Sidecar,
+                true,
+            )?;
         } else {
-            let mut rel_top = String::default();
+            common
+                .out
+                .write_page(&path, json.as_bytes(), OutputCategory::Sidecar, true)?;
+        }
 
-            for _ in 0..(n_levels - 1) {
-                rel_top.push_str("../");
-            }
+        Ok(())
+    }
 
-            self.context.insert("tduxRelTop", &rel_top);
+    /// Emit the font of last resort, if the file being built needs it and it
+    /// hasn't been emitted already.
+    ///
+    /// If the font is to be subset, only its `@font-face` rule is emitted
+    /// now, and the characters that the file needs are noted, for
+    /// [`Self::write_last_resort_subset`].
+    fn emit_last_resort_font(&mut self, common: &mut Common) -> Result<()> {
+        if self.content_finished {
+            return Ok(());
         }
 
-        // Read in the template. Let's not cache it, in case someone wants to do
-        // something fancy with rewriting it.
+        let last_resort = match self.fonts.last_resort.as_ref() {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
 
-        let mut ih = atry!(
-            common.hooks.io().input_open_name(&self.next_template_path, common.status).must_exist();
-            ["unable to open input HTML template `{}`", &self.next_template_path]
-        );
+        let rescued: Vec<_> = self
+            .chars
+            .page_chars()
+            .filter(|c| {
+                last_resort.covers(*c) && !self.fonts.font_data.values().any(|fd| fd.covers(*c))
+            })
+            .collect();
+        let needed = !rescued.is_empty() && !self.last_resort_emitted;
+
+        #[cfg(feature = "fallback-font")]
+        if fallback::can_subset(last_resort.data()) {
+            if needed {
+                fallback::write_face_css(html::LAST_RESORT_FAMILY, &mut self.font_faces)?;
+                self.last_resort_emitted = true;
+            }
 
-        let mut template = String::new();
-        atry!(
-            ih.read_to_string(&mut template);
-            ["unable to read input HTML template `{}`", &self.next_template_path]
-        );
+            self.last_resort_chars.extend(rescued);
+            return Ok(());
+        }
 
-        let (name, digest_opt) = ih.into_name_digest();
-        common
-            .hooks
-            .event_input_closed(name, digest_opt, common.status);
+        if needed {
+            last_resort.emit_base(
+                &common.out,
+                true,
+                "",
+                html::LAST_RESORT_FAMILY,
+                &mut self.font_faces,
+            )?;
+            self.last_resort_emitted = true;
+        }
 
-        // Ready to render!
+        Ok(())
+    }
+
+    /// Write the subset of the font of last resort that covers the characters
+    /// that it has rescued so far, if there are any.
+    #[cfg(feature = "fallback-font")]
+    fn write_last_resort_subset(&mut self, common: &mut Common) -> Result<()> {
+        let last_resort = match self.fonts.last_resort.as_ref() {
+            Some(fd) if !self.last_resort_chars.is_empty() => fd,
+            _ => return Ok(()),
+        };
 
-        let rendered = atry!(
-            self.tera.render_str(&template, &self.context);
-            ["failed to render HTML template `{}` while creating `{}`", &self.next_template_path, &self.next_output_path]
+        let mapping: Vec<_> = self
+            .last_resort_chars
+            .iter()
+            .filter_map(|c| last_resort.glyph(*c).map(|g| (*c, g)))
+            .collect();
+        let subset = atry!(
+            fallback::subset(last_resort.data(), &mapping)
+                .and_then(|sfnt| fallback::to_woff2(&sfnt));
+            ["failed to subset the font of last resort `{}`", last_resort.basename()]
         );
 
-        // Save it.
+        // After tdux:resetContent, the subset covers all of the content so
+        // far, replacing the earlier one.
+        common.out.write_page(
+            fallback::SUBSET_NAME,
+            &subset,
+            OutputCategory::Font,
+            self.content_reset,
+        )?;
+        Ok(())
+    }
 
-        {
-            let mut out_file = atry!(
-                File::create(&out_path);
-                ["cannot open output file `{}`", out_path.display()]
-            );
+    /// Emit the unmodified files of the specified fonts, if they haven't been
+    /// emitted already, adding their CSS to the font faces.
+    fn emit_base_fonts(&mut self, fd_keys: &[usize], common: &mut Common) -> Result<()> {
+        for fd_key in fd_keys {
+            if self.emitted_fonts.contains(fd_key) {
+                continue;
+            }
 
-            atry!(
-                out_file.write_all(rendered.as_bytes());
-                ["cannot write output file `{}`", out_path.display()]
-            );
+            if let Some(data) = self.fonts.font_data.get(fd_key) {
+                let family = format!("tdux{}", fd_key);
+                data.emit_base(
+                    &common.out,
+                    common.options.copies_fonts(),
+                    common
+                        .options
+                        .font_url_prefix
+                        .as_deref()
+                        .unwrap_or_default(),
+                    &family,
+                    &mut self.font_faces,
+                )?;
+                self.emitted_fonts.insert(*fd_key);
+
+                if let (true, Some((src, index))) =
+                    (common.options.copies_fonts(), self.fonts.input(*fd_key))
+                {
+                    self.assets
+                        .add_font_face(data.basename(), src, FontFace { index, family });
+                }
+            }
         }
 
-        self.current_content = String::default();
         Ok(())
     }
 
+    /// Warn about any control characters found in the text of an output file.
+    fn report_control_chars(&mut self, page: &str, common: &mut Common) {
+        let found = self.content.take_control_chars();
+
+        if found.is_empty() {
+            return;
+        }
+
+        let total: usize = found.values().sum();
+        let summary = found
+            .iter()
+            .map(|(c, n)| format!("U+{:04X} ({})", *c as u32, n))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let action = if common.options.keeps_control_chars() {
+            "kept as character references"
+        } else {
+            "removed"
+        };
+
+        tt_warning!(
+            common.status,
+            "{} control character(s) in the text of `{}` were {}: {}",
+            total,
+            page,
+            action,
+            summary
+        );
+    }
+
     fn content_finished(&mut self, common: &mut Common) -> Result<()> {
-        if !self.current_content.is_empty() {
-            tt_warning!(common.status, "un-emitted content at end of HTML output");
-            self.current_content = String::default();
+        common.progress(|p| p.on_phase_change(Phase::Finishing));
+
+        self.write_held_pages(common)?;
+        self.drop_pending_attributes(common);
+
+        if self.direct_lines.take().is_some() {
+            common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!("dropping an HTML fragment with no tdux:directEnd special"),
+            )?;
+        }
+
+        self.end_footnote();
+
+        if self.deferring.take().is_some() {
+            self.content.set_destination(Destination::Main);
+        }
+
+        if !self.content.is_empty() || !self.footnotes.is_empty() {
+            common.warn_or_fail(
+                WarningCategories::DROPPED_CONTENT,
+                format_args!("un-emitted content at end of HTML output"),
+            )?;
+            self.content.take();
+            self.take_deferred();
+            self.footnotes.clear();
         }
 
         // The reason we're doing all this: we can now emit our customized font
         // files that provide access to glyphs that we can't get the browser to
-        // display directly.
+        // display directly. Fonts that no page has used yet haven't been
+        // emitted at all.
 
-        let mut faces = String::default();
+        // The fallbacks of the main body font depend on its data, so get its
+        // family before the data are consumed.
 
-        for (fd_key, data) in self.font_data.drain() {
-            data.emit(common.out_base, &format!("tdux{}", fd_key), &mut faces)?;
+        if !self.fonts.fonts.is_empty() {
+            self.context.insert(
+                "tduxMainBodyFontFamily",
+                &self.fonts.css_family(self.main_body_fd_key),
+            );
         }
 
-        self.context.insert("tduxFontFaces", &faces);
+        let coverage = self.chars.report(&self.fonts);
+        common.progress(|p| p.on_coverage_report(&coverage));
+
+        if common.options.uncovered_chars_variable {
+            self.context
+                .insert("tduxUncoveredChars", &coverage.uncovered);
+        }
 
-        for info in self.fonts.values() {
-            if info.role == FontRole::MainBody {
-                self.context
-                    .insert("tduxMainBodyFontFamily", &format!("tdux{}", info.fd_key));
+        let fd_keys: Vec<_> = self.fonts.font_data.keys().copied().collect();
+        self.stats.fonts = fd_keys.len();
+        self.emit_base_fonts(&fd_keys, common)?;
+
+        #[cfg(feature = "fallback-font")]
+        self.write_last_resort_subset(common)?;
+
+        // The font data are kept, since more content may use the fonts after
+        // tdux:resetContent. The variants are all emitted again then.
+
+        self.variant_faces_start = self.font_faces.len();
+        self.stats.font_variants = 0;
+
+        for fd_key in fd_keys {
+            let data = &self.fonts.font_data[&fd_key];
+            let family = format!("tdux{}", fd_key);
+            let variants = data.emit_variants(&common.out, &family, &mut self.font_faces)?;
+            self.stats.font_variants += variants.len();
+            self.usage
+                .set_variant_bytes(fd_key, variants.iter().map(|f| f.size).sum());
+
+            for file in variants {
+                self.assets.add_font_variant(
+                    &file.path,
+                    AssetOrigin::FontVariant {
+                        family: family.clone(),
+                        index: file.index,
+                        glyphs: file.mappings.iter().map(|(c, g)| (*g, *c)).collect(),
+                    },
+                );
             }
         }
 
+        let usage = self
+            .usage
+            .report(&self.fonts, common.options.copies_fonts());
+        common.progress(|p| p.on_font_usage(&usage));
+
+        if let (Pass::Process, Some(path)) = (common.pass, &common.options.font_usage_report) {
+            let json = format!(
+                "{:#}\n",
+                atry!(
+                    tera::to_value(&usage);
+                    ["failed to serialize the font usage report"]
+                )
+            );
+            // After tdux:resetContent, the report covers all of the content
+            // so far, replacing the earlier one.
+            common
+                .out
+                .write_trusted(path, json.as_bytes(), self.content_reset)?;
+        }
+
+        self.context.insert("tduxFontFaces", &self.font_faces);
+
         // OK.
         self.content_finished = true;
         Ok(())
     }
+
+    /// Start a new logical document after the content has been finished, so
+    /// that one run can produce several independent sets of output files.
+    ///
+    /// The fonts, anchors, and template variables carry over, but the
+    /// outline, the table of contents, and the deferred streams start out
+    /// empty.
+    fn reset_content(&mut self, common: &mut Common) -> Result<()> {
+        if !self.content_finished {
+            return common.warn_or_fail(
+                WarningCategories::MALFORMED_SPECIALS,
+                format_args!("ignoring tdux:resetContent special before tdux:contentFinished"),
+            );
+        }
+
+        self.content.take();
+        self.outline.clear();
+        self.toc.clear();
+        self.deferred_streams.clear();
+        self.font_faces.truncate(self.variant_faces_start);
+        self.context.insert("tduxFontFaces", &self.font_faces);
+        self.content_finished = false;
+        self.content_finished_warning_issued = false;
+        self.content_reset = true;
+        Ok(())
+    }
 }
 
 type FixedPoint = i32;
@@ -1045,3 +6141,287 @@ struct FontInfo {
 enum FontRole {
     MainBody,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn late_initialization() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("second.otf", test_font_data());
+        io.add("outer.html", "[{% include \"partial.html\" %}]");
+        io.add("partial.html", "{{ tduxContent }}");
+
+        let (result, status) = run_engine(io.clone(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+
+            // Repeated definitions, as found within pages, are fine.
+            s.define_test_font(1, TEST_FONT_SIZE)?;
+
+            // New ones still work, but draw a warning.
+            s.handle_define_native_font("second", 5, TEST_FONT_SIZE, 0, None, None, None, None)?;
+            s.text_in(5, "World")?;
+            s.special("tdux:emit")?;
+
+            s.special("tdux:addTemplate partial.html")?;
+            s.special("tdux:setTemplate outer.html")?;
+            s.text("Again")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.warnings.len(), 2, "{:?}", status.warnings);
+        assert!(status.warnings[0].starts_with("font `second` was defined after content started"));
+        assert!(status.warnings[1]
+            .starts_with("HTML template `partial.html` was added after content started"));
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert!(html.contains("font-family: tdux1"), "{}", html);
+        assert!(tempdir.path().join("second.otf").exists());
+        let html = std::fs::read_to_string(tempdir.path().join("two.html")).unwrap();
+        assert_eq!(html, "[Again]");
+
+        // Fonts that can't be found are an error rather than being dropped.
+        let (result, _status) = run_engine(io, tempdir.path(), |s| {
+            s.text("Hello")?;
+            s.handle_define_native_font("missing", 5, TEST_FONT_SIZE, 0, None, None, None, None)
+        });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "1 referenced input(s) could not be found: font `missing`"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let depfile = tempdir.path().join("deps.mk");
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+        io.add("bad.html", "{{ nosuchvariable }}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.depfile(Some(depfile.clone()));
+
+        // Nothing is written, but the pages are counted, once each.
+        let (n_pages, _status) = run_engine_validating(&mut engine, io.clone(), |s| {
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("World")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("Again")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        assert_eq!(n_pages.unwrap(), 2);
+        assert!(!depfile.exists());
+
+        // Problems surface just as they do when processing.
+        let missing_template = |s: &mut EngineState| -> Result<()> {
+            s.special("tdux:setTemplate nosuchpage.html")?;
+            s.special("tdux:emit")
+        };
+        let missing_file = |s: &mut EngineState| -> Result<()> {
+            s.special("tdux:provideFile nosuchfile.css extra.css")?;
+            s.text("Hello")
+        };
+        let bad_template = |s: &mut EngineState| -> Result<()> {
+            s.special("tdux:setTemplate bad.html")?;
+            s.special("tdux:emit")
+        };
+
+        fn check<F>(engine: &mut Spx2HtmlEngine, io: &TestIo, events: F)
+        where
+            F: Fn(&mut EngineState) -> Result<()> + Copy,
+        {
+            let (processed, _status) = run_engine_in_memory(engine, io.clone(), events);
+            let (validated, _status) = run_engine_validating(engine, io.clone(), events);
+            assert_eq!(
+                format!("{:#}", validated.unwrap_err()),
+                format!("{:#}", processed.unwrap_err())
+            );
+        }
+
+        check(&mut engine, &io, missing_template);
+        check(&mut engine, &io, missing_file);
+        check(&mut engine, &io, bad_template);
+    }
+
+    #[test]
+    fn process_reader() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let spx = SpxBuilder::default()
+            .font(1, TEST_FONT, TEST_FONT_SIZE)
+            .special("tdux:addTemplate page.html")
+            .special("tdux:setTemplate page.html")
+            .text(1, "Hello")
+            .special("tdux:emit")
+            .special("tdux:contentFinished")
+            .build();
+
+        let mut io = simple_io();
+        io.add("doc.spx", spx.clone());
+
+        let mut engine = Spx2HtmlEngine::default();
+        let mut status = CapturingStatus::default();
+        let named_out = tempdir.path().join("named");
+        let named = engine
+            .process_to_filesystem(
+                &mut tectonic_bridge_core::MinimalDriver::new(io.clone()),
+                &mut status,
+                "doc.spx",
+                &named_out,
+            )
+            .unwrap();
+
+        let read_out = tempdir.path().join("read");
+        let read = engine
+            .process_reader_to_filesystem(
+                &mut tectonic_bridge_core::MinimalDriver::new(io),
+                &mut status,
+                std::io::Cursor::new(spx),
+                &read_out,
+            )
+            .unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        assert_eq!(read, named);
+        assert!(named.files.iter().any(|f| f.path == "index.html"));
+
+        for file in &named.files {
+            assert_eq!(
+                std::fs::read(read_out.join(&file.path)).unwrap(),
+                std::fs::read(named_out.join(&file.path)).unwrap(),
+                "{}",
+                file.path
+            );
+        }
+
+        let html = std::fs::read_to_string(read_out.join("index.html")).unwrap();
+        assert!(html.contains("Hello"), "{}", html);
+    }
+
+    #[test]
+    fn driver_io_output() {
+        let spx = SpxBuilder::default()
+            .font(1, TEST_FONT, TEST_FONT_SIZE)
+            .special("tdux:addTemplate page.html")
+            .special("tdux:setTemplate page.html")
+            .text(1, "Hello")
+            .special("tdux:setOutputPath ch/one.html")
+            .special("tdux:emit")
+            .special("tdux:contentFinished")
+            .build();
+
+        let mut io = simple_io();
+        io.add("doc.spx", spx);
+
+        let mut engine = Spx2HtmlEngine::default();
+        let mut status = CapturingStatus::default();
+        let emitted = engine
+            .process_to_driver_io(
+                &mut tectonic_bridge_core::MinimalDriver::new(io.clone()),
+                &mut status,
+                "doc.spx",
+            )
+            .unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        // The outputs go through the I/O layer, at their paths relative to
+        // the output root.
+        let outputs = io.outputs();
+        let names: Vec<_> = outputs.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, ["ch/one.html", &format!("{}.otf", TEST_FONT)]);
+        assert_eq!(outputs["ch/one.html"], b"<html><body>Hello</body></html>");
+        assert_eq!(outputs[&format!("{}.otf", TEST_FONT)], test_font_data());
+
+        let paths: Vec<_> = emitted.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, names);
+    }
+
+    #[test]
+    fn reset_content() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let report = tempdir.path().join("fonts.json");
+        let mut io = simple_io();
+        io.add("faces.css", "{{ tduxFontFaces }}");
+
+        let x = glyph_for('x');
+        let y = glyph_for('y');
+        let mut engine = Spx2HtmlEngine::default();
+        engine.font_usage_report(Some(report.clone()));
+        let (files, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:addTemplate faces.css")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:setOutputPath site/index.html")?;
+            s.text("Site")?;
+            s.special("tdux:resetContent")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:setTemplate faces.css")?;
+            s.special("tdux:setOutputPath site/faces.css")?;
+            s.special("tdux:emit")?;
+
+            // The font data are still around for the canvases of the second
+            // document.
+            s.special("tdux:resetContent")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:setOutputPath print/index.html")?;
+            s.text("Print")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "y", 0, &[y], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.special("tdux:setTemplate faces.css")?;
+            s.special("tdux:setOutputPath print/faces.css")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        for (doc, text, glyph) in [("site", "Site", 'x'), ("print", "Print", 'y')] {
+            let html = String::from_utf8(files[&format!("{}/index.html", doc)].clone()).unwrap();
+            assert!(
+                html.starts_with(&format!("<html><body>{} ", text)),
+                "{}",
+                html
+            );
+            assert!(
+                html.contains(&format!("tdux0\">{}</span>", glyph)),
+                "{}",
+                html
+            );
+
+            let css = String::from_utf8(files[&format!("{}/faces.css", doc)].clone()).unwrap();
+            assert_eq!(css.matches("font-family: \"tdux0\"").count(), 1, "{}", css);
+        }
+
+        assert!(std::fs::read_to_string(&report)
+            .unwrap()
+            .contains(TEST_FONT));
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring tdux:resetContent special before tdux:contentFinished",
+                "ignoring tdux:contentFinished special: the content is already finished",
+            ]
+        );
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Rendering the glyphs of math canvases to MathML.
+//!
+//! Canvases of the kinds `mathml-math` and `mathml-dmath` are rendered as
+//! `<math>` elements, rather than as absolutely positioned glyphs, so that
+//! their contents reflow with the text, are read by screen readers, and can
+//! be copied. This is a best-effort reconstruction from the positioned
+//! glyphs: each glyph is mapped back to its character, and glyphs that are
+//! smaller than the base size of the formula or that use a script form are
+//! attached to the glyph before them as subscripts or superscripts,
+//! depending on whether they're lowered or raised. Characters become `<mn>`,
+//! `<mi>`, or `<mo>` tokens, or `<mtext>` if they can't be classified.
+//! Rules, such as fraction bars, have no counterpart.
+
+use std::fmt::Write;
+use tectonic_errors::prelude::*;
+
+use crate::{
+    content::escape_text,
+    font::MapEntry,
+    html::{CanvasOptions, FontLookup, PositionedGlyph},
+    FixedPoint,
+};
+
+/// The namespace of MathML elements.
+const MATHML_NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+
+/// The result of rendering a canvas to MathML.
+#[derive(Clone, Debug)]
+pub struct RenderedMath {
+    /// The `<math>` element.
+    pub html: String,
+
+    /// Glyphs that could not be reverse-mapped to Unicode, and were therefore
+    /// omitted from the output.
+    pub unmapped: Vec<PositionedGlyph>,
+}
+
+/// A MathML token element.
+#[derive(Debug, Eq, PartialEq)]
+struct Token {
+    element: &'static str,
+    text: String,
+}
+
+impl Token {
+    fn new(c: char, growing: bool) -> Self {
+        let element = if growing {
+            "mo"
+        } else if c.is_numeric() {
+            "mn"
+        } else if c.is_alphabetic() {
+            "mi"
+        } else if is_operator(c) {
+            "mo"
+        } else {
+            "mtext"
+        };
+
+        Token {
+            element,
+            text: c.to_string(),
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        write!(
+            out,
+            "<{}>{}</{}>",
+            self.element,
+            escape_text(&self.text),
+            self.element
+        )
+        .unwrap();
+    }
+}
+
+/// Returns true if a character is an operator, fence, or separator.
+fn is_operator(c: char) -> bool {
+    "+-=<>()[]{}|/\\,;:!.*'".contains(c)
+        || matches!(
+            c,
+            '\u{b1}'
+                | '\u{d7}'
+                | '\u{f7}'
+                | '\u{2016}'
+                | '\u{2032}'..='\u{2037}'
+                | '\u{2190}'..='\u{21ff}'
+                | '\u{2200}'..='\u{22ff}'
+                | '\u{2308}'..='\u{230b}'
+                | '\u{27e8}'..='\u{27ef}'
+                | '\u{2a00}'..='\u{2aff}'
+        )
+}
+
+/// Add a token to a run of them, merging consecutive digits into one number.
+fn push_token(tokens: &mut Vec<Token>, token: Token) {
+    if let Some(last) = tokens.last_mut() {
+        if last.element == "mn" && token.element == "mn" {
+            last.text.push_str(&token.text);
+            return;
+        }
+    }
+
+    tokens.push(token);
+}
+
+/// Write a run of tokens as a single element, which is an empty `<mrow>` if
+/// there are none.
+fn write_group(tokens: &[Token], out: &mut String) {
+    if let [token] = tokens {
+        token.write(out);
+        return;
+    }
+
+    out.push_str("<mrow>");
+
+    for token in tokens {
+        token.write(out);
+    }
+
+    out.push_str("</mrow>");
+}
+
+/// A base token with its scripts.
+#[derive(Debug, Default)]
+struct Atom {
+    base: Vec<Token>,
+    base_dy: i32,
+    sub: Vec<Token>,
+    sup: Vec<Token>,
+}
+
+impl Atom {
+    fn write(&self, out: &mut String) {
+        let element = match (self.sub.is_empty(), self.sup.is_empty()) {
+            (true, true) => {
+                write_group(&self.base, out);
+                return;
+            }
+            (false, true) => "msub",
+            (true, false) => "msup",
+            (false, false) => "msubsup",
+        };
+
+        write!(out, "<{}>", element).unwrap();
+        write_group(&self.base, out);
+
+        for script in [&self.sub, &self.sup] {
+            if !script.is_empty() {
+                write_group(script, out);
+            }
+        }
+
+        write!(out, "</{}>", element).unwrap();
+    }
+}
+
+/// Render a set of positioned glyphs as a MathML `<math>` element.
+///
+/// The element is a block, with `display="block"`, unless the options say
+/// that the canvas is inline. Its id, classes, and style are taken from the
+/// options, and its text alternative becomes its `alttext`; the other options
+/// only apply to [`crate::html::render_canvas`].
+pub fn render_mathml(
+    fonts: &FontLookup,
+    glyphs: &[PositionedGlyph],
+    options: &CanvasOptions,
+) -> Result<RenderedMath> {
+    // Map the glyphs to characters, noting their sizes.
+
+    let mut mapped = Vec::with_capacity(glyphs.len());
+    let mut unmapped = Vec::new();
+
+    for gi in glyphs {
+        let fi = a_ok_or!(
+            fonts.fonts.get(&gi.font_num);
+            ["undeclared font {} in canvas", gi.font_num]
+        );
+
+        match fonts.font_data[&fi.fd_key].lookup_mapping(gi.glyph) {
+            Some(mc) => mapped.push((gi, fi.size, mc)),
+            None => unmapped.push(*gi),
+        }
+    }
+
+    // The base size is the largest size of the glyphs in their ordinary
+    // forms. Scripts are usually drawn smaller.
+
+    let ordinary_size = mapped
+        .iter()
+        .filter(|(_, _, mc)| !matches!(mc, MapEntry::SubSuperScript(..)))
+        .map(|(_, size, _)| *size)
+        .max();
+    let base_size: FixedPoint = ordinary_size
+        .or_else(|| mapped.iter().map(|(_, size, _)| *size).max())
+        .unwrap_or_default();
+
+    mapped.sort_by_key(|(gi, _, _)| gi.dx);
+
+    let mut atoms: Vec<Atom> = Vec::new();
+
+    for (gi, size, mc) in mapped {
+        let (token, script) = match mc {
+            MapEntry::Direct(c) => (Token::new(c, false), size < base_size),
+            MapEntry::SubSuperScript(c, _) => (Token::new(c, false), true),
+            MapEntry::MathGrowingVariant(c, _, _) => (Token::new(c, true), size < base_size),
+        };
+
+        if !script {
+            match atoms.last_mut() {
+                Some(atom)
+                    if atom.sub.is_empty()
+                        && atom.sup.is_empty()
+                        && matches!(atom.base.as_slice(), [t] if t.element == "mn")
+                        && token.element == "mn" =>
+                {
+                    push_token(&mut atom.base, token);
+                }
+                _ => atoms.push(Atom {
+                    base: vec![token],
+                    base_dy: gi.dy,
+                    ..Atom::default()
+                }),
+            }
+
+            continue;
+        }
+
+        // A script at the very start of the formula has an empty base.
+
+        if atoms.is_empty() {
+            atoms.push(Atom::default());
+        }
+
+        let atom = atoms.last_mut().unwrap();
+
+        if gi.dy < atom.base_dy {
+            push_token(&mut atom.sup, token);
+        } else {
+            push_token(&mut atom.sub, token);
+        }
+    }
+
+    // Now write it out.
+
+    let mut html = format!("<math xmlns=\"{}\"", MATHML_NAMESPACE);
+
+    if !options.inline {
+        html.push_str(" display=\"block\"");
+    }
+
+    if let Some(id) = &options.id {
+        write!(html, " id=\"{}\"", id).unwrap();
+    }
+
+    if let Some(classes) = &options.classes {
+        write!(html, " class=\"{}\"", classes).unwrap();
+    }
+
+    if let Some(style) = &options.style {
+        write!(html, " style=\"{}\"", style).unwrap();
+    }
+
+    if let Some(alt) = &options.alt {
+        write!(html, " alttext=\"{}\"", tera::escape_html(alt)).unwrap();
+    }
+
+    html.push_str("><mrow>");
+
+    for atom in &atoms {
+        atom.write(&mut html);
+    }
+
+    html.push_str("</mrow></math>");
+    Ok(RenderedMath { html, unmapped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine};
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn scripts() {
+        let pt = 65536;
+        let mut fonts = FontLookup::default();
+        fonts
+            .add_font(1, "lmroman12-regular.otf", test_font_data(), 0, 12 * pt)
+            .unwrap();
+        fonts
+            .add_font(2, "lmroman12-regular.otf", test_font_data(), 0, 8 * pt)
+            .unwrap();
+
+        // x^2 + y_i^{10} = 42 & more, given out of order, with a glyph that
+        // doesn't map to anything.
+        let glyph = |font_num, c, dx, dy| PositionedGlyph {
+            font_num,
+            glyph: glyph_for(c),
+            dx: dx * pt,
+            dy: dy * pt,
+            color_rgba: None,
+        };
+        let mut glyphs = vec![
+            glyph(1, 'x', 0, 0),
+            glyph(2, '2', 6, -4),
+            glyph(1, '+', 12, 0),
+            glyph(1, 'y', 24, 0),
+            glyph(2, 'i', 30, 2),
+            glyph(2, '1', 30, -4),
+            glyph(2, '0', 34, -4),
+            glyph(1, '=', 42, 0),
+            glyph(1, '4', 54, 0),
+            glyph(1, '2', 60, 0),
+            glyph(1, '&', 72, 0),
+            glyph(1, 'm', 80, 0),
+        ];
+        glyphs.swap(0, 4);
+        glyphs.push(PositionedGlyph {
+            glyph: 0,
+            ..glyphs[0]
+        });
+
+        let mut options = CanvasOptions::new(1. / (12. * pt as f32));
+        let rendered = render_mathml(&fonts, &glyphs, &options).unwrap();
+        assert_eq!(
+            rendered.html,
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"block\"><mrow>\
+             <msup><mi>x</mi><mn>2</mn></msup><mo>+</mo>\
+             <msubsup><mi>y</mi><mi>i</mi><mn>10</mn></msubsup>\
+             <mo>=</mo><mn>42</mn><mtext>&amp;</mtext><mi>m</mi></mrow></math>"
+        );
+        check_mathml(&rendered.html);
+        assert_eq!(rendered.unmapped.len(), 1);
+
+        // A script with nothing before it gets an empty base, and the text
+        // alternative becomes the `alttext`.
+        options
+            .inline(true)
+            .classes(Some("eq"))
+            .alt(Some("x with \"n\" & more"));
+        let glyphs = [glyph(2, 'n', 0, -4), glyph(1, 'x', 6, 0)];
+        let rendered = render_mathml(&fonts, &glyphs, &options).unwrap();
+        assert_eq!(
+            rendered.html,
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" class=\"eq\" \
+             alttext=\"x with &quot;n&quot; &amp; more\"><mrow>\
+             <msup><mrow></mrow><mi>n</mi></msup><mi>x</mi></mrow></math>"
+        );
+        check_mathml(&rendered.html);
+    }
+
+    #[test]
+    fn mathml_canvases() {
+        let pt = TEST_FONT_SIZE / 12;
+        let x = glyph_for('x');
+        let two = glyph_for('2');
+        let y = glyph_for('y');
+        let i = glyph_for('i');
+
+        let (files, status) =
+            run_engine_in_memory(&mut Spx2HtmlEngine::default(), simple_io(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.define_test_font(2, 8 * pt)?;
+                s.text("Inline")?;
+                s.handle_special(10 * pt, 20 * pt, b"tdux:cs mathml-math")?;
+                s.handle_text_and_glyphs(1, "x", 0, &[x], &[10 * pt], &[20 * pt])?;
+                s.handle_text_and_glyphs(2, "2", 0, &[two], &[16 * pt], &[16 * pt])?;
+                s.special("tdux:ce mathml-math")?;
+                s.special("tdux:class eq")?;
+                s.handle_special(10 * pt, 40 * pt, b"tdux:cs mathml-dmath")?;
+                s.handle_text_and_glyphs(1, "y", 0, &[y], &[10 * pt], &[40 * pt])?;
+                s.handle_text_and_glyphs(2, "i", 0, &[i], &[16 * pt], &[42 * pt])?;
+                s.handle_rule(10 * pt, 50 * pt, pt / 2, 10 * pt)?;
+                s.special("tdux:ce mathml-dmath")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+        let files = files.unwrap();
+        let html = String::from_utf8(files["index.html"].clone()).unwrap();
+
+        assert!(!html.contains("class=\"canvas"), "{}", html);
+        let maths: Vec<_> = html
+            .match_indices("<math ")
+            .map(|(start, _)| {
+                let end = start + html[start..].find("</math>").unwrap() + "</math>".len();
+                &html[start..end]
+            })
+            .collect();
+        assert_eq!(
+            maths,
+            [
+                "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mrow>\
+                 <msup><mi>x</mi><mn>2</mn></msup></mrow></math>",
+                "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"block\" \
+                 class=\"eq\"><mrow><msub><mi>y</mi><mi>i</mi></msub></mrow></math>",
+            ]
+        );
+
+        for math in maths {
+            check_mathml(math);
+        }
+
+        assert_eq!(
+            status.warnings,
+            ["dropping 1 rule(s) in a `mathml-dmath` canvas, which MathML can't represent"]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Giving templates access to the metrics of the document's fonts.
+//!
+//! The `glyph_metrics` Tera function looks up a character in one of the
+//! fonts and returns an object with `advance`, `lsb` (left side bearing),
+//! `ascent`, and `descent` fields, in rems. Its arguments are:
+//!
+//! - `char`: the character to look up
+//! - `family` (optional): the file name of the font, such as
+//!   `lmroman12-regular.otf`, or `body` for the main body font, which is the
+//!   default
+//! - `size_rem` (optional): the font size to assume, in rems; by default,
+//!   the size at which the document uses the font
+//!
+//! If the font doesn't map the character to a glyph, all of the fields are
+//! null. For instance, a template could size a drop cap like so:
+//!
+//! ```text
+//! {% set m = glyph_metrics(char="T", size_rem=3) %}
+//! <span class="drop-cap" style="font-size: 3rem; width: {{ m.advance }}rem;
+//!   height: {{ m.ascent }}rem">T</span>
+//! ```
+//!
+//! The metrics are copied out of the font data when initialization finishes,
+//! so that they remain available no matter when pages are rendered.
+
+use std::{collections::HashMap, sync::Arc};
+use tera::{Tera, Value};
+
+use crate::{font::CharMetrics, html::FontLookup};
+
+/// The metrics of a font available to templates.
+#[derive(Clone, Debug)]
+pub struct FontMetrics {
+    /// The character metrics of the font.
+    pub metrics: CharMetrics,
+
+    /// The size at which the document uses the font, in rems.
+    pub size_rem: f32,
+}
+
+/// The fonts available to templates, keyed by file name, plus `body` for the
+/// main body font.
+pub type MetricsTable = HashMap<String, FontMetrics>;
+
+/// Collect the metrics of all of the fonts known to a lookup.
+///
+/// The size of each font is taken from the lowest-numbered font that uses
+/// its data.
+pub fn collect(fonts: &FontLookup, rems_per_tex: f32) -> MetricsTable {
+    let mut font_nums: Vec<_> = fonts.fonts.keys().copied().collect();
+    font_nums.sort_unstable();
+
+    let mut table = MetricsTable::new();
+
+    for font_num in font_nums {
+        let fi = &fonts.fonts[&font_num];
+        let fd = &fonts.font_data[&fi.fd_key];
+
+        if table.contains_key(fd.basename()) {
+            continue;
+        }
+
+        let entry = FontMetrics {
+            metrics: fd.char_metrics(),
+            size_rem: fi.size as f32 * rems_per_tex,
+        };
+
+        if fonts.main_body_fd_key == Some(fi.fd_key) {
+            table.insert("body".to_owned(), entry.clone());
+        }
+
+        table.insert(fd.basename().to_owned(), entry);
+    }
+
+    table
+}
+
+fn glyph_metrics(table: &MetricsTable, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let c = args.get("char").and_then(|v| v.as_str()).and_then(|s| {
+        let mut chars = s.chars();
+
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    });
+
+    let c = c.ok_or_else(|| {
+        tera::Error::msg("the `glyph_metrics` function requires a single-character `char` argument")
+    })?;
+
+    let family = match args.get("family") {
+        None => "body",
+        Some(Value::String(s)) => s.as_str(),
+        Some(_) => {
+            return Err(tera::Error::msg(
+                "the `family` argument of the `glyph_metrics` function must be a string",
+            ))
+        }
+    };
+
+    let font = table.get(family).ok_or_else(|| {
+        tera::Error::msg(format!(
+            "the `glyph_metrics` function was asked about unknown font `{}`",
+            family
+        ))
+    })?;
+
+    let size_rem = match args.get("size_rem") {
+        None => font.size_rem,
+        Some(v) => v.as_f64().ok_or_else(|| {
+            tera::Error::msg(
+                "the `size_rem` argument of the `glyph_metrics` function must be a number",
+            )
+        })? as f32,
+    };
+
+    let mut result = tera::Map::new();
+
+    match font.metrics.lookup(c) {
+        Some(m) => {
+            result.insert("advance".into(), (m.advance * size_rem).into());
+            result.insert("lsb".into(), (m.lsb * size_rem).into());
+            result.insert("ascent".into(), (m.ascent * size_rem).into());
+            result.insert("descent".into(), (m.descent * size_rem).into());
+        }
+
+        None => {
+            for key in ["advance", "lsb", "ascent", "descent"] {
+                result.insert(key.into(), Value::Null);
+            }
+        }
+    }
+
+    Ok(Value::Object(result))
+}
+
+/// Register the `glyph_metrics` function with a Tera instance.
+pub fn register_function(tera: &mut Tera, table: MetricsTable) {
+    let table = Arc::new(table);
+    tera.register_function("glyph_metrics", move |args: &HashMap<String, Value>| {
+        glyph_metrics(&table, args)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+
+    #[test]
+    fn glyph_metrics() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add(
+            "metrics.html",
+            "{% set m = glyph_metrics(char=\"x\") %}\
+             {% set d = glyph_metrics(char=\"x\", family=\"lmroman12-regular.otf\", size_rem=2) %}\
+             {% set u = glyph_metrics(char=\"\u{e000}\") %}\
+             {{ m.advance > 0 and m.ascent > 0 and m.descent < 0 }} \
+             {{ d.advance / m.advance }} {{ u.advance | json_encode() }}",
+        );
+
+        let (result, _status) = run_engine(io, tempdir.path(), |s| {
+            s.special("tdux:setTemplate metrics.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        let out = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(out, "true 2 null");
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Patterns for automatically generating output paths.
+//!
+//! A pattern is set with `tdux:setOutputPathPattern` and is resolved afresh
+//! every time an output file is emitted. Patterns may contain the following
+//! placeholders:
+//!
+//! - `{n}`: the 1-based number of the output file being emitted; `{n:03}`
+//!   zero-pads it to three digits, and so on
+//! - `{slug}`: a slug derived from the text of the most recent heading
+//! - `{var:NAME}`: the value of the template variable `NAME`
+//!
+//! A literal brace is written as `{{` or `}}`.
+
+use tectonic_errors::prelude::*;
+
+/// One piece of a parsed pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Piece {
+    Literal(String),
+    Counter { width: usize },
+    Slug,
+    Var(String),
+}
+
+/// A parsed output path pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputPathPattern {
+    pieces: Vec<Piece>,
+}
+
+impl OutputPathPattern {
+    /// Parse a pattern.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+
+                '{' => {
+                    let mut name = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => {
+                                bail!("unterminated placeholder in output path pattern `{}`", text)
+                            }
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    pieces.push(Self::parse_placeholder(&name, text)?);
+                }
+
+                '}' => bail!("unpaired `}}` in output path pattern `{}`", text),
+
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+
+        Ok(OutputPathPattern { pieces })
+    }
+
+    fn parse_placeholder(name: &str, text: &str) -> Result<Piece> {
+        if name == "n" {
+            Ok(Piece::Counter { width: 0 })
+        } else if let Some(spec) = name.strip_prefix("n:") {
+            let width = a_ok_or!(
+                spec.strip_prefix('0').and_then(|w| w.parse::<usize>().ok());
+                ["invalid counter format `{}` in output path pattern `{}`", spec, text]
+            );
+            Ok(Piece::Counter { width })
+        } else if name == "slug" {
+            Ok(Piece::Slug)
+        } else if let Some(varname) = name.strip_prefix("var:") {
+            Ok(Piece::Var(varname.to_owned()))
+        } else {
+            bail!(
+                "unrecognized placeholder `{{{}}}` in output path pattern `{}`",
+                name,
+                text
+            );
+        }
+    }
+
+    /// Resolve the pattern into an output path.
+    ///
+    /// *n* is the number of the output file being emitted, *slug* is the slug
+    /// of the most recent heading, if any, and *lookup_var* looks up template
+    /// variables. If a slug or variable placeholder can't be resolved, the
+    /// counter is substituted in its place and a description of the problem
+    /// is returned as well, so that the caller can warn about it.
+    pub fn resolve<F>(&self, n: usize, slug: Option<&str>, lookup_var: F) -> (String, Vec<String>)
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut path = String::new();
+        let mut problems = Vec::new();
+
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => path.push_str(s),
+
+                Piece::Counter { width } => {
+                    path.push_str(&format!("{:0width$}", n, width = *width));
+                }
+
+                Piece::Slug => match slug {
+                    Some(s) if !s.is_empty() => path.push_str(s),
+                    _ => {
+                        problems.push("there is no heading to derive a slug from".to_owned());
+                        path.push_str(&n.to_string());
+                    }
+                },
+
+                Piece::Var(name) => match lookup_var(name) {
+                    Some(v) if !v.is_empty() => path.push_str(&v),
+                    _ => {
+                        problems.push(format!("template variable `{}` is not set", name));
+                        path.push_str(&n.to_string());
+                    }
+                },
+            }
+        }
+
+        (path, problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn vars(name: &str) -> Option<String> {
+        if name == "chapter" {
+            Some("intro".to_owned())
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let p = OutputPathPattern::parse("page-{n}.html").unwrap();
+        assert_eq!(p.resolve(7, None, vars), ("page-7.html".to_owned(), vec![]));
+
+        let p = OutputPathPattern::parse("{var:chapter}/{n:03}-{slug}.html").unwrap();
+        assert_eq!(
+            p.resolve(7, Some("results"), vars),
+            ("intro/007-results.html".to_owned(), vec![])
+        );
+
+        let p = OutputPathPattern::parse("{{{n}}}.html").unwrap();
+        assert_eq!(p.resolve(1, None, vars).0, "{1}.html");
+    }
+
+    #[test]
+    fn fallbacks() {
+        let p = OutputPathPattern::parse("{var:part}/{slug}.html").unwrap();
+        let (path, problems) = p.resolve(3, None, vars);
+        assert_eq!(path, "3/3.html");
+        assert_eq!(problems.len(), 2);
+    }
+
+    #[test]
+    fn bad_patterns() {
+        assert!(OutputPathPattern::parse("{n").is_err());
+        assert!(OutputPathPattern::parse("n}").is_err());
+        assert!(OutputPathPattern::parse("{n:3}").is_err());
+        assert!(OutputPathPattern::parse("{page}").is_err());
+    }
+
+    #[test]
+    fn output_path_pattern() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("path.html", "{{ tduxCurrentOutputPath }}");
+
+        let (result, status) = run_engine(io, tempdir.path(), |s| {
+            s.special("tdux:addTemplate path.html")?;
+            s.special("tdux:setTemplate path.html")?;
+            s.special("tdux:setOutputPathPattern {n:02}-{slug}.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:as h1")?;
+            s.text("Getting Started")?;
+            s.special("tdux:ae h1")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath fixed.html")?;
+            s.text("x")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplateVariable part appendix")?;
+            s.special("tdux:setOutputPathPattern {var:part}/{n}.html")?;
+            s.text("y")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath fixed.html")?;
+            s.text("z")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        // No heading had been seen for the first file.
+        assert_eq!(status.warnings.len(), 2);
+        assert!(status.warnings[0].contains("no heading"));
+        assert!(status.warnings[1].contains("already been emitted"));
+
+        for path in [
+            "01-1.html",
+            "02-getting-started.html",
+            "fixed.html",
+            "appendix/4.html",
+        ] {
+            let html = std::fs::read_to_string(tempdir.path().join(path)).unwrap();
+            assert_eq!(html, path);
+        }
+    }
+}
@@ -0,0 +1,1713 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Writing output files.
+//!
+//! Several engine runs may target the same output directory at once (e.g., two
+//! variants of the same document being built in parallel). To make sure that
+//! readers never see partially-written files, and that two writers never
+//! interleave their data, every output file is written to a uniquely-named
+//! temporary sibling and then atomically renamed into place.
+//!
+//! The permissions of output files are set explicitly according to an
+//! [`OutputPolicy`], rather than being inherited from the process umask or the
+//! files that they were copied from.
+//!
+//! Alternatively, the output files can be kept in memory, for applications
+//! that don't want the engine to touch the filesystem, or handed to an
+//! [`OutputSink`] supplied by the application, which can store them however it
+//! likes. Either way, all output files go through an [`OutputTree`], which
+//! applies the same checks to their paths before anything is written.
+//!
+//! For incremental builds, a tree can also be told to only write the HTML
+//! pages, or only the other files, with an [`OutputSelection`]. Files that
+//! aren't selected are still checked, but not written.
+//!
+//! A [`ZipSink`] stores the output files in a ZIP archive, for publishing
+//! pipelines that would rather deal with a single file.
+//!
+//! The tree keeps track of the files that it writes, so that applications
+//! can learn exactly what a run produced, as [`EmittedOutputs`].
+
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tectonic_errors::prelude::*;
+
+use crate::{
+    assets::MAX_INLINE_ASSET_SIZE, CompressionKinds, OutputSelection, OverwritePolicy,
+    Spx2HtmlError, UrlStyle,
+};
+
+/// How output files and directories are created.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputPolicy {
+    /// The Unix permissions given to output files.
+    pub file_mode: u32,
+
+    /// The Unix permissions given to output directories created by the
+    /// engine.
+    pub dir_mode: u32,
+
+    /// If not None, the Unix user and group IDs that should own the outputs.
+    pub owner: Option<(u32, u32)>,
+
+    /// What happens to files that already exist.
+    pub overwrite: OverwritePolicy,
+}
+
+impl Default for OutputPolicy {
+    fn default() -> Self {
+        OutputPolicy {
+            file_mode: 0o644,
+            dir_mode: 0o755,
+            owner: None,
+            overwrite: OverwritePolicy::default(),
+        }
+    }
+}
+
+impl OutputPolicy {
+    /// Apply the policy to a newly-created file or directory.
+    #[cfg(unix)]
+    fn apply(&self, path: &Path, is_dir: bool) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = if is_dir {
+            self.dir_mode
+        } else {
+            self.file_mode
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+        if let Some((uid, gid)) = self.owner {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the policy to a newly-created file or directory.
+    ///
+    /// Permissions and ownership are only managed on Unix, so this does
+    /// nothing.
+    #[cfg(not(unix))]
+    fn apply(&self, _path: &Path, _is_dir: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Check whether a file that this run hasn't written yet should be
+    /// written to *path*, given what might already be there. The
+    /// *normalized* path is used in errors. If *if_changed* is true, an
+    /// identical file is left alone, as with
+    /// [`OverwritePolicy::SkipIfIdentical`], unless the policy is stricter.
+    fn should_overwrite(
+        &self,
+        path: &Path,
+        normalized: &str,
+        data: &[u8],
+        if_changed: bool,
+    ) -> Result<bool> {
+        let io_err = |e| Spx2HtmlError::Io {
+            path: path.display().to_string(),
+            source: e,
+        };
+
+        let overwrite = match (self.overwrite, if_changed) {
+            (OverwritePolicy::Always, true) => OverwritePolicy::SkipIfIdentical,
+            (policy, _) => policy,
+        };
+
+        match overwrite {
+            OverwritePolicy::Always => Ok(true),
+
+            OverwritePolicy::ErrorIfExists => match std::fs::symlink_metadata(path) {
+                Ok(_) => Err(Spx2HtmlError::OutputExists {
+                    path: normalized.to_owned(),
+                }
+                .into()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+                Err(e) => Err(io_err(e).into()),
+            },
+
+            OverwritePolicy::SkipIfIdentical => match file_has_contents(path, data) {
+                Ok(identical) => Ok(!identical),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+                Err(e) => Err(io_err(e).into()),
+            },
+        }
+    }
+
+    /// Create a directory and any missing parents, applying the policy to
+    /// those that are newly created.
+    fn create_dirs(&self, dir: &Path) -> std::io::Result<()> {
+        if dir.as_os_str().is_empty() || dir.is_dir() {
+            return Ok(());
+        }
+
+        if let Some(parent) = dir.parent() {
+            self.create_dirs(parent)?;
+        }
+
+        match std::fs::create_dir(dir) {
+            Ok(()) => self.apply(dir, true),
+            // Someone else might have created it in the meantime.
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && dir.is_dir() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A process-wide counter used to make temporary file names unique across
+/// threads.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Compute the name of a temporary file to be used when writing `path`.
+///
+/// The name is unique to this process and this call, and lives in the same
+/// directory as the final destination so that the rename is atomic.
+fn temp_sibling(path: &Path) -> PathBuf {
+    let base = path
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    path.with_file_name(format!(".{}.tdux-{}-{}.tmp", base, std::process::id(), n))
+}
+
+/// Write data to an output file atomically.
+///
+/// The data are first written to a temporary file next to the destination,
+/// which is then renamed over the destination. If the destination already
+/// exists, it is replaced. Concurrent writers of the same path never produce a
+/// file containing a mixture of their data: the last rename wins. Missing
+/// parent directories are created. The *policy* is applied to the file, and
+/// to any directories, before they become visible.
+pub fn write_atomically(path: &Path, data: &[u8], policy: &OutputPolicy) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        policy.create_dirs(parent).map_err(|e| Spx2HtmlError::Io {
+            path: parent.display().to_string(),
+            source: e,
+        })?;
+    }
+
+    let tmp_path = temp_sibling(path);
+    let result = std::fs::write(&tmp_path, data)
+        .and_then(|_| policy.apply(&tmp_path, false))
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ignored = std::fs::remove_file(&tmp_path);
+    }
+
+    result.map_err(|e| Spx2HtmlError::Io {
+        path: path.display().to_string(),
+        source: e,
+    })?;
+    Ok(())
+}
+
+/// Check whether the file at *path* has exactly the contents *data*.
+///
+/// The file is compared a chunk at a time, so that checking a big page
+/// doesn't mean reading all of it into memory, and not at all if its size
+/// differs.
+fn file_has_contents(path: &Path, data: &[u8]) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+
+    if file.metadata()?.len() != data.len() as u64 {
+        return Ok(false);
+    }
+
+    let mut chunk = vec![0; 64 * 1024];
+    let mut rest = data;
+
+    loop {
+        let n = match file.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        if n == 0 {
+            return Ok(rest.is_empty());
+        }
+
+        if n > rest.len() || chunk[..n] != rest[..n] {
+            return Ok(false);
+        }
+
+        rest = &rest[n..];
+    }
+}
+
+/// Resolve a path relative to the output directory.
+///
+/// Paths that would escape the output directory are rejected. Returns the
+/// full path of the output file and the normalized form of the relative path.
+pub fn resolve_output_path(out_base: &Path, rel_path: &str) -> Result<(PathBuf, String)> {
+    let mut out_path = out_base.to_owned();
+    let mut normalized = Vec::new();
+
+    for piece in rel_path.split('/') {
+        if piece.is_empty() || piece == "." {
+            continue;
+        }
+
+        let reason = if piece == ".." {
+            Some("it contains a `..` component")
+        } else if Path::new(piece).is_absolute() || Path::new(piece).has_root() {
+            Some("it contains an absolute/rooted component")
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            return Err(Spx2HtmlError::PathRejected {
+                path: rel_path.to_owned(),
+                reason: reason.to_owned(),
+            }
+            .into());
+        }
+
+        out_path.push(piece);
+        normalized.push(piece);
+    }
+
+    Ok((out_path, normalized.join("/")))
+}
+
+/// The categories of output files.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputCategory {
+    /// An HTML page.
+    Html,
+
+    /// A layout sidecar file, describing an HTML page.
+    Sidecar,
+
+    /// A font file, including the variants with customized character maps
+    /// and the font of last resort.
+    Font,
+
+    /// A file provided with `tdux:provideFile` or
+    /// [`crate::Spx2HtmlEngine::provide_asset`].
+    CopiedAsset,
+
+    /// The stylesheet of the engine, `tdux-base.css`.
+    Stylesheet,
+
+    /// A file at a path chosen by the application, such as the depfile.
+    Requested,
+}
+
+impl OutputCategory {
+    /// Check whether files of this category are pages, which are selected by
+    /// [`OutputSelection::PagesOnly`].
+    fn is_page(self) -> bool {
+        matches!(self, OutputCategory::Html | OutputCategory::Sidecar)
+    }
+
+    /// Check whether files of this category are assets, which are selected
+    /// by [`OutputSelection::AssetsOnly`] and can be read back.
+    fn is_asset(self) -> bool {
+        matches!(
+            self,
+            OutputCategory::Font | OutputCategory::CopiedAsset | OutputCategory::Stylesheet
+        )
+    }
+}
+
+impl CompressionKinds {
+    /// Check whether files of a category are compressed.
+    fn covers(&self, category: OutputCategory) -> bool {
+        match category {
+            OutputCategory::Html | OutputCategory::Sidecar | OutputCategory::Stylesheet => true,
+            OutputCategory::Font => self.fonts,
+            OutputCategory::CopiedAsset => self.assets,
+            OutputCategory::Requested => false,
+        }
+    }
+
+    /// Compress the contents of a file, returning each compressed variant
+    /// with the extension that's added to the name of the file.
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<(&'static str, Vec<u8>)>> {
+        let mut variants = Vec::new();
+
+        if self.gzip {
+            // The header records no file name or timestamp, so the output is
+            // reproducible.
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(data)?;
+            variants.push(("gz", encoder.finish()?));
+        }
+
+        #[cfg(feature = "brotli")]
+        if self.brotli {
+            let mut compressed = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+                writer.write_all(data)?;
+                writer.flush()?;
+            }
+            variants.push(("br", compressed));
+        }
+
+        Ok(variants)
+    }
+}
+
+/// A file written to the output tree.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct EmittedFile {
+    /// The path of the file, relative to the output root.
+    pub path: String,
+
+    /// The size of the file, in bytes.
+    pub size: u64,
+
+    /// The category of the file.
+    pub category: OutputCategory,
+
+    /// Whether the file was left alone, because the output directory
+    /// already had it with the same contents. See
+    /// [`crate::OverwritePolicy::SkipIfIdentical`] and `tdux:emitIfChanged`.
+    pub unchanged: bool,
+}
+
+/// The files written to the output tree by a run of the engine.
+///
+/// Each file is listed once, with its final size, even if it was written
+/// several times. Files at paths chosen by the application that lie outside
+/// of the output tree aren't listed, and neither are files left unwritten
+/// because of the [`OutputSelection`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct EmittedOutputs {
+    /// The files, in order of their paths.
+    pub files: Vec<EmittedFile>,
+}
+
+/// Make a path absolute, resolving its `.` and `..` components lexically.
+fn lexical_absolute(path: &Path) -> std::io::Result<PathBuf> {
+    let mut result = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        std::env::current_dir()?
+    };
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            c => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
+
+/// A destination for output files, supplied by the application.
+///
+/// See [`crate::Spx2HtmlEngine::process_to_sink`].
+pub trait OutputSink: Send {
+    /// Store an output file.
+    ///
+    /// The *rel_path* is relative to the output root, with components
+    /// separated by `/`. It has already been checked and normalized, so it
+    /// contains no empty, `.`, or `..` components. The same path may be
+    /// written more than once, in which case the last contents win.
+    fn write_file(&mut self, rel_path: &str, data: &[u8]) -> Result<()>;
+
+    /// Finish storing files. This is called once processing has succeeded.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An [`OutputSink`] that stores the output files in a ZIP archive.
+///
+/// The files are compressed with Deflate, and given no timestamps, so that
+/// the same output produces the same archive. An archive can't hold two
+/// files at the same path, so it's an error for a file to be written more
+/// than once, as a page emitted twice at the same path would be. When the
+/// sink is finished, the archive is completed and its writer is placed in
+/// the slot given to [`ZipSink::new`].
+pub struct ZipSink<W: Write + Seek> {
+    zip: Option<zip::ZipWriter<W>>,
+    names: HashSet<String>,
+    finished: Arc<Mutex<Option<W>>>,
+}
+
+impl<W: Write + Seek> ZipSink<W> {
+    /// Create a sink that writes an archive to *writer*, and hands it back
+    /// through *finished*.
+    pub fn new(writer: W, finished: Arc<Mutex<Option<W>>>) -> Self {
+        ZipSink {
+            zip: Some(zip::ZipWriter::new(writer)),
+            names: HashSet::new(),
+            finished,
+        }
+    }
+}
+
+impl<W: Write + Seek + Send> OutputSink for ZipSink<W> {
+    fn write_file(&mut self, rel_path: &str, data: &[u8]) -> Result<()> {
+        if !self.names.insert(rel_path.to_owned()) {
+            return Err(Spx2HtmlError::OutputConflict {
+                path: rel_path.to_owned(),
+            }
+            .into());
+        }
+
+        let zip = match self.zip.as_mut() {
+            Some(z) => z,
+            None => bail!("cannot add `{}` to a finished ZIP archive", rel_path),
+        };
+
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        atry!(
+            zip.start_file(rel_path, options);
+            ["failed to start ZIP archive entry `{}`", rel_path]
+        );
+        atry!(
+            zip.write_all(data);
+            ["failed to write ZIP archive entry `{}`", rel_path]
+        );
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(mut zip) = self.zip.take() {
+            let writer = atry!(
+                zip.finish();
+                ["failed to finish the ZIP archive"]
+            );
+            *self.finished.lock().unwrap() = Some(writer);
+        }
+
+        Ok(())
+    }
+}
+
+/// Post-processes output files before they're written, on behalf of the
+/// application.
+///
+/// See [`crate::Spx2HtmlEngine::post_processor`]. This is implemented for
+/// closures with the same signature as [`PostProcessor::process`].
+pub trait PostProcessor: Send {
+    /// Transform the contents of an output file in place.
+    ///
+    /// The *rel_path* is the normalized path of the file, relative to the
+    /// output root. Returning an error aborts processing.
+    fn process(&mut self, rel_path: &str, data: &mut Vec<u8>) -> Result<()>;
+}
+
+impl<F> PostProcessor for F
+where
+    F: FnMut(&str, &mut Vec<u8>) -> Result<()> + Send,
+{
+    fn process(&mut self, rel_path: &str, data: &mut Vec<u8>) -> Result<()> {
+        self(rel_path, data)
+    }
+}
+
+/// Where the output files of an engine run go.
+///
+/// Clones share the same files.
+#[derive(Clone, Debug)]
+pub struct OutputTree {
+    /// The output directory, or None if the files are kept in memory or
+    /// handed to a sink.
+    base: Option<PathBuf>,
+
+    /// How files and directories are created in the output directory.
+    policy: OutputPolicy,
+
+    state: Arc<Mutex<TreeState>>,
+}
+
+#[derive(Default)]
+struct TreeState {
+    /// The hashes and sizes of the contents of the files written so far,
+    /// keyed by their normalized paths.
+    written: HashMap<String, (u64, u64)>,
+
+    /// The contents of the files, if they're kept in memory.
+    files: HashMap<String, Vec<u8>>,
+
+    /// The sink that the files are handed to, if any.
+    sink: Option<Box<dyn OutputSink>>,
+
+    /// Which files are written.
+    selection: OutputSelection,
+
+    /// Which compressed variants of the files are written.
+    compression: CompressionKinds,
+
+    /// The contents of the assets that weren't written because of the
+    /// selection, or that were handed to the sink and are small enough to be
+    /// inlined, so that they can still be read back.
+    held: HashMap<String, Vec<u8>>,
+
+    /// The sizes and categories of the files actually written to the tree,
+    /// keyed by their normalized paths, and whether they were left alone
+    /// because they were already there.
+    emitted: BTreeMap<String, (u64, OutputCategory, bool)>,
+}
+
+impl TreeState {
+    /// Record that a file is about to be written at *key*, returning whether
+    /// it actually needs to be.
+    ///
+    /// Files may well be written more than once with the same contents, such
+    /// as fonts, which are written when they're defined and again before the
+    /// first page that uses them. Otherwise, it's an error to write a file
+    /// again, unless *replace* is true.
+    fn register(&mut self, key: &str, data: &[u8], replace: bool) -> Result<bool> {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        match self
+            .written
+            .insert(key.to_owned(), (hash, data.len() as u64))
+        {
+            Some((prev, _)) if prev == hash => Ok(false),
+            Some(_) if !replace => Err(Spx2HtmlError::OutputConflict {
+                path: key.to_owned(),
+            }
+            .into()),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl fmt::Debug for TreeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreeState")
+            .field("written", &self.written)
+            .field("files", &self.files.keys())
+            .field("sink", &self.sink.is_some())
+            .field("selection", &self.selection)
+            .field("compression", &self.compression)
+            .field("held", &self.held.keys())
+            .field("emitted", &self.emitted)
+            .finish()
+    }
+}
+
+impl OutputTree {
+    /// Create a tree that writes files to the directory *base*.
+    pub fn directory(base: &Path, policy: OutputPolicy) -> Self {
+        OutputTree {
+            base: Some(base.to_owned()),
+            policy,
+            state: Default::default(),
+        }
+    }
+
+    /// Create a tree that keeps files in memory.
+    pub fn memory() -> Self {
+        OutputTree {
+            base: None,
+            policy: OutputPolicy::default(),
+            state: Default::default(),
+        }
+    }
+
+    /// Create a tree that hands files to *sink*.
+    pub fn sink(sink: Box<dyn OutputSink>) -> Self {
+        let tree = OutputTree::memory();
+        tree.state.lock().unwrap().sink = Some(sink);
+        tree
+    }
+
+    /// Choose which files are written. The choice applies to all clones of
+    /// the tree.
+    pub fn select(&self, selection: OutputSelection) {
+        self.state.lock().unwrap().selection = selection;
+    }
+
+    /// Choose which compressed variants of files are written. The choice
+    /// applies to all clones of the tree.
+    pub fn precompress(&self, kinds: CompressionKinds) {
+        self.state.lock().unwrap().compression = kinds;
+    }
+
+    /// Get the output directory, which is empty if files aren't written to
+    /// the filesystem.
+    pub fn base(&self) -> &Path {
+        self.base.as_deref().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// Check a path relative to the output root, returning its normalized
+    /// form. Paths that would escape the output root are rejected.
+    pub fn resolve(&self, rel_path: &str) -> Result<String> {
+        resolve_output_path(self.base(), rel_path).map(|(_, normalized)| normalized)
+    }
+
+    /// Get a description of the output file at a normalized path, for use in
+    /// error messages.
+    pub fn display(&self, normalized: &str) -> String {
+        self.base().join(normalized).display().to_string()
+    }
+
+    /// Write an output file of some category, returning the normalized form
+    /// of its path.
+    ///
+    /// It's an error for the file to have already been written with
+    /// different contents in this run.
+    pub fn write(&self, rel_path: &str, data: &[u8], category: OutputCategory) -> Result<String> {
+        self.put(rel_path, data, false, category, false)
+    }
+
+    /// Like [`Self::write`], but for an HTML page or a file describing one.
+    ///
+    /// If *replace* is true, the file replaces any that was already written
+    /// in this run, rather than conflicting with it.
+    pub fn write_page(
+        &self,
+        rel_path: &str,
+        data: &[u8],
+        category: OutputCategory,
+        replace: bool,
+    ) -> Result<String> {
+        self.put(rel_path, data, replace, category, false)
+    }
+
+    /// Like [`Self::write_page`], but leaving a file that's already in the
+    /// output directory alone if it has the same contents, unless the
+    /// [`OverwritePolicy`] forbids overwriting it at all.
+    pub fn write_page_if_changed(
+        &self,
+        rel_path: &str,
+        data: &[u8],
+        category: OutputCategory,
+        replace: bool,
+    ) -> Result<String> {
+        self.put(rel_path, data, replace, category, true)
+    }
+
+    /// Write a file at a path chosen by the application, rather than the
+    /// document, such as a depfile.
+    ///
+    /// Such paths aren't confined to the output directory: they may be
+    /// absolute, or relative to the current directory, and may contain `..`
+    /// components. A path that ends up within the output directory is
+    /// treated like any other output file there. Otherwise, the file is
+    /// written to the filesystem, even if the other output files aren't. In
+    /// both cases, it's an error for the file to have already been written
+    /// with different contents in this run, unless *replace* is true.
+    pub fn write_trusted(&self, path: &Path, data: &[u8], replace: bool) -> Result<()> {
+        let io_err = |e| Spx2HtmlError::Io {
+            path: path.display().to_string(),
+            source: e,
+        };
+        let path = lexical_absolute(path).map_err(io_err)?;
+
+        if let Some(base) = &self.base {
+            let base = lexical_absolute(base).map_err(io_err)?;
+
+            if let Some(rel) = path.strip_prefix(&base).ok().and_then(|r| r.to_str()) {
+                let rel = rel.replace(std::path::MAIN_SEPARATOR, "/");
+                self.put(&rel, data, replace, OutputCategory::Requested, false)?;
+                return Ok(());
+            }
+        }
+
+        let key = path.display().to_string();
+
+        if self.state.lock().unwrap().register(&key, data, replace)? {
+            write_atomically(&path, data, &self.policy)?;
+        }
+
+        Ok(())
+    }
+
+    fn put(
+        &self,
+        rel_path: &str,
+        data: &[u8],
+        replace: bool,
+        category: OutputCategory,
+        if_changed: bool,
+    ) -> Result<String> {
+        let (normalized, written) = self.put_one(rel_path, data, replace, category, if_changed)?;
+        let kinds = self.state.lock().unwrap().compression;
+
+        if written && kinds.covers(category) {
+            let variants = atry!(
+                kinds.compress(data);
+                ["failed to compress output file `{}`", normalized]
+            );
+
+            for (ext, compressed) in variants {
+                let path = format!("{}.{}", normalized, ext);
+                self.put_one(&path, &compressed, replace, category, if_changed)?;
+            }
+        }
+
+        Ok(normalized)
+    }
+
+    /// Write one file, returning the normalized form of its path and whether
+    /// it was actually written, rather than being left out by the selection
+    /// or having already been written with the same contents.
+    fn put_one(
+        &self,
+        rel_path: &str,
+        data: &[u8],
+        replace: bool,
+        category: OutputCategory,
+        if_changed: bool,
+    ) -> Result<(String, bool)> {
+        let (out_path, normalized) = resolve_output_path(self.base(), rel_path)?;
+        let mut state = self.state.lock().unwrap();
+
+        if !state.register(&normalized, data, replace)? {
+            return Ok((normalized, false));
+        }
+
+        let selected = match (state.selection, category) {
+            (OutputSelection::All, _) | (_, OutputCategory::Requested) => true,
+            (OutputSelection::AssetsOnly, category) => category.is_asset(),
+            (OutputSelection::PagesOnly, category) => category.is_page(),
+            (OutputSelection::Nothing, _) => false,
+        };
+
+        let mut unchanged = false;
+
+        if !selected {
+            if category.is_asset() {
+                state.held.insert(normalized.clone(), data.to_owned());
+            }
+
+            return Ok((normalized, false));
+        }
+
+        if let Some(sink) = state.sink.as_mut() {
+            atry!(
+                sink.write_file(&normalized, data);
+                ["failed to write output file `{}`", normalized]
+            );
+
+            if category.is_asset() && data.len() as u64 <= MAX_INLINE_ASSET_SIZE {
+                state.held.insert(normalized.clone(), data.to_owned());
+            }
+        } else if self.base.is_some() {
+            if state.emitted.contains_key(&normalized)
+                || self
+                    .policy
+                    .should_overwrite(&out_path, &normalized, data, if_changed)?
+            {
+                write_atomically(&out_path, data, &self.policy)?;
+            } else {
+                unchanged = true;
+            }
+        } else {
+            state.files.insert(normalized.clone(), data.to_owned());
+        }
+
+        state
+            .emitted
+            .insert(normalized.clone(), (data.len() as u64, category, unchanged));
+        Ok((normalized, true))
+    }
+
+    /// Get the size of the output file at a normalized path, which must have
+    /// been written in this run.
+    pub fn size(&self, normalized: &str) -> std::io::Result<u64> {
+        match self.state.lock().unwrap().written.get(normalized) {
+            Some((_, size)) => Ok(*size),
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Read back the output file at a normalized path.
+    pub fn read(&self, normalized: &str) -> std::io::Result<Vec<u8>> {
+        if let Some(data) = self.state.lock().unwrap().held.get(normalized) {
+            return Ok(data.clone());
+        }
+
+        match &self.base {
+            Some(base) => std::fs::read(base.join(normalized)),
+            None => self
+                .state
+                .lock()
+                .unwrap()
+                .files
+                .get(normalized)
+                .cloned()
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Let the sink, if there is one, finish storing files.
+    pub fn finish(&self) -> Result<()> {
+        match self.state.lock().unwrap().sink.as_mut() {
+            Some(sink) => sink.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the files written to the tree so far.
+    pub fn emitted(&self) -> EmittedOutputs {
+        let files = self
+            .state
+            .lock()
+            .unwrap()
+            .emitted
+            .iter()
+            .map(|(path, (size, category, unchanged))| EmittedFile {
+                path: path.clone(),
+                size: *size,
+                category: *category,
+                unchanged: *unchanged,
+            })
+            .collect();
+
+        EmittedOutputs { files }
+    }
+
+    /// Take the files kept in memory, keyed by their normalized paths.
+    pub fn take_files(&self) -> HashMap<String, Vec<u8>> {
+        std::mem::take(&mut self.state.lock().unwrap().files)
+    }
+}
+
+/// Get the path of the file that a page with output path *path* is emitted
+/// to, which depends on the URL style.
+pub fn page_file(style: UrlStyle, path: &str) -> String {
+    if path.is_empty() || path.ends_with('/') {
+        return format!("{}index.html", path);
+    }
+
+    match path.strip_suffix(".html") {
+        Some(stem) if style == UrlStyle::Directory && !is_index(path) => {
+            format!("{}/index.html", stem)
+        }
+        _ => path.to_owned(),
+    }
+}
+
+/// Get the URL, relative to the output root, of the page emitted to the
+/// normalized path *file*.
+pub fn page_url(style: UrlStyle, file: &str) -> String {
+    let url = match style {
+        UrlStyle::Html => return file.to_owned(),
+        _ if is_index(file) => &file[..file.len() - "index.html".len()],
+        UrlStyle::Directory => file,
+        UrlStyle::Bare => file.strip_suffix(".html").unwrap_or(file),
+    };
+
+    if url.is_empty() {
+        "./".to_owned()
+    } else {
+        url.to_owned()
+    }
+}
+
+fn is_index(path: &str) -> bool {
+    path == "index.html" || path.ends_with("/index.html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assets::{AssetManifest, AssetOrigin, ManifestEntry},
+        test_util::*,
+        EngineState, LayoutDetail, Spx2HtmlEngine,
+    };
+    use std::time::Duration;
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn concurrent_runs_share_out_base() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let manifest_path = tempdir.path().join("assets.json");
+
+        let events = |s: &mut EngineState| -> Result<()> {
+            let x = glyph_for('x');
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "xx", 0, &[x, x], &[0, 100], &[0, 0])?;
+            s.special("tdux:ce math")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        };
+
+        // The test font has no math or script alternates that the canvas
+        // glyphs could need variants for, so the variants are pinned through
+        // a manifest, which makes every run emit them.
+        let mut engine = Spx2HtmlEngine::default();
+        engine.asset_manifest(Some(manifest_path.clone()));
+        let (result, _status) = run_engine_in_memory(&mut engine, simple_io(), events);
+        result.unwrap();
+
+        let mut manifest: AssetManifest =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        let family = match &manifest.entries[0].origin {
+            AssetOrigin::FontFile { faces, .. } => faces[0].family.clone(),
+            other => panic!("unexpected asset {:?}", other),
+        };
+
+        for (index, c) in "xyz".chars().enumerate() {
+            manifest.entries.push(ManifestEntry {
+                path: format!("vg-{}.otf", index),
+                origin: AssetOrigin::FontVariant {
+                    family: family.clone(),
+                    index,
+                    glyphs: vec![(glyph_for(c), 'q')],
+                },
+            });
+        }
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.precomputed_assets(Some(manifest.clone()));
+        let (files, _status) = run_engine_in_memory(&mut engine, simple_io(), events);
+        let files = files.unwrap();
+        let expected: BTreeMap<_, _> = files
+            .iter()
+            .filter(|(name, _)| name.starts_with("vg"))
+            .map(|(name, data)| (name.clone(), data.clone()))
+            .collect();
+        assert_eq!(expected.len(), 3);
+
+        let out = tempdir.path().join("out");
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let out_base = out.clone();
+                let manifest = manifest.clone();
+
+                std::thread::spawn(move || {
+                    let mut engine = Spx2HtmlEngine::default();
+                    engine.precomputed_assets(Some(manifest));
+                    run_engine_with(&mut engine, simple_io(), &out_base, events).0
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap().unwrap();
+        }
+
+        let mut variants = BTreeMap::new();
+
+        for entry in std::fs::read_dir(&out).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+
+            if name.starts_with("vg") {
+                let data = std::fs::read(out.join(&name)).unwrap();
+                crate::font::FontData::from_opentype(name.clone(), data.clone(), 0).unwrap();
+                variants.insert(name, data);
+            }
+        }
+
+        assert_eq!(variants, expected);
+
+        let font = std::fs::read(out.join(format!("{}.otf", TEST_FONT))).unwrap();
+        assert_eq!(font, test_font_data());
+        crate::font::FontData::from_opentype(TEST_FONT.to_owned(), font, 0).unwrap();
+
+        let html = std::fs::read(out.join("index.html")).unwrap();
+        assert_eq!(html, files["index.html"]);
+
+        // No temporary files should be left behind.
+        for entry in std::fs::read_dir(&out).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert!(!name.to_string_lossy().ends_with(".tmp"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        fn mode(path: &Path) -> u32 {
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let (result, _status) = run_engine(simple_io(), tempdir.path(), simple_events);
+        result.unwrap();
+        assert_eq!(mode(&tempdir.path().join("index.html")), 0o644);
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut engine = Spx2HtmlEngine::default();
+        engine.output_file_mode(0o640).output_dir_mode(0o750);
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:provideFile extra.css assets/css/extra.css")?;
+            s.special("tdux:setOutputPath ch1/index.html")?;
+            simple_events(s)
+        });
+        result.unwrap();
+
+        let p = tempdir.path();
+        assert_eq!(mode(&p.join("ch1/index.html")), 0o640);
+        assert_eq!(mode(&p.join(format!("{}.otf", TEST_FONT))), 0o640);
+        assert_eq!(mode(&p.join("assets/css/extra.css")), 0o640);
+        assert_eq!(mode(&p.join("assets/css")), 0o750);
+        assert_eq!(mode(&p.join("assets")), 0o750);
+        assert_eq!(mode(&p.join("ch1")), 0o750);
+    }
+
+    #[test]
+    fn memory_output() {
+        let mut io = simple_io();
+        io.add("logo.svg", "<svg/>");
+        let mut engine = Spx2HtmlEngine::default();
+
+        let (result, status) = run_engine_in_memory(&mut engine, io.clone(), |s| {
+            s.special("tdux:provideFile logo.svg img/logo.svg")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:setOutputPath ./chapters//one.html")?;
+            s.special("tdux:emit")?;
+            s.text("Again")?;
+            s.special("tdux:setOutputPath chapters/one.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = result.unwrap();
+
+        let mut names: Vec<_> = files.keys().map(|k| k.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            ["chapters/one.html", "img/logo.svg", "lmroman12-regular.otf"]
+        );
+        assert_eq!(files["img/logo.svg"], b"<svg/>");
+        assert_eq!(
+            files["chapters/one.html"],
+            b"<html><body>Again</body></html>"
+        );
+
+        // Re-emitting a page still only draws the usual warning.
+        assert_eq!(status.warnings.len(), 1);
+        assert!(status.warnings[0].contains("has already been emitted"));
+
+        // Paths escaping the output root are rejected the same way.
+        let (result, _status) = run_engine_in_memory(&mut engine, io.clone(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:setOutputPath ../escape.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::PathRejected { path, .. }) if path == "../escape.html"
+        ));
+
+        // Different files can't land at the same path, as can happen with
+        // fonts from different directories.
+        io.add("a/face.otf", test_font_data());
+        io.add("b/face.otf", remapped_test_font('x', 'y'));
+        let (result, _status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.handle_define_native_font("a/face", 2, TEST_FONT_SIZE, 0, None, None, None, None)?;
+            s.handle_define_native_font("b/face", 3, TEST_FONT_SIZE, 0, None, None, None, None)
+        });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::OutputConflict { path }) if path == "face.otf"
+        ));
+    }
+
+    #[test]
+    fn output_selection() {
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+        io.add(
+            "inline.html",
+            "<style>{{ inline_asset(path=\"css/extra.css\") }}</style>{{ tduxContent }}",
+        );
+
+        let events = |s: &mut EngineState| {
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:addTemplate inline.html")?;
+            s.special("tdux:setTemplate inline.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")
+        };
+
+        let list = |dir: &Path| {
+            let mut files = Vec::new();
+            let mut dirs = vec![dir.to_owned()];
+
+            while let Some(d) = dirs.pop() {
+                for entry in std::fs::read_dir(d).unwrap() {
+                    let path = entry.unwrap().path();
+
+                    if path.is_dir() {
+                        dirs.push(path);
+                    } else {
+                        let rel = path.strip_prefix(dir).unwrap();
+                        files.push(rel.to_string_lossy().into_owned());
+                    }
+                }
+            }
+
+            files.sort();
+            files
+        };
+
+        for (selection, expected) in [
+            (
+                OutputSelection::AssetsOnly,
+                &["css/extra.css", "lmroman12-regular.otf"][..],
+            ),
+            (OutputSelection::PagesOnly, &["index.html"][..]),
+        ] {
+            let tempdir = tempfile::tempdir().unwrap();
+            let out = tempdir.path().join("out");
+            let depfile = tempdir.path().join("deps.mk");
+            let mut engine = Spx2HtmlEngine::default();
+            engine
+                .output_selection(selection)
+                .depfile(Some(depfile.clone()));
+
+            let (result, _status) = run_engine_with(&mut engine, io.clone(), &out, events);
+            result.unwrap();
+            assert_eq!(list(&out), expected);
+
+            // Dependencies are tracked either way.
+            let deps = std::fs::read_to_string(&depfile).unwrap();
+            assert!(deps.contains("inline.html"), "{}", deps);
+            assert!(deps.contains("lmroman12-regular.otf"), "{}", deps);
+
+            if selection == OutputSelection::PagesOnly {
+                let html = std::fs::read_to_string(out.join("index.html")).unwrap();
+                assert_eq!(html, "<style>p {}</style>Hello");
+            }
+        }
+    }
+
+    #[test]
+    fn output_sink() {
+        #[derive(Clone, Default)]
+        struct RecordingSink(Arc<Mutex<(Vec<String>, bool)>>);
+
+        impl OutputSink for RecordingSink {
+            fn write_file(&mut self, rel_path: &str, _data: &[u8]) -> Result<()> {
+                let mut state = self.0.lock().unwrap();
+                assert!(!state.1, "wrote `{}` after finishing", rel_path);
+                state.0.push(rel_path.to_owned());
+                Ok(())
+            }
+
+            fn finish(&mut self) -> Result<()> {
+                self.0.lock().unwrap().1 = true;
+                Ok(())
+            }
+        }
+
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+        io.add(
+            "inline.html",
+            "<style>{{ inline_asset(path=\"css/extra.css\") }}</style>{{ tduxContent }}",
+        );
+
+        let sink = RecordingSink::default();
+        let (result, _status) = run_engine_to_sink(
+            &mut Spx2HtmlEngine::default(),
+            io.clone(),
+            Box::new(sink.clone()),
+            |s| {
+                s.special("tdux:provideFile extra.css ./css//extra.css")?;
+                s.special("tdux:addTemplate inline.html")?;
+                s.special("tdux:setTemplate inline.html")?;
+                s.special("tdux:setOutputPath ./ch1//one.html")?;
+                s.text("Hello")?;
+                s.special("tdux:emit")
+            },
+        );
+        result.unwrap();
+
+        let (mut paths, finished) = sink.0.lock().unwrap().clone();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["ch1/one.html", "css/extra.css", "lmroman12-regular.otf"]
+        );
+        assert!(finished);
+
+        // Paths are checked before the sink sees them, and the sink isn't
+        // finished if processing fails.
+        let sink = RecordingSink::default();
+        let (result, _status) = run_engine_to_sink(
+            &mut Spx2HtmlEngine::default(),
+            io,
+            Box::new(sink.clone()),
+            |s| {
+                s.special("tdux:setOutputPath ../escape.html")?;
+                simple_events(s)
+            },
+        );
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::PathRejected { .. })
+        ));
+        let state = sink.0.lock().unwrap();
+        assert!(!state.0.iter().any(|p| p.contains("escape")));
+        assert!(!state.1);
+    }
+
+    #[test]
+    fn emitted_outputs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .base_css_file(true)
+            .layout_sidecars(Some(LayoutDetail::Canvases))
+            .depfile(Some(tempdir.path().join("deps.mk")));
+
+        let out = OutputTree::directory(&tempdir.path().join("out"), OutputPolicy::default());
+        let (result, _status) = run_engine_into(&mut engine, io, out.clone(), |s| {
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath ch/two.html")?;
+            s.text("World")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        // Each file is listed once, in order of its path, even though the
+        // font is written both when it's defined and before the first page.
+        // The depfile lies outside of the output directory.
+        let emitted = out.emitted();
+        let listed: Vec<_> = emitted
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.category))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("ch/two.html", OutputCategory::Html),
+                ("ch/two.html.layout.json", OutputCategory::Sidecar),
+                ("css/extra.css", OutputCategory::CopiedAsset),
+                ("index.html", OutputCategory::Html),
+                ("index.html.layout.json", OutputCategory::Sidecar),
+                (&format!("{}.otf", TEST_FONT), OutputCategory::Font),
+                ("tdux-base.css", OutputCategory::Stylesheet),
+            ]
+        );
+
+        for file in &emitted.files {
+            let size = std::fs::metadata(tempdir.path().join("out").join(&file.path))
+                .unwrap()
+                .len();
+            assert_eq!(file.size, size, "{}", file.path);
+        }
+    }
+
+    #[test]
+    fn post_processor() {
+        let run = |engine: &mut Spx2HtmlEngine| {
+            let mut io = simple_io();
+            io.add("extra.css", "p {}");
+
+            run_engine_in_memory(engine, io, |s| {
+                s.special("tdux:provideFile extra.css extra.css")?;
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Hello")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:setOutputPath ch/two.html")?;
+                s.text("World")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            })
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.post_processor(Box::new(|path: &str, data: &mut Vec<u8>| {
+            data.extend_from_slice(format!("<!-- {} -->", path).as_bytes());
+            Ok(())
+        }));
+
+        // By default, only the pages are post-processed.
+        let (result, _status) = run(&mut engine);
+        let files = result.unwrap();
+        assert!(files["index.html"].ends_with(b"</html><!-- index.html -->"));
+        assert!(files["ch/two.html"].ends_with(b"</html><!-- ch/two.html -->"));
+        assert_eq!(files["extra.css"], b"p {}");
+
+        engine.post_process_assets(true);
+        let (result, _status) = run(&mut engine);
+        let files = result.unwrap();
+        assert!(files["index.html"].ends_with(b"</html><!-- index.html -->"));
+        assert_eq!(files["extra.css"], b"p {}<!-- extra.css -->");
+
+        // A failure aborts processing, naming the file.
+        engine.post_processor(Box::new(|path: &str, _data: &mut Vec<u8>| {
+            if path.starts_with("ch/") {
+                bail!("no chapters allowed");
+            }
+
+            Ok(())
+        }));
+
+        let (result, _status) = run(&mut engine);
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("`ch/two.html`"), "{}", err);
+        assert!(err.contains("no chapters allowed"), "{}", err);
+    }
+
+    #[test]
+    fn precompress() {
+        let mut io = simple_io();
+        io.add("extra.css", "p {}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.base_css_file(true).precompress(CompressionKinds {
+            gzip: true,
+            ..Default::default()
+        });
+
+        let out = OutputTree::memory();
+        let (result, _status) = run_engine_into(&mut engine, io, out.clone(), |s| {
+            s.special("tdux:provideFile extra.css extra.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        // The fonts and provided files aren't compressed unless asked.
+        let listed: Vec<_> = out
+            .emitted()
+            .files
+            .iter()
+            .map(|f| (f.path.clone(), f.category))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("extra.css".to_owned(), OutputCategory::CopiedAsset),
+                ("index.html".to_owned(), OutputCategory::Html),
+                ("index.html.gz".to_owned(), OutputCategory::Html),
+                (format!("{}.otf", TEST_FONT), OutputCategory::Font),
+                ("tdux-base.css".to_owned(), OutputCategory::Stylesheet),
+                ("tdux-base.css.gz".to_owned(), OutputCategory::Stylesheet),
+            ]
+        );
+
+        let files = out.take_files();
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&files["index.html.gz"][..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, files["index.html"]);
+    }
+
+    #[test]
+    fn zip_output() {
+        fn events(s: &mut EngineState) -> Result<()> {
+            s.special("tdux:provideFile extra.css css/extra.css")?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath ch/two.html")?;
+            s.text("World")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        }
+
+        let io = || {
+            let mut io = simple_io();
+            io.add("extra.css", "p {}");
+            io
+        };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let (result, _status) =
+            run_engine_with(&mut Spx2HtmlEngine::default(), io(), tempdir.path(), events);
+        result.unwrap();
+
+        let finished = Arc::new(Mutex::new(None));
+        let sink = ZipSink::new(std::io::Cursor::new(Vec::new()), finished.clone());
+        let (result, _status) =
+            run_engine_to_sink(&mut Spx2HtmlEngine::default(), io(), Box::new(sink), events);
+        result.unwrap();
+
+        // The archive holds exactly the files of the output directory.
+        let archive = finished.lock().unwrap().take().unwrap();
+        let mut archive = zip::ZipArchive::new(archive).unwrap();
+        let mut names: Vec<_> = archive.file_names().map(|n| n.to_owned()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "ch/two.html",
+                "css/extra.css",
+                "index.html",
+                &format!("{}.otf", TEST_FONT),
+            ]
+        );
+
+        for name in &names {
+            let mut contents = Vec::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(
+                contents,
+                std::fs::read(tempdir.path().join(name)).unwrap(),
+                "{}",
+                name
+            );
+        }
+
+        // An archive can't hold a page emitted twice at the same path.
+        let finished = Arc::new(Mutex::new(None));
+        let sink = ZipSink::new(std::io::Cursor::new(Vec::new()), finished.clone());
+        let (result, _status) =
+            run_engine_to_sink(&mut Spx2HtmlEngine::default(), io(), Box::new(sink), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text("Hello")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:setOutputPath index.html")?;
+                s.text("Again")?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::OutputConflict { path }) if path == "index.html"
+        ));
+        assert!(finished.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn overwrite_policy() {
+        let run = |engine: &mut Spx2HtmlEngine, dir: &Path, text: &'static str| {
+            let mut io = simple_io();
+            io.add("page.html", "<p>{{ tduxContent }}</p>");
+
+            let (result, _status) = run_engine_with(engine, io, dir, move |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text(text)?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+            result
+        };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let index = tempdir.path().join("index.html");
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mtime = || std::fs::metadata(&index).unwrap().modified().unwrap();
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.overwrite_policy(OverwritePolicy::ErrorIfExists);
+        run(&mut engine, tempdir.path(), "Hello").unwrap();
+
+        // Identical files are left alone, with their modification times.
+        engine.overwrite_policy(OverwritePolicy::SkipIfIdentical);
+        let file = std::fs::File::options().write(true).open(&index).unwrap();
+        file.set_modified(old).unwrap();
+        drop(file);
+        run(&mut engine, tempdir.path(), "Hello").unwrap();
+        assert_eq!(mtime(), old);
+
+        // Files that changed are replaced.
+        run(&mut engine, tempdir.path(), "World").unwrap();
+        assert_ne!(mtime(), old);
+        assert_eq!(std::fs::read_to_string(&index).unwrap(), "<p>World</p>");
+
+        // Existing files can't be clobbered at all.
+        engine.overwrite_policy(OverwritePolicy::ErrorIfExists);
+        let err = run(&mut engine, tempdir.path(), "Again").unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::OutputExists { .. })
+        ));
+        assert_eq!(std::fs::read_to_string(&index).unwrap(), "<p>World</p>");
+    }
+
+    #[test]
+    fn nested_output_directories() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let out = tempdir.path().join("site").join("out");
+        let mut io = simple_io();
+        io.add("logo.svg", "<svg/>");
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (result, status) = run_engine_with(&mut engine, io, &out, |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:provideFile logo.svg static/img/logo.svg")?;
+            s.text("Hello")?;
+            s.special("tdux:setOutputPath chapters/ch1/index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+        assert_eq!(status.warnings, Vec::<String>::new());
+
+        let page = std::fs::read_to_string(out.join("chapters/ch1/index.html")).unwrap();
+        assert_eq!(page, "<html><body>Hello</body></html>");
+        let logo = std::fs::read_to_string(out.join("static/img/logo.svg")).unwrap();
+        assert_eq!(logo, "<svg/>");
+    }
+
+    #[test]
+    fn percent_encoded_paths() {
+        let mut io = simple_io();
+        io.add("my page.html", "<p>{{ tduxContent }}</p>");
+        io.add("café notes.css", "p {}");
+        io.add("extra.css", "p {}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, io.clone(), |s| {
+            s.special("tdux:addTemplate my%20page.html")?;
+            s.special("tdux:setTemplate my%20page.html")?;
+            s.special("tdux:provideFile caf%C3%A9%20notes.css css/caf%C3%A9%20notes.css")?;
+            s.text("a")?;
+            s.special("tdux:setOutputPath ch%C3%A9/a%20b.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+        assert_eq!(status.warnings, Vec::<String>::new());
+        assert_eq!(files["css/café notes.css"], b"p {}");
+        assert_eq!(files["ché/a b.html"], b"<p>a</p>");
+
+        // Encoded `..` components are rejected like plain ones.
+        let mut engine = Spx2HtmlEngine::default();
+        let (result, _status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:provideFile extra.css %2e%2e/extra.css")?;
+            simple_events(s)
+        });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::PathRejected { .. })
+        ));
+    }
+
+    #[test]
+    fn emit_if_changed() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let base = tempdir.path().join("out");
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mtime = |path: &str| {
+            std::fs::metadata(base.join(path))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        let age = |path: &str| {
+            let file = std::fs::File::options()
+                .write(true)
+                .open(base.join(path))
+                .unwrap();
+            file.set_modified(old).unwrap();
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.layout_sidecars(Some(LayoutDetail::Canvases));
+        let mut run = |text: &'static str| {
+            let out = OutputTree::directory(&base, OutputPolicy::default());
+            let (result, _status) = run_engine_into(&mut engine, simple_io(), out.clone(), |s| {
+                s.special("tdux:addTemplate page.html")?;
+                s.special("tdux:setTemplate page.html")?;
+                s.text(text)?;
+                s.special("tdux:emitIfChanged")?;
+                s.special("tdux:setOutputPath two.html")?;
+                s.text(text)?;
+                s.special("tdux:emit")?;
+                s.special("tdux:contentFinished")
+            });
+            result.unwrap();
+
+            out.emitted()
+                .files
+                .into_iter()
+                .filter(|f| f.path.ends_with(".html") || f.path.ends_with(".json"))
+                .map(|f| (f.path, f.unchanged))
+                .collect::<Vec<_>>()
+        };
+
+        let emitted = run("Hello");
+        assert!(
+            emitted.iter().all(|(_, unchanged)| !unchanged),
+            "{:?}",
+            emitted
+        );
+
+        // The second time around, only the file emitted with
+        // tdux:emitIfChanged and its sidecar are left alone.
+        for path in ["index.html", "index.html.layout.json", "two.html"] {
+            age(path);
+        }
+
+        let emitted = run("Hello");
+        assert_eq!(
+            emitted,
+            [
+                ("index.html".to_owned(), true),
+                ("index.html.layout.json".to_owned(), true),
+                ("two.html".to_owned(), false),
+                ("two.html.layout.json".to_owned(), false),
+            ]
+        );
+        assert_eq!(mtime("index.html"), old);
+        assert_eq!(mtime("index.html.layout.json"), old);
+        assert_ne!(mtime("two.html"), old);
+
+        // Files that changed are rewritten, of course.
+        let emitted = run("World");
+        assert_eq!(emitted[0], ("index.html".to_owned(), false));
+        assert_ne!(mtime("index.html"), old);
+        assert_eq!(
+            std::fs::read_to_string(base.join("index.html")).unwrap(),
+            "<html><body>World</body></html>"
+        );
+
+        // A missing file is written, too.
+        std::fs::remove_file(base.join("index.html")).unwrap();
+        let emitted = run("World");
+        assert_eq!(emitted[0], ("index.html".to_owned(), false));
+        assert!(base.join("index.html").exists());
+    }
+}
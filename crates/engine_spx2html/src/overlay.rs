@@ -0,0 +1,273 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! The development-mode warnings overlay.
+//!
+//! When enabled with [`crate::Spx2HtmlEngine::dev_overlay`], the warnings and
+//! errors reported while each page is being produced are collected, and a
+//! fixed-position `<details class="tdux-warnings">` element listing them is
+//! added to the rendered page. The overlay is added after the template has
+//! been rendered, so templates don't need to know about it, and it never
+//! becomes part of the page content made available to the template.
+
+use std::{cell::Cell, fmt::Arguments, fmt::Write, rc::Rc};
+use tectonic_errors::Error;
+use tectonic_status_base::{MessageKind, StatusBackend};
+
+use crate::plan::EmissionPlan;
+
+/// The maximum number of diagnostics listed in an overlay.
+pub const MAX_OVERLAY_ENTRIES: usize = 50;
+
+/// The styling of the overlay.
+const OVERLAY_CSS: &str = "\
+.tdux-warnings { position: fixed; bottom: 1em; right: 1em; z-index: 2147483647; \
+max-width: 40em; max-height: 50vh; overflow: auto; padding: 0.5em 1em; \
+background: #fff8e1; color: #3e2723; border: 1px solid #ffb300; \
+border-radius: 4px; font: 13px/1.4 sans-serif; box-shadow: 0 2px 8px rgba(0,0,0,0.3); }
+.tdux-warnings summary { cursor: pointer; font-weight: bold; }
+.tdux-warnings ul { margin: 0.5em 0 0; padding-left: 1.2em; }
+.tdux-warnings .tdux-warnings-error { color: #b71c1c; }
+.tdux-warnings .tdux-warnings-loc { color: #6d4c41; }
+.tdux-warnings .tdux-plan { margin: 0.5em 0 0; padding-left: 1.6em; }
+";
+
+/// A diagnostic recorded for the overlay.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// Whether this is a warning or an error.
+    pub kind: MessageKind,
+
+    /// The text of the diagnostic.
+    pub message: String,
+
+    /// The offset in the SPX input at which the diagnostic was reported.
+    pub offset: u64,
+}
+
+/// A status backend that forwards everything to another backend, counting the
+/// warnings and optionally recording warnings and errors for the overlay along
+/// the way.
+pub struct CollectingStatus<'a> {
+    inner: &'a mut dyn StatusBackend,
+    input_pos: Rc<Cell<u64>>,
+    collected: Option<Vec<Diagnostic>>,
+    warnings: usize,
+}
+
+impl<'a> CollectingStatus<'a> {
+    /// Wrap a status backend. Diagnostics are only recorded if *collect* is
+    /// true; their locations are taken from *input_pos*.
+    pub fn new(inner: &'a mut dyn StatusBackend, collect: bool, input_pos: Rc<Cell<u64>>) -> Self {
+        CollectingStatus {
+            inner,
+            input_pos,
+            collected: if collect { Some(Vec::new()) } else { None },
+            warnings: 0,
+        }
+    }
+
+    /// Take the diagnostics recorded since the last call, or None if
+    /// diagnostics aren't being recorded.
+    pub fn take(&mut self) -> Option<Vec<Diagnostic>> {
+        self.collected.as_mut().map(std::mem::take)
+    }
+
+    /// Get the number of warnings reported so far.
+    pub fn warning_count(&self) -> usize {
+        self.warnings
+    }
+}
+
+impl<'a> StatusBackend for CollectingStatus<'a> {
+    fn report(&mut self, kind: MessageKind, args: Arguments, err: Option<&Error>) {
+        if kind == MessageKind::Warning {
+            self.warnings += 1;
+        }
+
+        if let Some(collected) = self.collected.as_mut() {
+            if kind != MessageKind::Note {
+                let mut message = args.to_string();
+
+                if let Some(e) = err {
+                    write!(message, ": {}", e).unwrap();
+                }
+
+                collected.push(Diagnostic {
+                    kind,
+                    message,
+                    offset: self.input_pos.get(),
+                });
+            }
+        }
+
+        self.inner.report(kind, args, err);
+    }
+
+    fn note_highlighted(&mut self, before: &str, highlighted: &str, after: &str) {
+        self.inner.note_highlighted(before, highlighted, after);
+    }
+
+    fn dump_error_logs(&mut self, output: &[u8]) {
+        self.inner.dump_error_logs(output);
+    }
+}
+
+/// Generate the overlay for a set of diagnostics, and optionally the
+/// emission plan of the page.
+///
+/// If there are no diagnostics and no plan, the overlay is empty. The overlay
+/// starts out expanded only if there are diagnostics.
+pub fn render(diagnostics: &[Diagnostic], plan: Option<&EmissionPlan>) -> String {
+    let mut html = String::new();
+
+    if diagnostics.is_empty() && plan.is_none() {
+        return html;
+    }
+
+    write!(
+        html,
+        "<style>{}</style><details class=\"tdux-warnings\"{}><summary>{} conversion diagnostic{}</summary>",
+        OVERLAY_CSS,
+        if diagnostics.is_empty() { "" } else { " open" },
+        diagnostics.len(),
+        if diagnostics.len() == 1 { "" } else { "s" }
+    )
+    .unwrap();
+
+    if let Some(plan) = plan {
+        write!(
+            html,
+            "<details><summary>how this page was produced</summary>{}</details>",
+            plan.to_html()
+        )
+        .unwrap();
+    }
+
+    html.push_str("<ul>");
+
+    for d in diagnostics.iter().take(MAX_OVERLAY_ENTRIES) {
+        let (class, label) = match d.kind {
+            MessageKind::Error => ("tdux-warnings-error", "error"),
+            _ => ("tdux-warnings-warning", "warning"),
+        };
+
+        write!(
+            html,
+            "<li class=\"{}\"><strong>{}:</strong> {} <span class=\"tdux-warnings-loc\">(SPX offset {})</span></li>",
+            class,
+            label,
+            tera::escape_html(&d.message),
+            d.offset
+        )
+        .unwrap();
+    }
+
+    if diagnostics.len() > MAX_OVERLAY_ENTRIES {
+        write!(
+            html,
+            "<li>+{} more</li>",
+            diagnostics.len() - MAX_OVERLAY_ENTRIES
+        )
+        .unwrap();
+    }
+
+    html.push_str("</ul></details>");
+    html
+}
+
+/// Add an overlay to a rendered page.
+///
+/// The overlay goes just before the closing `</body>` tag if there is one,
+/// and at the very end of the page otherwise.
+pub fn insert(page: &mut String, overlay: &str) {
+    match page.rfind("</body>") {
+        Some(pos) => page.insert_str(pos, overlay),
+        None => page.push_str(overlay),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, EngineState, Spx2HtmlEngine};
+
+    fn diag(message: &str) -> Diagnostic {
+        Diagnostic {
+            kind: MessageKind::Warning,
+            message: message.to_owned(),
+            offset: 42,
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(render(&[], None), "");
+    }
+
+    #[test]
+    fn escaping_and_cap() {
+        let html = render(&[diag("<b> & co")], None);
+        assert!(html.contains("&lt;b&gt; &amp; co"));
+        assert!(html.contains("(SPX offset 42)"));
+        assert!(html.contains("1 conversion diagnostic<"));
+
+        let many: Vec<_> = (0..MAX_OVERLAY_ENTRIES + 3)
+            .map(|i| diag(&i.to_string()))
+            .collect();
+        let html = render(&many, None);
+        assert_eq!(html.matches("<li").count(), MAX_OVERLAY_ENTRIES + 1);
+        assert!(html.contains("<li>+3 more</li>"));
+    }
+
+    #[test]
+    fn placement() {
+        let mut page = "<html><body>x</body></html>".to_owned();
+        insert(&mut page, "O");
+        assert_eq!(page, "<html><body>xO</body></html>");
+
+        let mut page = "x".to_owned();
+        insert(&mut page, "O");
+        assert_eq!(page, "xO");
+    }
+
+    #[test]
+    fn dev_overlay() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let events = |s: &mut EngineState| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:asSemantic marquee")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath clean.html")?;
+            s.text("Clean")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        };
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.dev_overlay(true);
+        let (result, status) = run_engine_with(&mut engine, simple_io(), tempdir.path(), events);
+        result.unwrap();
+        assert_eq!(status.warnings.len(), 1);
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        let overlay_start = html.find("<style>").unwrap();
+        assert!(html.starts_with("<html><body>Hello<style>"));
+        assert!(html.ends_with("</ul></details></body></html>"));
+        assert!(html[overlay_start..].contains(
+            "<li class=\"tdux-warnings-warning\"><strong>warning:</strong> ignoring unrecognized \
+             semantic element `marquee` (special `tdux:asSemantic marquee`) \
+             <span class=\"tdux-warnings-loc\">(SPX offset 0)</span></li>"
+        ));
+
+        let html = std::fs::read_to_string(tempdir.path().join("clean.html")).unwrap();
+        assert_eq!(html, "<html><body>Clean</body></html>");
+
+        let (result, _status) = run_engine(simple_io(), tempdir.path(), events);
+        result.unwrap();
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "<html><body>Hello</body></html>");
+    }
+}
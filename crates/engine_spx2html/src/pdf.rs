@@ -0,0 +1,391 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Links from the `pdf:` specials of hyperref.
+//!
+//! With the `xdvipdfmx` driver, hyperref marks link targets with
+//! `pdf:dest (<name>) [...]` and links with `pdf:bann <<...>>` ... `pdf:eann`,
+//! where the annotation dictionary says where the link goes: to a named
+//! destination, with a `/GoTo` action or a `/Dest` entry, or to a URL, with a
+//! `/URI` action. The engine turns destinations into anchors and links into
+//! `<a>` elements, so that documents that use hyperref get working links
+//! without any tdux-specific markup. The rest of the annotation dictionary,
+//! such as the border and highlighting of the link, only makes sense in a
+//! PDF, and is ignored.
+
+/// A PDF object, as far as we care to understand it.
+#[derive(Clone, Debug, PartialEq)]
+enum Object {
+    Name(String),
+    String(String),
+    Array(Vec<Object>),
+    Dict(Vec<(String, Object)>),
+    /// Numbers, booleans, references, and such, which we don't use.
+    Other,
+}
+
+/// A minimal parser of PDF objects in the text of a special.
+struct Parser<'a> {
+    text: &'a str,
+}
+
+/// Returns true if a character delimits PDF tokens.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()<>[]{}/%".contains(c)
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        self.text = self.text.trim_start();
+    }
+
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let end = self.text.find(|c| !pred(c)).unwrap_or(self.text.len());
+        let (taken, rest) = self.text.split_at(end);
+        self.text = rest;
+        taken
+    }
+
+    /// Parse the next object, or None if the text is malformed or exhausted.
+    fn object(&mut self) -> Option<Object> {
+        self.skip_whitespace();
+
+        if let Some(rest) = self.text.strip_prefix("<<") {
+            self.text = rest;
+            let mut entries = Vec::new();
+
+            loop {
+                self.skip_whitespace();
+
+                if let Some(rest) = self.text.strip_prefix(">>") {
+                    self.text = rest;
+                    return Some(Object::Dict(entries));
+                }
+
+                let key = match self.object()? {
+                    Object::Name(n) => n,
+                    _ => return None,
+                };
+                entries.push((key, self.object()?));
+            }
+        }
+
+        let mut chars = self.text.chars();
+
+        match chars.next()? {
+            '/' => {
+                self.text = chars.as_str();
+                Some(Object::Name(
+                    self.take_while(|c| !is_delimiter(c)).to_owned(),
+                ))
+            }
+
+            '(' => {
+                self.text = chars.as_str();
+                self.literal_string().map(Object::String)
+            }
+
+            '<' => {
+                self.text = chars.as_str();
+                let hex: String = self
+                    .take_while(|c| c != '>')
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect();
+                self.text = self.text.strip_prefix('>')?;
+                hex_string(&hex).map(Object::String)
+            }
+
+            '[' => {
+                self.text = chars.as_str();
+                let mut items = Vec::new();
+
+                loop {
+                    self.skip_whitespace();
+
+                    if let Some(rest) = self.text.strip_prefix(']') {
+                        self.text = rest;
+                        return Some(Object::Array(items));
+                    }
+
+                    items.push(self.object()?);
+                }
+            }
+
+            c if !is_delimiter(c) => {
+                self.take_while(|c| !is_delimiter(c));
+                Some(Object::Other)
+            }
+
+            _ => None,
+        }
+    }
+
+    /// Parse the rest of a literal string, whose opening parenthesis has been
+    /// consumed.
+    fn literal_string(&mut self) -> Option<String> {
+        let mut value = String::new();
+        let mut depth = 0;
+        let mut chars = self.text.chars();
+
+        loop {
+            match chars.next()? {
+                '\\' => match chars.next()? {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    c @ '0'..='7' => {
+                        let mut code = c.to_digit(8).unwrap();
+
+                        for _ in 0..2 {
+                            match chars.clone().next().and_then(|c| c.to_digit(8)) {
+                                Some(d) => {
+                                    code = code * 8 + d;
+                                    chars.next();
+                                }
+                                None => break,
+                            }
+                        }
+
+                        value.push(char::from_u32(code & 0xff)?);
+                    }
+                    // A backslash at the end of a line continues the string.
+                    '\n' => {}
+                    c => value.push(c),
+                },
+
+                '(' => {
+                    depth += 1;
+                    value.push('(');
+                }
+
+                ')' if depth == 0 => break,
+
+                ')' => {
+                    depth -= 1;
+                    value.push(')');
+                }
+
+                c => value.push(c),
+            }
+        }
+
+        self.text = chars.as_str();
+        Some(value)
+    }
+}
+
+/// Decode the digits of a hexadecimal string.
+fn hex_string(hex: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let digits: Vec<u8> = hex
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
+
+    for pair in digits.chunks(2) {
+        bytes.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+    }
+
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Where a link goes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LinkTarget {
+    /// A named destination in the document.
+    Destination(String),
+
+    /// A URL.
+    Url(String),
+}
+
+/// A parsed link annotation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LinkAnnotation {
+    /// Where the link goes, or None if that isn't something we support.
+    pub target: Option<LinkTarget>,
+
+    /// Descriptions of the kinds of things in the annotation that were
+    /// ignored, such as "the `/Border` attribute".
+    pub ignored: Vec<String>,
+}
+
+/// Parse the name of the destination of a `pdf:dest` special, given its
+/// arguments.
+pub fn parse_dest(args: &str) -> Option<String> {
+    match (Parser { text: args }).object()? {
+        Object::String(s) | Object::Name(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Parse the annotation dictionary of a `pdf:bann` special, given its
+/// arguments.
+pub fn parse_link(args: &str) -> Option<LinkAnnotation> {
+    let entries = match (Parser { text: args }).object()? {
+        Object::Dict(entries) => entries,
+        _ => return None,
+    };
+
+    let mut target = None;
+    let mut ignored = Vec::new();
+
+    for (key, value) in entries {
+        match (key.as_str(), value) {
+            ("Type", _) => {}
+            ("Subtype", Object::Name(n)) if n == "Link" => {}
+            ("Dest", Object::String(d) | Object::Name(d)) => {
+                target = Some(LinkTarget::Destination(d));
+            }
+            ("A", Object::Dict(action)) => {
+                let get = |k: &str| action.iter().find(|(ak, _)| ak == k).map(|(_, v)| v);
+
+                match (get("S"), get("D"), get("URI")) {
+                    (Some(Object::Name(s)), Some(Object::String(d) | Object::Name(d)), _)
+                        if s == "GoTo" =>
+                    {
+                        target = Some(LinkTarget::Destination(d.clone()));
+                    }
+                    (Some(Object::Name(s)), _, Some(Object::String(u))) if s == "URI" => {
+                        target = Some(LinkTarget::Url(u.clone()));
+                    }
+                    (Some(Object::Name(s)), _, _) => ignored.push(format!("`/{}` actions", s)),
+                    _ => ignored.push("malformed actions".to_owned()),
+                }
+            }
+            (key, _) => ignored.push(format!("the `/{}` attribute", key)),
+        }
+    }
+
+    Some(LinkAnnotation { target, ignored })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine};
+
+    #[test]
+    fn destinations() {
+        assert_eq!(
+            parse_dest("(section.1) [@thispage /XYZ @xpos @ypos null]").as_deref(),
+            Some("section.1")
+        );
+        assert_eq!(parse_dest("(a\\(b\\) (c)) []").as_deref(), Some("a(b) (c)"));
+        assert_eq!(parse_dest("<48692e> []").as_deref(), Some("Hi."));
+        assert_eq!(parse_dest("(\\101\\102) []").as_deref(), Some("AB"));
+        assert_eq!(parse_dest("(unterminated"), None);
+        assert_eq!(parse_dest("[1 0 R]"), None);
+    }
+
+    #[test]
+    fn links() {
+        let link = parse_link(
+            "<</Type/Annot/Subtype/Link/Border[0 0 1]/H/I/C[1 0 0]\
+             /A<</S/GoTo/D(section.1)>>>>",
+        )
+        .unwrap();
+        assert_eq!(
+            link,
+            LinkAnnotation {
+                target: Some(LinkTarget::Destination("section.1".to_owned())),
+                ignored: vec![
+                    "the `/Border` attribute".to_owned(),
+                    "the `/H` attribute".to_owned(),
+                    "the `/C` attribute".to_owned(),
+                ],
+            }
+        );
+
+        let link = parse_link(
+            "<< /Type /Annot /Subtype /Link /A << /S /URI /URI (https://example.com/a%20b) >> >>",
+        )
+        .unwrap();
+        assert_eq!(
+            link.target,
+            Some(LinkTarget::Url("https://example.com/a%20b".to_owned()))
+        );
+        assert!(link.ignored.is_empty());
+
+        let link = parse_link("<</Dest(page.2)>>").unwrap();
+        assert_eq!(
+            link.target,
+            Some(LinkTarget::Destination("page.2".to_owned()))
+        );
+
+        let link = parse_link("<</A<</S/GoToR/F(other.pdf)/D(x)>>>>").unwrap();
+        assert_eq!(link.target, None);
+        assert_eq!(link.ignored, vec!["`/GoToR` actions".to_owned()]);
+
+        assert_eq!(parse_link("<</A<</S/URI"), None);
+        assert_eq!(parse_link("(not a dictionary)"), None);
+    }
+
+    #[test]
+    fn hyperref_links() {
+        const ANNOT: &str = "<</Type/Annot/Subtype/Link/Border[0 0 1]/H/I/C[1 0 0]";
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("pdf:dest (Doc-Start) [@thispage /XYZ @xpos @ypos null]")?;
+            s.special(&format!("pdf:bann{}/A<</S/GoTo/D(section.1)>>>>", ANNOT))?;
+            s.text("fwd")?;
+            s.special("pdf:eann")?;
+            s.special(&format!(
+                "pdf:bann {} /A<</S/URI/URI(https://example.com/)>> >>",
+                ANNOT
+            ))?;
+            s.text("web")?;
+            s.special("pdf:eann")?;
+            s.special("pdf:eann")?;
+            s.special("pdf:bann<</A<</S/GoToR/F(x.pdf)/D(y)>>>>")?;
+            s.text("remote")?;
+            s.special("pdf:eann")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.special("pdf:dest (section.1) [@thispage /XYZ @xpos @ypos null]")?;
+            s.special("pdf:bann<</Dest(Doc-Start)>>")?;
+            s.text("top")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")?;
+            s.special("pdf:eann")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec![
+                "ignoring the `/Border` attribute in pdf:bann link annotations; \
+                 further instances won't be reported",
+                "ignoring the `/H` attribute in pdf:bann link annotations; \
+                 further instances won't be reported",
+                "ignoring the `/C` attribute in pdf:bann link annotations; \
+                 further instances won't be reported",
+                "ignoring pdf:eann special with no matching pdf:bann",
+                "ignoring `/GoToR` actions in pdf:bann link annotations; \
+                 further instances won't be reported",
+            ]
+        );
+
+        assert_eq!(
+            String::from_utf8_lossy(&files["index.html"]),
+            "<html><body>a<span id=\"Doc-Start\"></span>\
+             <a href=\"two.html#section.1\">fwd</a>\
+             <a href=\"https:&#x2F;&#x2F;example.com&#x2F;\">web</a>remote</body></html>"
+        );
+
+        // The link left open at the end of the file is closed quietly.
+        assert_eq!(
+            String::from_utf8_lossy(&files["two.html"]),
+            "<html><body><span id=\"section.1\"></span>\
+             <a href=\"index.html#Doc-Start\">top</a></body></html>"
+        );
+    }
+}
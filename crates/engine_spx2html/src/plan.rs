@@ -0,0 +1,314 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Recording how the engine decides what goes into each output file.
+//!
+//! The specials that choose the output path, template, and template variables
+//! of a file can be interleaved with its content in any order. Whichever
+//! settings are in effect when `tdux:emit` is seen win, and all of the content
+//! seen since the previous emission goes into the file. When emission plans
+//! are enabled with [`crate::Spx2HtmlEngine::emission_plans`], the engine
+//! records the timeline of these events for each output file, so that authors
+//! can see exactly what it decided. Each [`EmissionPlan`] is delivered to
+//! [`crate::progress::ProgressSink::on_emission_plan`] and, if the development
+//! overlay is enabled, listed in the overlay of its page.
+
+use serde::Serialize;
+use std::fmt::Write;
+
+/// An event that affected an output file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlanStep {
+    /// Text was added to the content. Consecutive runs of text are merged.
+    Text {
+        /// The number of characters of text.
+        chars: usize,
+    },
+
+    /// A canvas was added to the content.
+    Canvas {
+        /// The kind of the canvas, such as `math`.
+        canvas: String,
+    },
+
+    /// The output path was set with `tdux:setOutputPath`.
+    SetOutputPath {
+        /// The requested path.
+        path: String,
+    },
+
+    /// The output path pattern was set with `tdux:setOutputPathPattern`.
+    SetOutputPathPattern {
+        /// The requested pattern.
+        pattern: String,
+    },
+
+    /// The template was set with `tdux:setTemplate`.
+    SetTemplate {
+        /// The requested template.
+        path: String,
+    },
+
+    /// A template for the next output file was set with
+    /// `tdux:setTemplateOnce`.
+    SetTemplateOnce {
+        /// The requested template.
+        path: String,
+    },
+
+    /// A template variable was set with `tdux:setTemplateVariable`.
+    SetVariable {
+        /// The name of the variable.
+        name: String,
+
+        /// Its new value.
+        value: String,
+    },
+}
+
+impl PlanStep {
+    /// Describe the step in a few words.
+    pub fn describe(&self) -> String {
+        match self {
+            PlanStep::Text { chars } => format!("{} characters of text", chars),
+            PlanStep::Canvas { canvas } => format!("`{}` canvas", canvas),
+            PlanStep::SetOutputPath { path } => format!("output path set to `{}`", path),
+            PlanStep::SetOutputPathPattern { pattern } => {
+                format!("output path pattern set to `{}`", pattern)
+            }
+            PlanStep::SetTemplate { path } => format!("template set to `{}`", path),
+            PlanStep::SetTemplateOnce { path } => {
+                format!("template for this file only set to `{}`", path)
+            }
+            PlanStep::SetVariable { name, value } => {
+                format!("variable `{}` set to `{}`", name, value)
+            }
+        }
+    }
+}
+
+/// The timeline of an output file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct EmissionPlan {
+    /// The output path that the file was written to, relative to the output
+    /// directory.
+    pub path: String,
+
+    /// The template that the file was rendered with.
+    pub template: String,
+
+    /// The events since the previous output file was emitted, or since the
+    /// start of the document, in order.
+    pub steps: Vec<PlanStep>,
+}
+
+impl EmissionPlan {
+    /// Render the plan as an HTML list.
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+
+        write!(
+            html,
+            "<ol class=\"tdux-plan\" title=\"{} rendered with {}\">",
+            tera::escape_html(&self.path),
+            tera::escape_html(&self.template)
+        )
+        .unwrap();
+
+        for step in &self.steps {
+            write!(html, "<li>{}</li>", tera::escape_html(&step.describe())).unwrap();
+        }
+
+        html.push_str("</ol>");
+        html
+    }
+}
+
+/// Records the steps of the output file being built, if plans are enabled.
+#[derive(Debug, Default)]
+pub(crate) struct PlanRecorder {
+    steps: Option<Vec<PlanStep>>,
+}
+
+impl PlanRecorder {
+    /// Create a recorder, which does nothing unless *enabled*.
+    pub fn new(enabled: bool) -> Self {
+        PlanRecorder {
+            steps: if enabled { Some(Vec::new()) } else { None },
+        }
+    }
+
+    /// Record a special, if it's one that affects the output file.
+    pub fn note_special(&mut self, contents: &str) {
+        let steps = match self.steps.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+
+        let step = if let Some(path) = contents.strip_prefix("tdux:setOutputPath ") {
+            PlanStep::SetOutputPath {
+                path: path.to_owned(),
+            }
+        } else if let Some(pattern) = contents.strip_prefix("tdux:setOutputPathPattern ") {
+            PlanStep::SetOutputPathPattern {
+                pattern: pattern.to_owned(),
+            }
+        } else if let Some(path) = contents.strip_prefix("tdux:setTemplate ") {
+            PlanStep::SetTemplate {
+                path: path.to_owned(),
+            }
+        } else if let Some(path) = contents.strip_prefix("tdux:setTemplateOnce ") {
+            PlanStep::SetTemplateOnce {
+                path: path.to_owned(),
+            }
+        } else if let Some((name, value)) = contents
+            .strip_prefix("tdux:setTemplateVariable ")
+            .and_then(|r| r.split_once(' '))
+        {
+            PlanStep::SetVariable {
+                name: name.to_owned(),
+                value: value.to_owned(),
+            }
+        } else {
+            return;
+        };
+
+        steps.push(step);
+    }
+
+    /// Record text added to the content.
+    pub fn note_text(&mut self, text: &str) {
+        if let Some(steps) = self.steps.as_mut() {
+            let n = text.chars().count();
+
+            if let Some(PlanStep::Text { chars }) = steps.last_mut() {
+                *chars += n;
+            } else {
+                steps.push(PlanStep::Text { chars: n });
+            }
+        }
+    }
+
+    /// Record a canvas added to the content.
+    pub fn note_canvas(&mut self, kind: &str) {
+        if let Some(steps) = self.steps.as_mut() {
+            steps.push(PlanStep::Canvas {
+                canvas: kind.to_owned(),
+            });
+        }
+    }
+
+    /// Finish the plan of the output file that has just been emitted.
+    pub fn finish(&mut self, path: &str, template: &str) -> Option<EmissionPlan> {
+        self.steps.as_mut().map(|steps| EmissionPlan {
+            path: path.to_owned(),
+            template: template.to_owned(),
+            steps: std::mem::take(steps),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{progress::ProgressSink, test_util::*, Spx2HtmlEngine};
+    use tectonic_errors::prelude::*;
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn emission_plans() {
+        #[derive(Clone, Default)]
+        struct PlanProgress(std::sync::Arc<std::sync::Mutex<Vec<EmissionPlan>>>);
+
+        impl ProgressSink for PlanProgress {
+            fn on_emission_plan(&mut self, plan: &EmissionPlan) -> Result<()> {
+                self.0.lock().unwrap().push(plan.clone());
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("other.html", "{{ tduxContent }}");
+        let sink = PlanProgress::default();
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .emission_plans(true)
+            .dev_overlay(true)
+            .progress_sink(Box::new(sink.clone()));
+        let x = glyph_for('x');
+
+        let (result, status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.text("ab")?;
+            s.special("tdux:setOutputPath first.html")?;
+            s.text("c")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.handle_special(0, 0, b"tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:ce math")?;
+            s.text("d")?;
+            s.special("tdux:setTemplateVariable part one")?;
+            s.special("tdux:setTemplateOnce other.html")?;
+            s.special("tdux:emit")?;
+            s.text("e")?;
+            s.special("tdux:setOutputPath second.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        assert_eq!(status.warnings.len(), 1);
+        assert!(status.warnings[0].starts_with("text `ab` was added before any template"));
+
+        let plans = sink.0.lock().unwrap();
+        assert_eq!(plans.len(), 2);
+        assert_eq!(plans[0].path, "first.html");
+        assert_eq!(plans[0].template, "other.html");
+        assert_eq!(
+            plans[0].steps,
+            [
+                PlanStep::Text { chars: 2 },
+                PlanStep::SetOutputPath {
+                    path: "first.html".to_owned()
+                },
+                PlanStep::Text { chars: 1 },
+                PlanStep::SetTemplate {
+                    path: "page.html".to_owned()
+                },
+                PlanStep::Canvas {
+                    canvas: "math".to_owned()
+                },
+                PlanStep::Text { chars: 1 },
+                PlanStep::SetVariable {
+                    name: "part".to_owned(),
+                    value: "one".to_owned()
+                },
+                PlanStep::SetTemplateOnce {
+                    path: "other.html".to_owned()
+                },
+            ]
+        );
+
+        assert_eq!(
+            serde_json::to_value(&plans[1]).unwrap(),
+            serde_json::json!({
+                "path": "second.html",
+                "template": "page.html",
+                "steps": [
+                    { "kind": "text", "chars": 1 },
+                    { "kind": "set_output_path", "path": "second.html" },
+                ],
+            })
+        );
+
+        // The plans are listed in the overlay, which is otherwise collapsed
+        // if there's nothing to warn about.
+        let html = std::fs::read_to_string(tempdir.path().join("first.html")).unwrap();
+        assert!(html.contains("<details class=\"tdux-warnings\" open>"));
+        assert!(html.contains("<li>template set to `page.html`</li>"));
+        let html = std::fs::read_to_string(tempdir.path().join("second.html")).unwrap();
+        assert!(html.contains("<details class=\"tdux-warnings\"><summary>0 conversion"));
+        assert!(html.contains("<li>output path set to `second.html`</li></ol>"));
+    }
+}
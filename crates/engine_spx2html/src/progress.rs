@@ -0,0 +1,282 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Reporting the progress of the engine to embedding applications.
+//!
+//! Applications that want to display progress, such as GUI frontends, can
+//! register a [`ProgressSink`] with [`crate::Spx2HtmlEngine::progress_sink`].
+//! If no sink is registered, progress reporting costs essentially nothing.
+
+use serde::Serialize;
+use std::{
+    cell::Cell,
+    io::{Read, Result as IoResult, Seek, SeekFrom},
+    rc::Rc,
+};
+use tectonic_errors::prelude::*;
+
+use crate::{
+    coverage::CoverageReport, deps::OutputDependencies, plan::EmissionPlan, usage::FontUsageReport,
+};
+
+/// A phase of the engine's processing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Fonts, templates, and template variables are being collected.
+    Initializing,
+
+    /// HTML output files are being emitted.
+    Emitting,
+
+    /// The document content is finished, and the fonts are being emitted.
+    Finishing,
+
+    /// All processing is done.
+    Done,
+}
+
+/// A template compiled by the engine.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TemplateInfo {
+    /// The name of the template.
+    pub name: String,
+
+    /// The SHA-256 digest of the template's contents, in hexadecimal.
+    pub digest: String,
+}
+
+/// Callbacks that receive progress updates from the engine.
+///
+/// All methods have default implementations that do nothing, so implementors
+/// only need to provide the ones that they're interested in. Errors returned
+/// by the callbacks are reported as warnings, and never abort processing.
+pub trait ProgressSink: Send {
+    /// Called when the engine enters a new phase of processing.
+    fn on_phase_change(&mut self, _phase: Phase) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a font has been defined and loaded. The *name* is that of
+    /// the font file.
+    fn on_font_defined(&mut self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when initialization is finished, with the templates that were
+    /// compiled, in order of their names.
+    fn on_templates_compiled(&mut self, _templates: &[TemplateInfo]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when an HTML output file has been written. The *path* is
+    /// relative to the output directory, *index* counts the files emitted so
+    /// far, starting at zero, and *template* is the path of the template that
+    /// the file was rendered with.
+    fn on_page_emitted(&mut self, _path: &str, _index: usize, _template: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after an HTML output file has been written, with the plan of
+    /// how it was produced, if emission plans are enabled with
+    /// [`crate::Spx2HtmlEngine::emission_plans`].
+    fn on_emission_plan(&mut self, _plan: &EmissionPlan) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the content is finished, with a report on how the
+    /// document's fonts cover the characters of its text.
+    fn on_coverage_report(&mut self, _report: &CoverageReport) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the content is finished, with a report on how the document
+    /// uses its fonts. See [`crate::usage`] for details.
+    fn on_font_usage(&mut self, _report: &FontUsageReport) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when all of the output files have been emitted, with the inputs
+    /// that each of them depends on, in the order in which they were emitted.
+    /// See [`crate::deps`] for details.
+    fn on_dependencies(&mut self, _outputs: &[OutputDependencies]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when all processing is done, just before the phase changes to
+    /// [`Phase::Done`]. The *clean* flag is false if the document didn't
+    /// finish its content with `tdux:contentFinished`, in which case the
+    /// engine finished it on the document's behalf, and the output may be
+    /// incomplete.
+    fn on_completed(&mut self, _clean: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called periodically as the SPX input is processed. The *total* is the
+    /// size of the input, if it's known. The processed byte count runs a bit
+    /// ahead of the content that has actually been handled, since the input
+    /// is read in chunks.
+    fn on_bytes_processed(&mut self, _done: u64, _total: Option<u64>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader wrapper that tracks the current position in the stream.
+///
+/// The position is shared through a cell so that the engine can inspect it
+/// while the XDV parser owns the reader.
+#[derive(Debug)]
+pub(crate) struct PositionTrackingReader<R> {
+    inner: R,
+    pos: Rc<Cell<u64>>,
+}
+
+impl<R> PositionTrackingReader<R> {
+    pub fn new(inner: R, pos: Rc<Cell<u64>>) -> Self {
+        PositionTrackingReader { inner, pos }
+    }
+}
+
+impl<R: Read> Read for PositionTrackingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos.set(self.pos.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for PositionTrackingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new_pos = self.inner.seek(pos)?;
+        self.pos.set(new_pos);
+        Ok(new_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine, Spx2HtmlError};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+    use tectonic_xdv::XdvEvents;
+
+    /// A progress sink that records the callbacks that it receives.
+    #[derive(Clone, Debug, Default)]
+    struct RecordingProgress {
+        log: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ProgressSink for RecordingProgress {
+        fn on_phase_change(&mut self, phase: Phase) -> Result<()> {
+            self.log.lock().unwrap().push(format!("phase {:?}", phase));
+            Ok(())
+        }
+
+        fn on_font_defined(&mut self, name: &str) -> Result<()> {
+            self.log.lock().unwrap().push(format!("font {}", name));
+            Ok(())
+        }
+
+        fn on_page_emitted(&mut self, path: &str, index: usize, template: &str) -> Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("page {} {} {}", index, path, template));
+            Ok(())
+        }
+
+        fn on_bytes_processed(&mut self, done: u64, total: Option<u64>) -> Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("bytes {} {:?}", done, total));
+            bail!("progress UI went away");
+        }
+    }
+
+    #[test]
+    fn progress_callbacks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let sink = RecordingProgress::default();
+        let mut engine = Spx2HtmlEngine::default();
+        engine.progress_sink(Box::new(sink.clone()));
+
+        let (result, status) = run_engine_with(&mut engine, simple_io(), tempdir.path(), |s| {
+            s.handle_begin_page(&[1], -1)?;
+            simple_events(s)
+        });
+        result.unwrap();
+
+        assert_eq!(
+            *sink.log.lock().unwrap(),
+            [
+                "phase Initializing",
+                "font lmroman12-regular.otf",
+                "bytes 0 None",
+                "phase Emitting",
+                "page 0 index.html page.html",
+                "phase Finishing",
+                "bytes 0 None",
+                "phase Done",
+            ]
+        );
+
+        // Callback failures are only warnings.
+        assert_eq!(status.warnings.len(), 2);
+        assert!(status.warnings[0].contains("progress reporting callback failed"));
+    }
+
+    #[test]
+    fn cancellation() {
+        struct CancellingSink(Arc<AtomicBool>);
+
+        impl ProgressSink for CancellingSink {
+            fn on_page_emitted(
+                &mut self,
+                _path: &str,
+                _index: usize,
+                _template: &str,
+            ) -> Result<()> {
+                self.0.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("page.html", "<p>{{ tduxContent }}</p>");
+
+        let token = Arc::new(AtomicBool::new(false));
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .cancellation_token(Some(token.clone()))
+            .progress_sink(Box::new(CancellingSink(token)));
+
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("One")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("Two")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::Cancelled)
+        ));
+
+        let mut names: Vec<_> = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["index.html", "lmroman12-regular.otf"]);
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "<p>One</p>");
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Providing files fetched from remote URLs.
+//!
+//! The source of a `tdux:provideFile` special may be an `http://` or
+//! `https://` URL, as in `tdux:provideFile https://example.com/logo.svg
+//! logo.svg`. This crate does no networking of its own: such sources are only
+//! allowed if the application driving the engine has supplied a
+//! [`RemoteFetcher`] with [`crate::Spx2HtmlEngine::remote_fetcher`], so that
+//! sandboxed builds can simply decline to do so. Fetched contents are limited
+//! in size, see [`crate::Spx2HtmlEngine::max_remote_size`], and are cached for
+//! the rest of the run, so that a URL is only fetched once no matter how many
+//! times it's referenced.
+
+use std::collections::HashMap;
+use tectonic_errors::prelude::*;
+use tectonic_io_base::digest::{self, Digest, DigestData};
+
+use crate::Spx2HtmlError;
+
+/// The default limit on the size of a fetched file, in bytes.
+pub const DEFAULT_MAX_REMOTE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Fetches the contents of remote URLs on behalf of the engine.
+pub trait RemoteFetcher: Send {
+    /// Fetch the contents of *url*.
+    ///
+    /// Implementations should give up once more than *limit* bytes have been
+    /// received. The engine rejects larger contents in any case.
+    fn fetch(&mut self, url: &str, limit: u64) -> Result<Vec<u8>>;
+}
+
+/// Whether the source of a `tdux:provideFile` special is a remote URL.
+pub fn is_remote(src: &str) -> bool {
+    src.starts_with("https://") || src.starts_with("http://")
+}
+
+/// The contents of a fetched URL.
+#[derive(Clone, Debug)]
+pub(crate) struct Fetched {
+    /// The contents.
+    pub contents: Vec<u8>,
+
+    /// The SHA-256 digest of the contents, in hexadecimal.
+    pub digest: String,
+}
+
+/// The URLs fetched so far in a run.
+#[derive(Debug, Default)]
+pub(crate) struct RemoteCache {
+    fetched: HashMap<String, Fetched>,
+}
+
+impl RemoteCache {
+    /// Get the contents of *url*, fetching them with *fetcher* if they
+    /// haven't been already.
+    ///
+    /// The boolean is true if the contents were fetched by this call.
+    pub fn get(
+        &mut self,
+        fetcher: Option<&mut (dyn RemoteFetcher + '_)>,
+        url: &str,
+        limit: u64,
+    ) -> Result<(&Fetched, bool)> {
+        if self.fetched.contains_key(url) {
+            return Ok((&self.fetched[url], false));
+        }
+
+        let fetcher = fetcher.ok_or_else(|| Spx2HtmlError::RemoteDisabled {
+            url: url.to_owned(),
+        })?;
+
+        let contents = fetcher
+            .fetch(url, limit)
+            .map_err(|e| Spx2HtmlError::RemoteFetch {
+                url: url.to_owned(),
+                source: e.into(),
+            })?;
+
+        if contents.len() as u64 > limit {
+            return Err(Spx2HtmlError::RemoteTooLarge {
+                url: url.to_owned(),
+                size: contents.len() as u64,
+                limit,
+            }
+            .into());
+        }
+
+        let mut dc = digest::create();
+        dc.update(&contents);
+        let digest = DigestData::from(dc).to_string();
+
+        let fetched = self
+            .fetched
+            .entry(url.to_owned())
+            .or_insert(Fetched { contents, digest });
+        Ok((fetched, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, EngineState, Spx2HtmlEngine};
+    use std::sync::{Arc, Mutex};
+
+    struct Counting(usize);
+
+    impl RemoteFetcher for Counting {
+        fn fetch(&mut self, url: &str, _limit: u64) -> Result<Vec<u8>> {
+            self.0 += 1;
+            Ok(url.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn caching() {
+        let mut cache = RemoteCache::default();
+        let mut fetcher = Counting(0);
+
+        let (f, fresh) = cache
+            .get(Some(&mut fetcher), "https://example.com/a", 100)
+            .unwrap();
+        assert!(fresh);
+        assert_eq!(f.contents, b"https://example.com/a");
+        assert_eq!(f.digest.len(), 64);
+
+        let (_, fresh) = cache
+            .get(Some(&mut fetcher), "https://example.com/a", 100)
+            .unwrap();
+        assert!(!fresh);
+        assert_eq!(fetcher.0, 1);
+
+        // Cached contents are available even without a fetcher.
+        assert!(cache.get(None, "https://example.com/a", 100).is_ok());
+
+        let err = cache.get(None, "https://example.com/b", 100).unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::RemoteDisabled { .. })
+        ));
+
+        let err = cache
+            .get(Some(&mut fetcher), "https://example.com/b", 10)
+            .unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::RemoteTooLarge {
+                size: 21,
+                limit: 10,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn remote_provide_file() {
+        struct MockFetcher(Arc<Mutex<Vec<String>>>);
+
+        impl RemoteFetcher for MockFetcher {
+            fn fetch(&mut self, url: &str, _limit: u64) -> Result<Vec<u8>> {
+                self.0.lock().unwrap().push(url.to_owned());
+                Ok(b"<svg/>".to_vec())
+            }
+        }
+
+        const URL: &str = "https://example.com/logo.svg";
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("logo.html", "{{ asset_url(path=\"img/logo.svg\") }}");
+
+        let events = |s: &mut EngineState| {
+            s.special(&format!("tdux:provideFile {} img/logo.svg", URL))?;
+            s.special("tdux:addTemplate logo.html")?;
+            s.special("tdux:setTemplate logo.html")?;
+            s.text("Hello")?;
+            s.special(&format!("tdux:provideFile {} img/logo.svg", URL))?;
+            s.special(&format!("tdux:provideFile {} copy.svg", URL))?;
+            s.special("tdux:emit")
+        };
+
+        // Remote sources must be enabled by the application.
+        let (result, _status) = run_engine(io.clone(), tempdir.path(), events);
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::RemoteDisabled { url }) if url == URL
+        ));
+        assert!(err.to_string().contains("Spx2HtmlEngine::remote_fetcher"));
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Spx2HtmlEngine::default();
+        engine.remote_fetcher(Box::new(MockFetcher(log.clone())));
+        let (result, status) = run_engine_with(&mut engine, io.clone(), tempdir.path(), events);
+        result.unwrap();
+
+        // The URL is only fetched once, and its digest is reported.
+        assert_eq!(*log.lock().unwrap(), [URL]);
+        let fetched: Vec<_> = status
+            .notes
+            .iter()
+            .filter(|n| n.starts_with("fetched"))
+            .collect();
+        assert_eq!(fetched.len(), 1);
+        assert!(fetched[0].contains("6 bytes with SHA-256 digest"));
+
+        for path in ["img/logo.svg", "copy.svg"] {
+            let svg = std::fs::read_to_string(tempdir.path().join(path)).unwrap();
+            assert_eq!(svg, "<svg/>");
+        }
+
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "img/logo.svg");
+
+        // A remote source can't take over a path claimed by another source.
+        io.add("logo.svg", "<svg></svg>");
+        let (result, _status) = run_engine_with(&mut engine, io.clone(), tempdir.path(), |s| {
+            s.special("tdux:provideFile logo.svg logo.svg")?;
+            s.special(&format!("tdux:provideFile {} logo.svg", URL))?;
+            s.text("Hello")
+        });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "output file `logo.svg` would be provided by both \
+             `tdux:provideFile` of `logo.svg` and `tdux:provideFile` of `https://example.com/logo.svg`"
+        );
+
+        // The size of fetched files is limited.
+        engine.max_remote_size(4);
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), events);
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::RemoteTooLarge {
+                size: 6,
+                limit: 4,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Rendering HTML templates, with guards against runaway templates.
+//!
+//! A template that includes itself, or loops over an accidentally huge array,
+//! could otherwise hang the build or produce an enormous page. Tera renders
+//! synchronously and can't be interrupted, so to impose a time limit the
+//! rendering happens on a helper thread that we wait on with a timeout. If the
+//! limit is exceeded, the thread is abandoned rather than stopped: it keeps
+//! running in the background until it finishes or the process exits. Since
+//! exceeding the limit is a fatal error, that's an acceptable price for being
+//! able to report the problem at all.
+//!
+//! How long each render took and how large its page was, and whether it hit
+//! a limit, is recorded in the run's [`RenderStats`].
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tectonic_errors::prelude::*;
+
+use crate::{stats::RenderStats, Spx2HtmlError};
+
+/// Limits on the rendering of a single page.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RenderLimits {
+    /// The maximum time that rendering may take.
+    pub time: Option<Duration>,
+
+    /// The maximum size of the rendered page, in bytes.
+    pub size: Option<usize>,
+}
+
+impl Default for RenderLimits {
+    fn default() -> Self {
+        RenderLimits {
+            time: Some(Duration::from_secs(120)),
+            size: Some(256 * 1024 * 1024),
+        }
+    }
+}
+
+/// Render a template, enforcing the limits.
+///
+/// The *name* of the template and the *page* being created are used in error
+/// messages. The render is recorded in *stats*, whether or not it succeeds.
+pub fn render(
+    tera: &Arc<Mutex<tera::Tera>>,
+    template: String,
+    context: &tera::Context,
+    name: &str,
+    page: &str,
+    limits: &RenderLimits,
+    stats: &mut RenderStats,
+) -> Result<String> {
+    let start = Instant::now();
+
+    let result = match limits.time {
+        None => lock(tera).render_str(&template, context),
+
+        Some(limit) => {
+            let (tx, rx) = mpsc::channel();
+            let tera = tera.clone();
+            let context = context.clone();
+
+            std::thread::spawn(move || {
+                let result = lock(&tera).render_str(&template, &context);
+                let _ignored = tx.send(result);
+            });
+
+            match rx.recv_timeout(limit) {
+                Ok(r) => r,
+
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    stats.add(start.elapsed(), None);
+                    stats.timeouts += 1;
+                    return Err(Spx2HtmlError::RenderTimeout {
+                        name: name.to_owned(),
+                        page: page.to_owned(),
+                        limit,
+                    }
+                    .into());
+                }
+
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    stats.add(start.elapsed(), None);
+                    bail!(
+                        "the thread rendering HTML template `{}` for `{}` died unexpectedly",
+                        name,
+                        page
+                    );
+                }
+            }
+        }
+    };
+
+    stats.add(start.elapsed(), result.as_ref().ok().map(String::len));
+
+    let rendered = result.map_err(|e| Spx2HtmlError::TemplateRender {
+        name: name.to_owned(),
+        page: page.to_owned(),
+        source: e,
+    })?;
+
+    if let Some(limit) = limits.size {
+        if rendered.len() > limit {
+            stats.oversized_pages += 1;
+            return Err(Spx2HtmlError::PageTooLarge {
+                name: name.to_owned(),
+                page: page.to_owned(),
+                size: rendered.len(),
+                limit,
+            }
+            .into());
+        }
+    }
+
+    Ok(rendered)
+}
+
+/// Lock the Tera instance, tolerating poisoning: if a rendering thread
+/// panicked, Tera's state is still usable.
+pub fn lock(tera: &Mutex<tera::Tera>) -> std::sync::MutexGuard<'_, tera::Tera> {
+    tera.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        progress::{ProgressSink, TemplateInfo},
+        test_util::*,
+        Spx2HtmlEngine,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn render_limits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add(
+            "slow.html",
+            "{% for i in range(end=1000) %}{% for j in range(end=1000) %}{% endfor %}{% endfor %}",
+        );
+
+        // The loop takes about half a second in a debug build, and still well
+        // over the limit in an optimized one. The thread running it keeps
+        // going in the background after it's abandoned, so it's kept short.
+        let mut engine = Spx2HtmlEngine::default();
+        engine.render_time_limit(Some(Duration::from_millis(10)));
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), |s| {
+            s.special("tdux:setTemplate slow.html")?;
+            s.special("tdux:emit")
+        });
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::RenderTimeout { name, page, .. }) if name == "slow.html" && page == "index.html"
+        ));
+        let stats = engine.processing_stats().unwrap().rendering;
+        assert_eq!((stats.timeouts, stats.oversized_pages), (1, 0));
+        assert!(stats.slowest >= Duration::from_millis(10));
+        assert_eq!(stats.largest_page, 0);
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.render_time_limit(None).max_page_size(Some(10));
+        let (result, _status) =
+            run_engine_with(&mut engine, simple_io(), tempdir.path(), simple_events);
+        let err = result.unwrap_err();
+        assert!(matches!(
+            Spx2HtmlError::from_error(&err),
+            Some(Spx2HtmlError::PageTooLarge {
+                size: 31,
+                limit: 10,
+                ..
+            })
+        ));
+        assert!(!tempdir.path().join("index.html").exists());
+        let stats = engine.processing_stats().unwrap().rendering;
+        assert_eq!((stats.timeouts, stats.oversized_pages), (0, 1));
+        assert_eq!(stats.largest_page, 31);
+        assert_eq!(stats.limit_hits(), 1);
+    }
+
+    #[test]
+    fn template_search_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = TestIo::new();
+        io.add("chapters/templates/page.html", "searched {{ tduxContent }}");
+        io.add("chapters/templates/only.html", "only {{ tduxContent }}");
+        io.add("other/page.html", "other {{ tduxContent }}");
+        io.add("page.html", "literal {{ tduxContent }}");
+        io.add("chapters/templates/logo.svg", "<svg/>");
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.search_provided_files(true);
+
+        let (result, _status) = run_engine_with(&mut engine, io.clone(), tempdir.path(), |s| {
+            s.special("tdux:templateSearchPath other/")?;
+            s.special("tdux:templateSearchPath chapters/templates")?;
+            s.special("tdux:addTemplate only.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.special("tdux:provideFile logo.svg logo.svg")?;
+            s.text("a")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setTemplate only.html")?;
+            s.special("tdux:setOutputPath only.html")?;
+            s.text("b")?;
+            s.special("tdux:emit")
+        });
+        result.unwrap();
+
+        // The literal path wins over the search path, and the directories of
+        // the search path are tried in order.
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "literal a");
+        let html = std::fs::read_to_string(tempdir.path().join("only.html")).unwrap();
+        assert_eq!(html, "only b");
+        assert!(tempdir.path().join("logo.svg").exists());
+
+        let (result, _status) = run_engine(io, tempdir.path(), |s| {
+            s.special("tdux:templateSearchPath other")?;
+            s.special("tdux:templateSearchPath chapters")?;
+            s.special("tdux:addTemplate nosuch.html")?;
+            s.text("a")?;
+            unreachable!("emission should not begin");
+        });
+
+        let msg = result.unwrap_err().to_string();
+        assert!(
+            msg.contains(
+                "HTML template `nosuch.html` (tried `nosuch.html`, `other/nosuch.html`, `chapters/nosuch.html`)"
+            ),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn template_once() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = TestIo::new();
+        io.add("a.html", "A");
+        io.add("b.html", "B");
+        io.add("c.html", "C");
+
+        let (result, status) = run_engine(io, tempdir.path(), |s| {
+            // During initialization:
+            s.special("tdux:setTemplate a.html")?;
+            s.special("tdux:setTemplateOnce b.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath 2.html")?;
+            s.special("tdux:emit")?;
+
+            // A persistent setting overrides a pending one-off one:
+            s.special("tdux:setTemplateOnce c.html")?;
+            s.special("tdux:setTemplate b.html")?;
+            s.special("tdux:setOutputPath 3.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath 4.html")?;
+            s.special("tdux:emit")?;
+
+            // The last of several one-off settings wins:
+            s.special("tdux:setTemplateOnce a.html")?;
+            s.special("tdux:setTemplateOnce c.html")?;
+            s.special("tdux:setOutputPath 5.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath 6.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        for (path, expected) in [
+            ("index.html", "B"),
+            ("2.html", "A"),
+            ("3.html", "B"),
+            ("4.html", "B"),
+            ("5.html", "C"),
+            ("6.html", "B"),
+        ] {
+            let html = std::fs::read_to_string(tempdir.path().join(path)).unwrap();
+            assert_eq!(html, expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn redefined_templates() {
+        struct InventorySink(Arc<Mutex<Vec<TemplateInfo>>>);
+
+        impl ProgressSink for InventorySink {
+            fn on_templates_compiled(&mut self, templates: &[TemplateInfo]) -> Result<()> {
+                *self.0.lock().unwrap() = templates.to_vec();
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut io = simple_io();
+        io.add("other.html", "other");
+
+        // Identical additions are fine.
+        let inventory = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .add_template("page.html", TEMPLATE)
+            .progress_sink(Box::new(InventorySink(inventory.clone())));
+        let (result, status) = run_engine_with(&mut engine, io.clone(), tempdir.path(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:addTemplate other.html")?;
+            s.special("tdux:addTemplate page.html")?;
+            simple_events(s)
+        });
+        result.unwrap();
+        assert!(status.warnings.is_empty(), "{:?}", status.warnings);
+
+        let inventory = inventory.lock().unwrap();
+        let names: Vec<_> = inventory.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["other.html", "page.html"]);
+        assert_eq!(
+            inventory[0].digest,
+            "d9298a10d1b0735837dc4bd85dac641b0f3cef27a47e5d53a54f2f3f5b2fcffa"
+        );
+
+        // Differing ones draw a warning, and the last one, here the
+        // document's, wins.
+        let mut engine = Spx2HtmlEngine::default();
+        engine.add_template("page.html", "driver");
+        let (result, status) =
+            run_engine_with(&mut engine, io.clone(), tempdir.path(), simple_events);
+        result.unwrap();
+        assert_eq!(
+            status.warnings,
+            [
+                "HTML template `page.html` was added more than once with different contents; \
+              using the one added last"
+            ]
+        );
+        let html = std::fs::read_to_string(tempdir.path().join("index.html")).unwrap();
+        assert_eq!(html, "<html><body>Hello</body></html>");
+
+        // In strict mode, they're an error.
+        engine.strict(true);
+        let (result, _status) = run_engine_with(&mut engine, io, tempdir.path(), simple_events);
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::TemplateRedefined { name }) if name == "page.html"
+        ));
+    }
+}
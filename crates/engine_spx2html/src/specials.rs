@@ -0,0 +1,606 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Normalizing the text of specials.
+//!
+//! Macro code often emits specials with trailing whitespace, or with several
+//! spaces between arguments. Before a special is handled, [`normalize`]
+//! rewrites it into a canonical form: the name and arguments separated by
+//! single spaces, and nothing trailing. Free-text arguments, such as the
+//! values of template variables, run to the end of the special and keep any
+//! whitespace within them. Required arguments that turn out to be empty are
+//! reported, rather than being passed along to fail in confusing ways later.
+//! The HTML passed through with `tdux:direct` and `tdux:directLine` is left
+//! exactly as given.
+//!
+//! The paths given to `tdux:addTemplate`, `tdux:setTemplate`,
+//! `tdux:setTemplateOnce`, `tdux:setOutputPath`, `tdux:provideFile`,
+//! `tdux:provideDirectory`, `tdux:addCss`, `tdux:addJs`, `tdux:provideData`,
+//! `tdux:provideDataChunk`, and `tdux:img` are percent-decoded before they're
+//! used, except for remote URLs. Producers must encode spaces in paths as
+//! `%20`, since they would otherwise separate arguments, and literal percent
+//! signs as `%25`. Paths are checked for components like `..` only after
+//! decoding.
+//!
+//! The arguments of `tdux:setTemplateVariable`, `tdux:img`, and the specials
+//! that provide files, such as `tdux:provideFile`, are split with
+//! [`split_args`], so that any of them can be enclosed in double quotes, such
+//! as a variable name with spaces in it. Within quotes, a backslash escapes
+//! the next character, which is how quotes and backslashes themselves are
+//! written. Normalization keeps quoted arguments intact.
+
+use std::borrow::Cow;
+
+/// An argument of a special.
+#[derive(Clone, Copy, Debug)]
+enum Arg {
+    /// A required argument consisting of a single word.
+    Word(&'static str),
+
+    /// A required argument consisting of the rest of the special.
+    Text(&'static str),
+
+    /// Optional text consisting of the rest of the special.
+    OptionalText,
+
+    /// Optional text consisting of the rest of the special after the
+    /// whitespace character separating it from the name, kept exactly as
+    /// given. This must be the only argument.
+    Verbatim,
+}
+
+/// The arguments of the specials that take any. The arguments of other
+/// specials, if any, are normalized as a list of words.
+const SYNTAX: &[(&str, &[Arg])] = &[
+    ("tdux:addTemplate", &[Arg::Text("path")]),
+    ("tdux:setTemplate", &[Arg::Text("path")]),
+    ("tdux:setTemplateOnce", &[Arg::Text("path")]),
+    ("tdux:setOutputPath", &[Arg::Text("path")]),
+    ("tdux:setOutputPathPattern", &[Arg::Text("pattern")]),
+    ("tdux:setBodyFontSize", &[Arg::Word("size")]),
+    (
+        "tdux:setTemplateVariable",
+        &[Arg::Word("name"), Arg::Text("value")],
+    ),
+    ("tdux:unsetTemplateVariable", &[Arg::Text("name")]),
+    ("tdux:setPageMeta", &[Arg::Word("key"), Arg::Text("value")]),
+    (
+        "tdux:setFontFallback",
+        &[Arg::Word("family"), Arg::Text("stack")],
+    ),
+    ("tdux:templateSearchPath", &[Arg::Text("directory")]),
+    (
+        "tdux:provideFile",
+        &[Arg::Word("source"), Arg::Text("destination")],
+    ),
+    (
+        "tdux:addCss",
+        &[Arg::Word("source"), Arg::Text("destination")],
+    ),
+    (
+        "tdux:addJs",
+        &[Arg::Word("source"), Arg::Text("destination")],
+    ),
+    (
+        "tdux:provideData",
+        &[Arg::Word("destination"), Arg::Text("payload")],
+    ),
+    (
+        "tdux:provideDataChunk",
+        &[Arg::Word("destination"), Arg::Text("payload")],
+    ),
+    (
+        "tdux:provideDirectory",
+        &[Arg::Word("source"), Arg::Text("destination")],
+    ),
+    ("tdux:setLanguage", &[Arg::Text("tag")]),
+    ("tdux:deferStart", &[Arg::Word("stream")]),
+    ("tdux:as", &[Arg::Word("element")]),
+    ("tdux:asp", &[Arg::Word("element"), Arg::OptionalText]),
+    ("tdux:ae", &[Arg::Word("element")]),
+    ("tdux:class", &[Arg::Text("names")]),
+    ("tdux:style", &[Arg::Text("declarations")]),
+    ("tdux:canvasAlt", &[Arg::Text("text")]),
+    ("tdux:asSemantic", &[Arg::Word("alias"), Arg::OptionalText]),
+    ("tdux:aeSemantic", &[Arg::Word("alias")]),
+    ("tdux:cs", &[Arg::Word("kind")]),
+    ("tdux:ce", &[Arg::Word("kind")]),
+    ("tdux:anchor", &[Arg::Text("name")]),
+    ("tdux:footnoteStart", &[Arg::Text("mark")]),
+    (
+        "tdux:tocEntry",
+        &[Arg::Word("level"), Arg::Word("anchor"), Arg::Text("title")],
+    ),
+    ("tdux:refStart", &[Arg::Text("name")]),
+    ("tdux:hrefStart", &[Arg::Text("url")]),
+    (
+        "tdux:img",
+        &[
+            Arg::Word("source"),
+            Arg::Word("destination"),
+            Arg::Word("width"),
+            Arg::Word("height"),
+            Arg::OptionalText,
+        ],
+    ),
+    ("tdux:direct", &[Arg::Verbatim]),
+    ("tdux:directLine", &[Arg::Verbatim]),
+    ("tdux:formatVersion", &[Arg::Word("version")]),
+];
+
+/// The names of the other specials that the engine knows, which take no
+/// arguments or check their own.
+const OTHER_SPECIALS: &[&str] = &[
+    "tdux:emit",
+    "tdux:emitIfChanged",
+    "tdux:contentFinished",
+    "tdux:resetContent",
+    "tdux:pushVariableScope",
+    "tdux:popVariableScope",
+    "tdux:printBreakBefore",
+    "tdux:refEnd",
+    "tdux:hrefEnd",
+    "tdux:deferEnd",
+    "tdux:endLanguage",
+    "tdux:footnoteEnd",
+    "tdux:directStart",
+    "tdux:directEnd",
+    "tdux:tableStart",
+    "tdux:tableEnd",
+    "tdux:rowStart",
+    "tdux:rowEnd",
+    "tdux:cellStart",
+    "tdux:cellEnd",
+];
+
+/// Returns true if the engine knows the `tdux:` special with the given name.
+pub fn is_known(name: &str) -> bool {
+    SYNTAX.iter().any(|(n, _)| *n == name) || OTHER_SPECIALS.contains(&name)
+}
+
+/// Find the end of the quoted argument at the start of *text*, which starts
+/// with the opening quote. Returns the position just after the closing
+/// quote, or None if there's none.
+fn quote_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Split the first word off some text, returning it and the rest of the
+/// text, with leading whitespace removed. A word that starts with a double
+/// quote runs at least to the closing quote, if there is one.
+fn split_word(text: &str) -> (&str, &str) {
+    let start = if text.starts_with('"') {
+        quote_end(text).unwrap_or(0)
+    } else {
+        0
+    };
+    let end = text[start..]
+        .find(char::is_whitespace)
+        .map(|i| start + i)
+        .unwrap_or(text.len());
+    (&text[..end], text[end..].trim_start())
+}
+
+/// Remove the quotes around a quoted argument, and resolve its escapes.
+fn unquote(arg: &str) -> Result<String, String> {
+    let end = match quote_end(arg) {
+        Some(e) => e,
+        None => return Err(format!("the quoted argument `{}` isn't terminated", arg)),
+    };
+
+    if end != arg.len() {
+        return Err(format!(
+            "the quoted argument `{}` is followed by `{}`",
+            &arg[..end],
+            &arg[end..]
+        ));
+    }
+
+    let mut value = String::new();
+    let mut chars = arg[1..end - 1].chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next()),
+            c => value.push(c),
+        }
+    }
+
+    Ok(value)
+}
+
+/// Split the normalized arguments of a special into *n* arguments: *n* - 1
+/// words, and then the rest of the text.
+///
+/// Each argument may be enclosed in double quotes, in which case it's
+/// unquoted; otherwise, it's returned as it is. Returns a description of the
+/// problem if there are too few arguments or the quoting is malformed.
+pub fn split_args(text: &str, n: usize) -> Result<Vec<Cow<'_, str>>, String> {
+    let mut args = Vec::with_capacity(n);
+    let mut rest = text;
+
+    for i in 0..n {
+        let (arg, remainder) = if i + 1 == n {
+            (rest, "")
+        } else {
+            split_word(rest)
+        };
+
+        if arg.is_empty() {
+            return Err(format!("it needs {} arguments, but has {}", n, i));
+        }
+
+        if arg.starts_with('"') {
+            args.push(Cow::Owned(unquote(arg)?));
+        } else {
+            args.push(Cow::Borrowed(arg));
+        }
+
+        rest = remainder;
+    }
+
+    Ok(args)
+}
+
+/// Normalize the text of a special.
+///
+/// Returns a description of the problem if a required argument is empty.
+pub fn normalize(contents: &str) -> Result<Cow<'_, str>, String> {
+    let trimmed = contents.trim_end();
+    let (name, mut rest) = split_word(trimmed);
+    let args = SYNTAX
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, args)| *args)
+        .unwrap_or_default();
+
+    let mut normalized = name.to_owned();
+
+    // Verbatim text starts after the separator, which is a single character,
+    // in the untrimmed special.
+    let mut verbatim = contents[name.len()..].chars();
+    verbatim.next();
+
+    for arg in args {
+        let (value, remainder) = match arg {
+            Arg::Word(_) => split_word(rest),
+            Arg::Text(_) | Arg::OptionalText => (rest, ""),
+            Arg::Verbatim => (verbatim.as_str(), ""),
+        };
+
+        match (arg, value.is_empty()) {
+            (Arg::Word(arg_name) | Arg::Text(arg_name), true) => {
+                return Err(format!("its `{}` argument is empty", arg_name));
+            }
+            (Arg::OptionalText | Arg::Verbatim, true) => {}
+            (_, false) => {
+                normalized.push(' ');
+                normalized.push_str(value);
+            }
+        }
+
+        rest = remainder;
+    }
+
+    // Any further arguments are words.
+
+    while !rest.is_empty() {
+        let (word, remainder) = split_word(rest);
+        normalized.push(' ');
+        normalized.push_str(word);
+        rest = remainder;
+    }
+
+    if normalized == contents {
+        Ok(Cow::Borrowed(contents))
+    } else {
+        Ok(Cow::Owned(normalized))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine};
+
+    #[test]
+    fn normalize_whitespace() {
+        const CASES: &[(&str, &str)] = &[
+            // Specials without arguments
+            ("tdux:emit", "tdux:emit"),
+            ("tdux:emit ", "tdux:emit"),
+            ("tdux:emit\t\n", "tdux:emit"),
+            ("tdux:cellStart  right   2 ", "tdux:cellStart right 2"),
+            ("tdux:cellStart\tright\t2", "tdux:cellStart right 2"),
+            // Path arguments
+            (
+                "tdux:setOutputPath index.html ",
+                "tdux:setOutputPath index.html",
+            ),
+            (
+                "tdux:setOutputPath  index.html",
+                "tdux:setOutputPath index.html",
+            ),
+            (
+                "tdux:setOutputPath\tindex.html\t",
+                "tdux:setOutputPath index.html",
+            ),
+            (
+                "tdux:setOutputPath my  page.html",
+                "tdux:setOutputPath my  page.html",
+            ),
+            (
+                "tdux:setTemplate   page.html  ",
+                "tdux:setTemplate page.html",
+            ),
+            (
+                "tdux:setTemplateOnce page.html\n",
+                "tdux:setTemplateOnce page.html",
+            ),
+            ("tdux:addTemplate  page.html", "tdux:addTemplate page.html"),
+            (
+                "tdux:templateSearchPath  tmpl/ ",
+                "tdux:templateSearchPath tmpl/",
+            ),
+            (
+                "tdux:setOutputPathPattern  ch{n}.html ",
+                "tdux:setOutputPathPattern ch{n}.html",
+            ),
+            // Free-text arguments keep their inner whitespace
+            (
+                "tdux:setTemplateVariable  title  A  Title ",
+                "tdux:setTemplateVariable title A  Title",
+            ),
+            (
+                "tdux:setTemplateVariable\ttitle\tA\tTitle",
+                "tdux:setTemplateVariable title A\tTitle",
+            ),
+            (
+                "tdux:setFontFallback  body   \"Noto Serif\",  serif ",
+                "tdux:setFontFallback body \"Noto Serif\",  serif",
+            ),
+            (
+                "tdux:provideFile  a.css   css/a.css ",
+                "tdux:provideFile a.css css/a.css",
+            ),
+            (
+                "tdux:provideDirectory  img\t static/img/ ",
+                "tdux:provideDirectory img static/img/",
+            ),
+            // Element and canvas arguments
+            ("tdux:as  div ", "tdux:as div"),
+            ("tdux:ae div\t", "tdux:ae div"),
+            ("tdux:cs  math ", "tdux:cs math"),
+            ("tdux:ce math  ", "tdux:ce math"),
+            ("tdux:aeSemantic  note ", "tdux:aeSemantic note"),
+            (
+                "tdux:asSemantic  note  id=\"a  b\" ",
+                "tdux:asSemantic note id=\"a  b\"",
+            ),
+            ("tdux:asSemantic note  ", "tdux:asSemantic note"),
+            (
+                "tdux:asp  div  class=\"a  b\" ",
+                "tdux:asp div class=\"a  b\"",
+            ),
+            ("tdux:anchor  sec:intro ", "tdux:anchor sec:intro"),
+            ("tdux:refStart\tsec: intro", "tdux:refStart sec: intro"),
+            (
+                "tdux:hrefStart  https://example.com/a%20b ",
+                "tdux:hrefStart https://example.com/a%20b",
+            ),
+            (
+                "tdux:img  a.png  img/a.png 100\t200  \"A  plot\" ",
+                "tdux:img a.png img/a.png 100 200 \"A  plot\"",
+            ),
+            ("tdux:class  note   wide ", "tdux:class note   wide"),
+            ("tdux:setLanguage  fr-CA ", "tdux:setLanguage fr-CA"),
+            ("tdux:deferStart\tfootnotes ", "tdux:deferStart footnotes"),
+            (
+                "tdux:style  margin-left:  2em; color: red ",
+                "tdux:style margin-left:  2em; color: red",
+            ),
+            // Quoted arguments are kept intact
+            (
+                "tdux:setTemplateVariable  \"page  title\"  A  Title ",
+                "tdux:setTemplateVariable \"page  title\" A  Title",
+            ),
+            (
+                "tdux:provideFile \"my \\\" file.css\"\tcss/a.css",
+                "tdux:provideFile \"my \\\" file.css\" css/a.css",
+            ),
+            (
+                "tdux:provideFile \"unterminated  a.css",
+                "tdux:provideFile \"unterminated a.css",
+            ),
+            // Verbatim HTML
+            ("tdux:direct", "tdux:direct"),
+            ("tdux:direct ", "tdux:direct"),
+            ("tdux:direct  <p>  a </p> ", "tdux:direct  <p>  a </p> "),
+            ("tdux:direct\t<hr>\n", "tdux:direct <hr>\n"),
+            ("tdux:directLine   ", "tdux:directLine   "),
+            ("tdux:directEnd ", "tdux:directEnd"),
+            // Other specials
+            ("color push  Black ", "color push Black"),
+        ];
+
+        for (input, expected) in CASES {
+            assert_eq!(normalize(input).unwrap(), *expected, "input {:?}", input);
+        }
+
+        assert!(matches!(normalize("tdux:emit"), Ok(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn known_specials() {
+        assert!(is_known("tdux:emit"));
+        assert!(is_known("tdux:setTemplateVariable"));
+        assert!(is_known("tdux:cellStart"));
+        assert!(!is_known("tdux:emitt"));
+        assert!(!is_known("tdux:"));
+    }
+
+    #[test]
+    fn quoted_arguments() {
+        let split = |text| -> Vec<String> {
+            split_args(text, 2)
+                .unwrap()
+                .into_iter()
+                .map(|a| a.into_owned())
+                .collect()
+        };
+
+        assert_eq!(split("title A  Title"), ["title", "A  Title"]);
+        assert_eq!(split("\"page title\" A Title"), ["page title", "A Title"]);
+        assert_eq!(split("title \"  padded  \""), ["title", "  padded  "]);
+        assert_eq!(split("title say \"hi\""), ["title", "say \"hi\""]);
+        assert_eq!(
+            split(r#""a \"quoted\" name" "back\\slash""#),
+            ["a \"quoted\" name", "back\\slash"]
+        );
+        assert_eq!(split("\"\" empty"), ["", "empty"]);
+
+        assert!(matches!(
+            split_args("a b", 2).unwrap()[0],
+            Cow::Borrowed("a")
+        ));
+        assert_eq!(
+            split_args("title", 2).unwrap_err(),
+            "it needs 2 arguments, but has 1"
+        );
+        assert_eq!(
+            split_args("\"page title A Title", 2).unwrap_err(),
+            "the quoted argument `\"page` isn't terminated"
+        );
+        assert_eq!(
+            split_args("\"page\"title A", 2).unwrap_err(),
+            "the quoted argument `\"page\"` is followed by `title`"
+        );
+        assert_eq!(
+            split_args("title \"A\" Title", 2).unwrap_err(),
+            "the quoted argument `\"A\"` is followed by ` Title`"
+        );
+    }
+
+    #[test]
+    fn empty_arguments() {
+        const CASES: &[(&str, &str)] = &[
+            ("tdux:setOutputPath", "path"),
+            ("tdux:setOutputPath ", "path"),
+            ("tdux:setOutputPath \t ", "path"),
+            ("tdux:setTemplate  ", "path"),
+            ("tdux:setTemplateOnce", "path"),
+            ("tdux:addTemplate ", "path"),
+            ("tdux:setOutputPathPattern ", "pattern"),
+            ("tdux:setBodyFontSize ", "size"),
+            ("tdux:templateSearchPath  ", "directory"),
+            ("tdux:setTemplateVariable ", "name"),
+            ("tdux:setTemplateVariable title ", "value"),
+            ("tdux:setTemplateVariable  title\t", "value"),
+            ("tdux:setPageMeta title ", "value"),
+            ("tdux:setFontFallback body  ", "stack"),
+            ("tdux:provideFile ", "source"),
+            ("tdux:provideFile a.css  ", "destination"),
+            ("tdux:provideDirectory img ", "destination"),
+            ("tdux:as ", "element"),
+            ("tdux:ae", "element"),
+            ("tdux:class  ", "names"),
+            ("tdux:setLanguage ", "tag"),
+            ("tdux:deferStart  ", "stream"),
+            ("tdux:style ", "declarations"),
+            ("tdux:asp ", "element"),
+            ("tdux:asSemantic  ", "alias"),
+            ("tdux:aeSemantic\t", "alias"),
+            ("tdux:anchor ", "name"),
+            ("tdux:refStart\t", "name"),
+            ("tdux:hrefStart  ", "url"),
+            ("tdux:img a.png img/a.png 100 ", "height"),
+            ("tdux:cs ", "kind"),
+            ("tdux:ce", "kind"),
+        ];
+
+        for (input, arg) in CASES {
+            assert_eq!(
+                normalize(input).unwrap_err(),
+                format!("its `{}` argument is empty", arg),
+                "input {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn sloppy_specials() {
+        let mut io = simple_io();
+        io.add("title.html", "{{ title }}|{{ tduxContent }}");
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate  title.html ")?;
+            s.special("tdux:setTemplate\ttitle.html")?;
+            s.special("tdux:setTemplateVariable  title  A  Title ")?;
+            s.special("tdux:setTemplateVariable subtitle ")?;
+            s.special("tdux:setOutputPath  one.html ")?;
+            s.text("Hello")?;
+            s.special("tdux:emit ")?;
+            s.special("tdux:contentFinished\n")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(files["one.html"], b"A  Title|Hello");
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert_eq!(
+            status.warnings[0],
+            "ignoring malformed special `tdux:setTemplateVariable subtitle`: \
+             its `value` argument is empty"
+        );
+    }
+
+    #[test]
+    fn quoted_special_arguments() {
+        let mut io = simple_io();
+        io.add("title.html", "{{ title }}|{{ tduxContent }}");
+        io.add("my style.css", "p { color: red; }");
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate title.html")?;
+            s.special("tdux:setTemplate title.html")?;
+            s.special(r#"tdux:setTemplateVariable title "  A \"quoted\" title""#)?;
+            s.special(r#"tdux:setTemplateVariable "sub title" "unterminated"#)?;
+            s.text("Hello")?;
+            s.special(r#"tdux:provideFile "my style.css" "css/my style.css""#)?;
+            s.special(r#"tdux:provideFile "my style.css"extra.css css/extra.css"#)?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(files["index.html"], b"  A \"quoted\" title|Hello");
+        assert_eq!(files["css/my style.css"], b"p { color: red; }");
+        assert_eq!(status.warnings.len(), 2, "{:?}", status.warnings);
+        assert_eq!(
+            status.warnings[0],
+            "ignoring malformatted tdux:setTemplateVariable special \
+             `\"sub title\" \"unterminated`: \
+             the quoted argument `\"unterminated` isn't terminated"
+        );
+        assert_eq!(
+            status.warnings[1],
+            "ignoring malformatted tdux:provideFile special \
+             `\"my style.css\"extra.css css/extra.css`: \
+             the quoted argument `\"my style.css\"` is followed by `extra.css`"
+        );
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Setting aside rendered files that can't be written yet.
+//!
+//! A file that links to anchors that haven't been emitted yet is held back
+//! until the content is finished; see [`crate::xref`]. In a document whose
+//! links mostly point forward, that can be most of its files, all in memory
+//! at once. In the low-memory mode, see
+//! [`crate::Spx2HtmlEngine::spill_threshold`], held files are spilled to
+//! temporary files once the ones in memory add up to more than a threshold,
+//! and they're read back one at a time when they're finally written, so that
+//! the memory that they need stays bounded.
+//!
+//! The temporary files are created with unpredictable names in the system's
+//! temporary directory, so that concurrent runs can't trip over each other,
+//! and they're removed once they've been read back or are dropped.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use tectonic_errors::prelude::*;
+use tempfile::NamedTempFile;
+
+/// A rendered file set aside in a [`SpillArea`].
+#[derive(Debug)]
+pub struct SetAside {
+    /// The file, wherever it's kept.
+    place: Place,
+
+    /// The size of the file in bytes.
+    len: usize,
+}
+
+/// Where a file set aside is kept.
+#[derive(Debug)]
+enum Place {
+    /// In memory.
+    Resident(String),
+
+    /// In a temporary file.
+    Spilled(NamedTempFile),
+}
+
+/// Keeps track of the files set aside, and of how much memory they take.
+#[derive(Debug, Default)]
+pub struct SpillArea {
+    /// The number of bytes of files set aside that may be kept in memory, if
+    /// there's a limit.
+    threshold: Option<usize>,
+
+    /// The number of bytes of files set aside that are in memory.
+    resident: usize,
+
+    /// The largest number of bytes of rendered files that were in memory at
+    /// once, counting the ones set aside and the one being written.
+    peak_resident: usize,
+
+    /// The number of files that were spilled to temporary files.
+    n_spilled: usize,
+}
+
+impl SpillArea {
+    /// Create an area that keeps at most *threshold* bytes of files in
+    /// memory, or any number if it's None.
+    pub fn new(threshold: Option<usize>) -> Self {
+        SpillArea {
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    /// Set aside a rendered file, spilling it to a temporary file if
+    /// keeping it in memory would exceed the threshold.
+    pub fn set_aside(&mut self, data: String) -> Result<SetAside> {
+        let len = data.len();
+
+        match self.threshold {
+            Some(threshold) if self.resident + len > threshold => {
+                let mut file = atry!(
+                    tempfile::Builder::new().prefix("tdux-").suffix(".html").tempfile();
+                    ["failed to create a temporary file to set a rendered file aside in"]
+                );
+                atry!(
+                    file.write_all(data.as_bytes());
+                    ["failed to write to temporary file `{}`", file.path().display()]
+                );
+                self.n_spilled += 1;
+
+                Ok(SetAside {
+                    place: Place::Spilled(file),
+                    len,
+                })
+            }
+
+            _ => {
+                self.resident += len;
+                self.note_resident(0);
+
+                Ok(SetAside {
+                    place: Place::Resident(data),
+                    len,
+                })
+            }
+        }
+    }
+
+    /// Take back a file set aside.
+    pub fn take_back(&mut self, item: SetAside) -> Result<String> {
+        match item.place {
+            Place::Resident(data) => {
+                self.resident -= item.len;
+                Ok(data)
+            }
+
+            Place::Spilled(mut file) => {
+                let mut data = String::with_capacity(item.len);
+                atry!(
+                    file.seek(SeekFrom::Start(0))
+                        .and_then(|_| file.read_to_string(&mut data));
+                    ["failed to read back temporary file `{}`", file.path().display()]
+                );
+                Ok(data)
+            }
+        }
+    }
+
+    /// Forget a file set aside, because it's no longer needed.
+    pub fn discard(&mut self, item: SetAside) {
+        if let Place::Resident(_) = item.place {
+            self.resident -= item.len;
+        }
+    }
+
+    /// Note that a rendered file of *len* bytes is in memory, besides the
+    /// files set aside, for the peak number of resident bytes.
+    pub fn note_resident(&mut self, len: usize) {
+        self.peak_resident = self.peak_resident.max(self.resident + len);
+    }
+
+    /// Get the largest number of bytes of rendered files that were in memory
+    /// at once.
+    pub fn peak_resident(&self) -> usize {
+        self.peak_resident
+    }
+
+    /// Get the number of files that were spilled to temporary files.
+    pub fn n_spilled(&self) -> usize {
+        self.n_spilled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, EngineState, Spx2HtmlEngine};
+
+    /// Emit *n* pages with about *size* bytes of content each, each linking
+    /// to the next, so that all of them are held back until the end.
+    fn forward_links(s: &mut EngineState, n: usize, size: usize) -> Result<()> {
+        let filler = "lorem ipsum dolor sit amet ".repeat(20);
+
+        s.special("tdux:addTemplate page.html")?;
+        s.special("tdux:setTemplate page.html")?;
+
+        for i in 0..n {
+            s.special(&format!("tdux:anchor a{}", i))?;
+
+            for _ in 0..size / filler.len() {
+                s.text(&filler)?;
+            }
+
+            s.special(&format!("tdux:refStart a{}", i + 1))?;
+            s.text("next")?;
+            s.special("tdux:refEnd")?;
+            s.special(&format!("tdux:setOutputPath p{}.html", i))?;
+            s.special("tdux:emit")?;
+        }
+
+        s.special("tdux:contentFinished")
+    }
+
+    #[test]
+    fn spills_past_threshold() {
+        let mut area = SpillArea::new(Some(10));
+        let a = area.set_aside("0123456".to_owned()).unwrap();
+        let b = area.set_aside("789abc".to_owned()).unwrap();
+        let c = area.set_aside("def".to_owned()).unwrap();
+        assert_eq!(area.n_spilled(), 1);
+        assert_eq!(area.peak_resident(), 10);
+
+        let path = match &b.place {
+            Place::Spilled(file) => file.path().to_owned(),
+            Place::Resident(_) => panic!("not spilled"),
+        };
+        assert!(path.exists());
+
+        assert_eq!(area.take_back(a).unwrap(), "0123456");
+        assert_eq!(area.take_back(b).unwrap(), "789abc");
+        assert!(!path.exists());
+        area.discard(c);
+        assert_eq!(area.resident, 0);
+    }
+
+    #[test]
+    fn no_threshold() {
+        let mut area = SpillArea::new(None);
+        let a = area.set_aside("x".repeat(1000)).unwrap();
+        area.note_resident(24);
+        assert_eq!(area.n_spilled(), 0);
+        assert_eq!(area.peak_resident(), 1024);
+        assert_eq!(area.take_back(a).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn spills_held_pages() {
+        const N: usize = 20;
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) =
+            run_engine_in_memory(&mut engine, simple_io(), |s| forward_links(s, N, 1100));
+        let files = files.unwrap();
+        let kept = engine.processing_stats().unwrap().clone();
+        assert_eq!(kept.spilled_files, 0);
+
+        // With a threshold that fits two of the pages, of about 1200 bytes
+        // each, the rest are spilled to temporary files, and read back at the
+        // end.
+        let mut engine = Spx2HtmlEngine::default();
+        engine.spill_threshold(Some(3000));
+        let (spilled_files, spilled_status) =
+            run_engine_in_memory(&mut engine, simple_io(), |s| forward_links(s, N, 1100));
+        let spilled = engine.processing_stats().unwrap().clone();
+        assert_eq!(spilled.spilled_files, N - 2);
+        assert!(
+            spilled.peak_resident_bytes * 4 < kept.peak_resident_bytes,
+            "{} {}",
+            spilled.peak_resident_bytes,
+            kept.peak_resident_bytes
+        );
+
+        assert_eq!(spilled_files.unwrap(), files);
+        assert_eq!(spilled_status.warnings, status.warnings);
+        assert_eq!(
+            status.warnings,
+            [format!(
+                "`p{}.html` links to anchor `a{}`, which was never emitted",
+                N - 1,
+                N
+            )]
+        );
+        assert!(files["p0.html"].ends_with(b"<a href=\"p1.html#a1\">next</a></body></html>"));
+    }
+
+    /// Check that the memory needed for a large document whose pages are all
+    /// held back is bounded in the low-memory mode. This takes a while, and
+    /// must run on its own for the allocator's count to be meaningful, so
+    /// it's ignored by default.
+    #[test]
+    #[ignore]
+    fn bounded_memory_stress() {
+        const N: usize = 1000;
+        const SIZE: usize = 32 * 1024;
+        const THRESHOLD: usize = 1024 * 1024;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut engine = Spx2HtmlEngine::default();
+        engine.spill_threshold(Some(THRESHOLD));
+
+        let baseline = ALLOCATOR.allocated();
+        ALLOCATOR.reset_peak();
+        let (result, _) = run_engine_with(&mut engine, simple_io(), tempdir.path(), |s| {
+            forward_links(s, N, SIZE)
+        });
+        result.unwrap();
+        let peak = ALLOCATOR.peak() - baseline;
+
+        let stats = engine.processing_stats().unwrap();
+        assert!(stats.spilled_files >= N - THRESHOLD / SIZE);
+        assert!(stats.peak_resident_bytes < (THRESHOLD + 2 * SIZE) as u64);
+
+        // All of the pages together would take more than 32 MiB.
+        assert!(peak < 16 * 1024 * 1024, "peak allocation of {} bytes", peak);
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Statistics about a run of the engine.
+//!
+//! Some problems with a document don't cause any errors, but show up plainly
+//! in a few numbers: a document whose `tdux:emit` specials are missing emits
+//! no pages, and one that loads the same font at many sizes ends up with
+//! many fonts. After each successful run, the engine makes such numbers
+//! available as [`ProcessingStats`], through
+//! [`crate::Spx2HtmlEngine::processing_stats`], and can also summarize them
+//! in a note; see [`crate::Spx2HtmlEngine::report_stats`].
+//!
+//! A run that fails because a page hit one of the rendering limits, see
+//! [`crate::Spx2HtmlEngine::render_time_limit`], leaves its
+//! [`RenderStats`] behind, so that the hit can be told apart from other
+//! failures.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+/// Statistics about the processing of a document.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ProcessingStats {
+    /// The number of times that an HTML page was emitted. A page emitted
+    /// again at the same path counts again.
+    pub pages: usize,
+
+    /// The number of specials handled, keyed by the name of the special,
+    /// such as `tdux:emit`. Malformed specials that were ignored aren't
+    /// counted.
+    pub specials: BTreeMap<String, u64>,
+
+    /// The number of glyphs drawn inside canvases.
+    pub canvas_glyphs: u64,
+
+    /// The number of distinct font files, and faces within them, that the
+    /// document loaded.
+    pub fonts: usize,
+
+    /// The number of variants of the fonts, with customized character maps,
+    /// that were generated.
+    pub font_variants: usize,
+
+    /// The number of pieces of content, such as runs of text or tags, that
+    /// were dropped because they came after `tdux:contentFinished`.
+    pub dropped_content: u64,
+
+    /// The largest number of bytes of rendered output files that were in
+    /// memory at once: the file being written, and the files held back
+    /// because they link forward that weren't spilled to temporary files.
+    pub peak_resident_bytes: u64,
+
+    /// The number of output files held back that were spilled to temporary
+    /// files; see [`crate::Spx2HtmlEngine::spill_threshold`].
+    pub spilled_files: usize,
+
+    /// The statistics of the rendering of the HTML templates.
+    pub rendering: RenderStats,
+}
+
+/// Statistics about the rendering of the HTML templates.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct RenderStats {
+    /// The total time spent rendering, including renders that were abandoned
+    /// because they took too long.
+    pub total_time: Duration,
+
+    /// The time taken by the slowest render.
+    pub slowest: Duration,
+
+    /// The size of the largest rendered page, in bytes, including pages that
+    /// were too large to write.
+    pub largest_page: u64,
+
+    /// The number of renders abandoned because they took longer than the
+    /// time limit.
+    pub timeouts: usize,
+
+    /// The number of rendered pages that exceeded the size limit.
+    pub oversized_pages: usize,
+}
+
+impl RenderStats {
+    /// Record a render that took *time*, and produced *size* bytes if it
+    /// finished.
+    pub(crate) fn add(&mut self, time: Duration, size: Option<usize>) {
+        self.total_time += time;
+        self.slowest = self.slowest.max(time);
+
+        if let Some(size) = size {
+            self.largest_page = self.largest_page.max(size as u64);
+        }
+    }
+
+    /// Get the number of times that one of the limits was hit.
+    pub fn limit_hits(&self) -> usize {
+        self.timeouts + self.oversized_pages
+    }
+}
+
+impl ProcessingStats {
+    /// Count a special, given its normalized text.
+    pub(crate) fn add_special(&mut self, contents: &str) {
+        let name = contents.split(' ').next().unwrap_or_default();
+        *self.specials.entry(name.to_owned()).or_default() += 1;
+    }
+
+    /// Get the total number of specials handled.
+    pub fn n_specials(&self) -> u64 {
+        self.specials.values().sum()
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    /// Summarize the statistics in one line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pages, {} specials, {} glyphs in canvases, {} fonts ({} variants), \
+             {} items of dropped content, {} bytes of pages in memory at most \
+             ({} spilled), {:.1?} rendering (slowest {:.1?}, largest {} bytes), \
+             {} rendering limits hit",
+            self.pages,
+            self.n_specials(),
+            self.canvas_glyphs,
+            self.fonts,
+            self.font_variants,
+            self.dropped_content,
+            self.peak_resident_bytes,
+            self.spilled_files,
+            self.rendering.total_time,
+            self.rendering.slowest,
+            self.rendering.largest_page,
+            self.rendering.limit_hits()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::*, Spx2HtmlEngine};
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn processing_stats() {
+        let mut engine = Spx2HtmlEngine::default();
+        engine.report_stats(true);
+        assert_eq!(engine.processing_stats(), None);
+
+        let x = glyph_for('x');
+        let (result, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:cs math")?;
+            s.handle_text_and_glyphs(1, "xx", 0, &[x, x], &[0, 10], &[0, 0])?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("b")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")?;
+            s.text("c")?;
+            s.special("tdux:as p")
+        });
+        result.unwrap();
+
+        let stats = engine.processing_stats().unwrap();
+        assert_eq!(stats.pages, 2);
+        assert_eq!(stats.specials["tdux:emit"], 2);
+        assert_eq!(stats.specials["tdux:cs"], 1);
+        assert_eq!(stats.n_specials(), 9);
+        assert_eq!(stats.canvas_glyphs, 2);
+        assert_eq!(stats.fonts, 1);
+        assert_eq!(stats.font_variants, 0);
+        assert_eq!(stats.dropped_content, 2);
+        assert!(stats.peak_resident_bytes > 0);
+        assert_eq!(stats.spilled_files, 0);
+        assert!(stats.rendering.slowest <= stats.rendering.total_time);
+        assert!(stats.rendering.largest_page > 0);
+        assert_eq!(stats.rendering.limit_hits(), 0);
+
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert_eq!(
+            status.notes,
+            [format!(
+                "2 pages, 9 specials, 2 glyphs in canvases, 1 fonts (0 variants), \
+                 2 items of dropped content, {} bytes of pages in memory at most (0 spilled), \
+                 {:.1?} rendering (slowest {:.1?}, largest {} bytes), 0 rendering limits hit",
+                stats.peak_resident_bytes,
+                stats.rendering.total_time,
+                stats.rendering.slowest,
+                stats.rendering.largest_page
+            )]
+        );
+    }
+}
@@ -0,0 +1,559 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Helpers for the unit tests.
+//!
+//! Rather than checking in binary SPX files, the tests drive the engine
+//! directly through its [`XdvEvents`] implementation, with inputs served from
+//! memory.
+
+use pinot::{FontDataRef, TableProvider};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt::Arguments,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tectonic_bridge_core::MinimalDriver;
+use tectonic_errors::prelude::*;
+use tectonic_io_base::{InputHandle, InputOrigin, IoProvider, OpenResult, OutputHandle};
+use tectonic_status_base::{MessageKind, StatusBackend};
+use tectonic_xdv::{FileType, XdvEvents};
+
+use crate::{
+    font::FontData,
+    output::{OutputSink, OutputTree},
+    stats::{ProcessingStats, RenderStats},
+    AssetManifest, EngineState, Outcome, OutputSelection, Pass, Spx2HtmlEngine,
+};
+
+/// A minimal HTML template, `page.html` in [`simple_io`].
+pub const TEMPLATE: &str = "<html><body>{{ tduxContent }}</body></html>";
+
+/// Get an I/O provider serving [`TEMPLATE`] as `page.html`.
+pub fn simple_io() -> TestIo {
+    let mut io = TestIo::new();
+    io.add("page.html", TEMPLATE);
+    io
+}
+
+/// Emit a page saying `Hello` with [`TEMPLATE`].
+pub fn simple_events(s: &mut EngineState) -> Result<()> {
+    s.special("tdux:addTemplate page.html")?;
+    s.special("tdux:setTemplate page.html")?;
+    s.text("Hello")?;
+    s.special("tdux:emit")?;
+    s.special("tdux:contentFinished")
+}
+
+/// The TeX name of the font used in the tests.
+pub const TEST_FONT: &str = "lmroman12-regular";
+
+/// The size of the test font, in TeX units.
+pub const TEST_FONT_SIZE: i32 = 12 * 65536;
+
+/// Get the ID of the glyph that the test font maps a character to.
+pub fn glyph_for(c: char) -> u16 {
+    let data = test_font_data();
+    let fd = FontDataRef::new(&data).unwrap();
+    fd.get(0).unwrap().cmap().unwrap().map(c as u32).unwrap()
+}
+
+/// Create a variant of the test font whose character map only maps *usv* to
+/// the glyph that the test font uses for *c*.
+///
+/// This provides a font covering a character that the test font lacks.
+pub fn remapped_test_font(c: char, usv: char) -> Vec<u8> {
+    let mut fd = FontData::from_opentype("remapped.otf".to_owned(), test_font_data(), 0).unwrap();
+    fd.request_alternative(glyph_for(c), usv);
+
+    let out = OutputTree::memory();
+    fd.emit_variants(&out, "f", String::new()).unwrap();
+
+    out.take_files()
+        .into_iter()
+        .find(|(name, _)| name.ends_with("-remapped.otf"))
+        .unwrap()
+        .1
+}
+
+/// Get the path to the main Tectonic test assets directory.
+pub fn test_assets_dir() -> PathBuf {
+    let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    p.push("../../tests/assets");
+    p
+}
+
+/// Load the contents of the test font.
+pub fn test_font_data() -> Vec<u8> {
+    std::fs::read(test_assets_dir().join(format!("{}.otf", TEST_FONT))).unwrap()
+}
+
+/// Check that some markup is a valid MathML `<math>` element, as far as the
+/// elements that the engine produces go, panicking if it isn't.
+pub fn check_mathml(xml: &str) {
+    const NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+
+    fn check(node: roxmltree::Node) {
+        let name = node.tag_name();
+        assert_eq!(name.namespace(), Some(NAMESPACE), "{:?}", node);
+        let children: Vec<_> = node.children().filter(|n| n.is_element()).collect();
+
+        let n_children = match name.name() {
+            "mi" | "mn" | "mo" | "mtext" => {
+                assert!(children.is_empty(), "token with children: {:?}", node);
+                assert!(!node.text().unwrap_or_default().is_empty(), "{:?}", node);
+                return;
+            }
+            "math" | "mrow" => None,
+            "msub" | "msup" => Some(2),
+            "msubsup" => Some(3),
+            other => panic!("unexpected MathML element `{}`", other),
+        };
+
+        if let Some(n) = n_children {
+            assert_eq!(children.len(), n, "{:?}", node);
+        }
+
+        for child in children {
+            check(child);
+        }
+    }
+
+    let doc = roxmltree::Document::parse(xml).unwrap();
+    assert_eq!(doc.root_element().tag_name().name(), "math");
+    check(doc.root_element());
+}
+
+/// An I/O provider serving inputs from memory, and capturing outputs there.
+///
+/// The outputs are shared by all clones of the provider.
+#[derive(Clone, Debug, Default)]
+pub struct TestIo {
+    files: HashMap<String, Vec<u8>>,
+    outputs: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl TestIo {
+    /// Create a provider that knows about the test font.
+    pub fn new() -> Self {
+        let mut io = TestIo::default();
+        io.add(format!("{}.otf", TEST_FONT), test_font_data());
+        io
+    }
+
+    /// Add an input file.
+    pub fn add(&mut self, name: impl Into<String>, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.files.insert(name.into(), data.into());
+        self
+    }
+
+    /// Get the outputs written so far, keyed by their names.
+    pub fn outputs(&self) -> BTreeMap<String, Vec<u8>> {
+        self.outputs.lock().unwrap().clone()
+    }
+}
+
+/// An output opened through a [`TestIo`].
+struct CapturedOutput {
+    name: String,
+    outputs: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut outputs = self.outputs.lock().unwrap();
+        outputs.get_mut(&self.name).unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl IoProvider for TestIo {
+    fn input_open_name(
+        &mut self,
+        name: &str,
+        _status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        match self.files.get(name) {
+            Some(data) => OpenResult::Ok(InputHandle::new(
+                name,
+                Cursor::new(data.clone()),
+                InputOrigin::Other,
+            )),
+            None => OpenResult::NotAvailable,
+        }
+    }
+
+    fn output_open_name(&mut self, name: &str) -> OpenResult<OutputHandle> {
+        self.outputs
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), Vec::new());
+        let output = CapturedOutput {
+            name: name.to_owned(),
+            outputs: self.outputs.clone(),
+        };
+        OpenResult::Ok(OutputHandle::new(name, output))
+    }
+}
+
+/// A status backend that records the messages sent to it.
+#[derive(Debug, Default)]
+pub struct CapturingStatus {
+    pub warnings: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+impl StatusBackend for CapturingStatus {
+    fn report(&mut self, kind: MessageKind, args: Arguments, _err: Option<&Error>) {
+        match kind {
+            MessageKind::Warning => self.warnings.push(args.to_string()),
+            MessageKind::Note => self.notes.push(args.to_string()),
+            MessageKind::Error => {}
+        }
+    }
+
+    fn dump_error_logs(&mut self, _output: &[u8]) {}
+}
+
+/// Builds small SPX files, for the tests that need to go through the parser.
+///
+/// The file has a single page, and its fonts are defined in the postamble.
+#[derive(Debug, Default)]
+pub struct SpxBuilder {
+    page: Vec<u8>,
+    fonts: Vec<u8>,
+}
+
+impl SpxBuilder {
+    /// Define a native font.
+    pub fn font(&mut self, font_num: i32, name: &str, size: i32) -> &mut Self {
+        self.fonts.push(252);
+        self.fonts.extend_from_slice(&font_num.to_be_bytes());
+        self.fonts.extend_from_slice(&size.to_be_bytes());
+        self.fonts.extend_from_slice(&0u16.to_be_bytes()); // flags
+        self.fonts.push(name.len() as u8);
+        self.fonts.extend_from_slice(name.as_bytes());
+        self.fonts.extend_from_slice(&0u32.to_be_bytes()); // face index
+        self
+    }
+
+    /// Add a special.
+    pub fn special(&mut self, text: &str) -> &mut Self {
+        self.page.push(242);
+        self.page
+            .extend_from_slice(&(text.len() as u32).to_be_bytes());
+        self.page.extend_from_slice(text.as_bytes());
+        self
+    }
+
+    /// Add some text, without glyphs, in a font numbered below 64.
+    pub fn text(&mut self, font_num: u8, text: &str) -> &mut Self {
+        let chars: Vec<u16> = text.encode_utf16().collect();
+        self.page.push(171 + font_num);
+        self.page.push(254);
+        self.page
+            .extend_from_slice(&(chars.len() as u16).to_be_bytes());
+
+        for c in chars {
+            self.page.extend_from_slice(&c.to_be_bytes());
+        }
+
+        self.page.extend_from_slice(&0i32.to_be_bytes()); // width
+        self.page.extend_from_slice(&0u16.to_be_bytes()); // glyphs
+        self
+    }
+
+    /// Get the contents of the SPX file.
+    pub fn build(&self) -> Vec<u8> {
+        const SPX_ID: u8 = 100;
+        let units = |spx: &mut Vec<u8>| {
+            spx.extend_from_slice(&25_400_000u32.to_be_bytes());
+            spx.extend_from_slice(&473_628_672u32.to_be_bytes());
+            spx.extend_from_slice(&1000u32.to_be_bytes()); // mag
+        };
+
+        let mut spx = vec![247, SPX_ID];
+        units(&mut spx);
+        spx.push(0); // comment
+
+        let bop = spx.len() as u32;
+        spx.push(139);
+        spx.extend_from_slice(&[0; 40]); // counters
+        spx.extend_from_slice(&(-1i32).to_be_bytes());
+        spx.extend_from_slice(&self.page);
+        spx.push(140);
+
+        let post = spx.len() as u32;
+        spx.push(248);
+        spx.extend_from_slice(&bop.to_be_bytes());
+        units(&mut spx);
+        spx.extend_from_slice(&[0; 8]); // page dimensions
+        spx.extend_from_slice(&1u16.to_be_bytes()); // stack depth
+        spx.extend_from_slice(&1u16.to_be_bytes()); // pages
+        spx.extend_from_slice(&self.fonts);
+
+        spx.push(249);
+        spx.extend_from_slice(&post.to_be_bytes());
+        spx.push(SPX_ID);
+        spx.extend_from_slice(&[0xDF; 4]);
+
+        while spx.len() % 4 != 0 {
+            spx.push(0xDF);
+        }
+
+        spx
+    }
+}
+
+/// Run the engine with the given inputs, driving it with the callback.
+///
+/// The header and the definition of the test font, as font number 1, are
+/// handled automatically. The captured status messages are returned alongside
+/// the result of the processing.
+pub fn run_engine<F>(io: TestIo, out_base: &Path, events: F) -> (Result<()>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    run_engine_with(&mut Spx2HtmlEngine::default(), io, out_base, events)
+}
+
+/// Like [`run_engine`], but using the settings of the specified engine.
+pub fn run_engine_with<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    out_base: &Path,
+    events: F,
+) -> (Result<()>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    let out = OutputTree::directory(out_base, engine.options.output.clone());
+    run_engine_into(engine, io, out, events)
+}
+
+/// Like [`run_engine_with`], but keeping the output files in memory. They're
+/// returned if processing succeeds.
+pub fn run_engine_in_memory<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    events: F,
+) -> (Result<HashMap<String, Vec<u8>>>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    let out = OutputTree::memory();
+    let (result, status) = run_engine_into(engine, io, out.clone(), events);
+    (result.map(|_| out.take_files()), status)
+}
+
+/// Like [`run_engine_with`], but handing the output files to a sink.
+pub fn run_engine_to_sink<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    sink: Box<dyn OutputSink>,
+    events: F,
+) -> (Result<()>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    run_engine_into(engine, io, OutputTree::sink(sink), events)
+}
+
+/// Like [`run_engine_with`], but writing to the given output tree.
+pub fn run_engine_into<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    out: OutputTree,
+    events: F,
+) -> (Result<()>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    let (result, status) = drive_engine(engine, io, out, Pass::Process, events);
+    (result.map(|_| ()), status)
+}
+
+/// Like [`run_engine_with`], but only scanning the document for its assets,
+/// as [`Spx2HtmlEngine::process_for_assets`] does.
+pub fn run_engine_for_assets<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    events: F,
+) -> (Result<AssetManifest>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    let (result, status) = drive_engine(engine, io, OutputTree::memory(), Pass::Scan, events);
+    (result.map(|o| o.assets), status)
+}
+
+/// Like [`run_engine_with`], but only validating the document, as
+/// [`Spx2HtmlEngine::validate`] does. Returns the number of pages.
+pub fn run_engine_validating<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    events: F,
+) -> (Result<usize>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    let (result, status) = drive_engine(engine, io, OutputTree::memory(), Pass::Validate, events);
+    (result.map(|o| o.n_pages), status)
+}
+
+fn drive_engine<F>(
+    engine: &mut Spx2HtmlEngine,
+    io: TestIo,
+    out: OutputTree,
+    pass: Pass,
+    events: F,
+) -> (Result<Outcome>, CapturingStatus)
+where
+    F: FnOnce(&mut EngineState) -> Result<()>,
+{
+    let mut hooks = MinimalDriver::new(io);
+    let mut status = CapturingStatus::default();
+    let render_stats = Rc::new(Cell::new(RenderStats::default()));
+
+    let result = (|| {
+        engine.read_asset_streams()?;
+        let mut state = EngineState::new(
+            &mut hooks,
+            &mut status,
+            &engine.options,
+            crate::borrow_progress_sink(&mut engine.progress),
+            crate::borrow_remote_fetcher(&mut engine.remote_fetcher),
+            crate::borrow_post_processor(&mut engine.post_processor),
+            out.clone(),
+        );
+        state.common.pass = pass;
+        state.common.render_stats = render_stats.clone();
+        state.common.special_handlers = &mut engine.special_handlers;
+
+        if pass != Pass::Process {
+            out.select(OutputSelection::Nothing);
+        }
+
+        state.handle_header(FileType::Spx, b"")?;
+        state.handle_define_native_font(TEST_FONT, 1, TEST_FONT_SIZE, 0, None, None, None, None)?;
+        events(&mut state)?;
+        let outcome = state.finished()?;
+        out.finish()?;
+
+        if pass != Pass::Process {
+            assert!(out.take_files().is_empty());
+        }
+
+        Ok(outcome)
+    })();
+
+    match &result {
+        Ok(outcome) => {
+            engine.body_font_size_used = outcome.body_font_size;
+            engine.stats = Some(outcome.stats.clone());
+        }
+
+        Err(_) => {
+            engine.stats = Some(ProcessingStats {
+                rendering: render_stats.get(),
+                ..Default::default()
+            });
+        }
+    }
+
+    (result, status)
+}
+
+/// Convenience extension methods for driving the engine in tests.
+pub trait EventsExt {
+    /// Handle a special.
+    fn special(&mut self, text: &str) -> Result<()>;
+
+    /// Handle a run of text in the test font, outside of any canvas.
+    fn text(&mut self, text: &str) -> Result<()>;
+
+    /// Handle a run of text in the specified font, outside of any canvas.
+    fn text_in(&mut self, font_num: i32, text: &str) -> Result<()>;
+
+    /// Define an additional font number using the test font.
+    fn define_test_font(&mut self, font_num: i32, size: i32) -> Result<()>;
+}
+
+impl<'a> EventsExt for EngineState<'a> {
+    fn special(&mut self, text: &str) -> Result<()> {
+        self.handle_special(0, 0, text.as_bytes())
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        self.text_in(1, text)
+    }
+
+    fn text_in(&mut self, font_num: i32, text: &str) -> Result<()> {
+        self.handle_text_and_glyphs(font_num, text, 0, &[], &[], &[])
+    }
+
+    fn define_test_font(&mut self, font_num: i32, size: i32) -> Result<()> {
+        self.handle_define_native_font(TEST_FONT, font_num, size, 0, None, None, None, None)
+    }
+}
+
+/// The global allocator of the test binary, which keeps track of the number
+/// of bytes allocated, for tests of memory use.
+///
+/// The count covers all threads, so it's only meaningful in tests that run
+/// on their own, such as the ones that are ignored by default.
+#[global_allocator]
+pub static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// An allocator that counts the bytes allocated with the system allocator.
+pub struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+
+        if !ptr.is_null() {
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_ALLOCATED.fetch_max(now, Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+impl CountingAllocator {
+    /// Start measuring the peak number of bytes allocated from now.
+    pub fn reset_peak(&self) {
+        PEAK_ALLOCATED.store(ALLOCATED.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Get the number of bytes allocated now.
+    pub fn allocated(&self) -> usize {
+        ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    /// Get the peak number of bytes allocated since [`Self::reset_peak`].
+    pub fn peak(&self) -> usize {
+        PEAK_ALLOCATED.load(Ordering::Relaxed)
+    }
+}
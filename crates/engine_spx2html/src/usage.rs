@@ -0,0 +1,295 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Reporting how the document uses its fonts.
+//!
+//! Documents often load fonts "just in case", and every loaded font ends up
+//! in the HTML output. The engine knows exactly which fonts the emitted
+//! content used, so when the content is finished it assembles a
+//! [`FontUsageReport`] listing, for each font file, how much it was used and
+//! what it cost. The report is delivered to the
+//! [`crate::progress::ProgressSink`], and can also be written out as JSON with
+//! [`crate::Spx2HtmlEngine::font_usage_report`], so that authors, or tools
+//! on the TeX side, can prune the fonts that aren't needed.
+//!
+//! The JSON form is stable: fields won't be renamed or removed without
+//! bumping [`FONT_USAGE_VERSION`], but new ones may be added. Its contents
+//! are deterministic, so reports from different runs can be compared with
+//! ordinary diffs.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::html::FontLookup;
+
+/// The version of the font usage report format.
+pub const FONT_USAGE_VERSION: u32 = 1;
+
+/// How the document uses its fonts.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FontUsageReport {
+    /// The version of the report format, [`FONT_USAGE_VERSION`].
+    pub version: u32,
+
+    /// The usage of each font file, in the order in which the fonts were
+    /// loaded.
+    pub fonts: Vec<FontUsage>,
+}
+
+/// How the document uses one font file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FontUsage {
+    /// The name of the font, as it was opened through the I/O layer.
+    pub name: String,
+
+    /// The file name of the font.
+    pub family: String,
+
+    /// The index of the face within the font file.
+    pub face_index: u32,
+
+    /// The sizes at which the document defined the font, in TeX scaled points
+    /// (1/65536 of a point), smallest first.
+    pub sizes: Vec<i32>,
+
+    /// Whether any glyphs of the emitted content were set in the font.
+    pub used: bool,
+
+    /// The number of glyphs of the emitted content set in the font, whether
+    /// as text or in canvases.
+    pub glyphs: u64,
+
+    /// The output paths of the files whose content used the font, in the
+    /// order in which the files were emitted. Every file refers to the main
+    /// body font, but it's only listed for those that used it.
+    pub pages: Vec<String>,
+
+    /// The number of bytes of font files written to the output for the font,
+    /// including its variants.
+    pub emitted_bytes: u64,
+
+    /// The number of variants of the font, with customized character maps,
+    /// that were needed to display glyphs that the browser can't otherwise
+    /// reach.
+    pub variants: usize,
+}
+
+/// Tallies the use of the fonts.
+#[derive(Debug, Default)]
+pub(crate) struct UsageTally {
+    /// The number of glyphs set in each font, keyed by font data key.
+    glyphs: HashMap<usize, u64>,
+
+    /// The output files whose content used each font, keyed by font data
+    /// key.
+    pages: HashMap<usize, Vec<String>>,
+
+    /// The number of bytes of the variants of each font last written, keyed
+    /// by font data key.
+    variant_bytes: HashMap<usize, u64>,
+}
+
+impl UsageTally {
+    /// Tally glyphs set in a font.
+    pub fn add_glyphs(&mut self, fd_key: usize, n: usize) {
+        *self.glyphs.entry(fd_key).or_default() += n as u64;
+    }
+
+    /// Record that the content of the file emitted at *path* used a font.
+    pub fn add_page(&mut self, fd_key: usize, path: &str) {
+        let pages = self.pages.entry(fd_key).or_default();
+
+        if pages.last().map(|p| p.as_str()) != Some(path) {
+            pages.push(path.to_owned());
+        }
+    }
+
+    /// Record the number of bytes of the variants written for a font. This
+    /// replaces any earlier number, since the variants are written anew
+    /// after `tdux:resetContent`.
+    pub fn set_variant_bytes(&mut self, fd_key: usize, n: u64) {
+        self.variant_bytes.insert(fd_key, n);
+    }
+
+    /// Report the use of the fonts. The fonts are listed in order of their
+    /// font data keys.
+    ///
+    /// The emitted bytes account for the unmodified font files, which are
+    /// taken to have been written if *copied* is true, and the variants
+    /// recorded with [`Self::set_variant_bytes`].
+    pub fn report(&self, fonts: &FontLookup, copied: bool) -> FontUsageReport {
+        let entries = fonts
+            .font_data
+            .iter()
+            .map(|(fd_key, fd)| {
+                let mut sizes: Vec<_> = fonts
+                    .fonts
+                    .values()
+                    .filter(|fi| fi.fd_key == *fd_key)
+                    .map(|fi| fi.size)
+                    .collect();
+                sizes.sort_unstable();
+                sizes.dedup();
+
+                let glyphs = self.glyphs.get(fd_key).copied().unwrap_or_default();
+                let variant_bytes = self.variant_bytes.get(fd_key).copied().unwrap_or_default();
+                let (name, face_index) = fonts.input(*fd_key).unwrap_or_default();
+
+                FontUsage {
+                    name: name.to_owned(),
+                    family: fd.basename().to_owned(),
+                    face_index,
+                    sizes,
+                    used: glyphs > 0,
+                    glyphs,
+                    pages: self.pages.get(fd_key).cloned().unwrap_or_default(),
+                    emitted_bytes: if copied { fd.file_size() } else { 0 } + variant_bytes,
+                    variants: fd.n_variants(),
+                }
+            })
+            .collect();
+
+        FontUsageReport {
+            version: FONT_USAGE_VERSION,
+            fonts: entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{progress::ProgressSink, test_util::*, EngineState, Spx2HtmlEngine, Spx2HtmlError};
+    use std::sync::{Arc, Mutex};
+    use tectonic_errors::prelude::*;
+    use tectonic_xdv::XdvEvents;
+
+    #[test]
+    fn font_usage() {
+        #[derive(Default)]
+        struct UsageSink(Arc<Mutex<Option<FontUsageReport>>>);
+
+        impl ProgressSink for UsageSink {
+            fn on_font_usage(&mut self, report: &FontUsageReport) -> Result<()> {
+                *self.0.lock().unwrap() = Some(report.clone());
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let report_path = tempdir.path().join("fonts.json");
+        let out = tempdir.path().join("out");
+        let mut io = simple_io();
+        io.add("second.otf", test_font_data());
+        io.add("unused.otf", test_font_data());
+
+        let reported = Arc::new(Mutex::new(None));
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .font_usage_report(Some(report_path.clone()))
+            .progress_sink(Box::new(UsageSink(reported.clone())));
+
+        let x = glyph_for('x');
+        let (result, _status) = run_engine_with(&mut engine, io, &out, |s| {
+            s.handle_define_native_font("unused", 3, TEST_FONT_SIZE, 0, None, None, None, None)?;
+            s.handle_define_native_font("second", 2, TEST_FONT_SIZE, 0, None, None, None, None)?;
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.handle_text_and_glyphs(1, "xx", 0, &[x, x], &[0, 100], &[0, 0])?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.handle_text_and_glyphs(2, "x", 0, &[x], &[0], &[0])?;
+            s.handle_text_and_glyphs(1, "x", 0, &[x], &[0], &[0])?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        result.unwrap();
+
+        let report = reported.lock().unwrap().take().unwrap();
+        assert_eq!(report.version, FONT_USAGE_VERSION);
+
+        let summary: Vec<_> = report
+            .fonts
+            .iter()
+            .map(|f| {
+                format!(
+                    "{} {:?} {} {} {}",
+                    f.name,
+                    f.sizes,
+                    f.used,
+                    f.glyphs,
+                    f.pages.join(",")
+                )
+            })
+            .collect();
+        let size = TEST_FONT_SIZE;
+        assert_eq!(
+            summary,
+            [
+                format!("{}.otf [{}] true 3 index.html,two.html", TEST_FONT, size),
+                format!("unused.otf [{}] false 0 ", size),
+                format!("second.otf [{}] true 1 two.html", size),
+            ]
+        );
+        assert!(report.fonts.iter().all(|f| f.emitted_bytes > 0));
+
+        let json = std::fs::read_to_string(&report_path).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["version"], 1);
+        assert_eq!(parsed["fonts"][1]["used"], false);
+        assert_eq!(parsed["fonts"][2]["pages"][0], "two.html");
+    }
+
+    #[test]
+    fn unfinished_content() {
+        struct CompletionSink(Arc<Mutex<Vec<bool>>>);
+
+        impl ProgressSink for CompletionSink {
+            fn on_completed(&mut self, clean: bool) -> Result<()> {
+                self.0.lock().unwrap().push(clean);
+                Ok(())
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let report = tempdir.path().join("fonts.json");
+        let completions = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Spx2HtmlEngine::default();
+        engine
+            .font_usage_report(Some(report.clone()))
+            .progress_sink(Box::new(CompletionSink(completions.clone())));
+
+        // The stream ends right after the last page.
+        let events = |s: &mut EngineState| -> Result<()> {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("Hello")?;
+            s.special("tdux:emit")?;
+            s.text("Again")?;
+            s.special("tdux:setOutputPath two.html")
+        };
+
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), events);
+        let files = files.unwrap();
+
+        assert_eq!(status.warnings.len(), 1, "{:?}", status.warnings);
+        assert!(status.warnings[0].starts_with("the document ended without `tdux:contentFinished`"));
+        assert_eq!(files["two.html"], b"<html><body>Again</body></html>");
+        assert!(files.contains_key(&format!("{}.otf", TEST_FONT)));
+        assert!(report.exists());
+
+        // Documents that finish their content are distinguished.
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), simple_events);
+        files.unwrap();
+        assert!(status.warnings.is_empty());
+        assert_eq!(*completions.lock().unwrap(), [false, true]);
+
+        // In strict mode, unfinished content is an error.
+        engine.strict(true);
+        let (result, _status) = run_engine_in_memory(&mut engine, simple_io(), events);
+        assert!(matches!(
+            Spx2HtmlError::from_error(&result.unwrap_err()),
+            Some(Spx2HtmlError::ContentNotFinished)
+        ));
+    }
+}
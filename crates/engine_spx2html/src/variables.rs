@@ -0,0 +1,192 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Scopes of template variables.
+//!
+//! `tdux:pushVariableScope` ... `tdux:popVariableScope` delimit a group of
+//! changes to the template variables, made with `tdux:setTemplateVariable`
+//! and `tdux:unsetTemplateVariable`, that are reverted wholesale at the end of
+//! the scope, such as the variables of a section. Scopes nest. The first time
+//! that a variable changes within a scope, its previous value is saved, so
+//! that it can be restored when the scope is popped.
+
+/// The stack of open variable scopes.
+#[derive(Debug)]
+pub struct VariableScopes<V> {
+    /// The saved values of the variables changed in each scope, innermost
+    /// last, or None for variables that weren't defined.
+    scopes: Vec<Vec<(String, Option<V>)>>,
+}
+
+impl<V> Default for VariableScopes<V> {
+    fn default() -> Self {
+        VariableScopes { scopes: Vec::new() }
+    }
+}
+
+impl<V> VariableScopes<V> {
+    /// Open a new scope.
+    pub fn push(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Close the innermost scope, returning the variables to restore, in the
+    /// order in which they should be restored, or None if no scope is open.
+    pub fn pop(&mut self) -> Option<impl Iterator<Item = (String, Option<V>)>> {
+        self.scopes.pop().map(|saved| saved.into_iter().rev())
+    }
+
+    /// Note that a variable is about to change. If this is its first change
+    /// in the innermost scope, its current value, obtained with *current*, is
+    /// saved.
+    pub fn save(&mut self, name: &str, current: impl FnOnce() -> Option<V>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            if !scope.iter().any(|(n, _)| n == name) {
+                scope.push((name.to_owned(), current()));
+            }
+        }
+    }
+
+    /// Convert the saved values of all of the open scopes.
+    pub fn map<W>(self, mut f: impl FnMut(&str, Option<V>) -> Option<W>) -> VariableScopes<W> {
+        VariableScopes {
+            scopes: self
+                .scopes
+                .into_iter()
+                .map(|scope| {
+                    scope
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let value = f(&name, value);
+                            (name, value)
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::*, Spx2HtmlEngine};
+
+    #[test]
+    fn saves_first_change() {
+        let mut scopes = VariableScopes::default();
+        scopes.save("ignored", || Some(0));
+        scopes.push();
+        scopes.save("a", || Some(1));
+        scopes.save("a", || Some(2));
+        scopes.save("b", || None);
+        scopes.push();
+        scopes.save("a", || Some(3));
+
+        assert_eq!(
+            scopes.pop().unwrap().collect::<Vec<_>>(),
+            [("a".to_owned(), Some(3))]
+        );
+        assert_eq!(
+            scopes.pop().unwrap().collect::<Vec<_>>(),
+            [("b".to_owned(), None), ("a".to_owned(), Some(1))]
+        );
+        assert!(scopes.pop().is_none());
+    }
+
+    #[test]
+    fn page_meta() {
+        let mut io = simple_io();
+        io.add(
+            "meta.html",
+            "<title>{{ tduxPageMeta.title | default(value=\"Untitled\") | escape }}</title>\
+             {% if tduxPageMeta.canonical %}<link rel=\"canonical\" href=\"{{ tduxPageMeta.canonical }}\">{% endif %}\
+             {{ tduxContent }}",
+        );
+
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate meta.html")?;
+            s.special("tdux:setTemplate meta.html")?;
+            s.special("tdux:setPageMeta title The  First Page")?;
+            s.special("tdux:setPageMeta canonical https://example.com/one")?;
+            s.text("one")?;
+            s.special("tdux:setOutputPath one.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:setPageMeta title Second & Last")?;
+            s.text("two")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.special("tdux:emit")?;
+            s.text("three")?;
+            s.special("tdux:setOutputPath three.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+        assert_eq!(status.warnings, Vec::<String>::new());
+
+        // Each page gets its own metadata, which doesn't carry over.
+        assert_eq!(
+            files["one.html"],
+            b"<title>The  First Page</title>\
+              <link rel=\"canonical\" href=\"https://example.com/one\">one"
+        );
+        assert_eq!(files["two.html"], b"<title>Second &amp; Last</title>two");
+        assert_eq!(files["three.html"], b"<title>Untitled</title>three");
+    }
+
+    #[test]
+    fn variable_scopes() {
+        let mut io = simple_io();
+        io.add(
+            "vars.html",
+            "{{ title | default(value=\"-\") }}/{{ site | default(value=\"-\") }}|{{ tduxContent }}",
+        );
+
+        let mut engine = Spx2HtmlEngine::default();
+        engine.set_variable("site", "Site".into());
+        let (files, status) = run_engine_in_memory(&mut engine, io, |s| {
+            s.special("tdux:addTemplate vars.html")?;
+            s.special("tdux:setTemplate vars.html")?;
+            s.special("tdux:setTemplateVariable title One")?;
+            s.special("tdux:pushVariableScope")?;
+            s.special("tdux:unsetTemplateVariable site")?;
+            s.text("A")?;
+            s.special("tdux:emit")?;
+
+            // The scope opened during initialization restores the driver's
+            // value.
+            s.special("tdux:popVariableScope")?;
+            s.special("tdux:unsetTemplateVariable title")?;
+            s.special("tdux:setOutputPath two.html")?;
+            s.text("B")?;
+            s.special("tdux:emit")?;
+
+            s.special("tdux:pushVariableScope")?;
+            s.special("tdux:setTemplateVariable title Three")?;
+            s.special("tdux:setTemplateVariable title Again")?;
+            s.special("tdux:unsetTemplateVariable site")?;
+            s.special("tdux:setOutputPath three.html")?;
+            s.text("C")?;
+            s.special("tdux:emit")?;
+
+            s.special("tdux:popVariableScope")?;
+            s.special("tdux:popVariableScope")?;
+            s.special("tdux:unsetTemplateVariable title")?;
+            s.special("tdux:setOutputPath four.html")?;
+            s.text("D")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(files["index.html"], b"One/-|A");
+        assert_eq!(files["two.html"], b"-/Site|B");
+        assert_eq!(files["three.html"], b"Again/-|C");
+        assert_eq!(files["four.html"], b"-/Site|D");
+        assert_eq!(
+            status.warnings,
+            ["ignoring tdux:popVariableScope special with no matching tdux:pushVariableScope"]
+        );
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright 2022 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Cross-references between positions in the content.
+//!
+//! `tdux:anchor <name>` marks a position that links can point to, and
+//! `tdux:refStart <name>` ... `tdux:refEnd` make the content in between into
+//! a link to the anchor with that name, like LaTeX's `\label` and `\ref`.
+//! The anchor may well be in another output file than the link, even one
+//! emitted later, so links are written with placeholder URLs, which are
+//! resolved once the file containing them has been rendered. A file with
+//! links to anchors that haven't been emitted yet is held back until the
+//! content is finished, when all of the anchors are known. Held files are
+//! kept in a [`crate::spill::SpillArea`], which may keep them on disk.
+//!
+//! The same mechanism makes URLs of other files in the output, such as the
+//! images of `tdux:img`, relative to the file that refers to them, and
+//! resolves the URLs of the entries of the table of contents given with
+//! `tdux:tocEntry`.
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashMap;
+
+use crate::{output, spill::SetAside, UrlStyle};
+
+/// The characters that are percent-encoded in the ids of anchors, so that
+/// the ids contain no whitespace and can be used in URLs as they are.
+const ID_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`');
+
+/// The character that delimits the placeholders of link URLs. It's a
+/// noncharacter, which documents have no business using.
+const PLACEHOLDER_MARK: char = '\u{fdd0}';
+
+/// The placeholder for the relative path from an output file to the top of
+/// the output tree, which is empty between the marks.
+const TOP_PLACEHOLDER: &str = "\u{fdd0}\u{fdd0}";
+
+/// An output file that was held back because of unresolved links.
+#[derive(Debug)]
+pub struct HeldPage {
+    /// The normalized output path of the file.
+    pub path: String,
+
+    /// The rendered file, with placeholders.
+    pub html: SetAside,
+
+    /// Whether the file replaces one emitted earlier at the same path.
+    pub rewrite: bool,
+
+    /// Whether the file was emitted with `tdux:emitIfChanged`.
+    pub if_changed: bool,
+}
+
+/// Tracks the anchors and the links to them.
+#[derive(Debug, Default)]
+pub struct CrossRefs {
+    /// The names and ids of the anchors in the output file being built.
+    page_anchors: Vec<(String, String)>,
+
+    /// The output path and id of each anchor emitted so far, keyed by name.
+    anchors: HashMap<String, (String, String)>,
+
+    /// The names of the anchors that links point to, indexed by the numbers
+    /// in their placeholders.
+    targets: Vec<String>,
+
+    /// The placeholder numbers of the names in `targets`.
+    target_numbers: HashMap<String, usize>,
+
+    /// The output files held back because of unresolved links.
+    held: Vec<HeldPage>,
+}
+
+/// Get the id of the anchor with the given name.
+fn anchor_id(name: &str) -> String {
+    utf8_percent_encode(name, ID_ENCODE_SET).to_string()
+}
+
+impl CrossRefs {
+    /// Add an anchor to the output file being built, returning its id, or
+    /// None if there's already an anchor by that name.
+    pub fn add_anchor(&mut self, name: &str) -> Option<String> {
+        if self.anchors.contains_key(name) || self.page_anchors.iter().any(|(n, _)| n == name) {
+            return None;
+        }
+
+        let id = anchor_id(name);
+        self.page_anchors.push((name.to_owned(), id.clone()));
+        Some(id)
+    }
+
+    /// Get the placeholder URL of a link to the anchor with the given name.
+    pub fn placeholder(&mut self, name: &str) -> String {
+        let n = match self.target_numbers.get(name) {
+            Some(n) => *n,
+            None => {
+                self.targets.push(name.to_owned());
+                self.target_numbers
+                    .insert(name.to_owned(), self.targets.len() - 1);
+                self.targets.len() - 1
+            }
+        };
+
+        format!("{}{}{}", PLACEHOLDER_MARK, n, PLACEHOLDER_MARK)
+    }
+
+    /// Get the placeholder for the relative path from the output file being
+    /// built to the top of the output tree, like `tduxRelTop`.
+    pub fn top_placeholder(&self) -> &'static str {
+        TOP_PLACEHOLDER
+    }
+
+    /// Record that the output file being built is emitted at the normalized
+    /// path *page*, along with its anchors.
+    pub fn finish_page(&mut self, page: &str) {
+        for (name, id) in self.page_anchors.drain(..) {
+            self.anchors.insert(name, (page.to_owned(), id));
+        }
+    }
+
+    /// Resolve the placeholders of the links in the output file at the
+    /// normalized path *page*.
+    ///
+    /// Links to anchors that haven't been emitted point to the fragment that
+    /// the anchor would have had. The names of those anchors are returned
+    /// along with the resolved file, so that the caller can hold the file
+    /// back until the content is finished, and warn about them then.
+    pub fn resolve(&self, html: &str, page: &str, style: UrlStyle) -> (String, Vec<String>) {
+        let mut parts = html.split(PLACEHOLDER_MARK);
+        let mut resolved = parts.next().unwrap_or_default().to_owned();
+        let mut unresolved = Vec::new();
+        let rel_top = "../".repeat(page.split('/').count() - 1);
+
+        while let Some(part) = parts.next() {
+            let rest = parts.next();
+
+            if let (true, Some(rest)) = (part.is_empty(), rest) {
+                resolved.push_str(&rel_top);
+                resolved.push_str(rest);
+                continue;
+            }
+
+            let name = part.parse::<usize>().ok().and_then(|n| self.targets.get(n));
+
+            let (name, rest) = match (name, rest) {
+                (Some(name), Some(rest)) => (name, rest),
+                // Not one of our placeholders, after all.
+                _ => {
+                    resolved.push(PLACEHOLDER_MARK);
+                    resolved.push_str(part);
+
+                    if let Some(rest) = rest {
+                        resolved.push(PLACEHOLDER_MARK);
+                        resolved.push_str(rest);
+                    }
+
+                    continue;
+                }
+            };
+
+            match self.anchors.get(name) {
+                Some((path, id)) if path == page => {
+                    resolved.push('#');
+                    resolved.push_str(id);
+                }
+
+                Some((path, id)) => {
+                    let url = output::page_url(style, path);
+                    resolved.push_str(&rel_top);
+
+                    // The top-level index is just `../` from below.
+                    if url != "./" || rel_top.is_empty() {
+                        resolved.push_str(&url);
+                    }
+
+                    resolved.push('#');
+                    resolved.push_str(id);
+                }
+
+                None => {
+                    resolved.push('#');
+                    resolved.push_str(&anchor_id(name));
+
+                    if !unresolved.contains(name) {
+                        unresolved.push(name.clone());
+                    }
+                }
+            }
+
+            resolved.push_str(rest);
+        }
+
+        (resolved, unresolved)
+    }
+
+    /// Hold back an output file until the content is finished.
+    pub fn hold(&mut self, page: HeldPage) {
+        self.held.push(page);
+    }
+
+    /// Take out any held-back output file at the normalized path *page*,
+    /// because it's being replaced.
+    pub fn drop_held(&mut self, page: &str) -> Option<HeldPage> {
+        let index = self.held.iter().position(|p| p.path == page)?;
+        Some(self.held.remove(index))
+    }
+
+    /// Take the held-back output files, in the order in which they were
+    /// emitted.
+    pub fn take_held(&mut self) -> Vec<HeldPage> {
+        std::mem::take(&mut self.held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::*, Spx2HtmlEngine};
+
+    #[test]
+    fn cross_references() {
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("a")?;
+            s.special("tdux:anchor top")?;
+            s.special("tdux:refStart sec:two words")?;
+            s.text("fwd")?;
+            s.special("tdux:refEnd")?;
+            s.special("tdux:refStart top")?;
+            s.text("self")?;
+            s.special("tdux:refEnd")?;
+            s.special("tdux:refStart nowhere")?;
+            s.text("lost")?;
+            s.special("tdux:refEnd")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:anchor sec:two words")?;
+            s.special("tdux:anchor top")?;
+            s.special("tdux:refStart top")?;
+            s.text("back")?;
+            s.special("tdux:refEnd")?;
+            s.special("tdux:setOutputPath ch/two.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec![
+                "ignoring special `tdux:anchor top`: there's already an anchor by that name",
+                "`index.html` links to anchor `nowhere`, which was never emitted",
+            ]
+        );
+
+        // The first page links forward, so it's held back until the end.
+        assert_eq!(
+            String::from_utf8_lossy(&files["index.html"]),
+            "<html><body>a<span id=\"top\"></span>\
+             <a href=\"ch/two.html#sec:two%20words\">fwd</a>\
+             <a href=\"#top\">self</a><a href=\"#nowhere\">lost</a></body></html>"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&files["ch/two.html"]),
+            "<html><body><span id=\"sec:two%20words\"></span>\
+             <a href=\"../index.html#top\">back</a></body></html>"
+        );
+    }
+
+    #[test]
+    fn external_links() {
+        let mut engine = Spx2HtmlEngine::default();
+        let (files, status) = run_engine_in_memory(&mut engine, simple_io(), |s| {
+            s.special("tdux:addTemplate page.html")?;
+            s.special("tdux:setTemplate page.html")?;
+            s.text("see")?;
+            s.special("tdux:hrefStart https://example.com/a%20b?x=1&y=\"2\"")?;
+            s.text("here")?;
+            s.special("tdux:hrefEnd")?;
+            s.special("tdux:hrefEnd")?;
+            s.special("tdux:hrefStart https://example.com/")?;
+            s.special("tdux:cs math")?;
+            s.text("x")?;
+            s.special("tdux:hrefEnd")?;
+            s.special("tdux:ce math")?;
+            s.special("tdux:hrefStart https://example.com/")?;
+            s.text("open")?;
+            s.special("tdux:setOutputPath index.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            vec![
+                "ignoring tdux:hrefEnd special with no matching tdux:hrefStart",
+                "closing 1 link(s) with no tdux:hrefEnd special at the end of `index.html`",
+            ]
+        );
+
+        let html = String::from_utf8_lossy(&files["index.html"]);
+        assert!(html.starts_with(
+            "<html><body>see<a href=\"https:&#x2F;&#x2F;example.com&#x2F;a%20b?x=1&amp;y=&quot;2&quot;\">\
+             here</a>"
+        ));
+        assert_eq!(html.matches("<a ").count(), 3);
+        assert_eq!(html.matches("</a>").count(), 3);
+        assert!(html.ends_with("open</a></body></html>"));
+        roxmltree::Document::parse(&html).unwrap();
+    }
+
+    #[test]
+    fn table_of_contents() {
+        let mut io = simple_io();
+        io.add(
+            "toc.html",
+            "{% for e in tduxToc %}{{ e.level }}<a href=\"{{ e.url }}\">{{ e.title }}</a> \
+             {% endfor %}| {{ tduxContent }}",
+        );
+
+        let (files, status) = run_engine_in_memory(&mut Spx2HtmlEngine::default(), io, |s| {
+            // Entries given up front, before the files with their anchors.
+            s.special("tdux:tocEntry 0 intro Introduction")?;
+            s.special("tdux:tocEntry 1 sec:one First steps")?;
+            s.special("tdux:tocEntry 1 \"sec two\" Going \"further\"")?;
+            s.special("tdux:tocEntry x sec:one Bad level")?;
+            s.special("tdux:addTemplate toc.html")?;
+            s.special("tdux:setTemplate toc.html")?;
+            s.text("a")?;
+            s.special("tdux:anchor intro")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:anchor sec:one")?;
+            s.text("b")?;
+            s.special("tdux:tocEntry 2 late Late")?;
+            s.special("tdux:setOutputPath ch/one.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:anchor sec two")?;
+            s.special("tdux:anchor late")?;
+            s.text("c")?;
+            s.special("tdux:setOutputPath ch/two.html")?;
+            s.special("tdux:emit")?;
+            s.special("tdux:contentFinished")
+        });
+        let files = files.unwrap();
+
+        assert_eq!(
+            status.warnings,
+            [
+                "ignoring malformatted tdux:tocEntry special `x sec:one Bad level`: \
+              its level `x` isn't an integer"
+            ]
+        );
+
+        let toc = |path: &str| {
+            let html = String::from_utf8_lossy(&files[path]).into_owned();
+            html.split_once("| ").unwrap().0.to_owned()
+        };
+
+        // The first two files link forward, so they're held back until the
+        // content is finished; an entry only appears in the files emitted
+        // after it's given.
+        assert_eq!(
+            toc("index.html"),
+            "0<a href=\"#intro\">Introduction</a> \
+             1<a href=\"ch/one.html#sec:one\">First steps</a> \
+             1<a href=\"ch/two.html#sec%20two\">Going \"further\"</a> "
+        );
+        assert_eq!(
+            toc("ch/one.html"),
+            "0<a href=\"../index.html#intro\">Introduction</a> \
+             1<a href=\"#sec:one\">First steps</a> \
+             1<a href=\"../ch/two.html#sec%20two\">Going \"further\"</a> \
+             2<a href=\"../ch/two.html#late\">Late</a> "
+        );
+        assert_eq!(
+            toc("ch/two.html"),
+            "0<a href=\"../index.html#intro\">Introduction</a> \
+             1<a href=\"../ch/one.html#sec:one\">First steps</a> \
+             1<a href=\"#sec%20two\">Going \"further\"</a> \
+             2<a href=\"#late\">Late</a> "
+        );
+    }
+}